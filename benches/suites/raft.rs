@@ -3,7 +3,7 @@
 use crate::DEFAULT_RAFT_SETS;
 use criterion::Criterion;
 use raft::eraftpb::ConfState;
-use raft::{storage::MemStorage, Config, Raft};
+use raft::{storage::MemStorage, CampaignType, Config, Raft};
 
 pub fn bench_raft(c: &mut Criterion) {
     bench_raft_new(c);
@@ -42,22 +42,24 @@ pub fn bench_raft_campaign(c: &mut Criterion) {
         .iter()
         .skip(1)
         .for_each(|(voters, learners)| {
-            // We don't want to make `raft::raft` public at this point.
-            let msgs = &[
-                "CampaignPreElection",
-                "CampaignElection",
-                "CampaignTransfer",
+            let campaign_types = &[
+                CampaignType::PreElection,
+                CampaignType::Election,
+                CampaignType::Transfer,
             ];
             // Skip the first since it's 0,0
-            for msg in msgs {
+            for campaign_type in campaign_types {
                 c.bench_function(
-                    &format!("Raft::campaign ({}, {}, {})", voters, learners, msg),
+                    &format!(
+                        "Raft::campaign ({}, {}, {:?})",
+                        voters, learners, campaign_type
+                    ),
                     move |b| {
                         let logger = raft::default_logger();
                         let storage = new_storage(*voters, *learners);
                         b.iter(|| {
                             let mut raft = quick_raft(storage.clone(), &logger);
-                            raft.campaign(msg.as_bytes());
+                            raft.campaign(*campaign_type);
                         })
                     },
                 );
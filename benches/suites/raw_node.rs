@@ -115,8 +115,8 @@ fn test_ready_raft_node(logger: &slog::Logger) -> RawNode<MemStorage> {
     let mut entries = vec![];
     for i in 1..101 {
         let mut e = Entry::default();
-        e.data = vec![0; 32 * 1024];
-        e.context = vec![];
+        e.data = vec![0; 32 * 1024].into();
+        e.context = vec![].into();
         e.index = i;
         e.term = 1;
         entries.push(e);
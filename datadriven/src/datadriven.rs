@@ -145,17 +145,16 @@ where
     }
 
     // test mode
-    if r.rewrite_buffer == None {
+    if r.rewrite_buffer.is_none() {
         assert_diff!(&actual, &r.data.expected, "\n", 0);
     } else {
         r.emit("----");
         if has_blank_line(&actual) {
             r.emit("----");
 
-            r.rewrite_buffer.as_mut().map(|rb| {
+            if let Some(rb) = r.rewrite_buffer.as_mut() {
                 rb.push_str(&actual);
-                rb
-            });
+            }
 
             r.emit("----");
             r.emit("----");
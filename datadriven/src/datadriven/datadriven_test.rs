@@ -239,13 +239,13 @@ fn test_unknown_data() {
 #[test]
 fn test_blank_line() {
     let str = "123\n  123\n";
-    assert_eq!(has_blank_line(str), false);
+    assert!(!has_blank_line(str));
     let str = "123\n  \n123\n";
-    assert_eq!(has_blank_line(str), true);
+    assert!(has_blank_line(str));
     let str = "123\n\n123\n";
-    assert_eq!(has_blank_line(str), true);
+    assert!(has_blank_line(str));
     let str = "\n";
-    assert_eq!(has_blank_line(str), true);
+    assert!(has_blank_line(str));
     let str = "";
-    assert_eq!(has_blank_line(str), false);
+    assert!(!has_blank_line(str));
 }
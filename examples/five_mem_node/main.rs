@@ -225,7 +225,7 @@ impl Node {
     fn step(&mut self, msg: Message, logger: &slog::Logger) {
         if self.raft_group.is_none() {
             if is_initial_msg(&msg) {
-                self.initialize_raft_from_message(&msg, &logger);
+                self.initialize_raft_from_message(&msg, logger);
             } else {
                 return;
             }
@@ -279,9 +279,12 @@ fn on_ready(
         }
     }
 
+    let reg = Regex::new("put ([0-9]+) (.+)").unwrap();
     let mut handle_committed_entries =
         |rn: &mut RawNode<MemStorage>, committed_entries: Vec<Entry>| {
             for entry in committed_entries {
+                // Save the last apply index to resume applying after restart.
+                store.set_applied(entry.index);
                 if entry.data.is_empty() {
                     // From new elected leaders.
                     continue;
@@ -296,8 +299,7 @@ fn on_ready(
                     // For normal proposals, extract the key-value pair and then
                     // insert them into the kv engine.
                     let data = str::from_utf8(&entry.data).unwrap();
-                    let reg = Regex::new("put ([0-9]+) (.+)").unwrap();
-                    if let Some(caps) = reg.captures(&data) {
+                    if let Some(caps) = reg.captures(data) {
                         kv_pairs.insert(caps[1].parse().unwrap(), caps[2].to_string());
                     }
                 }
@@ -324,7 +326,7 @@ fn on_ready(
 
     if let Some(hs) = ready.hs() {
         // Raft HardState changed, and we need to persist it.
-        store.wl().set_hardstate(hs.clone());
+        store.set_hard_state(hs.clone());
     }
 
     // Call `RawNode::advance` interface to update position flags in the raft.
@@ -11,7 +11,7 @@ use std::time::{Duration, Instant};
 
 use raft::eraftpb::ConfState;
 use raft::prelude::*;
-use raft::storage::MemStorage;
+use raft::storage::{MemStorage, SyncPolicy};
 
 type ProposeCallback = Box<dyn Fn() + Send>;
 
@@ -127,12 +127,11 @@ fn on_ready(raft_group: &mut RawNode<MemStorage>, cbs: &mut HashMap<u8, ProposeC
         store.wl().apply_snapshot(ready.snapshot().clone()).unwrap();
     }
 
-    let mut _last_apply_index = 0;
     let mut handle_committed_entries = |committed_entries: Vec<Entry>| {
         for entry in committed_entries {
-            // Mostly, you need to save the last apply index to resume applying
-            // after restart. Here we just ignore this because we use a Memory storage.
-            _last_apply_index = entry.index;
+            // Save the last apply index to resume applying after restart. A real
+            // application saves it along with the applied data.
+            store.set_applied(entry.index);
 
             if entry.data.is_empty() {
                 // Emtpy entry, when the peer becomes Leader it will send an empty entry.
@@ -140,7 +139,7 @@ fn on_ready(raft_group: &mut RawNode<MemStorage>, cbs: &mut HashMap<u8, ProposeC
             }
 
             if entry.get_entry_type() == EntryType::EntryNormal {
-                if let Some(cb) = cbs.remove(entry.data.get(0).unwrap()) {
+                if let Some(cb) = cbs.remove(entry.data.first().unwrap()) {
                     cb();
                 }
             }
@@ -150,15 +149,17 @@ fn on_ready(raft_group: &mut RawNode<MemStorage>, cbs: &mut HashMap<u8, ProposeC
     };
     handle_committed_entries(ready.take_committed_entries());
 
-    if !ready.entries().is_empty() {
-        // Append entries to the Raft log.
-        store.wl().append(&ready.entries()).unwrap();
-    }
-
-    if let Some(hs) = ready.hs() {
-        // Raft HardState changed, and we need to persist it.
-        store.wl().set_hardstate(hs.clone());
-    }
+    // Append entries to the Raft log and persist the HardState if it changed, in
+    // one write. The write can be synced later along with others if it's not a must.
+    let sync = if ready.must_sync() {
+        SyncPolicy::Sync
+    } else {
+        SyncPolicy::Defer
+    };
+    store
+        .wl()
+        .append_batch(ready.entries(), ready.hs().cloned(), sync)
+        .unwrap();
 
     // Advance the Raft.
     let mut light_rd = raft_group.advance(ready);
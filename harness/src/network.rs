@@ -53,12 +53,15 @@ pub struct Network {
 
 impl Network {
     /// Get a base config. Calling `Network::new` will initialize peers with this config.
+    ///
+    /// The rng is seeded so the randomized election timeouts are the same on every run.
     pub fn default_config() -> Config {
         Config {
             election_tick: 10,
             heartbeat_tick: 1,
             max_size_per_msg: NO_LIMIT,
             max_inflight_msgs: 256,
+            rng_seed: Some(1),
             ..Default::default()
         }
     }
@@ -75,6 +78,9 @@ impl Network {
     }
 
     /// Initialize a network from `peers` with explicitly specified `config`.
+    ///
+    /// If `config.rng_seed` is set, each new node is seeded with it xor its ID, so the
+    /// nodes don't share the same election timeouts.
     pub fn new_with_config(
         mut peers: Vec<Option<Interface>>,
         config: &Config,
@@ -92,6 +98,7 @@ impl Network {
                     nstorage.insert(*id, store.clone());
                     let mut config = config.clone();
                     config.id = *id;
+                    config.rng_seed = config.rng_seed.map(|seed| seed ^ *id);
                     let r = Raft::new(&config, store, l).unwrap().into();
                     npeers.insert(*id, r);
                 }
@@ -186,7 +193,7 @@ impl Network {
     ///
     /// Unlike `send` this does not gather and send any responses. It also does not ignore errors.
     pub fn dispatch(&mut self, messages: impl IntoIterator<Item = Message>) -> Result<()> {
-        for message in self.filter(messages.into_iter().map(Into::into)) {
+        for message in self.filter(messages) {
             let to = message.to;
             let peer = self.peers.get_mut(&to).unwrap();
             peer.step(message)?;
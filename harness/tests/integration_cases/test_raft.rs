@@ -17,6 +17,8 @@
 use std::cmp;
 use std::collections::HashMap;
 use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use harness::*;
 use protobuf::Message as PbMessage;
@@ -105,9 +107,9 @@ fn next_ents(r: &mut Raft<MemStorage>, s: &MemStorage) -> Vec<Entry> {
     s.wl().append(&unstable).expect("");
     let (last_idx, last_term) = (r.raft_log.last_index(), r.raft_log.last_term());
     r.on_persist_entries(last_idx, last_term);
-    let ents = r.raft_log.next_entries();
+    let ents = r.raft_log.next_entries(None);
     r.commit_apply(r.raft_log.committed);
-    ents.unwrap_or_else(Vec::new)
+    ents.unwrap_or_default()
 }
 
 #[test]
@@ -135,7 +137,7 @@ fn test_progress_committed_index() {
     // #1 test append entries
     // append entries between 1 and 2
     let mut test_entries = Entry::default();
-    test_entries.data = b"testdata".to_vec();
+    test_entries.data = b"testdata".to_vec().into();
     let m = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![test_entries]);
     nt.cut(1, 3);
     nt.send(vec![m.clone(), m]);
@@ -354,7 +356,7 @@ fn test_progress_paused() {
     m.to = 1;
     m.set_msg_type(MessageType::MsgPropose);
     let mut e = Entry::default();
-    e.data = b"some_data".to_vec();
+    e.data = b"some_data".to_vec().into();
     m.entries = vec![e].into();
     raft.step(m.clone()).expect("");
     raft.step(m.clone()).expect("");
@@ -379,7 +381,7 @@ fn test_progress_flow_control() {
 
     // While node 2 is in probe state, propose a bunch of entries.
     r.mut_prs().get_mut(2).unwrap().become_probe();
-    let data: String = std::iter::repeat('a').take(1000).collect();
+    let data: String = std::iter::repeat_n('a', 1000).collect();
     for _ in 0..10 {
         let msg = new_message_with_entries(
             1,
@@ -447,7 +449,7 @@ fn test_leader_election_pre_vote() {
 fn test_leader_election_with_config(pre_vote: bool, l: &Logger) {
     let mut config = Network::default_config();
     config.pre_vote = pre_vote;
-    let mut tests = vec![
+    let mut tests = [
         (
             Network::new_with_config(vec![None, None, None], &config, l),
             StateRole::Leader,
@@ -649,6 +651,45 @@ fn test_leader_election_overwrite_newer_logs_with_config(pre_vote: bool, l: &Log
     }
 }
 
+// Tests the scenario of figure 8 in the raft paper: a leader must not commit an
+// entry from a previous term by counting replicas, it's only committed
+// indirectly once an entry from its own term is replicated on a quorum.
+#[test]
+fn test_leader_commit_only_current_term_figure_8() {
+    let l = default_logger();
+    // Node 1 was the leader of term 2 and replicated index 2 to some followers
+    // before it crashed. Then it restarts and becomes the leader of term 4.
+    let mut r = ents_with_config(&[1, 2], false, 1, vec![1, 2, 3, 4, 5], &l);
+    r.term = 3;
+    r.become_candidate();
+    r.become_leader();
+    assert_eq!(r.term, 4);
+    // The empty entry of the new term.
+    assert_eq!(r.raft_log.last_index(), 3);
+    assert_eq!(r.raft_log.last_term(), 4);
+    r.persist();
+    r.read_messages();
+
+    // A quorum has index 2 now, but it's from term 2 and can still be
+    // overwritten by a leader with a term 3 entry at index 2.
+    for id in 2..=3 {
+        let mut m = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        m.term = 4;
+        m.index = 2;
+        r.step(m).expect("");
+    }
+    assert_eq!(r.raft_log.committed, 0);
+
+    // Once the entry of term 4 reaches the quorum, both are committed.
+    for id in 2..=3 {
+        let mut m = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        m.term = 4;
+        m.index = 3;
+        r.step(m).expect("");
+    }
+    assert_eq!(r.raft_log.committed, 3);
+}
+
 #[test]
 fn test_vote_from_any_state() {
     let l = default_logger();
@@ -669,7 +710,7 @@ fn test_vote_from_any_state_for_type(vt: MessageType, l: &Logger) {
         StateRole::Leader,
     ];
     for state in all_states {
-        let mut r = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), &l);
+        let mut r = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), l);
         r.term = 1;
         match state {
             StateRole::Follower => {
@@ -761,7 +802,7 @@ fn test_vote_from_any_state_for_type(vt: MessageType, l: &Logger) {
 #[test]
 fn test_log_replication() {
     let l = default_logger();
-    let mut tests = vec![
+    let mut tests = [
         (
             Network::new(vec![None, None, None], &l),
             vec![new_message(1, 1, MessageType::MsgPropose, 1)],
@@ -915,7 +956,7 @@ fn test_dueling_candidates() {
     // enough log.
     nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
 
-    let tests = vec![
+    let tests = [
         // role, term, committed, applied, last index.
         (StateRole::Follower, 2, (1, 0, 1)),
         (StateRole::Follower, 2, (1, 0, 1)),
@@ -966,7 +1007,7 @@ fn test_dueling_pre_candidates() {
     // With pre-vote, it does not disrupt the leader.
     nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
 
-    let tests = vec![
+    let tests = [
         // role, term, committed, applied, last index.
         (1, StateRole::Leader, 1, (1, 0, 1)),
         (2, StateRole::Follower, 1, (1, 0, 1)),
@@ -1113,7 +1154,7 @@ fn test_proposal() {
 #[test]
 fn test_proposal_by_proxy() {
     let l = default_logger();
-    let mut tests = vec![
+    let mut tests = [
         Network::new(vec![None, None, None], &l),
         Network::new(vec![None, None, NOP_STEPPER], &l),
     ];
@@ -1139,6 +1180,52 @@ fn test_proposal_by_proxy() {
     }
 }
 
+// Tests that a follower drops proposals instead of forwarding them when
+// disable_proposal_forwarding is set, and that proposals without a leader
+// fail with NoLeader.
+#[test]
+fn test_disable_proposal_forwarding() {
+    let l = default_logger();
+    let mut r1 = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), &l);
+    let mut r2 = new_test_raft(2, vec![1, 2, 3], 10, 1, new_storage(), &l);
+    let mut cfg = new_test_config(3, 10, 1);
+    cfg.disable_proposal_forwarding = true;
+    let mut r3 = new_test_raft_with_config(&cfg, new_storage(), &l);
+
+    // Nobody is the leader yet.
+    for r in [&mut r1, &mut r3] {
+        assert_eq!(
+            r.step(new_message(0, 0, MessageType::MsgPropose, 1)),
+            Err(Error::NoLeader)
+        );
+    }
+    r1.become_candidate();
+    assert_eq!(
+        r1.step(new_message(1, 1, MessageType::MsgPropose, 1)),
+        Err(Error::NoLeader)
+    );
+
+    r1.become_leader();
+    // Sync leader id to the followers.
+    r2.become_follower(1, 1);
+    r3.become_follower(1, 1);
+
+    // Forwarding is enabled on r2.
+    r2.step(new_message(0, 0, MessageType::MsgPropose, 1))
+        .expect("");
+    let msgs = r2.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgPropose);
+    assert_eq!(msgs[0].to, 1);
+
+    // Forwarding is disabled on r3.
+    assert_eq!(
+        r3.step(new_message(0, 0, MessageType::MsgPropose, 1)),
+        Err(Error::ProposalDropped)
+    );
+    assert!(r3.read_messages().is_empty());
+}
+
 #[test]
 fn test_commit() {
     let l = default_logger();
@@ -1240,7 +1327,7 @@ fn test_commit() {
 #[test]
 fn test_pass_election_timeout() {
     let l = default_logger();
-    let tests = vec![
+    let tests = [
         (5, 0f64, false),
         (10, 0.1, true),
         (13, 0.4, true),
@@ -1269,6 +1356,192 @@ fn test_pass_election_timeout() {
     }
 }
 
+// Ensures that with leader stickiness, a follower that recently heard from the
+// leader ignores MsgHup, but still campaigns on election timeout or transfer.
+#[test]
+fn test_leader_stickiness() {
+    let l = default_logger();
+    let peers = (1..=3)
+        .map(|id| {
+            let mut cfg = new_test_config(id, 10, 1);
+            cfg.leader_stickiness = true;
+            let s = MemStorage::new_with_conf_state((vec![1, 2, 3], vec![]));
+            Some(new_test_raft_with_config(&cfg, s, &l))
+        })
+        .collect();
+    let mut nt = Network::new(peers, &l);
+    // The first election has no leader to stick to.
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    let term = nt.peers[&1].term;
+
+    nt.send(vec![new_message(2, 2, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&2].state, StateRole::Follower);
+    assert_eq!(nt.peers[&2].term, term);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // A leadership transfer forces the campaign.
+    nt.send(vec![new_message(2, 1, MessageType::MsgTransferLeader, 0)]);
+    assert_eq!(nt.peers[&2].state, StateRole::Leader);
+    assert_eq!(nt.peers[&2].term, term + 1);
+
+    // Ticks campaign once the leader hasn't been heard from for the timeout.
+    nt.isolate(2);
+    let timeout = nt.peers[&3].randomized_election_timeout();
+    for _ in 0..timeout {
+        nt.peers.get_mut(&3).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&3].state, StateRole::Candidate);
+    assert_eq!(nt.peers[&3].term, term + 2);
+}
+
+// Ensures the election timeout jitter is reproducible with the same seed.
+#[test]
+fn test_rng_seed() {
+    let l = default_logger();
+    let timeouts = |seed| {
+        let mut cfg = new_test_config(1, 10, 1);
+        cfg.rng_seed = seed;
+        let s = new_storage();
+        s.initialize_with_conf_state((vec![1], vec![]));
+        let mut sm = new_test_raft_with_config(&cfg, s, &l);
+        (0..100)
+            .map(|_| {
+                sm.reset_randomized_election_timeout();
+                sm.randomized_election_timeout()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(timeouts(Some(7)), timeouts(Some(7)));
+    assert_ne!(timeouts(Some(7)), timeouts(Some(8)));
+}
+
+// Ensures a rejected append carries a hint that lets the leader skip a whole
+// divergent term at once.
+#[test]
+fn test_fast_log_rejection() {
+    let l = default_logger();
+    let log = |ents: &[(u64, u64)]| -> Vec<Entry> {
+        ents.iter().map(|&(t, i)| empty_entry(t, i)).collect()
+    };
+    let mut tests = vec![
+        // The follower has a long tail of term 3 the leader doesn't have, the
+        // leader has term 4 entries the follower doesn't have.
+        (
+            log(&[(1, 1), (2, 2), (2, 3), (4, 4), (4, 5), (4, 6), (4, 7)]),
+            log(&[
+                (1, 1),
+                (2, 2),
+                (2, 3),
+                (3, 4),
+                (3, 5),
+                (3, 6),
+                (3, 7),
+                (3, 8),
+                (3, 9),
+                (3, 10),
+                (3, 11),
+            ]),
+            3,
+            7,
+            2,
+            3,
+        ),
+        // Both have term 3 entries, the leader's log diverges later.
+        (
+            log(&[
+                (1, 1),
+                (2, 2),
+                (2, 3),
+                (3, 4),
+                (4, 5),
+                (4, 6),
+                (4, 7),
+                (5, 8),
+            ]),
+            log(&[
+                (1, 1),
+                (2, 2),
+                (2, 3),
+                (3, 4),
+                (3, 5),
+                (3, 6),
+                (3, 7),
+                (3, 8),
+                (3, 9),
+                (3, 10),
+                (3, 11),
+            ]),
+            3,
+            8,
+            3,
+            4,
+        ),
+        // The follower has greater terms than the leader.
+        (
+            log(&[(1, 1), (1, 2), (1, 3), (1, 4)]),
+            log(&[(1, 1), (2, 2), (2, 3), (4, 4)]),
+            1,
+            1,
+            1,
+            1,
+        ),
+        // The follower's log is shorter.
+        (
+            log(&[(1, 1), (2, 2), (2, 3), (4, 4), (5, 5)]),
+            log(&[(1, 1), (1, 2), (1, 3), (1, 4)]),
+            1,
+            4,
+            1,
+            1,
+        ),
+    ];
+
+    for (i, (leader_log, follower_log, hint_term, hint_index, next_term, next_index)) in
+        tests.drain(..).enumerate()
+    {
+        let s1 = new_storage();
+        s1.wl().append(&leader_log).unwrap();
+        let s2 = new_storage();
+        s2.wl().append(&follower_log).unwrap();
+        let mut n1 = new_test_raft(1, vec![1, 2], 10, 1, s1, &l);
+        let mut n2 = new_test_raft(2, vec![1, 2], 10, 1, s2, &l);
+
+        n1.become_candidate();
+        n1.become_leader();
+
+        n2.step(new_message(1, 2, MessageType::MsgHeartbeat, 0))
+            .unwrap();
+        let mut msgs = n2.read_messages();
+        assert_eq!(msgs.len(), 1, "#{}", i);
+        n1.step(msgs.remove(0)).unwrap();
+
+        let mut msgs = n1.read_messages();
+        assert_eq!(msgs.len(), 1, "#{}", i);
+        assert_eq!(msgs[0].get_msg_type(), MessageType::MsgAppend, "#{}", i);
+        n2.step(msgs.remove(0)).unwrap();
+
+        let mut msgs = n2.read_messages();
+        assert_eq!(msgs.len(), 1, "#{}", i);
+        assert_eq!(
+            msgs[0].get_msg_type(),
+            MessageType::MsgAppendResponse,
+            "#{}",
+            i
+        );
+        assert!(msgs[0].reject, "#{}", i);
+        assert_eq!(msgs[0].log_term, hint_term, "#{}", i);
+        assert_eq!(msgs[0].reject_hint, hint_index, "#{}", i);
+        n1.step(msgs.remove(0)).unwrap();
+
+        let msgs = n1.read_messages();
+        assert_eq!(msgs.len(), 1, "#{}", i);
+        assert_eq!(msgs[0].get_msg_type(), MessageType::MsgAppend, "#{}", i);
+        assert_eq!(msgs[0].log_term, next_term, "#{}", i);
+        assert_eq!(msgs[0].index, next_index, "#{}", i);
+    }
+}
+
 // test_handle_msg_append ensures:
 // 1. Reply false if log doesn’t contain an entry at prevLogIndex whose term matches prevLogTerm.
 // 2. If an existing entry conflicts with a new one (same index but different terms),
@@ -1320,7 +1593,7 @@ fn test_handle_msg_append() {
         );
 
         sm.become_follower(2, INVALID_ID);
-        sm.handle_append_entries(&m);
+        sm.handle_append_entries(&m).expect("");
         if sm.raft_log.last_index() != w_index {
             panic!(
                 "#{}: last_index = {}, want {}",
@@ -1369,8 +1642,8 @@ fn test_handle_heartbeat() {
         let cfg = new_test_config(1, 5, 1);
         let mut sm = new_test_raft_with_config(&cfg, store, &l);
         sm.become_follower(2, 2);
-        sm.raft_log.commit_to(commit);
-        sm.handle_heartbeat(m);
+        sm.raft_log.commit_to(commit).unwrap();
+        sm.handle_heartbeat(m).unwrap();
         if sm.raft_log.committed != w_commit {
             panic!(
                 "#{}: committed = {}, want = {}",
@@ -1404,7 +1677,7 @@ fn test_handle_heartbeat_resp() {
     sm.become_candidate();
     sm.become_leader();
     let last_index = sm.raft_log.last_index();
-    sm.raft_log.commit_to(last_index);
+    sm.raft_log.commit_to(last_index).unwrap();
 
     // A heartbeat response from a node that is behind; re-send MsgApp
     sm.step(new_message(2, 0, MessageType::MsgHeartbeatResponse, 0))
@@ -1443,7 +1716,7 @@ fn test_raft_frees_read_only_mem() {
     sm.become_candidate();
     sm.become_leader();
     let last_index = sm.raft_log.last_index();
-    sm.raft_log.commit_to(last_index);
+    sm.raft_log.commit_to(last_index).unwrap();
 
     let ctx = "ctx";
     let vec_ctx = ctx.as_bytes().to_vec();
@@ -1561,7 +1834,7 @@ fn test_recv_msg_request_vote_for_type(msg_type: MessageType, l: &Logger) {
         let store = MemStorage::new_with_conf_state((vec![1], vec![]));
         let ents = &[empty_entry(2, 1), empty_entry(2, 2)];
         store.wl().append(ents).unwrap();
-        let mut sm = new_test_raft(1, vec![1], 10, 1, store, &l);
+        let mut sm = new_test_raft(1, vec![1], 10, 1, store, l);
         sm.state = state;
         sm.vote = vote_for;
 
@@ -1726,7 +1999,7 @@ fn test_all_server_stepdown() {
         (StateRole::Leader, StateRole::Follower, 3, 1, 1),
     ];
 
-    let tmsg_types = vec![MessageType::MsgRequestVote, MessageType::MsgAppend];
+    let tmsg_types = [MessageType::MsgRequestVote, MessageType::MsgAppend];
     let tterm = 3u64;
 
     for (i, (state, wstate, wterm, windex, entries)) in tests.drain(..).enumerate() {
@@ -1794,11 +2067,11 @@ fn test_candidate_reset_term_msg_append() {
 // MsgHeartbeat or MsgAppend from leader, "step" resets the term
 // with leader's and reverts back to follower.
 fn test_candidate_reset_term(message_type: MessageType, l: &Logger) {
-    let a = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), &l);
-    let b = new_test_raft(2, vec![1, 2, 3], 10, 1, new_storage(), &l);
-    let c = new_test_raft(3, vec![1, 2, 3], 10, 1, new_storage(), &l);
+    let a = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), l);
+    let b = new_test_raft(2, vec![1, 2, 3], 10, 1, new_storage(), l);
+    let c = new_test_raft(3, vec![1, 2, 3], 10, 1, new_storage(), l);
 
-    let mut nt = Network::new(vec![Some(a), Some(b), Some(c)], &l);
+    let mut nt = Network::new(vec![Some(a), Some(b), Some(c)], l);
 
     nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
 
@@ -2224,6 +2497,73 @@ fn test_disruptive_follower_pre_vote() {
     assert_eq!(nt.peers[&1].state, StateRole::Leader);
 }
 
+// Ensures that a follower only surfaces read index responses matching the reads
+// it forwarded, and forgets forwarded reads when the leader changes or they time out.
+#[test]
+fn test_read_index_resp_matches_forwarded_read() {
+    let l = default_logger();
+    let mut nt = Network::new(vec![None, None, None], &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    let read = |id, ctx: &str| {
+        new_message_with_entries(
+            id,
+            id,
+            MessageType::MsgReadIndex,
+            vec![new_entry(0, 0, Some(ctx))],
+        )
+    };
+    nt.send(vec![read(2, "ctx1")]);
+    let committed = nt.peers[&1].raft_log.committed;
+    assert_eq!(
+        nt.peers[&2].read_states,
+        vec![ReadState {
+            index: committed,
+            request_ctx: b"ctx1".to_vec(),
+        }]
+    );
+    assert_eq!(nt.peers[&2].forwarded_read_count(), 0);
+    nt.peers.get_mut(&2).unwrap().read_states.clear();
+
+    // A response that matches no forwarded read is ignored.
+    let mut resp = new_message_with_entries(
+        1,
+        2,
+        MessageType::MsgReadIndexResp,
+        vec![new_entry(0, 0, Some("ctx1"))],
+    );
+    resp.term = nt.peers[&1].term;
+    resp.index = committed;
+    nt.send(vec![resp]);
+    assert!(nt.peers[&2].read_states.is_empty());
+
+    // Reads forwarded to a leader that is gone are dropped on leader change.
+    nt.isolate(1);
+    nt.send(vec![read(2, "ctx2"), read(2, "ctx2"), read(2, "ctx3")]);
+    assert_eq!(nt.peers[&2].forwarded_read_count(), 2);
+    nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&3].state, StateRole::Leader);
+    assert_eq!(nt.peers[&2].forwarded_read_count(), 0);
+    assert!(nt.peers[&2].read_states.is_empty());
+
+    // Reads not responded within an election timeout are given up.
+    nt.recover();
+    nt.isolate(3);
+    nt.send(vec![read(2, "ctx4")]);
+    assert_eq!(nt.peers[&2].forwarded_read_count(), 1);
+    let p = nt.peers.get_mut(&2).unwrap();
+    let timeout = p.election_timeout();
+    p.set_randomized_election_timeout(timeout * 2 - 1);
+    for _ in 0..timeout {
+        p.tick();
+    }
+    assert_eq!(p.forwarded_read_count(), 1);
+    p.tick();
+    assert_eq!(p.forwarded_read_count(), 0);
+    assert_eq!(p.state, StateRole::Follower);
+}
+
 #[test]
 fn test_read_only_option_safe() {
     let l = default_logger();
@@ -2367,9 +2707,8 @@ fn test_read_only_with_learner() {
             .read_states
             .drain(..)
             .collect();
-        assert_eq!(
-            read_states.is_empty(),
-            false,
+        assert!(
+            !read_states.is_empty(),
             "#{}: read_states is empty, want non-empty",
             i
         );
@@ -2388,6 +2727,39 @@ fn test_read_only_with_learner() {
     }
 }
 
+// Ensures a learner can issue a safe read index, and that its own heartbeat
+// response is not counted towards the quorum confirming the leadership.
+#[test]
+fn test_read_only_with_learner_not_in_quorum() {
+    let l = default_logger();
+    let mut peers = vec![];
+    for id in 1..=4 {
+        let r = new_test_learner_raft(id, vec![1, 2, 3], vec![4], 10, 1, new_storage(), &l);
+        peers.push(Some(r));
+    }
+    let mut nt = Network::new(peers, &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // Only the leader and the learner can acknowledge the read.
+    nt.isolate(2);
+    nt.isolate(3);
+    nt.send(vec![new_message_with_entries(
+        4,
+        4,
+        MessageType::MsgReadIndex,
+        vec![new_entry(0, 0, Some("ctx"))],
+    )]);
+    assert!(nt.peers[&4].read_states.is_empty());
+
+    nt.recover();
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    let rs = &nt.peers[&4].read_states;
+    assert_eq!(rs.len(), 1);
+    assert_eq!(rs[0].index, 1);
+    assert_eq!(rs[0].request_ctx, b"ctx".to_vec());
+}
+
 #[test]
 fn test_read_only_option_lease() {
     let l = default_logger();
@@ -2493,6 +2865,297 @@ fn test_read_only_option_lease_without_check_quorum() {
     assert_eq!(rs.index, 1);
     let vec_ctx = ctx.as_bytes().to_vec();
     assert_eq!(rs.request_ctx, vec_ctx);
+
+    // The leader has no lease without check quorum, so an isolated leader must not
+    // serve lease based reads.
+    nt.isolate(1);
+    let entry = new_entry(0, 0, Some("ctx2"));
+    nt.send(vec![new_message_with_entries(
+        1,
+        1,
+        MessageType::MsgReadIndex,
+        vec![entry],
+    )]);
+    assert!(nt.peers[&1].read_states.is_empty());
+}
+
+#[derive(Clone)]
+struct ManualClock(Arc<Mutex<Instant>>);
+
+impl ManualClock {
+    fn advance(&self, d: Duration) {
+        *self.0.lock().unwrap() += d;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+// Ensures an idle group quiesces when enabled, stops generating messages on
+// tick, and wakes up on the next proposal.
+#[test]
+fn test_quiesce() {
+    let l = default_logger();
+    let mut peers = vec![];
+    for id in 1..=3 {
+        let mut cfg = new_test_config(id, 10, 1);
+        cfg.quiesce = true;
+        let storage = new_storage();
+        storage.initialize_with_conf_state((vec![1, 2, 3], vec![]));
+        peers.push(Some(new_test_raft_with_config(&cfg, storage, &l)));
+    }
+    let mut nt = Network::new(peers, &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    let msgs = nt.peers.get_mut(&1).unwrap().read_messages();
+    assert!(msgs.is_empty());
+    nt.peers.get_mut(&1).unwrap().tick();
+    let msgs = nt.peers.get_mut(&1).unwrap().read_messages();
+    assert_eq!(msgs.len(), 2);
+    for m in &msgs {
+        assert_eq!(m.get_msg_type(), MessageType::MsgHeartbeat);
+        assert!(m.quiesce);
+    }
+    nt.send(msgs);
+    for id in 1..=3 {
+        assert!(nt.peers[&id].quiesced(), "{}", id);
+    }
+
+    // Nobody heartbeats or campaigns while quiesced.
+    for _ in 0..100 {
+        for id in 1..=3 {
+            let p = nt.peers.get_mut(&id).unwrap();
+            p.tick();
+            assert!(p.read_messages().is_empty(), "{}", id);
+        }
+    }
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    assert_eq!(nt.peers[&1].term, 1);
+
+    // A proposal wakes up the group.
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    for id in 1..=3 {
+        assert!(!nt.peers[&id].quiesced(), "{}", id);
+        assert_eq!(nt.peers[&id].raft_log.committed, 2, "{}", id);
+    }
+
+    // And it quiesces again once idle.
+    nt.peers.get_mut(&1).unwrap().tick();
+    let msgs = nt.peers.get_mut(&1).unwrap().read_messages();
+    nt.send(msgs);
+    for id in 1..=3 {
+        assert!(nt.peers[&id].quiesced(), "{}", id);
+    }
+}
+
+// Ensures quiesced followers elect a new leader if the quiesced leader goes down,
+// either after the quiesced election timeout or once the leader is reported
+// unreachable.
+#[test]
+fn test_quiesce_leader_down() {
+    let l = default_logger();
+    let new_network = || {
+        let mut peers = vec![];
+        for id in 1..=3 {
+            let mut cfg = new_test_config(id, 10, 1);
+            cfg.quiesce = true;
+            cfg.quiesced_election_tick = 50;
+            let storage = new_storage();
+            storage.initialize_with_conf_state((vec![1, 2, 3], vec![]));
+            peers.push(Some(new_test_raft_with_config(&cfg, storage, &l)));
+        }
+        let mut nt = Network::new(peers, &l);
+        nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+        nt.peers.get_mut(&1).unwrap().tick();
+        let msgs = nt.peers.get_mut(&1).unwrap().read_messages();
+        nt.send(msgs);
+        for id in 1..=3 {
+            assert!(nt.peers[&id].quiesced(), "{}", id);
+        }
+        nt.isolate(1);
+        nt
+    };
+    // Ticks the followers until one of them becomes leader, returns the ticks taken.
+    let elect = |nt: &mut Network| {
+        for ticks in 1..=100 {
+            for id in 2..=3 {
+                nt.peers.get_mut(&id).unwrap().tick();
+                let msgs = nt.peers.get_mut(&id).unwrap().read_messages();
+                nt.filter_and_send(msgs);
+            }
+            if (2..=3).any(|id| nt.peers[&id].state == StateRole::Leader) {
+                return ticks;
+            }
+        }
+        panic!("no leader elected");
+    };
+
+    // The followers wake up on their own, after the quiesced election timeout.
+    let mut nt = new_network();
+    let ticks = elect(&mut nt);
+    assert!(ticks > 50, "{}", ticks);
+    assert_eq!(nt.peers[&2].term, 2);
+
+    // A follower that finds the leader unreachable wakes up right away.
+    let mut nt = new_network();
+    nt.peers.get_mut(&2).unwrap().report_unreachable(1);
+    assert!(!nt.peers[&2].quiesced());
+    let ticks = elect(&mut nt);
+    assert!(ticks < 50, "{}", ticks);
+    assert_eq!(nt.peers[&2].state, StateRole::Leader);
+    assert_eq!(nt.peers[&2].term, 2);
+}
+
+// Ensures a leader woken up from quiescence serves no lease based read until a
+// quorum answers its heartbeats again, as others may have elected a new leader
+// while it wasn't ticking.
+#[test]
+fn test_quiesce_lease_read() {
+    let l = default_logger();
+    let mut peers = vec![];
+    for id in 1..=3 {
+        let mut cfg = new_test_config(id, 10, 1);
+        cfg.quiesce = true;
+        cfg.check_quorum = true;
+        cfg.read_only_option = ReadOnlyOption::LeaseBased;
+        let storage = new_storage();
+        storage.initialize_with_conf_state((vec![1, 2, 3], vec![]));
+        peers.push(Some(new_test_raft_with_config(&cfg, storage, &l)));
+    }
+    let mut nt = Network::new(peers, &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    let read = |ctx| {
+        new_message_with_entries(
+            1,
+            1,
+            MessageType::MsgReadIndex,
+            vec![new_entry(0, 0, Some(ctx))],
+        )
+    };
+    let quiesce = |nt: &mut Network| {
+        nt.peers.get_mut(&1).unwrap().tick();
+        let msgs = nt.peers.get_mut(&1).unwrap().read_messages();
+        nt.send(msgs);
+        assert!(nt.peers[&1].quiesced());
+        assert!(!nt.peers[&1].in_lease());
+    };
+
+    // Without a partition, the read waits for a heartbeat round, which renews the
+    // lease.
+    quiesce(&mut nt);
+    nt.send(vec![read("ctx1")]);
+    assert!(!nt.peers[&1].quiesced());
+    assert_eq!(nt.peers[&1].read_states.len(), 1);
+    assert!(nt.peers[&1].in_lease());
+    nt.peers.get_mut(&1).unwrap().read_states.clear();
+
+    // Others elect a new leader while the old one is quiesced.
+    quiesce(&mut nt);
+    nt.isolate(1);
+    for id in 2..=3 {
+        nt.peers.get_mut(&id).unwrap().unquiesce();
+    }
+    for _ in 0..20 {
+        for id in 2..=3 {
+            nt.peers.get_mut(&id).unwrap().tick();
+            let msgs = nt.peers.get_mut(&id).unwrap().read_messages();
+            nt.filter_and_send(msgs);
+        }
+    }
+    assert!((2..=3).any(|id| nt.peers[&id].state == StateRole::Leader));
+
+    // Woken up by a read, the old leader doesn't answer it from its lease.
+    nt.send(vec![read("ctx2")]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    assert!(!nt.peers[&1].in_lease());
+    assert!(nt.peers[&1].read_states.is_empty());
+
+    // And steps down as soon as it hears from the others.
+    nt.recover();
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Follower);
+    assert!(nt.peers[&1].read_states.is_empty());
+}
+
+// Ensures the lease of a leader expires by the installed clock even if it is not
+// ticked, and is only extended by responses from a quorum.
+#[test]
+fn test_read_only_option_lease_with_clock() {
+    let l = default_logger();
+    let mut peers = vec![];
+    for id in 1..=3 {
+        let mut r = new_test_raft(id, vec![1, 2, 3], 10, 1, new_storage(), &l);
+        r.read_only.option = ReadOnlyOption::LeaseBased;
+        r.check_quorum = true;
+        peers.push(Some(r));
+    }
+    let mut nt = Network::new(peers, &l);
+    let clock = ManualClock(Arc::new(Mutex::new(Instant::now())));
+    let lease = Duration::from_secs(1);
+    nt.peers
+        .get_mut(&1)
+        .unwrap()
+        .set_clock(Box::new(clock.clone()), lease);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    assert!(nt.peers[&1].in_lease());
+
+    let read = |ctx| {
+        new_message_with_entries(
+            1,
+            1,
+            MessageType::MsgReadIndex,
+            vec![new_entry(0, 0, Some(ctx))],
+        )
+    };
+    let leader = nt.peers.get_mut(&1).unwrap();
+    leader.step(read("ctx1")).unwrap();
+    assert_eq!(leader.read_states.len(), 1);
+    assert!(leader.read_messages().is_empty());
+
+    // The lease expires by the clock, so the read falls back to a heartbeat round.
+    clock.advance(lease);
+    assert!(!leader.in_lease());
+    leader.step(read("ctx2")).unwrap();
+    assert_eq!(leader.read_states.len(), 1);
+    let msgs = leader.read_messages();
+    assert_eq!(msgs.len(), 2);
+    for m in &msgs {
+        assert_eq!(m.get_msg_type(), MessageType::MsgHeartbeat);
+        assert_eq!(m.context, b"ctx2".to_vec());
+    }
+
+    // Ticking doesn't extend the lease, only the responses do.
+    leader.tick();
+    leader.read_messages();
+    assert!(!leader.in_lease());
+    nt.send(msgs);
+    let leader = nt.peers.get_mut(&1).unwrap();
+    assert_eq!(leader.read_states.len(), 2);
+    assert!(leader.in_lease());
+
+    // A response from one follower forms a quorum with the leader, and extends
+    // the lease from the time it's received.
+    clock.advance(lease / 2);
+    let mut resp = new_message(2, 1, MessageType::MsgHeartbeatResponse, 0);
+    resp.term = leader.term;
+    leader.step(resp).unwrap();
+    clock.advance(lease / 2);
+    assert!(leader.in_lease());
+    clock.advance(lease / 2);
+    assert!(!leader.in_lease());
+    leader.tick();
+    assert_eq!(leader.state, StateRole::Leader);
+
+    // The leader steps down on tick once the lease expires if configured.
+    leader.set_lease_step_down(true);
+    leader.tick();
+    assert_eq!(leader.state, StateRole::Follower);
 }
 
 // `test_read_only_for_new_leader` ensures that a leader only accepts MsgReadIndex message
@@ -2928,6 +3591,17 @@ fn test_recv_msg_unreachable() {
     let peer_2 = r.prs().get(2).unwrap();
     assert_eq!(peer_2.state, ProgressState::Probe);
     assert_eq!(peer_2.matched + 1, peer_2.next_idx);
+
+    // The same through `report_unreachable`. Only one probe is sent afterwards.
+    r.mut_prs().get_mut(2).unwrap().become_replicate();
+    r.mut_prs().get_mut(2).unwrap().optimistic_update(5);
+    r.report_unreachable(2);
+    assert_eq!(r.prs().get(2).unwrap().state, ProgressState::Probe);
+    r.send_append(2);
+    assert_eq!(r.read_messages().len(), 1);
+    assert!(r.prs().get(2).unwrap().is_paused());
+    r.send_append(2);
+    assert!(r.read_messages().is_empty());
 }
 
 #[test]
@@ -2959,7 +3633,7 @@ fn test_restore_ignore_snapshot() {
     let commit = 1u64;
     let mut sm = new_test_raft(1, vec![1, 2], 10, 1, new_storage(), &l);
     sm.raft_log.append(&previous_ents);
-    sm.raft_log.commit_to(commit);
+    sm.raft_log.commit_to(commit).unwrap();
 
     let mut s = new_snapshot(commit, 1, vec![1, 2]);
 
@@ -3047,7 +3721,7 @@ fn test_slow_node_restore() {
     for _ in 0..100 {
         nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
     }
-    next_ents(&mut nt.peers.get_mut(&1).unwrap(), &nt.storage[&1]);
+    next_ents(nt.peers.get_mut(&1).unwrap(), &nt.storage[&1]);
     nt.storage[&1]
         .wl()
         .commit_to(nt.peers[&1].raft_log.applied)
@@ -3120,7 +3794,10 @@ fn test_step_ignore_config() {
     let mut we = empty_entry(1, 3);
     we.set_entry_type(EntryType::EntryNormal);
     let wents = vec![we];
-    let entries = r.raft_log.entries(index + 1, None).expect("");
+    let entries = r
+        .raft_log
+        .entries(index + 1, None, GetEntriesContext::empty(false))
+        .expect("");
     assert_eq!(entries, wents);
     assert_eq!(r.pending_conf_index, pending_conf_index);
 }
@@ -3158,7 +3835,7 @@ fn test_add_node() -> Result<()> {
     let mut r = new_test_raft(1, vec![1], 10, 1, new_storage(), &l);
     r.apply_conf_change(&add_node(2))?;
     assert_iter_eq!(o r.prs().conf().voters().ids(),
-        vec![1, 2]
+        [1, 2]
     );
 
     Ok(())
@@ -3204,11 +3881,11 @@ fn test_remove_node() -> Result<()> {
     let l = default_logger();
     let mut r = new_test_raft(1, vec![1, 2], 10, 1, new_storage(), &l);
     r.apply_conf_change(&remove_node(2))?;
-    assert_iter_eq!(o r.prs().conf().voters().ids(), vec![1]);
+    assert_iter_eq!(o r.prs().conf().voters().ids(), [1]);
 
     // Removing all voters is not allowed.
     assert!(r.apply_conf_change(&remove_node(1)).is_err());
-    assert_iter_eq!(o r.prs().conf().voters().ids(), vec![1]);
+    assert_iter_eq!(o r.prs().conf().voters().ids(), [1]);
 
     Ok(())
 }
@@ -3219,8 +3896,8 @@ fn test_remove_node_itself() {
     let mut n1 = new_test_learner_raft(1, vec![1], vec![2], 10, 1, new_storage(), &l);
 
     assert!(n1.apply_conf_change(&remove_node(1)).is_err());
-    assert_iter_eq!(n1.prs().conf().learners(), vec![2]);
-    assert_iter_eq!(o n1.prs().conf().voters().ids(), vec![1]);
+    assert_iter_eq!(n1.prs().conf().learners(), [2]);
+    assert_iter_eq!(o n1.prs().conf().voters().ids(), [1]);
 }
 
 #[test]
@@ -3302,7 +3979,7 @@ fn test_commit_after_remove_node() -> Result<()> {
     cc.set_change_type(ConfChangeType::RemoveNode);
     cc.node_id = 2;
     let ccdata = cc.write_to_bytes().unwrap();
-    entry.data = ccdata;
+    entry.data = ccdata.into();
     msg.mut_entries().push(entry);
     r.step(msg).expect("");
     // Stabilize the log and make sure nothing is committed yet.
@@ -3332,7 +4009,7 @@ fn test_commit_after_remove_node() -> Result<()> {
     let ents = next_ents(&mut r, &s);
     assert_eq!(ents.len(), 1);
     assert_eq!(ents[0].get_entry_type(), EntryType::EntryNormal);
-    assert_eq!(ents[0].data, b"hello");
+    assert_eq!(ents[0].get_data(), b"hello");
 
     Ok(())
 }
@@ -3446,7 +4123,7 @@ fn test_leader_transfer_after_snapshot() {
     nt.isolate(3);
 
     nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
-    next_ents(&mut nt.peers.get_mut(&1).unwrap(), &nt.storage[&1]);
+    next_ents(nt.peers.get_mut(&1).unwrap(), &nt.storage[&1]);
     nt.storage[&1]
         .wl()
         .commit_to(nt.peers[&1].raft_log.applied)
@@ -3555,7 +4232,7 @@ fn test_leader_transfer_ignore_proposal() {
             .get_mut(&1)
             .unwrap()
             .step(new_message(1, 1, MessageType::MsgPropose, 1)),
-        Err(Error::ProposalDropped),
+        Err(Error::LeaderTransferring),
         "should return drop proposal error while transferring"
     );
 
@@ -3606,6 +4283,41 @@ fn test_leader_transfer_remove_node() -> Result<()> {
     Ok(())
 }
 
+// Ensures a leader removed by leaving a joint configuration steps down and
+// hands over to an up to date voter.
+#[test]
+fn test_leader_step_down_after_auto_leave_removal() -> Result<()> {
+    let l = default_logger();
+    let mut nt = Network::new(vec![None, None, None], &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+
+    let mut enter = conf_change_v2(vec![new_conf_change_single(1, ConfChangeType::RemoveNode)]);
+    enter.set_transition(ConfChangeTransition::Implicit);
+    for id in 1..=3 {
+        nt.peers.get_mut(&id).unwrap().apply_conf_change(&enter)?;
+    }
+    // Still part of the outgoing configuration.
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    let leave = ConfChangeV2::default();
+    for id in [2, 3, 1] {
+        nt.peers.get_mut(&id).unwrap().apply_conf_change(&leave)?;
+    }
+    assert_eq!(nt.peers[&1].state, StateRole::Follower);
+    assert_eq!(nt.peers[&1].leader_id, INVALID_ID);
+    let msgs = nt.peers.get_mut(&1).unwrap().read_messages();
+    assert!(msgs
+        .iter()
+        .any(|m| m.get_msg_type() == MessageType::MsgTimeoutNow && m.to == 2));
+
+    nt.send(msgs);
+    assert_eq!(nt.peers[&2].state, StateRole::Leader);
+    assert_eq!(nt.peers[&2].term, 2);
+    assert_eq!(nt.peers[&3].leader_id, 2);
+    Ok(())
+}
+
 // test_leader_transfer_back verifies leadership can transfer
 // back to self when last transfer is pending.
 #[test]
@@ -3952,8 +4664,8 @@ fn test_restore_with_learner() {
     assert!(sm.restore(s.clone()));
     assert_eq!(sm.raft_log.last_index(), 11);
     assert_eq!(sm.raft_log.term(11).unwrap(), 11);
-    assert_iter_eq!(o sm.prs().conf().voters().ids(), vec![1, 2]);
-    assert_iter_eq!(sm.prs().conf().learners(), vec![3]);
+    assert_iter_eq!(o sm.prs().conf().voters().ids(), [1, 2]);
+    assert_iter_eq!(sm.prs().conf().learners(), [3]);
 
     let conf_state = s.get_metadata().get_conf_state();
     for node in &conf_state.voters {
@@ -3986,7 +4698,7 @@ fn test_restore_with_voters_outgoing() {
     );
     assert_iter_eq!(
         o sm.prs().conf().voters().ids(),
-        vec![1, 2, 3, 4]
+        [1, 2, 3, 4]
     );
     assert!(!sm.restore(s));
 }
@@ -4074,7 +4786,7 @@ fn test_add_learner() -> Result<()> {
     let mut n1 = new_test_raft(1, vec![1], 10, 1, new_storage(), &l);
     n1.apply_conf_change(&add_learner(2))?;
 
-    assert_iter_eq!(n1.prs().conf().learners(), vec![2]);
+    assert_iter_eq!(n1.prs().conf().learners(), [2]);
     assert!(n1.prs().conf().learners().contains(&2));
 
     Ok(())
@@ -4087,12 +4799,12 @@ fn test_remove_learner() -> Result<()> {
     let l = default_logger();
     let mut n1 = new_test_learner_raft(1, vec![1], vec![2], 10, 1, new_storage(), &l);
     n1.apply_conf_change(&remove_node(2))?;
-    assert_iter_eq!(o n1.prs().conf().voters().ids(), vec![1]);
+    assert_iter_eq!(o n1.prs().conf().voters().ids(), [1]);
     assert!(n1.prs().conf().learners().is_empty());
 
     // Remove all voters are not allowed.
     assert!(n1.apply_conf_change(&remove_node(1)).is_err());
-    assert_iter_eq!(o n1.prs().conf().voters().ids(), vec![1]);
+    assert_iter_eq!(o n1.prs().conf().voters().ids(), [1]);
     assert!(n1.prs().conf().learners().is_empty());
 
     Ok(())
@@ -4106,15 +4818,15 @@ fn new_prevote_migration_cluster(l: &Logger) -> Network {
     // We intentionally do not enable pre_vote for n3, this is done so in order
     // to simulate a rolling restart process where it's possible to have a mixed
     // version cluster with replicas with pre_vote enabled, and replicas without.
-    let mut n1 = new_test_raft_with_prevote(1, vec![1, 2, 3], 10, 1, new_storage(), true, &l);
-    let mut n2 = new_test_raft_with_prevote(2, vec![1, 2, 3], 10, 1, new_storage(), true, &l);
-    let mut n3 = new_test_raft_with_prevote(3, vec![1, 2, 3], 10, 1, new_storage(), false, &l);
+    let mut n1 = new_test_raft_with_prevote(1, vec![1, 2, 3], 10, 1, new_storage(), true, l);
+    let mut n2 = new_test_raft_with_prevote(2, vec![1, 2, 3], 10, 1, new_storage(), true, l);
+    let mut n3 = new_test_raft_with_prevote(3, vec![1, 2, 3], 10, 1, new_storage(), false, l);
 
     n1.become_follower(1, INVALID_ID);
     n2.become_follower(1, INVALID_ID);
     n3.become_follower(1, INVALID_ID);
 
-    let mut nt = Network::new(vec![Some(n1), Some(n2), Some(n3)], &l);
+    let mut nt = Network::new(vec![Some(n1), Some(n2), Some(n3)], l);
 
     nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
 
@@ -4398,6 +5110,52 @@ fn test_prevote_with_check_quorum() {
     assert_eq!(network.peers[&3].state, StateRole::Follower, "peer 3 state",);
 }
 
+// ensure that after a partition heals, an up-to-date node can't disrupt the
+// leader with pre-votes while the others are still in lease.
+#[test]
+fn test_prevote_in_lease_after_partition_heal() {
+    let l = default_logger();
+    let bootstrap = |id| {
+        let mut cfg = new_test_config(id, 10, 1);
+        cfg.pre_vote = true;
+        cfg.check_quorum = true;
+        let s = MemStorage::new_with_conf_state((vec![1, 2, 3], vec![]));
+        let mut i = new_test_raft_with_config(&cfg, s, &l);
+        i.become_follower(1, INVALID_ID);
+        i
+    };
+    let (peer1, peer2, peer3) = (bootstrap(1), bootstrap(2), bootstrap(3));
+
+    let mut network = Network::new(vec![Some(peer1), Some(peer2), Some(peer3)], &l);
+    network.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(network.peers[&1].state, StateRole::Leader);
+    let term = network.peers[&1].term;
+
+    network.isolate(3);
+    let timeout = network.peers[&3].randomized_election_timeout();
+    for _ in 0..timeout {
+        network.peers.get_mut(&3).unwrap().tick();
+    }
+    assert_eq!(network.peers[&3].state, StateRole::PreCandidate);
+    network.peers.get_mut(&3).unwrap().read_messages();
+
+    // Node 3 has the full log, so only the lease keeps node 1 and 2 from
+    // granting its pre-vote.
+    network.recover();
+    network.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    assert_eq!(network.peers[&3].state, StateRole::PreCandidate);
+    for id in 1..=3 {
+        assert_eq!(network.peers[&id].term, term, "peer {} term", id);
+    }
+    assert_eq!(network.peers[&1].state, StateRole::Leader);
+    assert_eq!(network.peers[&2].state, StateRole::Follower);
+
+    // The next heartbeat brings node 3 back.
+    network.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    assert_eq!(network.peers[&3].state, StateRole::Follower);
+    assert_eq!(network.peers[&3].leader_id, 1);
+}
+
 // ensure a new Raft returns a Error::ConfigInvalid with an invalid config
 #[test]
 fn test_new_raft_with_bad_config_errors() {
@@ -4434,6 +5192,54 @@ fn test_batch_msg_append() {
     assert_eq!(raft.msgs.len(), 3);
 }
 
+// Tests that the followers at the same index share the entries read for a broadcast.
+#[test]
+fn test_bcast_append_shares_entries() {
+    #[derive(Default)]
+    struct Reads(Mutex<usize>);
+    impl StorageInstrumentation for Reads {
+        fn on_entries(&self, _: Duration, _: usize, _: u64) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    let l = default_logger();
+    let storage = new_storage();
+    let mut sm = new_test_raft(1, vec![1, 2, 3, 4], 10, 1, storage.clone(), &l);
+    sm.become_candidate();
+    sm.become_leader();
+    for _ in 0..3 {
+        let _ = sm.append_entry(&mut [empty_entry(0, 0)]);
+    }
+    sm.persist();
+    sm.read_messages();
+    for (id, next_idx) in [(2, 2), (3, 2), (4, 4)] {
+        let pr = sm.mut_prs().get_mut(id).unwrap();
+        pr.become_replicate();
+        pr.next_idx = next_idx;
+    }
+
+    let reads = Arc::new(Reads::default());
+    storage.wl().set_instrumentation(reads.clone());
+    sm.bcast_append();
+    // One read for 2 and 3, another one for 4.
+    assert_eq!(*reads.0.lock().unwrap(), 2);
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 3);
+    for m in &msgs {
+        let want: Vec<u64> = if m.to == 4 { vec![4] } else { vec![2, 3, 4] };
+        let indexes: Vec<u64> = m.entries.iter().map(|e| e.index).collect();
+        assert_eq!(indexes, want, "to {}", m.to);
+    }
+
+    // The entries are read again outside of a broadcast.
+    sm.mut_prs().get_mut(2).unwrap().next_idx = 2;
+    sm.mut_prs().get_mut(3).unwrap().next_idx = 2;
+    sm.send_append(2);
+    sm.send_append(3);
+    assert_eq!(*reads.0.lock().unwrap(), 4);
+}
+
 /// Tests if unapplied conf change is checked before campaign.
 #[test]
 fn test_conf_change_check_before_campaign() {
@@ -4448,7 +5254,7 @@ fn test_conf_change_check_before_campaign() {
     let mut cc = ConfChange::default();
     cc.set_change_type(ConfChangeType::RemoveNode);
     cc.node_id = 3;
-    e.data = protobuf::Message::write_to_bytes(&cc).unwrap();
+    e.data = protobuf::Message::write_to_bytes(&cc).unwrap().into();
     m.mut_entries().push(e);
     nt.send(vec![m]);
 
@@ -4529,10 +5335,10 @@ fn test_advance_commit_index_by_vote_request(use_prevote: bool) {
         let mut e = Entry::default();
         if let Some(v1) = cc.as_v1() {
             e.set_entry_type(EntryType::EntryConfChange);
-            e.set_data(v1.write_to_bytes().unwrap());
+            e.set_data(v1.write_to_bytes().unwrap().into());
         } else {
             e.set_entry_type(EntryType::EntryConfChangeV2);
-            e.set_data(cc.as_v2().write_to_bytes().unwrap());
+            e.set_data(cc.as_v2().write_to_bytes().unwrap().into());
         }
 
         // propose a confchange entry but don't let it commit
@@ -4680,10 +5486,10 @@ fn test_advance_commit_index_by_vote_response(use_prevote: bool) {
         let mut e = Entry::default();
         if let Some(v1) = cc.as_v1() {
             e.set_entry_type(EntryType::EntryConfChange);
-            e.set_data(v1.write_to_bytes().unwrap());
+            e.set_data(v1.write_to_bytes().unwrap().into());
         } else {
             e.set_entry_type(EntryType::EntryConfChangeV2);
-            e.set_data(cc.as_v2().write_to_bytes().unwrap());
+            e.set_data(cc.as_v2().write_to_bytes().unwrap().into());
         }
 
         // propose a confchange entry but don't let it commit
@@ -4800,7 +5606,7 @@ fn prepare_request_snapshot() -> (Network, Snapshot) {
             .wl()
             .apply_snapshot(new_snapshot(11, 11, ids.clone()))
             .unwrap();
-        let mut raft = new_test_raft(id, ids, 5, 1, store, &l);
+        let mut raft = new_test_raft(id, ids, 5, 1, store, l);
         raft.reset(11);
         raft
     }
@@ -4818,7 +5624,7 @@ fn prepare_request_snapshot() -> (Network, Snapshot) {
     nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
 
     let mut test_entries = Entry::default();
-    test_entries.data = b"testdata".to_vec();
+    test_entries.data = b"testdata".to_vec().into();
     let msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![test_entries]);
     nt.send(vec![msg.clone(), msg]);
     assert_eq!(nt.peers[&1].raft_log.committed, 14);
@@ -4837,7 +5643,7 @@ fn prepare_request_snapshot() -> (Network, Snapshot) {
 
     // Commit a new raft log.
     let mut test_entries = Entry::default();
-    test_entries.data = b"testdata".to_vec();
+    test_entries.data = b"testdata".to_vec().into();
     let msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![test_entries]);
     nt.send(vec![msg]);
 
@@ -4873,7 +5679,7 @@ fn test_follower_request_snapshot() {
 
     // New proposes can not be replicated to peer 2.
     let mut test_entries = Entry::default();
-    test_entries.data = b"testdata".to_vec();
+    test_entries.data = b"testdata".to_vec().into();
     let msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![test_entries]);
     nt.send(vec![msg.clone()]);
     assert_eq!(nt.peers[&1].raft_log.committed, 16);
@@ -5028,7 +5834,7 @@ fn test_request_snapshot_step_down() {
     // Commit a new entry and leader steps down while peer 2 is isolated.
     nt.isolate(2);
     let mut test_entries = Entry::default();
-    test_entries.data = b"testdata".to_vec();
+    test_entries.data = b"testdata".to_vec().into();
     let msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![test_entries]);
     nt.send(vec![msg]);
     nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
@@ -5158,6 +5964,42 @@ fn test_group_commit() {
     }
 }
 
+// Ensures group commit can be toggled and cleared at runtime without the
+// commit index going backwards.
+#[test]
+fn test_group_commit_runtime_toggle() {
+    let l = default_logger();
+    let store = MemStorage::new_with_conf_state((vec![1, 2, 3], vec![]));
+    let logs: Vec<_> = (1..=8).map(|i| empty_entry(1, i)).collect();
+    store.wl().append(&logs).unwrap();
+    let mut hs = HardState::default();
+    hs.term = 1;
+    store.wl().set_hardstate(hs);
+    let cfg = new_test_config(1, 5, 1);
+    let mut sm = new_test_raft_with_config(&cfg, store, &l);
+    for (id, m) in [(1, 8), (2, 6), (3, 4)].iter() {
+        let pr = sm.mut_prs().get_mut(*id).unwrap();
+        pr.matched = *m;
+        pr.next_idx = *m + 1;
+    }
+    sm.state = StateRole::Leader;
+
+    sm.enable_group_commit(true);
+    sm.assign_commit_groups(&[(1, 1), (2, 1), (3, 2)]);
+    assert_eq!(sm.raft_log.committed, 4);
+
+    sm.clear_commit_group_ids();
+    assert!(sm.prs().iter().all(|(_, pr)| pr.commit_group_id == 0));
+    assert_eq!(sm.raft_log.committed, 4);
+
+    sm.enable_group_commit(false);
+    assert_eq!(sm.raft_log.committed, 6);
+
+    // Re-enabling group commit must not move the commit index backwards.
+    sm.enable_group_commit(true);
+    assert_eq!(sm.raft_log.committed, 6);
+}
+
 #[test]
 fn test_group_commit_consistent() {
     let l = default_logger();
@@ -5301,7 +6143,7 @@ fn test_election_with_priority_log() {
         (false, false, true, 1, 1, 3, 1, StateRole::Leader),
     ];
 
-    for (_i, &(l1, l2, l3, p1, p2, p3, id, state)) in tests.iter().enumerate() {
+    for &(l1, l2, l3, p1, p2, p3, id, state) in tests.iter() {
         let l = default_logger();
         let mut n1 = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), &l);
         let mut n2 = new_test_raft(2, vec![1, 2, 3], 10, 1, new_storage(), &l);
@@ -5349,7 +6191,7 @@ fn test_election_after_change_priority() {
     // check state
     assert_eq!(network.peers[&1].state, StateRole::Follower, "peer 1 state");
 
-    let tests = vec![
+    let tests = [
         (1, 1, StateRole::Follower), //id, priority, state
         (1, 2, StateRole::Leader),
         (1, 3, StateRole::Leader),
@@ -5389,7 +6231,7 @@ fn test_read_when_quorum_becomes_less() {
     m.to = 1;
     m.set_msg_type(MessageType::MsgReadIndex);
     let mut e = Entry::default();
-    e.data = b"abcdefg".to_vec();
+    e.data = b"abcdefg".to_vec().into();
     m.set_entries(vec![e].into());
     network.dispatch(vec![m]).unwrap();
 
@@ -5421,29 +6263,29 @@ fn test_uncommitted_entries_size_limit() {
     let mut nt = Network::new_with_config(vec![None, None, None], config, &l);
     let data = b"hello world!".to_vec();
     let mut entry = Entry::default();
-    entry.data = data.to_vec();
+    entry.data = data.to_vec().into();
     let msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![entry]);
 
     nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
 
     // should return ok
-    let result = nt.dispatch(vec![msg.clone()].to_vec());
+    let result = nt.dispatch([msg.clone()].to_vec());
     assert!(result.is_ok());
 
     // then next proposal should be dropped
-    let result = nt.dispatch(vec![msg].to_vec());
-    assert!(!result.is_ok());
-    assert_eq!(result.unwrap_err(), raft::Error::ProposalDropped);
+    let result = nt.dispatch([msg].to_vec());
+    assert!(result.is_err());
+    assert_eq!(result.unwrap_err(), raft::Error::QuotaExceeded);
 
     // but entry with empty size should be accepted
     let entry = Entry::default();
     let empty_msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![entry]);
-    let result = nt.dispatch(vec![empty_msg].to_vec());
+    let result = nt.dispatch([empty_msg].to_vec());
     assert!(result.is_ok());
 
     // after reduce, new proposal should be accecpted
     let mut entry = Entry::default();
-    entry.data = data;
+    entry.data = data.into();
     entry.index = 3;
     nt.peers
         .get_mut(&1)
@@ -5454,22 +6296,22 @@ fn test_uncommitted_entries_size_limit() {
     // a huge proposal should be accepted when there is no uncommitted entry,
     // even it's bigger than max_uncommitted_size
     let mut entry = Entry::default();
-    entry.data = b"hello world and raft".to_vec();
+    entry.data = b"hello world and raft".to_vec().into();
     let long_msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![entry]);
-    let result = nt.dispatch(vec![long_msg].to_vec());
+    let result = nt.dispatch([long_msg].to_vec());
     assert!(result.is_ok());
 
     // but another huge one will be dropped
     let mut entry = Entry::default();
-    entry.data = b"hello world and raft".to_vec();
+    entry.data = b"hello world and raft".to_vec().into();
     let long_msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![entry]);
-    let result = nt.dispatch(vec![long_msg].to_vec());
-    assert!(!result.is_ok());
+    let result = nt.dispatch([long_msg].to_vec());
+    assert!(result.is_err());
 
     // entry with empty size should still be accepted
     let entry = Entry::default();
     let empty_msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![entry]);
-    let result = nt.dispatch(vec![empty_msg].to_vec());
+    let result = nt.dispatch([empty_msg].to_vec());
     assert!(result.is_ok());
 }
 
@@ -5484,7 +6326,7 @@ fn test_uncommitted_entry_after_leader_election() {
     let mut nt = Network::new_with_config(vec![None, None, None, None, None], config, &l);
     let data = b"hello world!".to_vec();
     let mut entry = Entry::default();
-    entry.data = data;
+    entry.data = data.into();
     let msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![entry]);
 
     nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
@@ -5520,7 +6362,7 @@ fn test_uncommitted_state_advance_ready_from_last_term() {
 
     let data = b"hello world!".to_vec();
     let mut ent = Entry::default();
-    ent.data = data.clone();
+    ent.data = data.clone().into();
 
     nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
 
@@ -5565,3 +6407,356 @@ fn test_uncommitted_state_advance_ready_from_last_term() {
     // uncommitted size should be 12(remain unchanged since there's only one uncommitted entries)
     assert_eq!(nt.peers.get_mut(&2).unwrap().uncommitted_size(), data.len());
 }
+
+// Tests that a leader's progress tracker can be checkpointed and restored
+// mid-scenario, and that the leader keeps making progress from the restored view.
+#[test]
+fn test_progress_tracker_checkpoint_restore() {
+    let l = default_logger();
+    let mut nt = Network::new(vec![None, None, None], &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    let checkpoint = nt.peers[&1].prs().to_state();
+
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    assert_ne!(nt.peers[&1].prs().to_state(), checkpoint);
+
+    *nt.peers.get_mut(&1).unwrap().mut_prs() =
+        ProgressTracker::from_state(checkpoint.clone(), l.clone());
+    assert_eq!(nt.peers[&1].prs().to_state(), checkpoint);
+
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    let last_index = nt.peers[&1].raft_log.last_index();
+    assert_eq!(nt.peers[&1].raft_log.committed, last_index);
+    for id in 2..=3 {
+        assert_eq!(nt.peers[&1].prs().get(id).unwrap().matched, last_index);
+    }
+}
+
+// Tests that a leader only sends log metadata to a witness while still counting
+// its acks.
+#[test]
+fn test_witness_replication() {
+    let l = default_logger();
+    let mut nt = Network::new(vec![None, None, None], &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    nt.peers.get_mut(&1).unwrap().set_witness(3, true).unwrap();
+    assert_eq!(
+        nt.peers.get_mut(&1).unwrap().set_witness(4, true),
+        Err(Error::NotExists(4, "progress"))
+    );
+
+    // Node 2 is down, so the entry can only commit with the witness's ack.
+    nt.isolate(2);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    let last_index = nt.peers[&1].raft_log.last_index();
+    assert_eq!(nt.peers[&1].raft_log.committed, last_index);
+
+    let leader_ents = nt.peers[&1].raft_log.all_entries();
+    let witness_ents = nt.peers[&3].raft_log.all_entries();
+    assert_eq!(leader_ents.len(), witness_ents.len());
+    for (le, we) in leader_ents.iter().zip(&witness_ents) {
+        assert_eq!((le.index, le.term), (we.index, we.term));
+        assert!(we.data.is_empty());
+    }
+    assert!(!leader_ents.last().unwrap().data.is_empty());
+}
+
+// Tests that entries acknowledged by a quorum of witnesses are not committed until
+// the leader, the only voter holding their data, persists them.
+#[test]
+fn test_witness_commit_requires_data_replica() {
+    let l = default_logger();
+    let mut r = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), &l);
+    r.become_candidate();
+    r.become_leader();
+    r.persist();
+    r.set_witness(2, true).unwrap();
+    r.set_witness(3, true).unwrap();
+    let first_index = r.raft_log.last_index();
+
+    // The leader hasn't persisted the proposal yet.
+    r.step(new_message(1, 1, MessageType::MsgPropose, 1))
+        .unwrap();
+    let last_index = r.raft_log.last_index();
+    for id in 2..=3 {
+        let mut m = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        m.term = r.term;
+        m.index = last_index;
+        r.step(m).unwrap();
+    }
+    assert_eq!(r.raft_log.committed, first_index);
+
+    r.persist();
+    assert_eq!(r.raft_log.committed, last_index);
+}
+
+// Tests that a quorum of witnesses doesn't commit entries until a follower holding
+// their data acknowledges them, even if the leader hasn't persisted them yet.
+#[test]
+fn test_witness_commit_with_follower_data_replica() {
+    let l = default_logger();
+    let mut r = new_test_raft(1, vec![1, 2, 3, 4, 5], 10, 1, new_storage(), &l);
+    r.become_candidate();
+    r.become_leader();
+    r.persist();
+    for id in 3..=5 {
+        r.set_witness(id, true).unwrap();
+    }
+    let first_index = r.raft_log.last_index();
+
+    // The leader hasn't persisted the proposal yet.
+    r.step(new_message(1, 1, MessageType::MsgPropose, 1))
+        .unwrap();
+    let last_index = r.raft_log.last_index();
+    let ack = |r: &mut Interface, id| {
+        let mut m = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        m.term = r.term;
+        m.index = last_index;
+        r.step(m).unwrap();
+    };
+    // The witnesses alone form a quorum, but none of them has the data, so only the
+    // entries persisted by the leader are committed.
+    for id in 3..=5 {
+        ack(&mut r, id);
+    }
+    assert_eq!(r.raft_log.committed, first_index);
+
+    ack(&mut r, 2);
+    assert_eq!(r.raft_log.committed, last_index);
+}
+
+// Tests that snapshots sent to a witness carry no data.
+#[test]
+fn test_witness_snapshot_without_data() {
+    let l = default_logger();
+    let s = new_snapshot(11, 11, vec![1, 2]);
+    let mut sm = new_test_raft(1, vec![1], 10, 1, new_storage(), &l);
+    sm.restore(s);
+    sm.persist();
+    sm.become_candidate();
+    sm.become_leader();
+    sm.set_witness(2, true).unwrap();
+
+    sm.mut_prs().get_mut(2).unwrap().next_idx = sm.raft_log.first_index();
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.index = sm.prs().get(2).unwrap().next_idx - 1;
+    m.reject = true;
+    sm.step(m).expect("");
+
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgSnapshot);
+    assert_eq!(msgs[0].get_snapshot().get_metadata().index, 11);
+    assert!(msgs[0].get_snapshot().data.is_empty());
+}
+
+// Tests that a witness known from the persisted conf state never becomes leader,
+// even when it holds the only other copy of the committed log.
+#[test]
+fn test_witness_never_campaigns() {
+    let l = default_logger();
+    let peers: Vec<_> = (1..=3)
+        .map(|id| {
+            let mut cs = ConfState::from((vec![1, 2, 3], vec![]));
+            cs.set_witnesses(vec![3]);
+            let storage = MemStorage::new_with_conf_state(cs);
+            Some(new_test_raft(id, vec![1, 2, 3], 10, 1, storage, &l))
+        })
+        .collect();
+    let mut nt = Network::new(peers, &l);
+    assert!(nt.peers[&3].is_witness());
+    assert!(!nt.peers[&3].promotable());
+    assert!(nt.peers[&1].prs().get(3).unwrap().is_witness);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // Node 2 is down, so the entry commits through the witness only.
+    nt.isolate(2);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    let last_index = nt.peers[&1].raft_log.last_index();
+    assert_eq!(nt.peers[&1].raft_log.committed, last_index);
+    assert_eq!(nt.peers[&3].raft_log.last_index(), last_index);
+
+    // The leader dies and node 2 comes back.
+    nt.recover();
+    nt.isolate(1);
+    let term = nt.peers[&3].term;
+    nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    nt.peers
+        .get_mut(&3)
+        .unwrap()
+        .campaign(CampaignType::Election);
+    for _ in 0..30 {
+        nt.peers.get_mut(&3).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+    assert_eq!(nt.peers[&3].term, term);
+    assert!(nt.peers.get_mut(&3).unwrap().read_messages().is_empty());
+
+    // Node 2 lacks the committed entry, so the witness doesn't vote for it.
+    nt.send(vec![new_message(2, 2, MessageType::MsgHup, 0)]);
+    assert_ne!(nt.peers[&2].state, StateRole::Leader);
+
+    // Leadership is never transferred to the witness.
+    nt.recover();
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    nt.send(vec![new_message(3, 1, MessageType::MsgTransferLeader, 0)]);
+    assert_eq!(nt.peers[&1].lead_transferee, None);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    assert!(nt.peers.get_mut(&1).unwrap().set_witness(1, true).is_err());
+}
+
+// Tests that `campaign` starts the requested kind of election regardless of
+// the pre-vote setting.
+#[test]
+fn test_campaign_types() {
+    let l = default_logger();
+    let tests = [
+        (
+            CampaignType::PreElection,
+            StateRole::PreCandidate,
+            1,
+            MessageType::MsgRequestPreVote,
+        ),
+        (
+            CampaignType::Election,
+            StateRole::Candidate,
+            2,
+            MessageType::MsgRequestVote,
+        ),
+        (
+            CampaignType::Transfer,
+            StateRole::Candidate,
+            2,
+            MessageType::MsgRequestVote,
+        ),
+    ];
+    for (i, &(campaign_type, state, term, msg_type)) in tests.iter().enumerate() {
+        let mut r = new_test_raft_with_prevote(1, vec![1, 2, 3], 10, 1, new_storage(), true, &l);
+        r.become_follower(1, INVALID_ID);
+        r.campaign(campaign_type);
+        assert_eq!(r.state, state, "#{}", i);
+        assert_eq!(r.term, term, "#{}", i);
+        let msgs = r.read_messages();
+        assert_eq!(msgs.len(), 2, "#{}", i);
+        for m in msgs {
+            assert_eq!(m.get_msg_type(), msg_type, "#{}", i);
+            assert_eq!(m.term, 2, "#{}", i);
+            let is_transfer = campaign_type == CampaignType::Transfer;
+            assert_eq!(m.context == b"CampaignTransfer", is_transfer, "#{}", i);
+        }
+    }
+}
+
+// Tests that interceptors observe, drop and rewrite stepped and sent messages.
+#[test]
+fn test_message_interceptor() {
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder {
+        stepped: Arc<Mutex<Vec<MessageType>>>,
+    }
+
+    impl MessageInterceptor for Recorder {
+        fn on_step(&mut self, m: Message) -> Option<Message> {
+            self.stepped.lock().unwrap().push(m.get_msg_type());
+            // Vetoes proposals.
+            if m.get_msg_type() == MessageType::MsgPropose {
+                return None;
+            }
+            Some(m)
+        }
+    }
+
+    struct Filter;
+
+    impl MessageInterceptor for Filter {
+        fn on_send(&mut self, mut m: Message) -> Option<Message> {
+            if m.to == 3 {
+                return None;
+            }
+            m.context = b"traced".to_vec();
+            Some(m)
+        }
+    }
+
+    let l = default_logger();
+    let mut r = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), &l);
+    let stepped = Arc::new(Mutex::new(vec![]));
+    r.add_interceptor(Box::new(Recorder {
+        stepped: stepped.clone(),
+    }));
+    r.add_interceptor(Box::new(Filter));
+
+    r.step(new_message(1, 1, MessageType::MsgHup, 0)).unwrap();
+    let msgs = r.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].to, 2);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgRequestVote);
+    assert_eq!(msgs[0].context, b"traced");
+
+    let last_index = r.raft_log.last_index();
+    r.step(new_message(1, 1, MessageType::MsgPropose, 1))
+        .unwrap();
+    assert_eq!(r.raft_log.last_index(), last_index);
+    assert_eq!(
+        *stepped.lock().unwrap(),
+        vec![MessageType::MsgHup, MessageType::MsgPropose]
+    );
+}
+
+// Collects the key values of every record logged, including the ones of the logger.
+struct CapturingDrain(Arc<Mutex<Vec<String>>>);
+
+struct KvCollector(String);
+
+impl slog::Serializer for KvCollector {
+    fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+        self.0.push_str(&format!("{}={} ", key, val));
+        Ok(())
+    }
+}
+
+impl slog::Drain for CapturingDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> std::result::Result<(), slog::Never> {
+        let mut kv = KvCollector(String::new());
+        slog::KV::serialize(values, record, &mut kv).unwrap();
+        self.0.lock().unwrap().push(kv.0);
+        Ok(())
+    }
+}
+
+// Ensures that logs are tagged with the group ID and the current term if configured.
+#[test]
+fn test_logger_tags() {
+    let records = Arc::new(Mutex::new(vec![]));
+    let l = Logger::root(CapturingDrain(records.clone()), slog::o!());
+    let mut config = new_test_config(1, 10, 1);
+    config.group_id = Some(7);
+    config.log_term = true;
+    let s = MemStorage::new_with_conf_state((vec![1, 2], vec![]));
+    let mut r = new_test_raft_with_config(&config, s, &l);
+    let last = |records: &Arc<Mutex<Vec<String>>>| records.lock().unwrap().last().cloned();
+
+    let tags = last(&records).unwrap();
+    assert!(tags.contains("raft_id=1 "), "{}", tags);
+    assert!(tags.contains("group_id=7 "), "{}", tags);
+    assert!(tags.contains("raft_term=0 "), "{}", tags);
+
+    r.become_candidate();
+    let tags = last(&records).unwrap();
+    assert!(tags.contains("raft_term=1 "), "{}", tags);
+    r.become_follower(5, 2);
+    let tags = last(&records).unwrap();
+    assert!(tags.contains("raft_term=5 "), "{}", tags);
+}
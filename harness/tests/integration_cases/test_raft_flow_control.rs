@@ -175,3 +175,44 @@ fn test_msg_app_flow_control_recv_heartbeat() {
         r.read_messages();
     }
 }
+
+// test_msg_app_flow_control_commit ensures that a peer with a full sending
+// window still gets the commit index as soon as it advances, by an empty
+// msgApp that isn't tracked in the window.
+#[test]
+fn test_msg_app_flow_control_commit() {
+    let l = default_logger();
+    let mut r = new_test_raft(1, vec![1, 2, 3], 5, 1, new_storage(), &l);
+    r.become_candidate();
+    r.become_leader();
+    r.persist();
+
+    r.mut_prs().get_mut(2).unwrap().become_replicate();
+    r.mut_prs().get_mut(3).unwrap().become_replicate();
+    for _ in 0..r.max_inflight {
+        r.step(new_message(1, 1, MessageType::MsgPropose, 1))
+            .expect("");
+    }
+    r.persist();
+    r.read_messages();
+    assert!(r.prs().get(2).unwrap().ins.full());
+
+    let last_index = r.raft_log.last_index();
+    let mut m = new_message(3, 1, MessageType::MsgAppendResponse, 0);
+    m.term = r.term;
+    m.index = last_index;
+    r.step(m).expect("");
+    assert_eq!(r.raft_log.committed, last_index);
+
+    let ms: Vec<_> = r
+        .read_messages()
+        .into_iter()
+        .filter(|m| m.to == 2)
+        .collect();
+    assert_eq!(ms.len(), 1);
+    assert_eq!(ms[0].get_msg_type(), MessageType::MsgAppend);
+    assert!(ms[0].entries.is_empty());
+    assert_eq!(ms[0].index, last_index);
+    assert_eq!(ms[0].commit, last_index);
+    assert!(r.prs().get(2).unwrap().ins.full());
+}
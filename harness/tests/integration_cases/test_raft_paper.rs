@@ -75,7 +75,7 @@ fn test_leader_update_term_from_message() {
 // it immediately reverts to follower state.
 // Reference: section 5.1
 fn test_update_term_from_message(state: StateRole, l: &Logger) {
-    let mut r = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), &l);
+    let mut r = new_test_raft(1, vec![1, 2, 3], 10, 1, new_storage(), l);
     match state {
         StateRole::Follower => r.become_follower(1, 2),
         StateRole::PreCandidate => r.become_pre_candidate(),
@@ -480,7 +480,7 @@ fn test_leader_commit_entry() {
 
     assert_eq!(r.raft_log.committed, li + 1);
     let wents = vec![new_entry(1, li + 1, SOME_DATA)];
-    assert_eq!(r.raft_log.next_entries(), Some(wents));
+    assert_eq!(r.raft_log.next_entries(None), Some(wents));
     let mut msgs = r.read_messages();
     msgs.sort_by_key(|m| format!("{:?}", m));
     for (i, m) in msgs.drain(..).enumerate() {
@@ -570,7 +570,7 @@ fn test_leader_commit_preceding_entries() {
             empty_entry(3, li + 1),
             new_entry(3, li + 2, SOME_DATA),
         ]);
-        let g = r.raft_log.next_entries();
+        let g = r.raft_log.next_entries(None);
         let wg = Some(tt);
         if g != wg {
             panic!("#{}: ents = {:?}, want {:?}", i, g, wg);
@@ -627,7 +627,7 @@ fn test_follower_commit_entry() {
             );
         }
         let wents = Some(ents[..commit as usize].to_vec());
-        let g = r.raft_log.next_entries();
+        let g = r.raft_log.next_entries(None);
         if g != wents {
             panic!("#{}: next_ents = {:?}, want {:?}", i, g, wents);
         }
@@ -645,12 +645,12 @@ fn test_follower_check_msg_append() {
     let ents = vec![empty_entry(1, 1), empty_entry(2, 2)];
     let mut tests = vec![
         // match with committed entries
-        (0, 0, 1, false, 0, 1),
-        (ents[0].term, ents[0].index, 1, false, 0, 1),
+        (0, 0, 1, false, 0, 0, 1),
+        (ents[0].term, ents[0].index, 1, false, 0, 0, 1),
         // match with uncommitted entries
-        (ents[1].term, ents[1].index, 2, false, 0, 1),
+        (ents[1].term, ents[1].index, 2, false, 0, 0, 1),
         // unmatch with existing entry
-        (ents[0].term, ents[1].index, ents[1].index, true, 2, 1),
+        (ents[0].term, ents[1].index, ents[1].index, true, 1, 1, 1),
         // unexisting entry
         (
             ents[1].term + 1,
@@ -658,10 +658,13 @@ fn test_follower_check_msg_append() {
             ents[1].index + 1,
             true,
             2,
+            2,
             1,
         ),
     ];
-    for (i, (term, index, windex, wreject, wreject_hint, w_commit)) in tests.drain(..).enumerate() {
+    for (i, (term, index, windex, wreject, wreject_hint, wlog_term, w_commit)) in
+        tests.drain(..).enumerate()
+    {
         let mut r = {
             let store = MemStorage::new_with_conf_state((vec![1, 2, 3], vec![]));
             store.wl().append(&ents).unwrap();
@@ -685,6 +688,7 @@ fn test_follower_check_msg_append() {
         if wreject {
             wm.reject = wreject;
             wm.reject_hint = wreject_hint;
+            wm.log_term = wlog_term;
         }
         let expect_msgs = vec![wm];
         if msgs != expect_msgs {
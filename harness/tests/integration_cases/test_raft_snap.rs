@@ -17,7 +17,9 @@
 use crate::test_util::*;
 use harness::Network;
 use raft::eraftpb::*;
-use raft::{default_logger, Error, ProgressState, INVALID_INDEX};
+use raft::{
+    default_logger, Error, Progress, ProgressState, SnapshotPolicy, SnapshotStatus, INVALID_INDEX,
+};
 
 fn testing_snap() -> Snapshot {
     new_snapshot(11, 11, vec![1, 2])
@@ -108,6 +110,233 @@ fn test_snapshot_succeed() {
     assert!(voter_2.paused);
 }
 
+#[test]
+fn test_report_snapshot() {
+    let l = default_logger();
+    for (status, next_idx) in [(SnapshotStatus::Finish, 12), (SnapshotStatus::Failure, 1)] {
+        let mut sm = new_test_raft(1, vec![1, 2], 10, 1, new_storage(), &l);
+        sm.restore(testing_snap());
+        sm.persist();
+
+        sm.become_candidate();
+        sm.become_leader();
+
+        sm.mut_prs().get_mut(2).unwrap().next_idx = 1;
+        sm.mut_prs().get_mut(2).unwrap().become_snapshot(11);
+
+        sm.report_snapshot(2, status);
+        let pr = sm.prs().get(2).unwrap();
+        assert_eq!(pr.state, ProgressState::Probe, "{:?}", status);
+        assert_eq!(pr.pending_snapshot, 0, "{:?}", status);
+        assert_eq!(pr.next_idx, next_idx, "{:?}", status);
+        assert!(pr.paused, "{:?}", status);
+
+        // Once the follower acks, it goes back to optimistic replication.
+        let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+        m.index = 11;
+        m.term = sm.term;
+        sm.step(m).expect("");
+        assert_eq!(
+            sm.prs().get(2).unwrap().state,
+            ProgressState::Replicate,
+            "{:?}",
+            status
+        );
+    }
+}
+
+// Ensures that the leader keeps the progress of a follower and retries later when
+// the snapshot is temporarily unavailable.
+#[test]
+fn test_snapshot_temporarily_unavailable() {
+    let l = default_logger();
+    let mut sm = new_test_raft(1, vec![1, 2], 10, 1, new_storage(), &l);
+    sm.restore(testing_snap());
+    sm.persist();
+
+    sm.become_candidate();
+    sm.become_leader();
+    sm.read_messages();
+
+    sm.mut_prs().get_mut(2).unwrap().next_idx = sm.raft_log.first_index();
+    sm.store().wl().trigger_snap_unavailable();
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.index = sm.raft_log.first_index() - 1;
+    m.reject = true;
+    m.term = sm.term;
+    sm.step(m).expect("");
+    assert!(sm.read_messages().is_empty());
+    let pr = sm.prs().get(2).unwrap();
+    assert_eq!(pr.state, ProgressState::Probe);
+    assert_eq!(pr.pending_snapshot, 0);
+
+    // The snapshot is sent on the next heartbeat response.
+    let mut m = new_message(2, 1, MessageType::MsgHeartbeatResponse, 0);
+    m.term = sm.term;
+    sm.step(m).expect("");
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgSnapshot);
+    assert_eq!(sm.prs().get(2).unwrap().state, ProgressState::Snapshot);
+}
+
+// Ensures that the leader keeps retrying on every heartbeat response until the
+// snapshot becomes available.
+#[test]
+fn test_snapshot_unavailable_retries() {
+    let l = default_logger();
+    let mut sm = new_test_raft(1, vec![1, 2], 10, 1, new_storage(), &l);
+    sm.restore(testing_snap());
+    sm.persist();
+
+    sm.become_candidate();
+    sm.become_leader();
+    sm.read_messages();
+
+    sm.mut_prs().get_mut(2).unwrap().next_idx = sm.raft_log.first_index();
+    sm.store().trigger_snap_unavailable(3);
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.index = sm.raft_log.first_index() - 1;
+    m.reject = true;
+    m.term = sm.term;
+    sm.step(m).expect("");
+    assert!(sm.read_messages().is_empty());
+
+    let mut heartbeat_resp = new_message(2, 1, MessageType::MsgHeartbeatResponse, 0);
+    heartbeat_resp.term = sm.term;
+    for _ in 0..2 {
+        sm.step(heartbeat_resp.clone()).expect("");
+        assert!(sm.read_messages().is_empty());
+        assert_eq!(sm.prs().get(2).unwrap().state, ProgressState::Probe);
+    }
+
+    sm.step(heartbeat_resp).expect("");
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgSnapshot);
+    assert_eq!(sm.prs().get(2).unwrap().state, ProgressState::Snapshot);
+}
+
+struct GapPolicy(u64);
+
+impl SnapshotPolicy for GapPolicy {
+    fn should_send_snapshot(&mut self, _: u64, pr: &Progress, _: u64, last_index: u64) -> bool {
+        last_index + 1 - pr.next_idx > self.0
+    }
+}
+
+// Ensures a snapshot policy can make the leader send a snapshot to a probing
+// follower even if the log still holds the missing entries.
+#[test]
+fn test_snapshot_policy() {
+    let l = default_logger();
+    for (next_idx, policy, want) in [
+        (13, None, MessageType::MsgAppend),
+        (13, Some(5), MessageType::MsgSnapshot),
+        (20, Some(5), MessageType::MsgAppend),
+    ] {
+        let mut sm = new_test_raft(1, vec![1, 2], 10, 1, new_storage(), &l);
+        sm.restore(testing_snap());
+        sm.persist();
+        if let Some(gap) = policy {
+            sm.set_snapshot_policy(Box::new(GapPolicy(gap)));
+        }
+
+        sm.become_candidate();
+        sm.become_leader();
+        for _ in 0..10 {
+            sm.step(new_message(1, 1, MessageType::MsgPropose, 1))
+                .expect("");
+        }
+        sm.persist();
+        sm.read_messages();
+        assert_eq!(sm.raft_log.last_index(), 22);
+
+        let pr = sm.mut_prs().get_mut(2).unwrap();
+        assert_eq!(pr.state, ProgressState::Probe);
+        pr.next_idx = next_idx;
+        pr.paused = false;
+        pr.recent_active = true;
+        sm.send_append(2);
+        let msgs = sm.read_messages();
+        assert_eq!(msgs.len(), 1, "{}, {:?}", next_idx, policy);
+        assert_eq!(msgs[0].get_msg_type(), want, "{}, {:?}", next_idx, policy);
+        if want == MessageType::MsgSnapshot {
+            assert_eq!(msgs[0].get_snapshot().get_metadata().index, 11);
+            assert_eq!(sm.prs().get(2).unwrap().state, ProgressState::Snapshot);
+        }
+    }
+}
+
+// Ensures the leader sends at most max_inflight_snapshots snapshots at a time.
+#[test]
+fn test_max_inflight_snapshots() {
+    let l = default_logger();
+    let mut cfg = new_test_config(1, 10, 1);
+    cfg.max_inflight_snapshots = 1;
+    let s = new_storage();
+    s.initialize_with_conf_state((vec![1, 2, 3], vec![]));
+    let mut sm = new_test_raft_with_config(&cfg, s, &l);
+    sm.restore(new_snapshot(11, 11, vec![1, 2, 3]));
+    sm.persist();
+
+    sm.become_candidate();
+    sm.become_leader();
+    sm.read_messages();
+    for id in [2, 3] {
+        let pr = sm.mut_prs().get_mut(id).unwrap();
+        pr.next_idx = 1;
+        pr.paused = false;
+        pr.recent_active = true;
+    }
+
+    sm.send_append(2);
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgSnapshot);
+    assert_eq!(sm.prs().get(2).unwrap().state, ProgressState::Snapshot);
+
+    // Neither the same peer nor another one gets a second snapshot.
+    sm.send_append(2);
+    sm.send_append(3);
+    assert!(sm.read_messages().is_empty());
+    assert_eq!(sm.prs().get(3).unwrap().state, ProgressState::Probe);
+
+    sm.report_snapshot(2, SnapshotStatus::Finish);
+    sm.send_append(3);
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgSnapshot);
+    assert_eq!(msgs[0].to, 3);
+
+    // A snapshot aborted by an append response frees its slot as well.
+    let mut m = new_message(3, 1, MessageType::MsgAppendResponse, 0);
+    m.term = sm.term;
+    m.index = 11;
+    sm.step(m).unwrap();
+    assert_ne!(sm.prs().get(3).unwrap().state, ProgressState::Snapshot);
+    sm.read_messages();
+    let pr = sm.mut_prs().get_mut(2).unwrap();
+    pr.next_idx = 1;
+    pr.paused = false;
+    sm.send_append(2);
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgSnapshot);
+    assert_eq!(msgs[0].to, 2);
+
+    // Removing a peer that is sent a snapshot frees its slot too.
+    sm.apply_conf_change(&remove_node(2)).unwrap();
+    let pr = sm.mut_prs().get_mut(3).unwrap();
+    pr.next_idx = 1;
+    pr.paused = false;
+    sm.send_append(3);
+    let msgs = sm.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgSnapshot);
+    assert_eq!(msgs[0].to, 3);
+}
+
 #[test]
 fn test_snapshot_abort() {
     let l = default_logger();
@@ -165,7 +394,7 @@ fn test_request_snapshot() {
             .unwrap()
             .request_snapshot(INVALID_INDEX + 1)
             .unwrap_err(),
-        Error::RequestSnapshotDropped
+        Error::RequestSnapshotDropped("no leader")
     );
 
     sm.become_candidate();
@@ -178,7 +407,7 @@ fn test_request_snapshot() {
             .unwrap()
             .request_snapshot(INVALID_INDEX + 1)
             .unwrap_err(),
-        Error::RequestSnapshotDropped
+        Error::RequestSnapshotDropped("peer is leader")
     );
 
     // Advance matched.
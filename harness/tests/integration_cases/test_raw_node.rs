@@ -55,6 +55,12 @@ fn must_cmp_ready(
     assert_eq!(r.messages().is_empty(), msg_is_empty);
 }
 
+fn persist_hard_state(store: &MemStorage, rd: &Ready) {
+    if let Some(hs) = rd.hs() {
+        store.wl().set_hardstate(hs.clone());
+    }
+}
+
 fn new_raw_node(
     id: u64,
     peers: Vec<u64>,
@@ -82,7 +88,7 @@ fn new_raw_node_with_config(
             .apply_snapshot(new_snapshot(1, 1, peers))
             .unwrap();
     }
-    RawNode::new(&config, storage, logger).unwrap()
+    RawNode::new(config, storage, logger).unwrap()
 }
 
 /// Ensures that RawNode::step ignore local message.
@@ -106,6 +112,49 @@ fn test_raw_node_step() {
     }
 }
 
+/// Ensures that RawNode::step rejects messages that can't be meant for it.
+#[test]
+fn test_raw_node_step_invalid_message() {
+    let l = default_logger();
+    let mut raw_node = new_raw_node(1, vec![1, 2], 10, 1, new_storage(), &l);
+    let heartbeat = |from, to, term| {
+        let mut m = new_message(from, to, MessageType::MsgHeartbeat, 0);
+        m.term = term;
+        m
+    };
+    let tests = vec![
+        (heartbeat(0, 1, 2), "sender is not set"),
+        (heartbeat(2, 0, 2), "receiver is not this peer"),
+        (heartbeat(2, 3, 2), "receiver is not this peer"),
+        (heartbeat(2, 1, u64::MAX), "term would overflow"),
+    ];
+    for (m, reason) in tests {
+        let (from, to) = (m.from, m.to);
+        assert_eq!(
+            raw_node.step(m),
+            Err(Error::InvalidMessage(from, to, reason))
+        );
+    }
+    assert_eq!(raw_node.raft.term, 1);
+
+    // Unknown senders are only rejected if configured.
+    raw_node.step(heartbeat(3, 1, 2)).unwrap();
+    assert_eq!(raw_node.raft.leader_id, 3);
+    let mut cfg = new_test_config(1, 10, 1);
+    cfg.reject_unknown_senders = true;
+    let mut raw_node = new_raw_node_with_config(vec![1, 2], &cfg, new_storage(), &l);
+    assert_eq!(
+        raw_node.step(heartbeat(3, 1, 2)),
+        Err(Error::InvalidMessage(
+            3,
+            1,
+            "sender is not in the configuration"
+        ))
+    );
+    raw_node.step(heartbeat(2, 1, 2)).unwrap();
+    assert_eq!(raw_node.raft.leader_id, 2);
+}
+
 /// Ensures that MsgReadIndex to old leader gets forwarded to the new leader and
 /// 'send' method does not attach its term.
 #[test]
@@ -121,7 +170,7 @@ fn test_raw_node_read_index_to_old_leader() {
     nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
 
     let mut test_entries = Entry::default();
-    test_entries.data = b"testdata".to_vec();
+    test_entries.data = b"testdata".to_vec().into();
 
     // send readindex request to r2(follower)
     let _ = nt.peers.get_mut(&2).unwrap().step(new_message_with_entries(
@@ -258,6 +307,7 @@ fn test_raw_node_propose_and_conf_change() {
         while cs.is_none() {
             let mut rd = raw_node.ready();
             s.wl().append(rd.entries()).unwrap();
+            persist_hard_state(&s, &rd);
             let mut handle_committed_entries =
                 |rn: &mut RawNode<MemStorage>, committed_entries: Vec<Entry>| {
                     for e in committed_entries {
@@ -273,7 +323,7 @@ fn test_raw_node_propose_and_conf_change() {
                     }
                 };
             handle_committed_entries(&mut raw_node, rd.take_committed_entries());
-            let is_leader = rd.ss().map_or(false, |ss| ss.leader_id == raw_node.raft.id);
+            let is_leader = rd.ss().is_some_and(|ss| ss.leader_id == raw_node.raft.id);
 
             let mut light_rd = raw_node.advance(rd);
             handle_committed_entries(&mut raw_node, light_rd.take_committed_entries());
@@ -301,7 +351,14 @@ fn test_raw_node_propose_and_conf_change() {
         // will not reflect any unstable entries that we'll only be presented
         // with in the next Ready.
         let last_index = s.last_index().unwrap();
-        let entries = s.entries(last_index - 1, last_index + 1, NO_LIMIT).unwrap();
+        let entries = s
+            .entries(
+                last_index - 1,
+                last_index + 1,
+                NO_LIMIT,
+                GetEntriesContext::empty(false),
+            )
+            .unwrap();
         assert_eq!(entries.len(), 2);
         assert_eq!(entries[0].get_data(), b"somedata");
         if cc.as_v1().is_some() {
@@ -381,6 +438,7 @@ fn test_raw_node_joint_auto_leave() {
     while cs.is_none() {
         let mut rd = raw_node.ready();
         s.wl().append(rd.entries()).unwrap();
+        persist_hard_state(&s, &rd);
         let mut handle_committed_entries =
             |rn: &mut RawNode<MemStorage>, committed_entries: Vec<Entry>| {
                 for e in committed_entries {
@@ -398,7 +456,7 @@ fn test_raw_node_joint_auto_leave() {
                 }
             };
         handle_committed_entries(&mut raw_node, rd.take_committed_entries());
-        let is_leader = rd.ss().map_or(false, |ss| ss.leader_id == raw_node.raft.id);
+        let is_leader = rd.ss().is_some_and(|ss| ss.leader_id == raw_node.raft.id);
 
         let mut light_rd = raw_node.advance(rd);
         handle_committed_entries(&mut raw_node, light_rd.take_committed_entries());
@@ -419,7 +477,14 @@ fn test_raw_node_joint_auto_leave() {
     // will not reflect any unstable entries that we'll only be presented
     // with in the next Ready.
     let last_index = s.last_index().unwrap();
-    let entries = s.entries(last_index - 1, last_index + 1, NO_LIMIT).unwrap();
+    let entries = s
+        .entries(
+            last_index - 1,
+            last_index + 1,
+            NO_LIMIT,
+            GetEntriesContext::empty(false),
+        )
+        .unwrap();
     assert_eq!(entries.len(), 2);
     assert_eq!(entries[0].get_data(), b"somedata");
     assert_eq!(entries[1].get_entry_type(), EntryType::EntryConfChangeV2);
@@ -430,16 +495,19 @@ fn test_raw_node_joint_auto_leave() {
     // Move the RawNode along. It should not leave joint because it's follower.
     let mut rd = raw_node.ready();
     assert!(rd.entries().is_empty());
+    persist_hard_state(&s, &rd);
     let _ = raw_node.advance(rd);
 
     // Make it leader again. It should leave joint automatically after moving apply index.
     raw_node.campaign().unwrap();
     rd = raw_node.ready();
     s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
     let _ = raw_node.advance(rd);
 
     rd = raw_node.ready();
     s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
 
     // Check that the right ConfChange comes out.
     assert_eq!(rd.entries().len(), 1);
@@ -456,6 +524,163 @@ fn test_raw_node_joint_auto_leave() {
     // we require the joint quorum and we're only running one node.
     let cs = raw_node.apply_conf_change(&leave_cc).unwrap();
     assert_eq!(cs, exp_cs2);
+
+    // Leaving again, e.g. when a later leader proposed the transition too, is a no-op.
+    let cs = raw_node.apply_conf_change(&leave_cc).unwrap();
+    assert_eq!(cs, exp_cs2);
+}
+
+/// Tests that dropped proposals are queued with their context and the reason.
+#[test]
+fn test_raw_node_dropped_proposals() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut cfg = new_test_config(1, 10, 1);
+    cfg.max_size_per_msg = 64;
+    cfg.max_uncommitted_size = 64;
+    let mut raw_node = new_raw_node_with_config(vec![1], &cfg, s.clone(), &l);
+
+    // Nothing is queued unless enabled.
+    assert_eq!(
+        raw_node.propose(b"ctx0".to_vec(), b"data".to_vec()),
+        Err(Error::NoLeader)
+    );
+    assert!(raw_node.take_dropped_proposals().is_empty());
+
+    let s = new_storage();
+    cfg.track_dropped_proposals = true;
+    let mut raw_node = new_raw_node_with_config(vec![1], &cfg, s.clone(), &l);
+    assert_eq!(
+        raw_node.propose(b"ctx1".to_vec(), b"data".to_vec()),
+        Err(Error::NoLeader)
+    );
+    assert_eq!(
+        raw_node.take_dropped_proposals(),
+        vec![DroppedProposal {
+            context: b"ctx1".to_vec(),
+            reason: ProposalDropReason::NoLeader,
+        }]
+    );
+    assert!(raw_node.take_dropped_proposals().is_empty());
+
+    raw_node.campaign().unwrap();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    let _ = raw_node.advance(rd);
+    assert_eq!(raw_node.raft.state, StateRole::Leader);
+
+    raw_node.propose(b"ctx2".to_vec(), vec![0; 40]).unwrap();
+    assert_eq!(
+        raw_node.propose(b"ctx3".to_vec(), vec![0; 40]),
+        Err(Error::QuotaExceeded)
+    );
+    let cc = conf_change(ConfChangeType::AddNode, 2);
+    raw_node
+        .propose_conf_change(b"cc1".to_vec(), cc.clone())
+        .unwrap();
+    let pending_index = raw_node.raft.pending_conf_index;
+    assert_eq!(
+        raw_node.propose_conf_change(b"cc2".to_vec(), cc),
+        Err(Error::ConfChangePending(pending_index))
+    );
+    assert_eq!(
+        raw_node.take_dropped_proposals(),
+        vec![
+            DroppedProposal {
+                context: b"ctx3".to_vec(),
+                reason: ProposalDropReason::QuotaExceeded,
+            },
+            DroppedProposal {
+                context: b"cc2".to_vec(),
+                reason: ProposalDropReason::ConfChangeRefused,
+            },
+        ]
+    );
+}
+
+/// Tests that the leader reports the peers it doesn't hear from in time.
+#[test]
+fn test_raw_node_inactive_peers() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut cfg = new_test_config(1, 10, 1);
+    cfg.inactive_peer_tick = 3;
+    let mut raw_node = new_raw_node_with_config(vec![1, 2, 3], &cfg, s, &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+
+    let heartbeat_resp = |raw_node: &RawNode<MemStorage>, from| {
+        let mut m = new_message(from, 1, MessageType::MsgHeartbeatResponse, 0);
+        m.term = raw_node.raft.term;
+        m
+    };
+    raw_node.tick();
+    raw_node.tick();
+    let m = heartbeat_resp(&raw_node, 2);
+    raw_node.step(m).unwrap();
+    raw_node.tick();
+    assert!(raw_node.take_inactive_peers().is_empty());
+    raw_node.tick();
+    assert_eq!(raw_node.take_inactive_peers(), vec![3]);
+    raw_node.tick();
+    assert!(raw_node.take_inactive_peers().is_empty());
+    raw_node.tick();
+    assert_eq!(raw_node.take_inactive_peers(), vec![2]);
+
+    // A peer is reported again only after it's heard from.
+    for _ in 0..5 {
+        raw_node.tick();
+    }
+    assert!(raw_node.take_inactive_peers().is_empty());
+    let m = heartbeat_resp(&raw_node, 3);
+    raw_node.step(m).unwrap();
+    for _ in 0..4 {
+        raw_node.tick();
+    }
+    assert_eq!(raw_node.take_inactive_peers(), vec![3]);
+}
+
+/// Tests that committed entries and dropped proposals can be correlated back to
+/// the tokens they were proposed with.
+#[test]
+fn test_raw_node_proposal_tokens() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut cfg = new_test_config(1, 10, 1);
+    cfg.track_dropped_proposals = true;
+    let mut raw_node = new_raw_node_with_config(vec![1], &cfg, s.clone(), &l);
+
+    assert_eq!(
+        raw_node.propose_with_token(1, b"data1".to_vec()),
+        Err(Error::NoLeader)
+    );
+    let dropped = raw_node.take_dropped_proposals();
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(ProposalContext::token_of_dropped(&dropped[0]), Some(1));
+
+    raw_node.campaign().unwrap();
+    let mut committed = vec![];
+    let mut handle_ready = |raw_node: &mut RawNode<MemStorage>| {
+        let mut rd = raw_node.ready();
+        s.wl().append(rd.entries()).unwrap();
+        persist_hard_state(&s, &rd);
+        committed.extend(rd.take_committed_entries());
+        committed.extend(raw_node.advance(rd).take_committed_entries());
+    };
+    handle_ready(&mut raw_node);
+
+    raw_node.propose_with_token(2, b"data2".to_vec()).unwrap();
+    let ctx = ProposalContext::with_data(u64::MAX, b"user".to_vec());
+    raw_node.propose(ctx.encode(), b"data3".to_vec()).unwrap();
+    handle_ready(&mut raw_node);
+    let tokens: Vec<_> = committed
+        .iter()
+        .map(ProposalContext::token_of_entry)
+        .collect();
+    // The empty entry of the new leader carries no token.
+    assert_eq!(tokens, vec![None, Some(2), Some(u64::MAX)]);
+    assert_eq!(ProposalContext::decode(&committed[2].context), Some(ctx));
 }
 
 /// Ensures that two proposes to add the same node should not affect the later propose
@@ -469,7 +694,8 @@ fn test_raw_node_propose_add_duplicate_node() {
     loop {
         let rd = raw_node.ready();
         s.wl().append(rd.entries()).expect("");
-        if rd.ss().map_or(false, |ss| ss.leader_id == raw_node.raft.id) {
+        persist_hard_state(&s, &rd);
+        if rd.ss().is_some_and(|ss| ss.leader_id == raw_node.raft.id) {
             let _ = raw_node.advance(rd);
             break;
         }
@@ -480,6 +706,7 @@ fn test_raw_node_propose_add_duplicate_node() {
         raw_node.propose_conf_change(vec![], cc).expect("");
         let mut rd = raw_node.ready();
         s.wl().append(rd.entries()).expect("");
+        persist_hard_state(&s, &rd);
         let handle_committed_entries =
             |rn: &mut RawNode<MemStorage>, committed_entries: Vec<Entry>| {
                 for e in committed_entries {
@@ -512,7 +739,14 @@ fn test_raw_node_propose_add_duplicate_node() {
     let last_index = s.last_index().unwrap();
 
     // the last three entries should be: ConfChange cc1, cc1, cc2
-    let mut entries = s.entries(last_index - 2, last_index + 1, None).unwrap();
+    let mut entries = s
+        .entries(
+            last_index - 2,
+            last_index + 1,
+            None,
+            GetEntriesContext::empty(false),
+        )
+        .unwrap();
     assert_eq!(entries.len(), 3);
     assert_eq!(entries[0].take_data(), ccdata1);
     assert_eq!(entries[2].take_data(), ccdata2);
@@ -531,7 +765,8 @@ fn test_raw_node_propose_add_learner_node() -> Result<()> {
     loop {
         let rd = raw_node.ready();
         s.wl().append(rd.entries()).expect("");
-        if rd.ss().map_or(false, |ss| ss.leader_id == raw_node.raft.id) {
+        persist_hard_state(&s, &rd);
+        if rd.ss().is_some_and(|ss| ss.leader_id == raw_node.raft.id) {
             let _ = raw_node.advance(rd);
             break;
         }
@@ -544,6 +779,7 @@ fn test_raw_node_propose_add_learner_node() -> Result<()> {
 
     let rd = raw_node.ready();
     s.wl().append(rd.entries()).expect("");
+    persist_hard_state(&s, &rd);
 
     let light_rd = raw_node.advance(rd);
 
@@ -581,11 +817,12 @@ fn test_raw_node_read_index() {
     loop {
         let rd = raw_node.ready();
         s.wl().append(rd.entries()).expect("");
-        if rd.ss().map_or(false, |ss| ss.leader_id == raw_node.raft.id) {
+        persist_hard_state(&s, &rd);
+        if rd.ss().is_some_and(|ss| ss.leader_id == raw_node.raft.id) {
             let _ = raw_node.advance(rd);
 
             // Once we are the leader, issue a read index request
-            raw_node.read_index(wrequest_ctx);
+            raw_node.read_index(wrequest_ctx).unwrap();
             break;
         }
         let _ = raw_node.advance(rd);
@@ -597,6 +834,7 @@ fn test_raw_node_read_index() {
     let rd = raw_node.ready();
     assert_eq!(*rd.read_states(), wrs);
     s.wl().append(rd.entries()).expect("");
+    persist_hard_state(&s, &rd);
     let _ = raw_node.advance(rd);
 
     // ensure raft.read_states is reset after advance
@@ -604,6 +842,524 @@ fn test_raw_node_read_index() {
     assert!(raw_node.raft.read_states.is_empty());
 }
 
+// Ensures that read states produced while a ready is being handled are returned by
+// the LightReady of that ready if enabled, after the ones in the ready itself, and
+// by the next ready otherwise.
+#[test]
+fn test_raw_node_read_index_light_ready() {
+    let l = default_logger();
+    for enabled in [false, true] {
+        let s = new_storage();
+        let mut config = new_test_config(1, 10, 1);
+        config.light_ready_read_states = enabled;
+        let mut raw_node = new_raw_node_with_config(vec![1], &config, s.clone(), &l);
+        raw_node.campaign().unwrap();
+        let rd = raw_node.ready();
+        s.wl().append(rd.entries()).unwrap();
+        persist_hard_state(&s, &rd);
+        let _ = raw_node.advance(rd);
+        assert_eq!(raw_node.raft.state, StateRole::Leader);
+
+        raw_node.read_index(b"r1".to_vec()).unwrap();
+        let rd = raw_node.ready();
+        assert_eq!(
+            *rd.read_states(),
+            vec![ReadState {
+                index: 2,
+                request_ctx: b"r1".to_vec(),
+            }]
+        );
+        raw_node.read_index(b"r2".to_vec()).unwrap();
+        let mut light_rd = raw_node.advance(rd);
+        let r2 = vec![ReadState {
+            index: 2,
+            request_ctx: b"r2".to_vec(),
+        }];
+        if enabled {
+            assert_eq!(light_rd.take_read_states(), r2);
+            assert!(!raw_node.has_ready());
+        } else {
+            assert!(light_rd.read_states().is_empty());
+            assert!(raw_node.has_ready());
+            assert_eq!(*raw_node.ready().read_states(), r2);
+        }
+    }
+}
+
+// Ensures that buffers returned by the application are reused by later readies.
+#[test]
+fn test_raw_node_recycle_buffers() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    let _ = raw_node.advance(rd);
+
+    raw_node.propose(vec![], b"foo".to_vec()).unwrap();
+    let mut rd = raw_node.ready();
+    let entries = rd.take_entries();
+    assert_eq!(entries.len(), 1);
+    s.wl().append(&entries).unwrap();
+    let msgs = rd.take_messages();
+    assert_eq!(msgs.iter().map(Vec::len).sum::<usize>(), 2);
+    let _ = raw_node.advance_append(rd);
+    let entries_ptr = entries.as_ptr();
+    raw_node.recycle_entry_buffer(entries);
+    raw_node.recycle_message_buffers(msgs);
+    for id in 2..=3 {
+        let mut m = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        m.term = 2;
+        m.index = 2;
+        raw_node.step(m).unwrap();
+    }
+
+    raw_node.propose(vec![], b"bar".to_vec()).unwrap();
+    let mut rd = raw_node.ready();
+    // The recycled buffers are handed out again instead of fresh allocations.
+    assert_eq!(rd.entries().as_ptr(), entries_ptr);
+    assert_eq!(rd.entries()[0].get_data(), b"bar");
+    assert!(!rd.messages().is_empty());
+    assert!(raw_node.raft.msgs.is_empty());
+    assert!(raw_node.raft.msgs.capacity() > 0);
+    s.wl().append(&rd.take_entries()).unwrap();
+    let _ = raw_node.advance_append(rd);
+}
+
+/// Ensures that RawNode::step_untrusted rejects messages with insane fields.
+#[test]
+fn test_raw_node_step_untrusted() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2], 10, 1, s, &l);
+    let append = |log_term, index, ents: Vec<(u64, u64)>| {
+        let ents = ents.into_iter().map(|(t, i)| empty_entry(t, i)).collect();
+        let mut m = new_message_with_entries(2, 1, MessageType::MsgAppend, ents);
+        m.term = 2;
+        m.log_term = log_term;
+        m.index = index;
+        m
+    };
+    let mut snap = new_message(2, 1, MessageType::MsgSnapshot, 0);
+    snap.term = 2;
+    let mut newer_snap = snap.clone();
+    newer_snap.set_snapshot(new_snapshot(10, 3, vec![1, 2]));
+    let mut heartbeat = new_message(2, 1, MessageType::MsgHeartbeat, 0);
+    heartbeat.term = 2;
+    heartbeat.set_entries(vec![empty_entry(2, 2)].into());
+
+    let tests = vec![
+        (append(3, 1, vec![]), "log term is larger than term"),
+        (
+            append(1, 1, vec![(2, 2), (2, 4)]),
+            "entries are not contiguous",
+        ),
+        (append(1, 1, vec![(2, 1)]), "entries are not contiguous"),
+        (
+            append(1, u64::MAX, vec![(2, 0)]),
+            "entries are not contiguous",
+        ),
+        (append(1, 1, vec![(2, 2), (1, 3)]), "entry terms decrease"),
+        (append(2, 1, vec![(1, 2)]), "entry terms decrease"),
+        (append(1, 1, vec![(3, 2)]), "entry term is larger than term"),
+        (snap, "snapshot is empty"),
+        (newer_snap, "snapshot term is larger than term"),
+        (heartbeat, "unexpected entries"),
+    ];
+    for (m, reason) in tests {
+        let desc = format!("{:?}", m);
+        assert_eq!(
+            raw_node.step_untrusted(m),
+            Err(Error::InvalidMessage(2, 1, reason)),
+            "{}",
+            desc
+        );
+    }
+    // Nothing is changed by the rejected messages.
+    assert_eq!(raw_node.raft.term, 1);
+    assert_eq!(raw_node.raft.raft_log.last_index(), 1);
+
+    raw_node
+        .step_untrusted(append(1, 1, vec![(2, 2), (2, 3)]))
+        .unwrap();
+    assert_eq!(raw_node.raft.raft_log.last_index(), 3);
+}
+
+/// Ensures that RawNode::step_untrusted rejects messages carrying too many or too
+/// large entries.
+#[test]
+fn test_raw_node_step_untrusted_size_limits() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut config = new_test_config(1, 10, 1);
+    config.max_untrusted_entries = 3;
+    config.max_untrusted_entry_size = 100;
+    config.max_untrusted_msg_size = 150;
+    let mut raw_node = new_raw_node_with_config(vec![1, 2], &config, s, &l);
+    let append = |sizes: &[usize]| {
+        let ents = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| {
+                let mut e = empty_entry(2, i as u64 + 2);
+                e.data = vec![0; *size].into();
+                e
+            })
+            .collect();
+        let mut m = new_message_with_entries(2, 1, MessageType::MsgAppend, ents);
+        m.term = 2;
+        m.log_term = 1;
+        m.index = 1;
+        m
+    };
+    let mut propose = new_message(2, 1, MessageType::MsgPropose, 0);
+    propose.set_entries(vec![new_entry(0, 0, Some(&"a".repeat(200)))].into());
+
+    let tests = vec![
+        (append(&[0, 0, 0, 0]), "too many entries"),
+        (append(&[200]), "entry is too large"),
+        (append(&[60, 60, 60]), "entries are too large"),
+        (propose, "entry is too large"),
+    ];
+    for (m, reason) in tests {
+        assert_eq!(
+            raw_node.step_untrusted(m),
+            Err(Error::InvalidMessage(2, 1, reason)),
+            "{}",
+            reason
+        );
+    }
+    assert_eq!(raw_node.raft.raft_log.last_index(), 1);
+
+    raw_node.step_untrusted(append(&[60, 60])).unwrap();
+    assert_eq!(raw_node.raft.raft_log.last_index(), 3);
+}
+
+// Ensures that batched ticks produce at most one plain heartbeat per peer.
+#[test]
+fn test_raw_node_tick_batch() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    let _ = raw_node.advance(rd);
+
+    assert!(raw_node.tick_batch(5));
+    let mut heartbeats: Vec<_> = raw_node
+        .raft
+        .msgs
+        .iter()
+        .filter(|m| m.get_msg_type() == MessageType::MsgHeartbeat)
+        .map(|m| m.to)
+        .collect();
+    heartbeats.sort_unstable();
+    assert_eq!(heartbeats, vec![2, 3]);
+
+    // Heartbeats of a pending read index request are deduplicated too.
+    for id in 2..=3 {
+        let mut m = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        m.term = 2;
+        m.index = 2;
+        raw_node.step(m).unwrap();
+    }
+    assert_eq!(raw_node.raft.raft_log.committed, 2);
+    raw_node.raft.msgs.clear();
+    raw_node.read_index(b"ctx".to_vec()).unwrap();
+    raw_node.tick_batch(2);
+    let heartbeats: Vec<_> = raw_node
+        .raft
+        .msgs
+        .iter()
+        .filter(|m| m.get_msg_type() == MessageType::MsgHeartbeat && m.to == 2)
+        .map(|m| m.context.clone())
+        .collect();
+    assert_eq!(heartbeats, vec![b"ctx".to_vec()]);
+}
+
+// Ensures that step_batch handles ticks and messages in a single Ready.
+#[test]
+fn test_raw_node_step_batch() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    let _ = raw_node.advance(rd);
+
+    let mut msgs = vec![];
+    for id in 2..=3 {
+        let mut m = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        m.term = 2;
+        m.index = 2;
+        msgs.push(m);
+    }
+    // A response from a peer out of the configuration is rejected.
+    let mut m = new_message(4, 1, MessageType::MsgAppendResponse, 0);
+    m.term = 2;
+    msgs.push(m);
+
+    let (mut rd, errors) = raw_node.step_batch(5, msgs);
+    assert_eq!(errors, vec![Error::StepPeerNotFound]);
+    assert_eq!(rd.hs().map(|hs| hs.commit), Some(2));
+    let mut heartbeats: Vec<_> = rd
+        .take_messages()
+        .into_iter()
+        .flatten()
+        .filter(|m| m.get_msg_type() == MessageType::MsgHeartbeat)
+        .map(|m| m.to)
+        .collect();
+    heartbeats.sort_unstable();
+    assert_eq!(heartbeats, vec![2, 3]);
+}
+
+// Ensures that entries fetched asynchronously by the storage are sent once the
+// application notifies they are fetched.
+#[test]
+fn test_raw_node_entries_fetched_async() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    raw_node.propose(vec![], b"foo".to_vec()).unwrap();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    let _ = raw_node.advance(rd);
+
+    let appends_to_2 = |raw_node: &RawNode<MemStorage>| {
+        raw_node
+            .raft
+            .msgs
+            .iter()
+            .filter(|m| m.to == 2 && m.get_msg_type() == MessageType::MsgAppend)
+            .map(|m| m.get_entries().iter().map(|e| e.index).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+    s.wl().trigger_log_unavailable(true);
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.term = 2;
+    m.index = 1;
+    raw_node.step(m).unwrap();
+    assert!(appends_to_2(&raw_node).is_empty());
+    assert_eq!(raw_node.raft.prs().get(2).unwrap().matched, 1);
+
+    let context = s.wl().take_get_entries_context().unwrap();
+    assert!(context.can_async());
+    // Node 2 is replicating, the entries up to the last one are read in order.
+    assert_eq!(context.read_ahead(), Some(4));
+    s.wl().trigger_log_unavailable(false);
+    raw_node.on_entries_fetched(context.clone());
+    assert_eq!(appends_to_2(&raw_node), vec![vec![2, 3]]);
+
+    // The context of a former term is ignored.
+    raw_node.raft.msgs.clear();
+    raw_node.raft.become_follower(3, 2);
+    raw_node.on_entries_fetched(context);
+    assert!(appends_to_2(&raw_node).is_empty());
+
+    // Contexts raft doesn't wait for are ignored.
+    raw_node.on_entries_fetched(GetEntriesContext::empty(false));
+    assert!(appends_to_2(&raw_node).is_empty());
+}
+
+// Ensures that a snapshot can be sent in chunks and the result is reported to raft.
+#[test]
+fn test_raw_node_snapshot_chunks() {
+    let l = default_logger();
+    let s = new_storage();
+    s.wl()
+        .apply_snapshot(new_snapshot(11, 11, vec![1, 2]))
+        .unwrap();
+    let mut raw_node = new_raw_node(1, vec![1, 2], 10, 1, s, &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    raw_node.raft.msgs.clear();
+
+    // Node 2 needs a snapshot.
+    let first_index = raw_node.raft.raft_log.first_index();
+    raw_node.raft.mut_prs().get_mut(2).unwrap().next_idx = first_index;
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.term = raw_node.raft.term;
+    m.index = first_index - 1;
+    m.reject = true;
+    raw_node.step(m).unwrap();
+    let mut msg = raw_node.raft.msgs.pop().unwrap();
+    assert_eq!(msg.get_msg_type(), MessageType::MsgSnapshot);
+    let mut snap = msg.take_snapshot();
+    snap.set_data(b"0123456789".to_vec());
+
+    let mut sender = SnapshotSender::from_snapshot(2, snap.clone(), 3);
+    let mut receiver = SnapshotReceiver::new(vec![]);
+    let mut received = None;
+    while let Some(chunk) = sender.next_chunk().unwrap() {
+        assert!(chunk.data.len() <= 3);
+        received = receiver.receive(chunk).unwrap();
+        sender.on_ack(receiver.offset());
+    }
+    let mut received = received.unwrap();
+    assert_eq!(received.get_metadata(), snap.get_metadata());
+    received.set_data(receiver.into_sink());
+    assert_eq!(received, snap);
+    let state = |raw_node: &RawNode<MemStorage>| raw_node.raft.prs().get(2).unwrap().state;
+    assert_eq!(state(&raw_node), ProgressState::Snapshot);
+    assert!(sender.report(&mut raw_node));
+    assert_eq!(state(&raw_node), ProgressState::Probe);
+}
+
+// Ensures that request snapshot reports why it's dropped.
+#[test]
+fn test_raw_node_request_snapshot() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2], 10, 1, s, &l);
+    assert_eq!(
+        raw_node.request_snapshot(1),
+        Err(Error::RequestSnapshotDropped("no leader"))
+    );
+
+    let mut m = new_message(2, 1, MessageType::MsgHeartbeat, 0);
+    m.term = 2;
+    raw_node.step(m).unwrap();
+    assert_eq!(raw_node.raft.leader_id, 2);
+    raw_node.request_snapshot(1).unwrap();
+    assert_eq!(
+        raw_node.request_snapshot(1),
+        Err(Error::RequestSnapshotDropped(
+            "a snapshot is already requested"
+        ))
+    );
+}
+
+// Ensures that must_sync is set if and only if term, vote, entries or snapshot
+// need to be persisted.
+#[test]
+fn test_raw_node_must_sync() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    let msg = |t: MessageType, log_term: u64, index: u64, commit: u64, entries: Vec<Entry>| {
+        let mut m = new_message_with_entries(2, 1, t, entries);
+        m.term = 2;
+        m.log_term = log_term;
+        m.index = index;
+        m.commit = commit;
+        m
+    };
+    let handle_ready = |raw_node: &mut RawNode<MemStorage>| {
+        let rd = raw_node.ready();
+        let must_sync = rd.must_sync();
+        let (has_hs, has_entries) = (rd.hs().is_some(), !rd.entries().is_empty());
+        if !rd.snapshot().is_empty() {
+            s.wl().apply_snapshot(rd.snapshot().clone()).unwrap();
+        }
+        s.wl().append(rd.entries()).unwrap();
+        persist_hard_state(&s, &rd);
+        let _ = raw_node.advance(rd);
+        (must_sync, has_hs, has_entries)
+    };
+
+    // Term and vote change with entries.
+    let mut e = new_entry(2, 2, SOME_DATA);
+    raw_node
+        .step(msg(MessageType::MsgAppend, 1, 1, 1, vec![e.clone()]))
+        .unwrap();
+    assert_eq!(handle_ready(&mut raw_node), (true, true, true));
+
+    // Only commit changes.
+    raw_node
+        .step(msg(MessageType::MsgHeartbeat, 0, 0, 2, vec![]))
+        .unwrap();
+    assert_eq!(handle_ready(&mut raw_node), (false, true, false));
+
+    // Only entries are appended.
+    e.index = 3;
+    raw_node
+        .step(msg(MessageType::MsgAppend, 2, 2, 2, vec![e]))
+        .unwrap();
+    assert_eq!(handle_ready(&mut raw_node), (true, false, true));
+
+    // Only messages are sent.
+    raw_node
+        .step(msg(MessageType::MsgHeartbeat, 0, 0, 2, vec![]))
+        .unwrap();
+    assert_eq!(handle_ready(&mut raw_node), (false, false, false));
+
+    // A snapshot is received.
+    let mut m = msg(MessageType::MsgSnapshot, 0, 0, 0, vec![]);
+    m.set_snapshot(new_snapshot(10, 2, vec![1, 2, 3]));
+    raw_node.step(m).unwrap();
+    assert_eq!(handle_ready(&mut raw_node), (true, true, false));
+}
+
+// Ensures that read index and proposals report no leader before an election.
+#[test]
+fn test_raw_node_read_index_no_leader() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2], 10, 1, s, &l);
+    assert_eq!(raw_node.read_index(b"ctx".to_vec()), Err(Error::NoLeader));
+    assert_eq!(
+        raw_node.propose(vec![], b"data".to_vec()),
+        Err(Error::NoLeader)
+    );
+
+    // A candidate has no leader either.
+    raw_node.campaign().unwrap();
+    assert_eq!(raw_node.raft.state, StateRole::Candidate);
+    assert_eq!(raw_node.read_index(b"ctx".to_vec()), Err(Error::NoLeader));
+    assert!(raw_node.raft.read_states.is_empty());
+}
+
+// Ensures that heartbeats of a leader are taken on tick without a Ready, while
+// messages that require persisting are left for Ready.
+#[test]
+fn test_raw_node_tick_and_take_messages() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    let mut light_rd = raw_node.advance(rd);
+    assert!(light_rd.take_messages().is_empty());
+    assert!(!raw_node.has_ready());
+
+    let (has_ready, msgs) = raw_node.tick_and_take_messages();
+    assert!(!has_ready);
+    let mut to: Vec<_> = msgs
+        .iter()
+        .map(|m| {
+            assert_eq!(m.get_msg_type(), MessageType::MsgHeartbeat);
+            m.to
+        })
+        .collect();
+    to.sort_unstable();
+    assert_eq!(to, vec![2, 3]);
+
+    let s = new_storage();
+    let mut raw_node = new_raw_node(2, vec![1, 2, 3], 10, 1, s, &l);
+    for _ in 0..raw_node.raft.randomized_election_timeout() {
+        let (has_ready, msgs) = raw_node.tick_and_take_messages();
+        assert!(msgs.is_empty());
+        assert_eq!(has_ready, raw_node.raft.state == StateRole::Candidate);
+    }
+    assert_eq!(raw_node.raft.state, StateRole::Candidate);
+    assert_eq!(raw_node.raft.msgs.len(), 2);
+}
+
 /// Ensures that a node can be started correctly. Note that RawNode requires the
 /// application to bootstrap the state, i.e. it does not accept peers and will not
 /// create faux configuration change entries.
@@ -630,6 +1386,7 @@ fn test_raw_node_start() {
         true,
     );
     store.wl().append(rd.entries()).expect("");
+    persist_hard_state(&store, &rd);
     let light_rd = raw_node.advance(rd);
     assert_eq!(light_rd.commit_index(), Some(2));
     assert_eq!(*light_rd.committed_entries(), vec![new_entry(2, 2, None)]);
@@ -648,6 +1405,7 @@ fn test_raw_node_start() {
         true,
     );
     store.wl().append(rd.entries()).expect("");
+    persist_hard_state(&store, &rd);
     let light_rd = raw_node.advance(rd);
     assert_eq!(light_rd.commit_index(), Some(3));
     assert_eq!(
@@ -658,6 +1416,23 @@ fn test_raw_node_start() {
     assert!(!raw_node.has_ready());
 }
 
+/// Ensures that advancing a ready whose hard state hasn't been persisted is detected.
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "is advanced before being persisted")]
+fn test_raw_node_advance_unpersisted_hard_state() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1], 10, 1, s.clone(), &l);
+    raw_node.campaign().unwrap();
+    let rd = raw_node.ready();
+    assert_eq!(rd.hs(), Some(&hard_state(2, 1, 1)));
+    s.wl().append(rd.entries()).unwrap();
+    // Persisting only the commit index is not enough, term and vote are lost.
+    s.wl().mut_hard_state().commit = 1;
+    let _ = raw_node.advance(rd);
+}
+
 #[test]
 fn test_raw_node_restart() {
     let l = default_logger();
@@ -696,6 +1471,74 @@ fn test_raw_node_restart_from_snapshot() {
     assert!(!raw_node.has_ready());
 }
 
+// Ensures that a restarted node with a large backlog of committed entries gets them
+// across several Ready cycles when max_committed_size_per_ready is set.
+#[test]
+fn test_raw_node_max_committed_size_per_ready() {
+    let l = default_logger();
+    let entries: Vec<_> = (1..=5).map(|i| new_entry(1, i, Some("foo"))).collect();
+    let entry_size = u64::from(entries[0].compute_size());
+
+    let mut cfg = new_test_config(1, 10, 1);
+    cfg.max_committed_size_per_ready = entry_size * 2;
+    let store = new_storage();
+    store.wl().set_hardstate(hard_state(1, 5, 0));
+    store.wl().append(&entries).unwrap();
+    let mut raw_node = RawNode::new(&cfg, store, &l).unwrap();
+
+    let mut batches = vec![];
+    while raw_node.has_ready() {
+        let mut rd = raw_node.ready();
+        batches.push(rd.take_committed_entries());
+        let mut light_rd = raw_node.advance(rd);
+        let committed_entries = light_rd.take_committed_entries();
+        if !committed_entries.is_empty() {
+            batches.push(committed_entries);
+        }
+        raw_node.advance_apply();
+    }
+    assert_eq!(
+        batches,
+        vec![
+            entries[..2].to_vec(),
+            entries[2..4].to_vec(),
+            entries[4..].to_vec()
+        ]
+    );
+}
+
+// Ensures that committed entries can be pulled in size limited batches without
+// going through Ready, and that they are not handed out again afterwards.
+#[test]
+fn test_raw_node_committed_entries_pager() {
+    let l = default_logger();
+    let entries: Vec<_> = (1..=5).map(|i| new_entry(1, i, Some("foo"))).collect();
+    let entry_size = u64::from(entries[0].compute_size());
+
+    let mut cfg = new_test_config(1, 10, 1);
+    cfg.max_committed_size_per_ready = entry_size * 2;
+    let store = new_storage();
+    store.wl().set_hardstate(hard_state(1, 5, 0));
+    store.wl().append(&entries).unwrap();
+    let mut raw_node = RawNode::new(&cfg, store, &l).unwrap();
+
+    assert!(raw_node.has_committed_entries());
+    let first = raw_node.committed_entries_pager().next().unwrap();
+    assert_eq!(first, entries[..2].to_vec());
+    raw_node.advance_apply_to(2);
+
+    let rest: Vec<_> = raw_node.committed_entries_pager().collect();
+    assert_eq!(rest, vec![entries[2..4].to_vec(), entries[4..].to_vec()]);
+    assert!(!raw_node.has_committed_entries());
+    assert_eq!(raw_node.committed_entries_pager().next(), None);
+    raw_node.advance_apply_to(5);
+
+    let rd = raw_node.ready();
+    assert!(rd.committed_entries().is_empty());
+    let _ = raw_node.advance(rd);
+    assert!(!raw_node.has_ready());
+}
+
 // test_skip_bcast_commit ensures that empty commit message is not sent out
 // when skip_bcast_commit is true.
 #[test]
@@ -714,7 +1557,7 @@ fn test_skip_bcast_commit() {
 
     // Without bcast commit, followers will not update its commit index immediately.
     let mut test_entries = Entry::default();
-    test_entries.data = b"testdata".to_vec();
+    test_entries.data = b"testdata".to_vec().into();
     let msg = new_message_with_entries(1, 1, MessageType::MsgPropose, vec![test_entries]);
     nt.send(vec![msg.clone()]);
     assert_eq!(nt.peers[&1].raft_log.committed, 2);
@@ -752,7 +1595,7 @@ fn test_skip_bcast_commit() {
     let data = cc.write_to_bytes().unwrap();
     let mut cc_entry = Entry::default();
     cc_entry.set_entry_type(EntryType::EntryConfChange);
-    cc_entry.data = data;
+    cc_entry.data = data.into();
     nt.send(vec![new_message_with_entries(
         1,
         1,
@@ -799,9 +1642,10 @@ fn test_bounded_uncommitted_entries_growth_with_partition() {
     raw_node.campaign().unwrap();
     loop {
         let rd = raw_node.ready();
+        persist_hard_state(&s, &rd);
         if rd
             .ss()
-            .map_or(false, |ss| ss.leader_id == raw_node.raft.leader_id)
+            .is_some_and(|ss| ss.leader_id == raw_node.raft.leader_id)
         {
             let _ = raw_node.advance(rd);
             break;
@@ -817,12 +1661,13 @@ fn test_bounded_uncommitted_entries_growth_with_partition() {
 
     // shoule be dropped
     let result = raw_node.propose(vec![], data.to_vec());
-    assert!(!result.is_ok());
-    assert_eq!(result.unwrap_err(), Error::ProposalDropped);
+    assert!(result.is_err());
+    assert_eq!(result.unwrap_err(), Error::QuotaExceeded);
 
     // should be accepted when previous data has been committed
     let rd = raw_node.ready();
     s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
     let _ = raw_node.advance(rd);
 
     let data = b"hello world!".to_vec();
@@ -842,8 +1687,9 @@ fn test_raw_node_with_async_apply() {
     // Single node should become leader.
     assert!(rd
         .ss()
-        .map_or(false, |ss| ss.leader_id == raw_node.raft.leader_id));
-    s.wl().append(&rd.entries()).unwrap();
+        .is_some_and(|ss| ss.leader_id == raw_node.raft.leader_id));
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
     let _ = raw_node.advance(rd);
 
     let mut last_index = raw_node.raft.raft_log.last_index();
@@ -877,6 +1723,56 @@ fn test_raw_node_with_async_apply() {
     }
 }
 
+/// Tests that committed entries can be applied before they are persisted locally
+/// within `max_apply_unpersisted_log_limit`.
+#[test]
+fn test_raw_node_apply_unpersisted_entries() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    let _ = raw_node.advance(rd);
+
+    raw_node.propose(vec![], b"data".to_vec()).unwrap();
+    let rd = raw_node.ready();
+    let last_index = rd.entries().last().unwrap().index;
+    // The entries are readable from the storage, but only durable once
+    // `on_persist_ready` is called.
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
+    raw_node.advance_append_async(rd);
+    for id in 2..=3 {
+        let mut resp = new_message(id, 1, MessageType::MsgAppendResponse, 0);
+        resp.term = raw_node.raft.term;
+        resp.index = last_index;
+        raw_node.step(resp).unwrap();
+    }
+    // The entries are committed by the followers but not persisted by the leader.
+    assert_eq!(raw_node.raft.raft_log.committed, last_index);
+    // Only the persisted empty entry of the leader can be applied.
+    let rd2 = raw_node.ready();
+    let committed: Vec<_> = rd2.committed_entries().iter().map(|e| e.index).collect();
+    assert_eq!(committed, vec![last_index - 1]);
+    raw_node.advance_append_async(rd2);
+
+    raw_node.set_max_apply_unpersisted_log_limit(1);
+    let rd3 = raw_node.ready();
+    let committed: Vec<_> = rd3.committed_entries().iter().map(|e| e.index).collect();
+    assert_eq!(committed, vec![last_index]);
+    assert_eq!(rd3.committed_entries()[0].data, b"data".to_vec());
+
+    let number = rd3.number();
+    raw_node.advance_append_async(rd3);
+    raw_node.on_persist_ready(number);
+    raw_node.advance_apply_to(last_index);
+    assert_eq!(raw_node.raft.raft_log.persisted, last_index);
+    assert_eq!(raw_node.raft.raft_log.applied, last_index);
+}
+
 /// Test if the ready process is expected when a follower receives a snapshot
 /// and some committed entries after its snapshot.
 #[test]
@@ -1054,8 +1950,9 @@ fn test_async_ready_leader() {
     let rd = raw_node.ready();
     assert!(rd
         .ss()
-        .map_or(false, |ss| ss.leader_id == raw_node.raft.leader_id));
-    s.wl().append(&rd.entries()).unwrap();
+        .is_some_and(|ss| ss.leader_id == raw_node.raft.leader_id));
+    s.wl().append(rd.entries()).unwrap();
+    persist_hard_state(&s, &rd);
     let _ = raw_node.advance(rd);
 
     assert_eq!(raw_node.raft.term, 2);
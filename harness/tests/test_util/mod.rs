@@ -44,6 +44,7 @@ pub fn new_test_config(id: u64, election_tick: usize, heartbeat_tick: usize) ->
         heartbeat_tick,
         max_size_per_msg: NO_LIMIT,
         max_inflight_msgs: 256,
+        rng_seed: Some(id),
         ..Default::default()
     }
 }
@@ -155,7 +156,7 @@ pub fn new_entry(term: u64, index: u64, data: Option<&str>) -> Entry {
     e.index = index;
     e.term = term;
     if let Some(d) = data {
-        e.data = d.as_bytes().to_vec();
+        e.data = d.as_bytes().to_vec().into();
     }
     e
 }
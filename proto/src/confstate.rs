@@ -27,6 +27,7 @@ pub fn conf_state_eq(lhs: &ConfState, rhs: &ConfState) -> bool {
         && lhs.get_learners() == rhs.get_learners()
         && lhs.get_voters_outgoing() == rhs.get_voters_outgoing()
         && lhs.get_learners_next() == rhs.get_learners_next()
+        && lhs.get_witnesses() == rhs.get_witnesses()
         && lhs.auto_leave == rhs.auto_leave
     {
         return true;
@@ -36,5 +37,6 @@ pub fn conf_state_eq(lhs: &ConfState, rhs: &ConfState) -> bool {
         && eq_without_order(lhs.get_learners(), rhs.get_learners())
         && eq_without_order(lhs.get_voters_outgoing(), rhs.get_voters_outgoing())
         && eq_without_order(lhs.get_learners_next(), rhs.get_learners_next())
+        && eq_without_order(lhs.get_witnesses(), rhs.get_witnesses())
         && lhs.auto_leave == rhs.auto_leave
 }
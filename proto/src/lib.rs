@@ -18,6 +18,8 @@ pub use crate::protos::eraftpb;
 #[allow(clippy::all)]
 #[allow(renamed_and_removed_lints)]
 #[allow(bare_trait_objects)]
+#[allow(unused_parens)]
+#[allow(mismatched_lifetime_syntaxes)]
 mod protos {
     include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
 
@@ -48,8 +50,8 @@ pub mod util {
     {
         fn from((voters, learners): (Iter1, Iter2)) -> Self {
             let mut conf_state = ConfState::default();
-            conf_state.mut_voters().extend(voters.into_iter());
-            conf_state.mut_learners().extend(learners.into_iter());
+            conf_state.mut_voters().extend(voters);
+            conf_state.mut_learners().extend(learners);
             conf_state
         }
     }
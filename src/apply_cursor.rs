@@ -0,0 +1,130 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::eraftpb::Entry;
+use crate::raw_node::RawNode;
+use crate::storage::Storage;
+
+/// Hands out the committed entries of a `RawNode` in batches, for an application that
+/// applies them at its own pace. It's created by `RawNode::apply_cursor`.
+///
+/// Like `RawNode::committed_entries_pager`, the entries handed out by the cursor are
+/// consumed: they won't appear in later `Ready`s or `LightReady`s, and the entries
+/// handed out by those are skipped by the cursor, so mixing them applies every entry
+/// exactly once. `advance_apply_to` should still be called once a batch is applied.
+///
+/// The cursor only remembers the index of the last entry it handed out, so it's still
+/// valid after entries are appended or committed between two batches, and it can be
+/// saved as a checkpoint in the middle of the committed window. As the entries are
+/// consumed, retrying a failed batch is up to the application.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ApplyCursor {
+    applied: u64,
+}
+
+impl ApplyCursor {
+    pub(crate) fn new(applied: u64) -> ApplyCursor {
+        ApplyCursor { applied }
+    }
+
+    /// The index of the last entry handed out by the cursor.
+    #[inline]
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Whether there are entries to hand out.
+    #[inline]
+    pub fn has_next<T: Storage>(&self, node: &RawNode<T>) -> bool {
+        node.has_committed_entries()
+    }
+
+    /// Returns the next entries of at most `max_size` bytes, but at least one, and
+    /// moves the cursor past them.
+    pub fn next_batch<T: Storage>(
+        &mut self,
+        node: &mut RawNode<T>,
+        max_size: impl Into<Option<u64>>,
+    ) -> Option<Vec<Entry>> {
+        let ents = node.take_committed_entries(max_size, |ents| ents.len());
+        self.applied = ents.last()?.index;
+        Some(ents)
+    }
+
+    /// Like `next_batch`, but the batch is also capped by the cost of applying it, as
+    /// told by `cost`. At least one entry is returned even if it costs more than
+    /// `max_cost`, the entries left out are returned by the next batch.
+    pub fn next_batch_by_cost<T: Storage, F: FnMut(&Entry) -> u64>(
+        &mut self,
+        node: &mut RawNode<T>,
+        max_size: impl Into<Option<u64>>,
+        max_cost: u64,
+        mut cost: F,
+    ) -> Option<Vec<Entry>> {
+        let ents = node.take_committed_entries(max_size, |ents| {
+            let mut total = 0;
+            let mut len = 0;
+            for e in ents {
+                total += cost(e);
+                if len > 0 && total > max_cost {
+                    break;
+                }
+                len += 1;
+            }
+            len
+        });
+        self.applied = ents.last()?.index;
+        Some(ents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::default_logger;
+    use crate::eraftpb::HardState;
+    use crate::storage::MemStorage;
+    use crate::Config;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.index = index;
+        e.term = term;
+        e.data = vec![0; index as usize].into();
+        e
+    }
+
+    fn indexes(ents: Option<Vec<Entry>>) -> Vec<u64> {
+        ents.unwrap_or_default().iter().map(|e| e.index).collect()
+    }
+
+    #[test]
+    fn test_apply_cursor() {
+        let store = MemStorage::new_with_conf_state((vec![1], vec![]));
+        let ents: Vec<_> = (1..=8).map(|i| new_entry(i, 1)).collect();
+        store.wl().append(&ents).unwrap();
+        let mut hs = HardState::default();
+        hs.term = 1;
+        hs.commit = 8;
+        store.wl().set_hardstate(hs);
+        let mut node = RawNode::new(&Config::new(1), store, &default_logger()).unwrap();
+
+        let mut cursor = node.apply_cursor();
+        assert_eq!(cursor.applied(), 0);
+        assert!(cursor.has_next(&node));
+        assert_eq!(indexes(cursor.next_batch(&mut node, 0)), vec![1]);
+        // The cost of an entry is its index here.
+        let batch = cursor.next_batch_by_cost(&mut node, None, 5, |e| e.index);
+        assert_eq!(indexes(batch), vec![2, 3]);
+        let batch = cursor.next_batch_by_cost(&mut node, None, 1, |e| e.index);
+        assert_eq!(indexes(batch), vec![4]);
+        assert_eq!(cursor.applied(), 4);
+
+        // Entries handed out by the cursor are consumed, and the other way around.
+        let rd = node.ready();
+        let committed: Vec<_> = rd.committed_entries().iter().map(|e| e.index).collect();
+        assert_eq!(committed, vec![5, 6, 7, 8]);
+        assert!(!cursor.has_next(&node));
+        assert_eq!(cursor.next_batch(&mut node, None), None);
+        assert_eq!(cursor.applied(), 4);
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::time::Instant;
+
+/// A monotonic time source.
+///
+/// By default raft only counts ticks to decide when the leader should check
+/// quorum and whether its lease is still valid. Ticks are not delivered while
+/// the process is paused, so embedders with their own time source (or tests
+/// driving simulated time) can install a clock with `Raft::set_clock` to make
+/// lease expiry follow that time instead.
+pub trait Clock: Send {
+    /// Returns the current time. Successive calls must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// A `Clock` backed by `Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use protobuf::Message as PbMessage;
+
+use crate::clock::{Clock, SystemClock};
+use crate::eraftpb::Entry;
+
+/// Decides how far a storage compacts its log automatically.
+///
+/// Registered with `MemStorageCore::set_compaction_policy`, the policy is consulted
+/// whenever entries are appended or the applied index advances. Whatever the policy
+/// returns, the log is never compacted beyond the applied index, so the entries still
+/// to be applied are kept. Persistent storages can follow the same approach.
+pub trait CompactionPolicy: Send + Sync {
+    /// Called after `ents` are appended to the log.
+    fn on_append(&mut self, _ents: &[Entry]) {}
+
+    /// Returns the index to compact the log to, that is the entries before it are
+    /// discarded. `entries` are all the entries in the log.
+    fn compact_index(&mut self, entries: &[Entry]) -> Option<u64>;
+}
+
+/// Keeps at most `max_entries` entries in the log.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactByCount {
+    /// The number of entries to keep.
+    pub max_entries: usize,
+}
+
+impl CompactionPolicy for CompactByCount {
+    fn compact_index(&mut self, entries: &[Entry]) -> Option<u64> {
+        if entries.len() <= self.max_entries {
+            return None;
+        }
+        Some(entries[entries.len() - self.max_entries].index)
+    }
+}
+
+/// Keeps the latest entries whose total size is at most `max_bytes`.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactBySize {
+    /// The size of the entries to keep.
+    pub max_bytes: u64,
+}
+
+impl CompactionPolicy for CompactBySize {
+    fn compact_index(&mut self, entries: &[Entry]) -> Option<u64> {
+        let mut size = 0;
+        for (i, e) in entries.iter().enumerate().rev() {
+            size += u64::from(e.compute_size());
+            if size > self.max_bytes {
+                return entries.get(i + 1).map(|e| e.index).or(Some(e.index + 1));
+            }
+        }
+        None
+    }
+}
+
+/// Discards the entries appended more than `max_age` ago.
+pub struct CompactByAge<C: Clock = SystemClock> {
+    max_age: Duration,
+    clock: C,
+    // The last index of every append and when it happens.
+    appends: VecDeque<(u64, Instant)>,
+}
+
+impl CompactByAge<SystemClock> {
+    /// Creates a policy which discards the entries appended more than `max_age` ago.
+    pub fn new(max_age: Duration) -> Self {
+        CompactByAge::with_clock(max_age, SystemClock)
+    }
+}
+
+impl<C: Clock> CompactByAge<C> {
+    /// Creates a policy which tells the age of entries with `clock`.
+    pub fn with_clock(max_age: Duration, clock: C) -> Self {
+        CompactByAge {
+            max_age,
+            clock,
+            appends: VecDeque::new(),
+        }
+    }
+}
+
+impl<C: Clock + Sync> CompactionPolicy for CompactByAge<C> {
+    fn on_append(&mut self, ents: &[Entry]) {
+        let (first, last) = match (ents.first(), ents.last()) {
+            (Some(first), Some(last)) => (first.index, last.index),
+            _ => return,
+        };
+        // The overwritten entries are appended again now.
+        while matches!(self.appends.back(), Some((i, _)) if *i >= first) {
+            self.appends.pop_back();
+        }
+        self.appends.push_back((last, self.clock.now()));
+    }
+
+    fn compact_index(&mut self, entries: &[Entry]) -> Option<u64> {
+        // Forget the appends that are compacted already.
+        let first = entries.first().map_or(u64::MAX, |e| e.index);
+        while matches!(self.appends.front(), Some((i, _)) if *i < first) {
+            self.appends.pop_front();
+        }
+        let now = self.clock.now();
+        self.appends
+            .iter()
+            .take_while(|(_, t)| now.duration_since(*t) > self.max_age)
+            .last()
+            .map(|(i, _)| i + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<Instant>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn new_entries(low: u64, high: u64) -> Vec<Entry> {
+        (low..high)
+            .map(|i| {
+                let mut e = Entry::default();
+                e.index = i;
+                e.term = 1;
+                e
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compact_by_age() {
+        let clock = ManualClock(Arc::new(Mutex::new(Instant::now())));
+        let advance = |secs| *clock.0.lock().unwrap() += Duration::from_secs(secs);
+        let mut policy = CompactByAge::with_clock(Duration::from_secs(10), clock.clone());
+
+        policy.on_append(&new_entries(1, 4));
+        advance(5);
+        policy.on_append(&new_entries(4, 6));
+        assert_eq!(policy.compact_index(&new_entries(1, 6)), None);
+        advance(6);
+        assert_eq!(policy.compact_index(&new_entries(1, 6)), Some(4));
+
+        // The overwritten entries are as young as the new ones.
+        policy.on_append(&new_entries(5, 7));
+        advance(6);
+        assert_eq!(policy.compact_index(&new_entries(1, 7)), Some(4));
+        advance(5);
+        assert_eq!(policy.compact_index(&new_entries(1, 7)), Some(7));
+        // Compacted appends are forgotten.
+        assert_eq!(policy.compact_index(&new_entries(7, 7)), None);
+    }
+}
@@ -103,5 +103,8 @@ pub fn restore(tracker: &mut ProgressTracker, next_idx: u64, cs: &ConfState) ->
         let (cfg, changes) = Changer::new(tracker).enter_joint(cs.auto_leave, &incoming)?;
         tracker.apply_conf(cfg, changes, next_idx);
     }
+    for id in cs.get_witnesses() {
+        tracker.set_witness(*id, true)?;
+    }
     Ok(())
 }
@@ -14,6 +14,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// `ReadState` is kept next to `ReadOnlyOption` as before, newer rustc reports it as
+// unused since the crate exports it from `read_only` directly.
+#[allow(unused_imports)]
 pub use super::read_only::{ReadOnlyOption, ReadState};
 use super::util::NO_LIMIT;
 use super::{
@@ -58,6 +61,11 @@ pub struct Config {
     /// TODO: feedback to application to limit the proposal rate?
     pub max_inflight_msgs: usize,
 
+    /// Limits the number of snapshots the leader sends concurrently. A peer is
+    /// never sent a second snapshot before the first one is reported finished or
+    /// failed, this bounds the total across all peers.
+    pub max_inflight_snapshots: usize,
+
     /// Specify if the leader should check quorum activity. Leader steps down when
     /// quorum is not active for an electionTimeout.
     pub check_quorum: bool,
@@ -95,6 +103,101 @@ pub struct Config {
     /// Specify maximum of uncommited entry size.
     /// When this limit is reached, all proposals to append new log will be dropped
     pub max_uncommitted_size: u64,
+
+    /// Followers drop proposals instead of forwarding them to the leader when it's
+    /// true. It's useful when the data of a proposal depends on local state of the
+    /// proposing node, e.g. a timestamp from a hybrid logical clock, and must only be
+    /// computed by the leader.
+    pub disable_proposal_forwarding: bool,
+
+    /// Max size for committed entries in a `Ready`. Committed entries beyond the
+    /// limit are returned in the following `Ready`s, at least one entry is always
+    /// returned.
+    pub max_committed_size_per_ready: u64,
+
+    /// Max number of committed entries that can be applied before they are
+    /// persisted locally. Such entries are returned as committed entries in the
+    /// same `Ready` as they are returned to be persisted, so the state machine must
+    /// tolerate replaying them in case they are lost on restart. It's 0 by
+    /// default, i.e. only persisted entries are applied.
+    pub max_apply_unpersisted_log_limit: u64,
+
+    /// Lets an idle group quiesce. Once all entries are committed and every peer
+    /// has caught up, the leader sends one last round of heartbeats and stops
+    /// ticking, and so do the followers receiving them. Any proposal or other
+    /// message wakes the group up again.
+    ///
+    /// A quiesced follower wakes up and campaigns once it hasn't heard from the
+    /// leader for `quiesced_election_tick` ticks, so the group recovers if the
+    /// leader is down. The application can wake it earlier with `Raft::unquiesce`,
+    /// or by reporting the leader unreachable.
+    /// A woken up leader serves no lease based read until a quorum answers its
+    /// heartbeats again, as the others may have elected a new leader meanwhile.
+    pub quiesce: bool,
+
+    /// The number of ticks a quiesced follower waits for the leader before it
+    /// campaigns, on top of the randomized election timeout. It should be much
+    /// longer than `election_tick`, as a healthy quiesced leader sends nothing.
+    /// 0, the default, means `10 * election_tick`.
+    pub quiesced_election_tick: usize,
+
+    /// Followers ignore `MsgHup` while they have heard from the leader within the
+    /// minimum election timeout, so a node that was merely paused can't start a
+    /// spurious election. Campaigns of a leadership transfer are still forced.
+    pub leader_stickiness: bool,
+
+    /// `RawNode::step` rejects messages from peers that are not in the
+    /// configuration. Keep it off if peers may talk to this node before it learns
+    /// about them, e.g. a leader replicating to a newly added node.
+    pub reject_unknown_senders: bool,
+
+    /// The most entries a message may carry in `RawNode::step_untrusted`.
+    pub max_untrusted_entries: usize,
+
+    /// The largest entry, in bytes, a message may carry in `RawNode::step_untrusted`.
+    pub max_untrusted_entry_size: u64,
+
+    /// The most bytes of entries a message may carry in `RawNode::step_untrusted`.
+    /// It should be well above `max_size_per_msg`, or appends of a healthy leader
+    /// are rejected too.
+    pub max_untrusted_msg_size: u64,
+
+    /// Returns the read states produced while a `Ready` is being handled in the
+    /// `LightReady` of `RawNode::advance`, instead of the next `Ready`. The
+    /// application must then take them from both.
+    pub light_ready_read_states: bool,
+
+    /// Queues the proposals dropped by raft with the reason, to be taken with
+    /// `RawNode::take_dropped_proposals`. Off by default, as the queue grows until
+    /// the application takes it.
+    pub track_dropped_proposals: bool,
+
+    /// Makes the leader report the peers it hasn't heard from in this many ticks, to be
+    /// taken with `RawNode::take_inactive_peers`. A peer is reported once, until it's
+    /// heard from again. 0, the default, disables it.
+    pub inactive_peer_tick: usize,
+
+    /// The ID of the group this peer belongs to, e.g. the region ID in a multi-raft
+    /// store. If set, it's tagged on all logs as `group_id`.
+    pub group_id: Option<u64>,
+
+    /// Tags all logs with the current term as `raft_term`, kept up to date as the
+    /// term changes.
+    pub log_term: bool,
+
+    /// Seeds the random number generator used for the election timeout jitter.
+    /// It's seeded from the thread local generator if it's `None`. Setting it
+    /// makes elections reproducible, e.g. in simulations.
+    pub rng_seed: Option<u64>,
+
+    /// The byte budget of the cache of the latest appended entries, which saves
+    /// reading them from the storage again when they're sent to followers. 0
+    /// disables the cache.
+    pub entry_cache_size: u64,
+
+    /// The number of the latest indexes whose terms are cached, which saves looking
+    /// them up in the storage. 0 disables the cache.
+    pub term_cache_size: usize,
 }
 
 impl Default for Config {
@@ -107,6 +210,7 @@ impl Default for Config {
             applied: 0,
             max_size_per_msg: 0,
             max_inflight_msgs: 256,
+            max_inflight_snapshots: usize::MAX,
             check_quorum: false,
             pre_vote: false,
             min_election_tick: 0,
@@ -116,6 +220,24 @@ impl Default for Config {
             batch_append: false,
             priority: 0,
             max_uncommitted_size: NO_LIMIT,
+            max_committed_size_per_ready: NO_LIMIT,
+            disable_proposal_forwarding: false,
+            max_apply_unpersisted_log_limit: 0,
+            quiesce: false,
+            quiesced_election_tick: 0,
+            leader_stickiness: false,
+            reject_unknown_senders: false,
+            max_untrusted_entries: 16 * 1024,
+            max_untrusted_entry_size: 64 * 1024 * 1024,
+            max_untrusted_msg_size: 256 * 1024 * 1024,
+            light_ready_read_states: false,
+            track_dropped_proposals: false,
+            inactive_peer_tick: 0,
+            group_id: None,
+            log_term: false,
+            rng_seed: None,
+            entry_cache_size: 0,
+            term_cache_size: 0,
         }
     }
 }
@@ -149,6 +271,16 @@ impl Config {
         }
     }
 
+    /// The number of ticks a quiesced follower waits for the leader before it campaigns.
+    #[inline]
+    pub fn quiesced_election_tick(&self) -> usize {
+        if self.quiesced_election_tick == 0 {
+            10 * self.election_tick
+        } else {
+            self.quiesced_election_tick
+        }
+    }
+
     /// Runs validations against the config.
     pub fn validate(&self) -> Result<()> {
         if self.id == INVALID_ID {
@@ -183,12 +315,32 @@ impl Config {
             )));
         }
 
+        if self.quiesced_election_tick() < self.election_tick {
+            return Err(Error::ConfigInvalid(format!(
+                "quiesced election tick {} should not be less than election tick {}",
+                self.quiesced_election_tick(),
+                self.election_tick
+            )));
+        }
+
         if self.max_inflight_msgs == 0 {
             return Err(Error::ConfigInvalid(
                 "max inflight messages must be greater than 0".to_owned(),
             ));
         }
 
+        if self.max_inflight_snapshots == 0 {
+            return Err(Error::ConfigInvalid(
+                "max inflight snapshots must be greater than 0".to_owned(),
+            ));
+        }
+
+        if self.max_untrusted_entry_size > self.max_untrusted_msg_size {
+            return Err(Error::ConfigInvalid(
+                "max untrusted entry size should not exceed max untrusted msg size".to_owned(),
+            ));
+        }
+
         if self.read_only_option == ReadOnlyOption::LeaseBased && !self.check_quorum {
             return Err(Error::ConfigInvalid(
                 "read_only_option == LeaseBased requires check_quorum == true".into(),
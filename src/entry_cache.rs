@@ -0,0 +1,196 @@
+// Copyright 2026 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use protobuf::Message as PbMessage;
+
+use crate::eraftpb::Entry;
+use crate::util::NO_LIMIT;
+
+/// Keeps the latest appended entries in memory, so that they're not read from
+/// `Storage` again when they're sent to followers shortly after being persisted.
+///
+/// The cached entries are contiguous and take at most `capacity` bytes, older entries
+/// are evicted first. A capacity of 0 disables the cache.
+#[derive(Debug, Default)]
+pub struct EntryCache {
+    entries: VecDeque<Entry>,
+    size: u64,
+    capacity: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl EntryCache {
+    /// Creates a cache holding entries of at most `capacity` bytes.
+    pub fn new(capacity: u64) -> EntryCache {
+        EntryCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// The byte budget of the cache.
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The total size of the cached entries.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The number of cached entries.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no entry is cached.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of reads served by the cache.
+    #[inline]
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of reads that had to go to `Storage`.
+    #[inline]
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Changes the byte budget, evicting entries if it's exceeded.
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Caches the entries appended to the log, replacing the conflicting ones.
+    pub fn append(&mut self, ents: &[Entry]) {
+        if self.capacity == 0 || ents.is_empty() {
+            return;
+        }
+        let first = ents[0].index;
+        match self.entries.front().map(|e| e.index) {
+            Some(offset) if first > offset && first <= offset + self.entries.len() as u64 => {
+                for e in self.entries.drain((first - offset) as usize..) {
+                    self.size -= u64::from(e.compute_size());
+                }
+            }
+            _ => self.clear(),
+        }
+        for e in ents {
+            self.size += u64::from(e.compute_size());
+            self.entries.push_back(e.clone());
+        }
+        self.evict();
+    }
+
+    /// Returns the entries in `[low, high)` if all of them are cached, limited by
+    /// `max_size` like `util::limit_size`. The entries past the limit aren't copied.
+    pub fn get(&self, low: u64, high: u64, max_size: Option<u64>) -> Option<Vec<Entry>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let offset = match self.entries.front() {
+            Some(e) if e.index <= low && high <= e.index + self.entries.len() as u64 => e.index,
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let range = (low - offset) as usize..(high - offset) as usize;
+        let max_size = match max_size {
+            None | Some(NO_LIMIT) => return Some(self.entries.range(range).cloned().collect()),
+            Some(max_size) => max_size,
+        };
+        let mut ents = vec![];
+        let mut size = 0;
+        for e in self.entries.range(range) {
+            size += u64::from(e.compute_size());
+            if !ents.is_empty() && size > max_size {
+                break;
+            }
+            ents.push(e.clone());
+        }
+        Some(ents)
+    }
+
+    /// Drops all the cached entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            match self.entries.pop_front() {
+                Some(e) => self.size -= u64::from(e.compute_size()),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.index = index;
+        e.term = term;
+        e
+    }
+
+    fn new_entries(low: u64, high: u64, term: u64) -> Vec<Entry> {
+        (low..high).map(|i| new_entry(i, term)).collect()
+    }
+
+    #[test]
+    fn test_entry_cache() {
+        let size = u64::from(new_entry(1, 1).compute_size());
+        let mut cache = EntryCache::new(3 * size);
+        assert_eq!(cache.get(1, 2, None), None);
+        assert_eq!(cache.misses(), 1);
+
+        cache.append(&new_entries(1, 3, 1));
+        assert_eq!(cache.get(1, 3, None), Some(new_entries(1, 3, 1)));
+        assert_eq!(cache.hits(), 1);
+
+        // The oldest entries are evicted.
+        cache.append(&new_entries(3, 5, 1));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.size(), 3 * size);
+        assert_eq!(cache.get(1, 3, None), None);
+        assert_eq!(cache.get(2, 5, None), Some(new_entries(2, 5, 1)));
+        // The size limit keeps at least one entry.
+        assert_eq!(cache.get(2, 5, Some(2 * size)), Some(new_entries(2, 4, 1)));
+        assert_eq!(cache.get(2, 5, Some(0)), Some(new_entries(2, 3, 1)));
+
+        // Conflicting entries are replaced.
+        cache.append(&new_entries(4, 5, 2));
+        assert_eq!(cache.get(2, 5, None).unwrap()[2], new_entry(4, 2));
+        assert_eq!(cache.get(5, 6, None), None);
+
+        // Entries that are not contiguous replace everything.
+        cache.append(&new_entries(7, 8, 2));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(4, 5, None), None);
+        assert_eq!((cache.hits(), cache.misses()), (5, 4));
+
+        cache.set_capacity(0);
+        assert!(cache.is_empty());
+        cache.append(&new_entries(8, 9, 2));
+        assert!(cache.is_empty());
+    }
+}
@@ -31,6 +31,18 @@ quick_error! {
         ProposalDropped {
             description("raft: proposal dropped")
         }
+        /// The request was dropped because there is no known leader to handle it.
+        NoLeader {
+            description("raft: dropped since there is no leader")
+        }
+        /// The proposal was dropped because the leader is transferring its leadership.
+        LeaderTransferring {
+            description("raft: proposal dropped since leader transfer is in progress")
+        }
+        /// The proposal was dropped because the uncommitted entries size quota is exceeded.
+        QuotaExceeded {
+            description("raft: proposal dropped since uncommitted size quota is exceeded")
+        }
         /// The configuration is invalid.
         ConfigInvalid(desc: String) {
             description(desc)
@@ -54,25 +66,69 @@ quick_error! {
         ConfChangeError(message: String) {
             display("{}", message)
         }
-        /// The request snapshot is dropped.
-        RequestSnapshotDropped {
-            description("raft: request snapshot dropped")
+        /// The conf change proposal was dropped because the one at the given index hasn't
+        /// been applied yet.
+        ConfChangePending(index: u64) {
+            display("raft: conf change dropped since the one at index {} is pending", index)
+        }
+        /// The request snapshot is dropped for the given reason.
+        RequestSnapshotDropped(reason: &'static str) {
+            display("raft: request snapshot dropped: {}", reason)
+        }
+        /// The snapshot chunk received doesn't fit into the transfer in progress.
+        InvalidSnapshotChunk(reason: &'static str) {
+            display("raft: invalid snapshot chunk: {}", reason)
+        }
+        /// The entries to initialize a storage with don't follow the snapshot.
+        InvalidInitialLog(reason: String) {
+            display("raft: invalid initial log: {}", reason)
+        }
+        /// The entries to append conflict with a committed entry, which can't be truncated.
+        CommittedConflict(index: u64, committed: u64) {
+            display("raft: entry {} conflicts with committed entry {}", index, committed)
+        }
+        /// The index is out of the valid range `[low, high]`, e.g. beyond the last index.
+        IndexOutOfRange(what: &'static str, index: u64, low: u64, high: u64) {
+            display("raft: {} {} is out of range [{}, {}]", what, index, low, high)
+        }
+        /// The message received is invalid for this peer, e.g. misrouted by the transport.
+        InvalidMessage(from: u64, to: u64, reason: &'static str) {
+            display("raft: invalid message from {} to {}: {}", from, to, reason)
         }
     }
 }
 
 impl PartialEq for Error {
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::match_same_arms))]
+    #[allow(clippy::match_same_arms)]
     fn eq(&self, other: &Error) -> bool {
         match (self, other) {
             (Error::StepPeerNotFound, Error::StepPeerNotFound) => true,
             (Error::ProposalDropped, Error::ProposalDropped) => true,
+            (Error::NoLeader, Error::NoLeader) => true,
+            (Error::LeaderTransferring, Error::LeaderTransferring) => true,
+            (Error::QuotaExceeded, Error::QuotaExceeded) => true,
             (Error::Store(ref e1), Error::Store(ref e2)) => e1 == e2,
             (Error::Io(ref e1), Error::Io(ref e2)) => e1.kind() == e2.kind(),
             (Error::StepLocalMsg, Error::StepLocalMsg) => true,
             (Error::ConfigInvalid(ref e1), Error::ConfigInvalid(ref e2)) => e1 == e2,
-            (Error::RequestSnapshotDropped, Error::RequestSnapshotDropped) => true,
+            (Error::RequestSnapshotDropped(r1), Error::RequestSnapshotDropped(r2)) => r1 == r2,
+            (Error::ConfChangePending(i1), Error::ConfChangePending(i2)) => i1 == i2,
+            (Error::InvalidSnapshotChunk(r1), Error::InvalidSnapshotChunk(r2)) => r1 == r2,
+            (Error::InvalidInitialLog(r1), Error::InvalidInitialLog(r2)) => r1 == r2,
+            (Error::CommittedConflict(i1, c1), Error::CommittedConflict(i2, c2)) => {
+                i1 == i2 && c1 == c2
+            }
+            (Error::IndexOutOfRange(w1, i1, l1, h1), Error::IndexOutOfRange(w2, i2, l2, h2)) => {
+                w1 == w2 && i1 == i2 && l1 == l2 && h1 == h2
+            }
             (Error::ConfChangeError(e1), Error::ConfChangeError(e2)) => e1 == e2,
+            (Error::Exists(id1, set1), Error::Exists(id2, set2)) => id1 == id2 && set1 == set2,
+            (Error::NotExists(id1, set1), Error::NotExists(id2, set2)) => {
+                id1 == id2 && set1 == set2
+            }
+            (Error::InvalidMessage(f1, t1, r1), Error::InvalidMessage(f2, t2, r2)) => {
+                f1 == f2 && t1 == t2 && r1 == r2
+            }
             _ => false,
         }
     }
@@ -90,6 +146,10 @@ quick_error! {
         Unavailable {
             description("log unavailable")
         }
+        /// The log is being fetched asynchronously.
+        LogTemporarilyUnavailable {
+            description("log is temporarily unavailable")
+        }
         /// The snapshot is out of date.
         SnapshotOutOfDate {
             description("snapshot out of date")
@@ -98,6 +158,19 @@ quick_error! {
         SnapshotTemporarilyUnavailable {
             description("snapshot is temporarily unavailable")
         }
+        /// The persisted log is corrupted, `index` is the first entry that can't be
+        /// recovered.
+        #[allow(missing_docs)]
+        Corruption { index: u64, details: String } {
+            description("log corrupted")
+            display("log corrupted at index {}: {}", index, details)
+        }
+        /// A write to the storage failed before, so it may be inconsistent and
+        /// refuses any further write.
+        Failed(details: String) {
+            description("storage failed")
+            display("storage failed: {}", details)
+        }
         /// Some other error occurred.
         Other(err: Box<dyn std::error::Error + Sync + Send>) {
             from()
@@ -109,21 +182,38 @@ quick_error! {
 }
 
 impl PartialEq for StorageError {
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::match_same_arms))]
+    #[allow(clippy::match_same_arms)]
     fn eq(&self, other: &StorageError) -> bool {
-        matches!(
-            (self, other),
-            (StorageError::Compacted, StorageError::Compacted)
-                | (StorageError::Unavailable, StorageError::Unavailable)
-                | (
-                    StorageError::SnapshotOutOfDate,
-                    StorageError::SnapshotOutOfDate
-                )
-                | (
-                    StorageError::SnapshotTemporarilyUnavailable,
-                    StorageError::SnapshotTemporarilyUnavailable,
-                )
-        )
+        match (self, other) {
+            (
+                StorageError::Corruption {
+                    index: i1,
+                    details: d1,
+                },
+                StorageError::Corruption {
+                    index: i2,
+                    details: d2,
+                },
+            ) => i1 == i2 && d1 == d2,
+            (StorageError::Failed(d1), StorageError::Failed(d2)) => d1 == d2,
+            _ => matches!(
+                (self, other),
+                (StorageError::Compacted, StorageError::Compacted)
+                    | (StorageError::Unavailable, StorageError::Unavailable)
+                    | (
+                        StorageError::LogTemporarilyUnavailable,
+                        StorageError::LogTemporarilyUnavailable
+                    )
+                    | (
+                        StorageError::SnapshotOutOfDate,
+                        StorageError::SnapshotOutOfDate
+                    )
+                    | (
+                        StorageError::SnapshotTemporarilyUnavailable,
+                        StorageError::SnapshotTemporarilyUnavailable,
+                    )
+            ),
+        }
     }
 }
 
@@ -161,8 +251,8 @@ mod tests {
             Error::ConfigInvalid(String::from("other error"))
         );
         assert_eq!(
-            Error::from(io::Error::new(io::ErrorKind::Other, "oh no!")),
-            Error::from(io::Error::new(io::ErrorKind::Other, "oh yes!"))
+            Error::from(io::Error::other("oh no!")),
+            Error::from(io::Error::other("oh yes!"))
         );
         assert_ne!(
             Error::StepPeerNotFound,
@@ -187,5 +277,11 @@ mod tests {
             StorageError::Other(Box::new(StorageError::Unavailable)),
             StorageError::Unavailable
         );
+        let corruption = |index, details: &str| StorageError::Corruption {
+            index,
+            details: details.to_owned(),
+        };
+        assert_eq!(corruption(3, "bad crc"), corruption(3, "bad crc"));
+        assert_ne!(corruption(3, "bad crc"), corruption(4, "bad crc"));
     }
 }
@@ -0,0 +1,38 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::time::Duration;
+
+use crate::errors::Error;
+
+/// The storage operations reported to a `StorageInstrumentation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageOp {
+    /// `Storage::entries`.
+    Entries,
+    /// Appending entries to the log.
+    Append,
+    /// `Storage::snapshot`.
+    Snapshot,
+}
+
+/// Hooks called by the storages of this crate around their reads, appends and snapshot
+/// builds, so embedders can feed their metrics without wrapping the whole `Storage`.
+///
+/// Registered with `MemStorageCore::set_instrumentation` (or
+/// `WalStorage::set_instrumentation`). The hooks are called in the calling thread, after
+/// the operation is done, so they should be cheap.
+pub trait StorageInstrumentation: Send + Sync {
+    /// Called after `count` entries of `bytes` in total are read.
+    fn on_entries(&self, _elapsed: Duration, _count: usize, _bytes: u64) {}
+
+    /// Called after `count` entries of `bytes` in total are appended. For a persistent
+    /// storage, `elapsed` includes writing the entries to disk.
+    fn on_append(&self, _elapsed: Duration, _count: usize, _bytes: u64) {}
+
+    /// Called after a snapshot of `bytes` is built.
+    fn on_snapshot(&self, _elapsed: Duration, _bytes: u64) {}
+
+    /// Called when an operation fails, including the temporary errors like
+    /// `StorageError::LogTemporarilyUnavailable`.
+    fn on_error(&self, _op: StorageOp, _err: &Error) {}
+}
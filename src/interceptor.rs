@@ -0,0 +1,27 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::eraftpb::Message;
+
+/// Hooks into the messages going in and out of `Raft`, so embedders can trace,
+/// filter, rate limit or rewrite them without forking the crate.
+///
+/// Interceptors are registered with `Raft::add_interceptor` and run in the
+/// order they were added. If one of them drops a message, the following ones
+/// don't see it.
+pub trait MessageInterceptor: Send {
+    /// Called with every message passed to `Raft::step`, including the local
+    /// messages raft steps internally on tick (e.g. `MsgHup` and `MsgBeat`).
+    ///
+    /// Returning `None` drops the message, `Raft::step` then returns `Ok(())`.
+    fn on_step(&mut self, m: Message) -> Option<Message> {
+        Some(m)
+    }
+
+    /// Called with every message raft queues to be sent to other peers, after
+    /// its `from` and `term` are filled.
+    ///
+    /// Returning `None` drops the message.
+    fn on_send(&mut self, m: Message) -> Option<Message> {
+        Some(m)
+    }
+}
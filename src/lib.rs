@@ -206,7 +206,7 @@ The `Ready` state contains quite a bit of information, and you need to check and
 by one:
 
 1. Check whether `messages` is empty or not. If not, it means that the node will send messages to
-other nodes:
+   other nodes:
 
     ```rust
     # use slog::{Drain, o};
@@ -233,7 +233,7 @@ other nodes:
     ```
 
 2. Check whether `snapshot` is empty or not. If not empty, it means that the Raft node has received
-a Raft snapshot from the leader and we must apply the snapshot:
+   a Raft snapshot from the leader and we must apply the snapshot:
 
     ```rust
     # use slog::{Drain, o};
@@ -261,8 +261,8 @@ a Raft snapshot from the leader and we must apply the snapshot:
     ```
 
 3. Check whether `committed_entires` is empty or not. If not, it means that there are some newly
-committed log entries which you must apply to the state machine. Of course, after applying, you
-need to update the applied index and resume `apply` later:
+   committed log entries which you must apply to the state machine. Of course, after applying, you
+   need to update the applied index and resume `apply` later:
 
     ```rust
     # use slog::{Drain, o};
@@ -305,7 +305,7 @@ need to update the applied index and resume `apply` later:
     ```
 
 4. Check whether `entries` is empty or not. If not empty, it means that there are newly added
-entries but have not been committed yet, we must append the entries to the Raft log:
+   entries but have not been committed yet, we must append the entries to the Raft log:
 
     ```rust
     # use slog::{Drain, o};
@@ -330,8 +330,8 @@ entries but have not been committed yet, we must append the entries to the Raft
     ```
 
 5. Check whether `hs` is empty or not. If not empty, it means that the `HardState` of the node has
-changed. For example, the node may vote for a new leader, or the commit index has been increased.
-We must persist the changed `HardState`:
+   changed. For example, the node may vote for a new leader, or the commit index has been increased.
+   We must persist the changed `HardState`:
 
     ```rust
     # use slog::{Drain, o};
@@ -355,8 +355,8 @@ We must persist the changed `HardState`:
     ```
 
 6. Call `advance` to notify that the previous work is completed. Get the return value `LightReady`
-and handle its `messages` and `committed_entries` like step 1 and step 3 does. Then call `advance_apply`
-to advance the applied index inside.
+   and handle its `messages` and `committed_entries` like step 1 and step 3 does. Then call `advance_apply`
+   to advance the applied index inside.
 
     ```rust
     # use slog::{Drain, o};
@@ -410,9 +410,9 @@ It (currently) does not:
 
 * Allow control of the replacement leader during a stepdown.
 * Optionally roll back a change during a peer group pause where the new peer group configuration
-fails.
+  fails.
 * Provide automated promotion of newly added voters from learner to voter when they are caught up.
-This must be done as a two stage process for now.
+  This must be done as a two stage process for now.
 
 > PRs to enable these are welcome! We'd love to mentor/support you through implementing it.
 
@@ -436,12 +436,12 @@ This process is a two-phase process, during the midst of it the peer group's lea
 **two independent, possibly overlapping peer sets**.
 
 > **Note:** In order to maintain resiliency guarantees  (progress while a majority of both peer sets is
-active), it is very important to wait until the entire peer group has exited the transition phase
-before taking old, removed peers offline.
+> active), it is very important to wait until the entire peer group has exited the transition phase
+> before taking old, removed peers offline.
 
 */
 
-#![cfg_attr(not(feature = "cargo-clippy"), allow(unknown_lints))]
+#![cfg_attr(not(clippy), allow(unknown_lints))]
 #![deny(clippy::all)]
 #![deny(missing_docs)]
 #![recursion_limit = "128"]
@@ -479,40 +479,82 @@ macro_rules! fatal {
     }};
 }
 
+mod apply_cursor;
+mod clock;
+mod compaction_policy;
 mod confchange;
 mod config;
+mod entry_cache;
 mod errors;
+mod instrumentation;
+mod interceptor;
 mod log_unstable;
+mod proposal_context;
+#[cfg(feature = "async")]
+mod proposal_future;
+mod purge;
 mod quorum;
 #[cfg(test)]
+#[allow(missing_docs)]
 pub mod raft;
 #[cfg(not(test))]
 mod raft;
 mod raft_log;
 pub mod raw_node;
 mod read_only;
+#[cfg(feature = "sled-storage")]
+mod sled_storage;
+mod snapshot_builder;
+mod snapshot_chunk;
+mod snapshot_policy;
 mod status;
 pub mod storage;
+mod term_cache;
 mod tracker;
 pub mod util;
+#[cfg(feature = "wal-storage")]
+mod wal_storage;
 
+pub use self::apply_cursor::ApplyCursor;
+pub use self::clock::{Clock, SystemClock};
+pub use self::compaction_policy::{CompactByAge, CompactByCount, CompactBySize, CompactionPolicy};
 pub use self::confchange::{Changer, MapChange};
 pub use self::config::Config;
+pub use self::entry_cache::EntryCache;
 pub use self::errors::{Error, Result, StorageError};
+pub use self::instrumentation::{StorageInstrumentation, StorageOp};
+pub use self::interceptor::MessageInterceptor;
 pub use self::log_unstable::Unstable;
+pub use self::proposal_context::ProposalContext;
+#[cfg(feature = "async")]
+pub use self::proposal_future::{CommitInfo, ProposalFuture};
+pub use self::purge::{PurgeProgress, PurgeStorage, Purger};
 pub use self::quorum::joint::Configuration as JointConfig;
 pub use self::quorum::majority::Configuration as MajorityConfig;
-pub use self::raft::{vote_resp_msg_type, Raft, SoftState, StateRole, INVALID_ID, INVALID_INDEX};
-pub use self::raft_log::{RaftLog, NO_LIMIT};
-pub use self::tracker::{Inflights, Progress, ProgressState, ProgressTracker};
+pub use self::raft::{
+    vote_resp_msg_type, CampaignType, DroppedProposal, ProposalDropReason, Raft, SoftState,
+    StateRole, INVALID_ID, INVALID_INDEX,
+};
+pub use self::raft_log::{LogDivergence, RaftLog, NO_LIMIT};
+pub use self::tracker::{
+    Inflights, Progress, ProgressState, ProgressTracker, ProgressTrackerState,
+};
 
 #[allow(deprecated)]
 pub use self::raw_node::is_empty_snap;
-pub use self::raw_node::{LightReady, Peer, RawNode, Ready, SnapshotStatus};
+pub use self::raw_node::{CommittedEntriesPager, LightReady, Peer, RawNode, Ready, SnapshotStatus};
 pub use self::read_only::{ReadOnlyOption, ReadState};
+#[cfg(feature = "sled-storage")]
+pub use self::sled_storage::SledStorage;
+pub use self::snapshot_builder::SnapshotBuilder;
+pub use self::snapshot_chunk::{SnapshotChunk, SnapshotReceiver, SnapshotSender, SnapshotSink};
+pub use self::snapshot_policy::SnapshotPolicy;
 pub use self::status::Status;
-pub use self::storage::{RaftState, Storage};
+pub use self::storage::{GetEntriesContext, RaftState, Storage, SyncPolicy};
+pub use self::term_cache::TermCache;
 pub use self::util::majority;
+#[cfg(feature = "wal-storage")]
+pub use self::wal_storage::{WalArchive, WalConfig, WalDirArchive, WalMetrics, WalStorage};
 pub use raft_proto::eraftpb;
 
 pub mod prelude {
@@ -555,6 +597,7 @@ pub fn default_logger() -> slog::Logger {
     static LOGGER_INITIALIZED: Once = Once::new();
     static mut LOGGER: Option<slog::Logger> = None;
 
+    #[allow(static_mut_refs)]
     let logger = unsafe {
         LOGGER_INITIALIZED.call_once(|| {
             let decorator = slog_term::TermDecorator::new().build();
@@ -566,7 +609,7 @@ pub fn default_logger() -> slog::Logger {
     };
     if let Some(case) = std::thread::current()
         .name()
-        .and_then(|v| v.split(':').last())
+        .and_then(|v| v.split(':').next_back())
     {
         logger.new(o!("case" => case.to_string()))
     } else {
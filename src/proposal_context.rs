@@ -0,0 +1,69 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::eraftpb::Entry;
+use crate::DroppedProposal;
+
+const TOKEN_LEN: usize = 8;
+
+/// An entry context carrying a token chosen by the application, so committed
+/// entries and dropped proposals can be correlated back to their proposers.
+///
+/// The token is encoded as 8 big-endian bytes in front of the optional user
+/// data. Contexts of all proposals should be built with it, otherwise a raw
+/// context may be decoded as a token.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProposalContext {
+    /// The token identifying the proposal.
+    pub token: u64,
+    /// The user data of the context.
+    pub data: Vec<u8>,
+}
+
+impl ProposalContext {
+    /// Creates a context carrying the given token and no user data.
+    pub fn new(token: u64) -> ProposalContext {
+        ProposalContext {
+            token,
+            data: vec![],
+        }
+    }
+
+    /// Creates a context carrying the given token and user data.
+    pub fn with_data(token: u64, data: Vec<u8>) -> ProposalContext {
+        ProposalContext { token, data }
+    }
+
+    /// Encodes the context to be passed to `RawNode::propose` or
+    /// `RawNode::propose_conf_change`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(TOKEN_LEN + self.data.len());
+        buf.extend_from_slice(&self.token.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    /// Decodes a context encoded by `encode`. Returns `None` if it's too short to
+    /// carry a token, e.g. the context of an empty entry appended by a new leader.
+    pub fn decode(context: &[u8]) -> Option<ProposalContext> {
+        if context.len() < TOKEN_LEN {
+            return None;
+        }
+        let (token, data) = context.split_at(TOKEN_LEN);
+        let mut buf = [0; TOKEN_LEN];
+        buf.copy_from_slice(token);
+        Some(ProposalContext {
+            token: u64::from_be_bytes(buf),
+            data: data.to_vec(),
+        })
+    }
+
+    /// Returns the token of a committed entry, if it was proposed with one.
+    pub fn token_of_entry(e: &Entry) -> Option<u64> {
+        ProposalContext::decode(e.get_context()).map(|c| c.token)
+    }
+
+    /// Returns the token of a dropped proposal, if it was proposed with one.
+    pub fn token_of_dropped(p: &DroppedProposal) -> Option<u64> {
+        ProposalContext::decode(&p.context).map(|c| c.token)
+    }
+}
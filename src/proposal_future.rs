@@ -0,0 +1,270 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::eraftpb::{Entry, EntryType};
+use crate::errors::{Error, Result};
+use crate::ProposalContext;
+
+// The most proposals forwarded to another leader that are waited on. They are only
+// resolved if their entries are committed, so the oldest ones are dropped beyond it.
+const MAX_FORWARDED: usize = 4096;
+
+/// Where a proposal ended up in the log once it's committed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// The index of the committed entry.
+    pub index: u64,
+    /// The term of the committed entry.
+    pub term: u64,
+}
+
+#[derive(Default)]
+struct Slot {
+    result: Option<Result<CommitInfo>>,
+    waker: Option<Waker>,
+}
+
+/// A future returned by `RawNode::propose_awaitable`, which resolves when the entry
+/// is handed out as committed or the proposal is known to be dropped.
+///
+/// It's resolved while the `RawNode` is driven, it never drives the node itself.
+pub struct ProposalFuture {
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl Future for ProposalFuture {
+    type Output = Result<CommitInfo>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<CommitInfo>> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Tracks the proposals made by `RawNode::propose_awaitable` that are not resolved yet.
+///
+/// Tokens are only unique to this instance, so the contexts also carry the node id
+/// and a random nonce, and committed entries proposed by other nodes or by a previous
+/// incarnation of this node are not matched.
+pub(crate) struct PendingProposals {
+    // The node id and nonce, encoded after the token in the contexts.
+    origin: Vec<u8>,
+    last_token: u64,
+    slots: HashMap<u64, Arc<Mutex<Slot>>>,
+    // Token of the proposals appended by this node as leader, by index.
+    indexes: BTreeMap<u64, u64>,
+    // Token of the proposals forwarded to another leader, oldest first.
+    forwarded: VecDeque<u64>,
+}
+
+impl PendingProposals {
+    pub fn new(id: u64) -> PendingProposals {
+        let mut origin = Vec::with_capacity(16);
+        origin.extend_from_slice(&id.to_be_bytes());
+        origin.extend_from_slice(&rand::random::<u64>().to_be_bytes());
+        PendingProposals {
+            origin,
+            last_token: 0,
+            slots: HashMap::new(),
+            indexes: BTreeMap::new(),
+            forwarded: VecDeque::new(),
+        }
+    }
+
+    /// Allocates a token for a new proposal and the future to wait on it. Returns the
+    /// token with the context to propose with.
+    pub fn register(&mut self) -> (u64, Vec<u8>, ProposalFuture) {
+        self.last_token += 1;
+        let slot = Arc::new(Mutex::new(Slot::default()));
+        self.slots.insert(self.last_token, slot.clone());
+        let ctx = ProposalContext::with_data(self.last_token, self.origin.clone()).encode();
+        (self.last_token, ctx, ProposalFuture { slot })
+    }
+
+    /// Records the index the proposal was appended at, so it can be resolved as
+    /// dropped if another entry is committed there.
+    pub fn appended(&mut self, token: u64, index: u64) {
+        self.indexes.insert(index, token);
+    }
+
+    /// Records that the proposal was forwarded to another leader. The oldest ones are
+    /// resolved as dropped once too many are waited on.
+    pub fn forwarded(&mut self, token: u64) {
+        self.forwarded.push_back(token);
+        while self.forwarded.len() > MAX_FORWARDED {
+            let t = self.forwarded.pop_front().unwrap();
+            self.resolve(t, Err(Error::ProposalDropped));
+        }
+    }
+
+    pub fn resolve(&mut self, token: u64, res: Result<CommitInfo>) {
+        let slot = match self.slots.remove(&token) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let mut slot = slot.lock().unwrap();
+        slot.result = Some(res);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Resolves the proposals with the committed entries.
+    pub fn on_committed(&mut self, ents: &[Entry]) {
+        if self.slots.is_empty() {
+            return;
+        }
+        for e in ents {
+            let token = if e.get_entry_type() == EntryType::EntryNormal {
+                ProposalContext::decode(e.get_context())
+                    .filter(|ctx| ctx.data == self.origin)
+                    .map(|ctx| ctx.token)
+            } else {
+                None
+            };
+            if let Some(token) = token {
+                let info = CommitInfo {
+                    index: e.index,
+                    term: e.term,
+                };
+                self.resolve(token, Ok(info));
+            }
+            // The proposal was overwritten by a new leader.
+            if let Some(t) = self.indexes.remove(&e.index) {
+                if Some(t) != token {
+                    self.resolve(t, Err(Error::ProposalDropped));
+                }
+            }
+        }
+        if let Some(e) = ents.last() {
+            self.indexes = self.indexes.split_off(&(e.index + 1));
+        }
+        while let Some(t) = self.forwarded.front() {
+            if self.slots.contains_key(t) {
+                break;
+            }
+            self.forwarded.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    use super::*;
+    use crate::default_logger;
+    use crate::storage::MemStorage;
+    use crate::{Config, RawNode};
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    fn poll(f: &mut ProposalFuture) -> Poll<Result<CommitInfo>> {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        Pin::new(f).poll(&mut Context::from_waker(&waker))
+    }
+
+    fn handle_ready(node: &mut RawNode<MemStorage>) {
+        let mut rd = node.ready();
+        node.mut_store().wl().append(rd.entries()).unwrap();
+        if let Some(hs) = rd.hs() {
+            node.mut_store().wl().set_hardstate(hs.clone());
+        }
+        let _ = rd.take_committed_entries();
+        let _ = node.advance(rd);
+    }
+
+    #[test]
+    fn test_propose_awaitable() {
+        let l = default_logger();
+        let store = MemStorage::new_with_conf_state((vec![1], vec![]));
+        let mut node = RawNode::new(&Config::new(1), store, &l).unwrap();
+
+        // Proposals without a leader are dropped right away.
+        let mut f = node.propose_awaitable(b"foo".to_vec());
+        assert_eq!(poll(&mut f), Poll::Ready(Err(Error::NoLeader)));
+
+        node.campaign().unwrap();
+        handle_ready(&mut node);
+        let mut f = node.propose_awaitable(b"foo".to_vec());
+        assert_eq!(poll(&mut f), Poll::Pending);
+        handle_ready(&mut node);
+        assert_eq!(
+            poll(&mut f),
+            Poll::Ready(Ok(CommitInfo { index: 2, term: 1 }))
+        );
+    }
+
+    #[test]
+    fn test_pending_proposals_overwritten() {
+        let mut pending = PendingProposals::new(1);
+        let (token, _, mut f) = pending.register();
+        pending.appended(token, 5);
+        let mut e = Entry::default();
+        e.index = 5;
+        e.term = 3;
+        pending.on_committed(&[e]);
+        assert_eq!(poll(&mut f), Poll::Ready(Err(Error::ProposalDropped)));
+    }
+
+    #[test]
+    fn test_pending_proposals_foreign_entries() {
+        let mut pending = PendingProposals::new(1);
+        let (token, ctx, mut f) = pending.register();
+        let mut e = Entry::default();
+        e.index = 5;
+        e.term = 3;
+
+        // The same token proposed by another node, or by a previous incarnation.
+        for mut other in [PendingProposals::new(2), PendingProposals::new(1)] {
+            let (t, other_ctx, _f) = other.register();
+            assert_eq!(t, token);
+            e.context = other_ctx.into();
+            pending.on_committed(&[e.clone()]);
+            assert_eq!(poll(&mut f), Poll::Pending);
+        }
+
+        // Conf changes are never matched.
+        e.set_entry_type(EntryType::EntryConfChange);
+        e.context = ctx.clone().into();
+        pending.on_committed(&[e.clone()]);
+        assert_eq!(poll(&mut f), Poll::Pending);
+
+        e.set_entry_type(EntryType::EntryNormal);
+        pending.on_committed(&[e]);
+        assert_eq!(
+            poll(&mut f),
+            Poll::Ready(Ok(CommitInfo { index: 5, term: 3 }))
+        );
+    }
+
+    #[test]
+    fn test_pending_proposals_forwarded_bound() {
+        let mut pending = PendingProposals::new(1);
+        let (token, _, mut f) = pending.register();
+        pending.forwarded(token);
+        for _ in 0..MAX_FORWARDED {
+            let (t, _, _f) = pending.register();
+            pending.forwarded(t);
+        }
+        assert_eq!(poll(&mut f), Poll::Ready(Err(Error::ProposalDropped)));
+        assert_eq!(pending.slots.len(), MAX_FORWARDED);
+        assert_eq!(pending.forwarded.len(), MAX_FORWARDED);
+    }
+}
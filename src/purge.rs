@@ -0,0 +1,211 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::errors::{Error, Result};
+use crate::storage::{MemStorage, Storage};
+
+/// A storage whose log can be discarded up to a given index.
+pub trait PurgeStorage: Storage + Send + 'static {
+    /// Discards all the log entries before `compact_index`. It's not an error if they're
+    /// already discarded.
+    fn purge(&self, compact_index: u64) -> Result<()>;
+}
+
+impl PurgeStorage for MemStorage {
+    fn purge(&self, compact_index: u64) -> Result<()> {
+        self.wl().compact(compact_index)
+    }
+}
+
+/// How far a `Purger` has gone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PurgeProgress {
+    /// The latest safe point, the entries up to it can be purged.
+    pub safe_point: u64,
+    /// The entries up to this index are purged.
+    pub purged: u64,
+}
+
+impl PurgeProgress {
+    /// Whether the log is purged up to the safe point.
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.purged >= self.safe_point
+    }
+}
+
+#[derive(Default)]
+struct PurgeState {
+    progress: PurgeProgress,
+    // The safe point the worker last purged to, successfully or not.
+    attempted: u64,
+    error: Option<Error>,
+    stopped: bool,
+}
+
+/// Purges the log of a storage in the background, up to the safe point of the cluster.
+///
+/// An entry is safe to purge once every peer has applied it, otherwise a peer recovering
+/// from a crash couldn't replay it, and once it's covered by the latest snapshot, otherwise
+/// a lagging peer couldn't be caught up. The application tells both with `advance`, and the
+/// purger discards the log up to the lower one in its own thread, so appends are not
+/// blocked behind deletions. The thread stops when the purger is dropped.
+pub struct Purger {
+    state: Arc<(Mutex<PurgeState>, Condvar)>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Purger {
+    /// Starts a purger for `storage`.
+    pub fn new<S: PurgeStorage>(storage: S) -> Purger {
+        let state = Arc::new((Mutex::new(PurgeState::default()), Condvar::new()));
+        let worker_state = state.clone();
+        let worker = thread::Builder::new()
+            .name("raft-purge".to_owned())
+            .spawn(move || run(storage, worker_state))
+            .unwrap();
+        Purger {
+            state,
+            worker: Some(worker),
+        }
+    }
+
+    /// Tells the minimum applied index across all peers and the index of the latest
+    /// snapshot. The safe point never goes back, so stale values are ignored.
+    pub fn advance(&self, min_applied: u64, snapshot_index: u64) {
+        let safe_point = cmp::min(min_applied, snapshot_index);
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if safe_point > state.progress.safe_point {
+            state.progress.safe_point = safe_point;
+            cvar.notify_all();
+        }
+    }
+
+    /// Returns how far the log is purged.
+    pub fn progress(&self) -> PurgeProgress {
+        self.state.0.lock().unwrap().progress
+    }
+
+    /// Returns the error of the last failed purge, if any. The purge is retried when the
+    /// safe point advances.
+    pub fn take_error(&self) -> Option<Error> {
+        self.state.0.lock().unwrap().error.take()
+    }
+
+    /// Waits until the entries up to `index` are purged or `timeout` elapses. Returns
+    /// whether they're purged.
+    pub fn wait_purged(&self, index: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.progress.purged < index {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl Drop for Purger {
+    fn drop(&mut self) {
+        {
+            let (lock, cvar) = &*self.state;
+            lock.lock().unwrap().stopped = true;
+            cvar.notify_all();
+        }
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run<S: PurgeStorage>(storage: S, state: Arc<(Mutex<PurgeState>, Condvar)>) {
+    let (lock, cvar) = &*state;
+    let mut guard = lock.lock().unwrap();
+    loop {
+        while !guard.stopped && guard.progress.safe_point == guard.attempted {
+            guard = cvar.wait(guard).unwrap();
+        }
+        if guard.stopped {
+            return;
+        }
+        let safe_point = guard.progress.safe_point;
+        guard.attempted = safe_point;
+        drop(guard);
+
+        let res = purge_to(&storage, safe_point);
+
+        guard = lock.lock().unwrap();
+        match res {
+            Ok(purged) => guard.progress.purged = cmp::max(guard.progress.purged, purged),
+            Err(e) => guard.error = Some(e),
+        }
+        cvar.notify_all();
+    }
+}
+
+// Purges the entries up to `safe_point`, but not beyond the last entry of the log.
+// Returns the index purged to.
+fn purge_to<S: PurgeStorage>(storage: &S, safe_point: u64) -> Result<u64> {
+    let index = cmp::min(safe_point, storage.last_index()?);
+    storage.purge(index + 1)?;
+    Ok(index)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eraftpb::Entry;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.index = index;
+        e.term = term;
+        e
+    }
+
+    #[test]
+    fn test_purger() {
+        let storage = MemStorage::new();
+        let ents: Vec<_> = (1..=10).map(|i| new_entry(i, 1)).collect();
+        storage.wl().append(&ents).unwrap();
+        let purger = Purger::new(storage.clone());
+        assert_eq!(purger.progress(), PurgeProgress::default());
+        assert!(purger.progress().is_done());
+
+        // Bounded by the snapshot.
+        purger.advance(6, 4);
+        assert!(purger.wait_purged(4, Duration::from_secs(5)));
+        assert_eq!(storage.first_index(), Ok(5));
+        assert_eq!(
+            purger.progress(),
+            PurgeProgress {
+                safe_point: 4,
+                purged: 4
+            }
+        );
+
+        // Stale safe points are ignored.
+        purger.advance(3, 8);
+        assert_eq!(purger.progress().safe_point, 4);
+
+        // Bounded by the applied index, and never beyond the log.
+        purger.advance(12, 20);
+        assert!(purger.wait_purged(10, Duration::from_secs(5)));
+        let progress = purger.progress();
+        assert_eq!((progress.safe_point, progress.purged), (12, 10));
+        assert!(!progress.is_done());
+        assert_eq!(storage.first_index(), Ok(11));
+        assert_eq!(storage.term(10), Ok(1));
+        assert!(purger.take_error().is_none());
+        drop(purger);
+    }
+}
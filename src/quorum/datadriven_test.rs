@@ -1,6 +1,7 @@
 use crate::quorum::{AckIndexer, AckedIndexer, Index};
 use crate::{default_logger, HashMap, HashSet, JointConfig, MajorityConfig};
 use datadriven::{run_test, TestData};
+use std::time::{Duration, Instant};
 
 fn test_quorum(data: &TestData) -> String {
     // Two majority configs. The first one is always used (though it may
@@ -304,6 +305,33 @@ fn test_quorum(data: &TestData) -> String {
             }
             buf.push_str(&format!("{}\n", r));
         }
+        "active_since" => {
+            // The idxs are the seconds since a base instant when each voter was
+            // last heard from.
+            let l = make_lookuper(&idxs, &ids, &idsj);
+            let base = Instant::now();
+            let active_at = |id| {
+                l.acked_index(id)
+                    .map(|i| base + Duration::from_secs(i.index))
+            };
+            let r = if joint {
+                let r = JointConfig::new_joint_from_majorities(c.clone(), cj.clone())
+                    .quorum_active_since(active_at);
+                // Interchanging the majorities shouldn't make a difference. If it does, print.
+                let ar =
+                    JointConfig::new_joint_from_majorities(cj, c).quorum_active_since(active_at);
+                if ar != r {
+                    buf.push_str(&format!("{:?} <-- via symmetry\n", ar));
+                }
+                r
+            } else {
+                c.quorum_active_since(active_at)
+            };
+            match r {
+                Some(at) => buf.push_str(&format!("{}\n", (at - base).as_secs())),
+                None => buf.push_str("none\n"),
+            }
+        }
         _ => {
             panic!("unknown command: {}", data.cmd);
         }
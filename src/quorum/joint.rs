@@ -1,10 +1,11 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
-use super::{AckedIndexer, VoteResult};
+use super::{AckedIndexer, Index, VoteResult};
 use crate::util::Union;
 use crate::HashSet;
 use crate::MajorityConfig;
 use std::cmp;
+use std::time::Instant;
 
 /// A configuration of two groups of (possibly overlapping) majority configurations.
 /// Decisions require the support of both majorities.
@@ -50,6 +51,22 @@ impl Configuration {
         (cmp::min(i_idx, o_idx), i_use_gc && o_use_gc)
     }
 
+    /// Like `committed_index`, but takes the acked indexes of all the voters already
+    /// sorted in descending order, see `MajorityConfig::committed_index_sorted`.
+    pub fn committed_index_sorted(
+        &self,
+        use_group_commit: bool,
+        sorted: impl Iterator<Item = (u64, Index)> + Clone,
+    ) -> (u64, bool) {
+        let (i_idx, i_use_gc) = self
+            .incoming
+            .committed_index_sorted(use_group_commit, sorted.clone());
+        let (o_idx, o_use_gc) = self
+            .outgoing
+            .committed_index_sorted(use_group_commit, sorted);
+        (cmp::min(i_idx, o_idx), i_use_gc && o_use_gc)
+    }
+
     /// Takes a mapping of voters to yes/no (true/false) votes and returns a result
     /// indicating whether the vote is pending, lost, or won. A joint quorum requires
     /// both majority quorums to vote in favor.
@@ -66,6 +83,24 @@ impl Configuration {
         }
     }
 
+    /// Returns the latest instant by which both majorities had been active. See
+    /// `MajorityConfig::quorum_active_since`.
+    pub fn quorum_active_since(
+        &self,
+        active_at: impl Fn(u64) -> Option<Instant>,
+    ) -> Option<Instant> {
+        // An empty half behaves like the other half.
+        let i = self.incoming.quorum_active_since(&active_at);
+        if self.outgoing.is_empty() {
+            return i;
+        }
+        let o = self.outgoing.quorum_active_since(active_at);
+        if self.incoming.is_empty() {
+            return o;
+        }
+        Some(cmp::min(i?, o?))
+    }
+
     /// Clears all IDs.
     pub fn clear(&mut self) {
         self.incoming.clear();
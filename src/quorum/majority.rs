@@ -7,7 +7,8 @@ use std::collections::hash_set::Iter;
 use std::fmt::Formatter;
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
-use std::{cmp, slice, u64};
+use std::time::Instant;
+use std::{cmp, slice};
 
 /// A set of IDs that uses majority quorums to make decisions.
 #[derive(Clone, Debug, Default, PartialEq)]
@@ -92,35 +93,25 @@ impl Configuration {
             heap_arr.as_mut().unwrap().as_mut_slice()
         };
         // Reverse sort.
-        matched.sort_by(|a, b| b.index.cmp(&a.index));
+        matched.sort_by_key(|a| cmp::Reverse(a.index));
+        committed_index_of(matched.iter().cloned(), matched.len(), use_group_commit)
+    }
 
-        let quorum = crate::majority(matched.len());
-        let quorum_index = matched[quorum - 1];
-        if !use_group_commit {
-            return (quorum_index.index, false);
-        }
-        let (quorum_commit_index, mut checked_group_id) =
-            (quorum_index.index, quorum_index.group_id);
-        let mut single_group = true;
-        for m in matched.iter() {
-            if m.group_id == 0 {
-                single_group = false;
-                continue;
-            }
-            if checked_group_id == 0 {
-                checked_group_id = m.group_id;
-                continue;
-            }
-            if checked_group_id == m.group_id {
-                continue;
-            }
-            return (cmp::min(m.index, quorum_commit_index), true);
-        }
-        if single_group {
-            (quorum_commit_index, false)
-        } else {
-            (matched.last().unwrap().index, false)
+    /// Like `committed_index`, but takes the acked indexes of the voters, and maybe of
+    /// other voters too, already sorted in descending order, so the sorting can be
+    /// shared with a joint config or other users.
+    pub fn committed_index_sorted(
+        &self,
+        use_group_commit: bool,
+        sorted: impl Iterator<Item = (u64, Index)> + Clone,
+    ) -> (u64, bool) {
+        if self.voters.is_empty() {
+            return (u64::MAX, true);
         }
+        let matched = sorted
+            .filter(|(id, _)| self.voters.contains(id))
+            .map(|(_, index)| index);
+        committed_index_of(matched, self.voters.len(), use_group_commit)
     }
 
     /// Takes a mapping of voters to yes/no (true/false) votes and returns
@@ -153,6 +144,23 @@ impl Configuration {
         }
     }
 
+    /// Returns the latest instant by which a quorum of voters had been active,
+    /// given when each voter was last heard from. It's where a leader lease based
+    /// on the quorum can be extended from. Returns `None` if there is no such
+    /// quorum, or the config is empty.
+    pub fn quorum_active_since(
+        &self,
+        active_at: impl Fn(u64) -> Option<Instant>,
+    ) -> Option<Instant> {
+        let mut times: Vec<_> = self.voters.iter().map(|v| active_at(*v)).collect();
+        // Reverse sort, voters never heard from come last.
+        times.sort_by(|a, b| b.cmp(a));
+        times
+            .get(crate::majority(times.len()) - 1)
+            .cloned()
+            .flatten()
+    }
+
     /// Describe returns a (multi-line) representation of the commit indexes for the
     /// given lookuper.
     /// Including `Index`,`Id` and the number of smaller index (represented as the bar)
@@ -206,7 +214,7 @@ impl Configuration {
             }
         }
 
-        info.sort_by(|a, b| a.id.cmp(&b.id));
+        info.sort_by_key(|a| a.id);
 
         let mut buf = String::new();
         buf.push_str(" ".repeat(n).as_str());
@@ -238,6 +246,41 @@ impl Configuration {
     }
 }
 
+// Computes the committed index from the acked indexes of `len` voters sorted in
+// descending order.
+fn committed_index_of(
+    matched: impl Iterator<Item = Index> + Clone,
+    len: usize,
+    use_group_commit: bool,
+) -> (u64, bool) {
+    let quorum = crate::majority(len);
+    let quorum_index = matched.clone().nth(quorum - 1).unwrap();
+    if !use_group_commit {
+        return (quorum_index.index, false);
+    }
+    let (quorum_commit_index, mut checked_group_id) = (quorum_index.index, quorum_index.group_id);
+    let mut single_group = true;
+    for m in matched.clone() {
+        if m.group_id == 0 {
+            single_group = false;
+            continue;
+        }
+        if checked_group_id == 0 {
+            checked_group_id = m.group_id;
+            continue;
+        }
+        if checked_group_id == m.group_id {
+            continue;
+        }
+        return (cmp::min(m.index, quorum_commit_index), true);
+    }
+    if single_group {
+        (quorum_commit_index, false)
+    } else {
+        (matched.last().unwrap().index, false)
+    }
+}
+
 impl Deref for Configuration {
     type Target = HashSet<u64>;
 
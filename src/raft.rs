@@ -14,8 +14,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(test)]
+mod datadriven_test;
+
 use std::cmp;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::eraftpb::{
     ConfChange, ConfChangeV2, ConfState, Entry, EntryType, HardState, Message, MessageType,
@@ -23,33 +29,45 @@ use crate::eraftpb::{
 };
 use protobuf::Message as _;
 use raft_proto::ConfChangeI;
-use rand::{self, Rng};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use slog::{self, Logger};
 
 use super::errors::{Error, Result, StorageError};
-use super::raft_log::RaftLog;
+use super::raft_log::{LogDivergence, RaftLog};
 use super::read_only::{ReadOnly, ReadOnlyOption, ReadState};
-use super::storage::Storage;
+use super::storage::{GetEntriesContext, GetEntriesFor, Storage};
 use super::Config;
-use crate::confchange::Changer;
+use crate::confchange::{Changer, MapChangeType};
 use crate::quorum::VoteResult;
 use crate::util;
 use crate::util::NO_LIMIT;
-use crate::{confchange, Progress, ProgressState, ProgressTracker};
-
-// CAMPAIGN_PRE_ELECTION represents the first phase of a normal election when
-// Config.pre_vote is true.
-const CAMPAIGN_PRE_ELECTION: &[u8] = b"CampaignPreElection";
-// CAMPAIGN_ELECTION represents a normal (time-based) election (the second phase
-// of the election when Config.pre_vote is true).
-const CAMPAIGN_ELECTION: &[u8] = b"CampaignElection";
-// CAMPAIGN_TRANSFER represents the type of leader transfer.
+use crate::{
+    confchange, Clock, HashMap, HashSet, MessageInterceptor, Progress, ProgressState,
+    ProgressTracker, SnapshotPolicy, SnapshotStatus,
+};
+
+// CAMPAIGN_TRANSFER is attached to vote requests of a leader transfer campaign.
 const CAMPAIGN_TRANSFER: &[u8] = b"CampaignTransfer";
 
+/// The type of a campaign.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CampaignType {
+    /// The first phase of a normal election when `Config::pre_vote` is true.
+    PreElection,
+    /// A normal (time-based) election (the second phase of the election when
+    /// `Config::pre_vote` is true).
+    Election,
+    /// A leader transfer. It skips pre-vote and forces the voters to respond even
+    /// if they are in lease.
+    Transfer,
+}
+
 /// The role of the node.
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum StateRole {
     /// The node is a follower of the leader.
+    #[default]
     Follower,
     /// The node could become a leader.
     Candidate,
@@ -59,12 +77,6 @@ pub enum StateRole {
     PreCandidate,
 }
 
-impl Default for StateRole {
-    fn default() -> StateRole {
-        StateRole::Follower
-    }
-}
-
 /// A constant represents invalid id of raft.
 pub const INVALID_ID: u64 = 0;
 /// A constant represents invalid index of raft log.
@@ -80,6 +92,36 @@ pub struct SoftState {
     pub raft_state: StateRole,
 }
 
+/// The reason why a proposal was dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposalDropReason {
+    /// There is no known leader to handle the proposal.
+    NoLeader,
+    /// The follower is configured not to forward proposals to the leader.
+    ForwardingDisabled,
+    /// The leader is transferring its leadership.
+    LeaderTransferring,
+    /// The leader was removed from the configuration.
+    NotMember,
+    /// The uncommitted entries size quota is exceeded.
+    QuotaExceeded,
+    /// The conf change can't be decoded.
+    InvalidConfChange,
+    /// The conf change was refused, e.g. because another one is pending. Only the
+    /// conf change entry is replaced by an empty one, other entries of the same
+    /// proposal are still appended.
+    ConfChangeRefused,
+}
+
+/// A proposal entry dropped by raft, keyed by the context of the entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DroppedProposal {
+    /// The context of the dropped entry.
+    pub context: Vec<u8>,
+    /// Why it was dropped.
+    pub reason: ProposalDropReason,
+}
+
 /// UncommittedState is used to keep track of imformation of uncommitted
 /// log entries on 'leader' node
 struct UncommittedState {
@@ -163,6 +205,19 @@ pub struct RaftCore<T: Storage> {
     /// The current read states.
     pub read_states: Vec<ReadState>,
 
+    /// The proposals dropped since the queue was last drained, if
+    /// `Config::track_dropped_proposals` is enabled. The application is expected to
+    /// take them to notify the proposers.
+    pub dropped_proposals: Vec<DroppedProposal>,
+    track_dropped_proposals: bool,
+
+    /// The peers that went inactive since the queue was last drained, if
+    /// `Config::inactive_peer_tick` is set. Only the leader tracks them.
+    pub inactive_peers: Vec<u64>,
+    track_inactive_peers: bool,
+    // The ticks since the node started, the clock of the inactivity deadlines.
+    ticks: u64,
+
     /// The persistent log.
     pub raft_log: RaftLog<T>,
 
@@ -172,6 +227,9 @@ pub struct RaftCore<T: Storage> {
     /// The maximum length (in bytes) of all the entries.
     pub max_msg_size: u64,
 
+    /// The maximum length (in bytes) of all the committed entries in a `Ready`.
+    pub max_committed_size_per_ready: u64,
+
     /// The peer is requesting snapshot, it is the index that the follower
     /// needs it to be included in a snapshot.
     pub pending_request_snapshot: u64,
@@ -207,6 +265,11 @@ pub struct RaftCore<T: Storage> {
     /// The queue of read-only requests.
     pub read_only: ReadOnly,
 
+    /// The contexts of read-only requests forwarded to the current leader that
+    /// haven't been responded yet, so responses can be matched to them, with the
+    /// tick after which they're given up.
+    forwarded_reads: HashMap<Vec<u8>, u64>,
+
     /// Ticks since it reached last electionTimeout when it is leader or candidate.
     /// Number of ticks since it reached last electionTimeout or received a
     /// valid message from current leader when it is a follower.
@@ -228,6 +291,22 @@ pub struct RaftCore<T: Storage> {
 
     skip_bcast_commit: bool,
     batch_append: bool,
+    disable_proposal_forwarding: bool,
+    quiesce: bool,
+    quiesced: bool,
+    quiesced_election_timeout: usize,
+    // The voters that have answered a heartbeat since the leader woke up from
+    // quiescence. The leader doesn't tick while quiesced, so it holds no lease until
+    // a quorum answers again.
+    lease_acks: Option<HashSet<u64>>,
+    leader_stickiness: bool,
+
+    rng: StdRng,
+
+    max_inflight_snapshots: usize,
+    /// The number of peers in `ProgressState::Snapshot`. It's updated whenever a
+    /// progress enters or leaves the state, or is removed.
+    inflight_snapshots: usize,
 
     heartbeat_timeout: usize,
     election_timeout: usize,
@@ -241,12 +320,33 @@ pub struct RaftCore<T: Storage> {
 
     /// The logger for the raft structure.
     pub(crate) logger: slog::Logger,
+    // The term tagged on logs when `Config::log_term` is set.
+    logged_term: Arc<AtomicU64>,
 
     /// The election priority of this node.
     pub priority: u64,
 
     /// Track uncommitted log entry on this node
     uncommitted_state: UncommittedState,
+
+    interceptors: Vec<Box<dyn MessageInterceptor>>,
+
+    clock: Option<Box<dyn Clock>>,
+    lease_duration: Duration,
+    /// The time the node became leader, only tracked when a clock is set. The
+    /// lease holds from then until it's extended by a quorum heard from later.
+    quorum_active_at: Option<Instant>,
+    /// The time the leader last heard from each peer, only tracked when a clock
+    /// is set.
+    active_at: HashMap<u64, Instant>,
+    lease_step_down: bool,
+
+    snapshot_policy: Option<Box<dyn SnapshotPolicy>>,
+
+    // Set during `bcast_append`, the followers at the same index share the entries
+    // fetched for the first of them instead of reading the log again.
+    broadcasting: bool,
+    bcast_entries: Vec<Arc<[Entry]>>,
 }
 
 /// A struct that represents the raft consensus itself. Stores details concerning the current
@@ -276,6 +376,7 @@ impl<T: Storage> DerefMut for Raft<T> {
     }
 }
 
+#[allow(dead_code)]
 trait AssertSend: Send {}
 
 impl<T: Storage + Send> AssertSend for Raft<T> {}
@@ -299,12 +400,33 @@ pub fn vote_resp_msg_type(t: MessageType) -> MessageType {
     }
 }
 
+/// Clears the payload of normal entries, keeping their term and index. Conf change
+/// entries are kept intact so a witness can still track membership.
+fn strip_payloads(ents: &mut [Entry]) {
+    for e in ents {
+        if e.get_entry_type() == EntryType::EntryNormal {
+            e.clear_data();
+            e.clear_context();
+        }
+    }
+}
+
 impl<T: Storage> Raft<T> {
     /// Creates a new raft for use on the node.
     #[allow(clippy::new_ret_no_self)]
     pub fn new(c: &Config, store: T, logger: &Logger) -> Result<Self> {
         c.validate()?;
-        let logger = logger.new(o!("raft_id" => c.id));
+        let mut logger = logger.new(o!("raft_id" => c.id));
+        if let Some(group_id) = c.group_id {
+            logger = logger.new(o!("group_id" => group_id));
+        }
+        let logged_term = Arc::new(AtomicU64::new(0));
+        if c.log_term {
+            let term = logged_term.clone();
+            logger = logger.new(o!(
+                "raft_term" => slog::FnValue(move |_| term.load(Ordering::Relaxed))
+            ));
+        }
         let raft_state = store.initial_state()?;
         let conf_state = &raft_state.conf_state;
         let voters = &conf_state.voters;
@@ -321,15 +443,22 @@ impl<T: Storage> Raft<T> {
             r: RaftCore {
                 id: c.id,
                 read_states: Default::default(),
+                dropped_proposals: Default::default(),
+                track_dropped_proposals: c.track_dropped_proposals,
+                inactive_peers: Default::default(),
+                track_inactive_peers: c.inactive_peer_tick > 0,
+                ticks: 0,
                 raft_log: RaftLog::new(store, logger.clone()),
                 max_inflight: c.max_inflight_msgs,
                 max_msg_size: c.max_size_per_msg,
+                max_committed_size_per_ready: c.max_committed_size_per_ready,
                 pending_request_snapshot: INVALID_INDEX,
                 state: StateRole::Follower,
                 promotable: false,
                 check_quorum: c.check_quorum,
                 pre_vote: c.pre_vote,
                 read_only: ReadOnly::new(c.read_only_option),
+                forwarded_reads: Default::default(),
                 heartbeat_timeout: c.heartbeat_tick,
                 election_timeout: c.election_tick,
                 leader_id: Default::default(),
@@ -344,15 +473,41 @@ impl<T: Storage> Raft<T> {
                 max_election_timeout: c.max_election_tick(),
                 skip_bcast_commit: c.skip_bcast_commit,
                 batch_append: c.batch_append,
+                disable_proposal_forwarding: c.disable_proposal_forwarding,
+                quiesce: c.quiesce,
+                quiesced: false,
+                quiesced_election_timeout: c.quiesced_election_tick(),
+                lease_acks: None,
+                leader_stickiness: c.leader_stickiness,
+                max_inflight_snapshots: c.max_inflight_snapshots,
+                inflight_snapshots: 0,
+                rng: match c.rng_seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_rng(rand::thread_rng()).unwrap(),
+                },
                 logger,
+                logged_term,
                 priority: c.priority,
                 uncommitted_state: UncommittedState {
                     max_uncommitted_size: c.max_uncommitted_size as usize,
                     uncommitted_size: 0,
                     last_log_tail_index: 0,
                 },
+                interceptors: vec![],
+                clock: None,
+                lease_duration: Duration::default(),
+                quorum_active_at: None,
+                active_at: Default::default(),
+                lease_step_down: false,
+                snapshot_policy: None,
+                broadcasting: false,
+                bcast_entries: vec![],
             },
         };
+        r.raft_log.max_apply_unpersisted_log_limit = c.max_apply_unpersisted_log_limit;
+        r.raft_log.entry_cache.set_capacity(c.entry_cache_size);
+        r.raft_log.term_cache.set_capacity(c.term_cache_size);
+        r.prs.set_inactive_timeout(c.inactive_peer_tick as u64);
         confchange::restore(&mut r.prs, r.r.raft_log.last_index(), conf_state)?;
         let new_cs = r.post_conf_change();
         if !raft_proto::conf_state_eq(&new_cs, conf_state) {
@@ -423,6 +578,13 @@ impl<T: Storage> Raft<T> {
         self.read_only.pending_read_count()
     }
 
+    /// Returns the number of read-only requests forwarded to the leader and
+    /// waiting for responses.
+    #[inline]
+    pub fn forwarded_read_count(&self) -> usize {
+        self.forwarded_reads.len()
+    }
+
     /// Returns how many read states exist.
     #[inline]
     pub fn ready_read_count(&self) -> usize {
@@ -447,8 +609,37 @@ impl<T: Storage> Raft<T> {
     }
 
     /// Returns whether the current raft is in lease.
+    ///
+    /// If a clock is set, the lease also expires once `lease_duration` has passed
+    /// since the leader last heard from a quorum, even if it hasn't been ticked
+    /// in between.
     pub fn in_lease(&self) -> bool {
-        self.state == StateRole::Leader && self.check_quorum
+        self.state == StateRole::Leader
+            && self.check_quorum
+            && !self.quiesced
+            && self.lease_acks.is_none()
+            && !self.lease_expired()
+    }
+
+    // The lease is extended from the latest time a quorum of voters, including
+    // the leader itself, had been heard from.
+    fn lease_expired(&self) -> bool {
+        let now = match &self.clock {
+            Some(clock) => clock.now(),
+            None => return false,
+        };
+        let self_id = self.id;
+        let acked_at = self.prs.conf().voters.quorum_active_since(|id| {
+            if id == self_id {
+                Some(now)
+            } else {
+                self.active_at.get(&id).cloned()
+            }
+        });
+        match cmp::max(self.quorum_active_at, acked_at) {
+            Some(at) => now.saturating_duration_since(at) >= self.lease_duration,
+            None => true,
+        }
     }
 
     /// For testing leader lease
@@ -490,6 +681,13 @@ impl<T: Storage> Raft<T> {
         self.batch_append = batch_append;
     }
 
+    /// Sets the max number of committed entries that can be applied before they
+    /// are persisted locally at runtime.
+    #[inline]
+    pub fn set_max_apply_unpersisted_log_limit(&mut self, limit: u64) {
+        self.raft_log.max_apply_unpersisted_log_limit = limit;
+    }
+
     /// Configures group commit.
     ///
     /// If group commit is enabled, only logs replicated to at least two
@@ -530,10 +728,25 @@ impl<T: Storage> Raft<T> {
     }
 
     /// Removes all commit group configurations.
+    ///
+    /// Same as `clear_commit_group_ids`.
     pub fn clear_commit_group(&mut self) {
+        self.clear_commit_group_ids();
+    }
+
+    /// Removes all commit group configurations and recomputes the commit index
+    /// if this node is the leader.
+    ///
+    /// The commit index never goes backwards. If group commit is still enabled,
+    /// peers without a group are treated conservatively until groups are
+    /// assigned again or group commit is disabled.
+    pub fn clear_commit_group_ids(&mut self) {
         for (_, pr) in self.mut_prs().iter_mut() {
             pr.commit_group_id = 0;
         }
+        if StateRole::Leader == self.state && self.maybe_commit() {
+            self.bcast_append();
+        }
     }
 
     /// Checks whether the raft group is using group commit and consistent
@@ -567,18 +780,33 @@ impl<T: Storage> Raft<T> {
     pub fn commit_to_current_term(&self) -> bool {
         self.raft_log
             .term(self.raft_log.committed)
-            .map_or(false, |t| t == self.term)
+            .is_ok_and(|t| t == self.term)
     }
 
     /// Checks if logs are applied to current term.
     pub fn apply_to_current_term(&self) -> bool {
         self.raft_log
             .term(self.raft_log.applied)
-            .map_or(false, |t| t == self.term)
+            .is_ok_and(|t| t == self.term)
     }
 }
 
 impl<T: Storage> RaftCore<T> {
+    // Queues every entry of the proposal as dropped for the given reason.
+    fn drop_proposal(&mut self, m: &Message, reason: ProposalDropReason) {
+        for e in m.get_entries() {
+            self.record_dropped(e.get_context().to_vec(), reason);
+        }
+    }
+
+    // Queues a dropped proposal if they're tracked.
+    pub(crate) fn record_dropped(&mut self, context: Vec<u8>, reason: ProposalDropReason) {
+        if self.track_dropped_proposals {
+            self.dropped_proposals
+                .push(DroppedProposal { context, reason });
+        }
+    }
+
     // send persists state to stable storage and then sends to its mailbox.
     fn send(&mut self, mut m: Message, msgs: &mut Vec<Message>) {
         debug!(
@@ -639,6 +867,12 @@ impl<T: Storage> RaftCore<T> {
         {
             m.priority = self.priority;
         }
+        for interceptor in &mut self.interceptors {
+            m = match interceptor.on_send(m) {
+                Some(m) => m,
+                None => return,
+            };
+        }
         msgs.push(m);
     }
 
@@ -651,6 +885,15 @@ impl<T: Storage> RaftCore<T> {
             );
             return false;
         }
+        if self.inflight_snapshots >= self.max_inflight_snapshots {
+            debug!(
+                self.logger,
+                "ignore sending snapshot to {} since {} snapshots are in flight",
+                to,
+                self.inflight_snapshots;
+            );
+            return false;
+        }
 
         m.set_msg_type(MessageType::MsgSnapshot);
         let snapshot_r = self.raft_log.snapshot(pr.pending_request_snapshot);
@@ -666,11 +909,15 @@ impl<T: Storage> RaftCore<T> {
             }
             fatal!(self.logger, "unexpected error: {:?}", e);
         }
-        let snapshot = snapshot_r.unwrap();
+        let mut snapshot = snapshot_r.unwrap();
         if snapshot.get_metadata().index == 0 {
             fatal!(self.logger, "need non-empty snapshot");
         }
         let (sindex, sterm) = (snapshot.get_metadata().index, snapshot.get_metadata().term);
+        if pr.is_witness {
+            // A witness only needs the metadata.
+            snapshot.clear_data();
+        }
         m.set_snapshot(snapshot);
         debug!(
             self.logger,
@@ -683,6 +930,7 @@ impl<T: Storage> RaftCore<T> {
             "progress" => ?pr,
         );
         pr.become_snapshot(sindex);
+        self.inflight_snapshots += 1;
         debug!(
             self.logger,
             "paused sending replication messages to {}",
@@ -774,14 +1022,25 @@ impl<T: Storage> RaftCore<T> {
             if !self.prepare_send_snapshot(&mut m, pr, to) {
                 return false;
             }
+        } else if self.policy_prefers_snapshot(to, pr) {
+            if !self.prepare_send_snapshot(&mut m, pr, to) {
+                return false;
+            }
         } else {
-            let ents = self.raft_log.entries(pr.next_idx, self.max_msg_size);
-            if !allow_empty && ents.as_ref().ok().map_or(true, |e| e.is_empty()) {
+            let ents = self.fetch_append_entries(to, pr);
+            if let Err(Error::Store(StorageError::LogTemporarilyUnavailable)) = ents {
+                // Wait for `on_entries_fetched` to send them, the progress is kept as is.
+                return false;
+            }
+            if !allow_empty && ents.as_ref().ok().is_none_or(|e| e.is_empty()) {
                 return false;
             }
             let term = self.raft_log.term(pr.next_idx - 1);
             match (term, ents) {
                 (Ok(term), Ok(mut ents)) => {
+                    if pr.is_witness {
+                        strip_payloads(&mut ents);
+                    }
                     if self.batch_append && self.try_batching(to, msgs, pr, &mut ents) {
                         return true;
                     }
@@ -799,6 +1058,77 @@ impl<T: Storage> RaftCore<T> {
         true
     }
 
+    // Fetches the entries to send to the peer. During a broadcast, they're reused for
+    // the next peers at the same index. Cloning them doesn't copy the payloads.
+    fn fetch_append_entries(&mut self, to: u64, pr: &Progress) -> Result<Vec<Entry>> {
+        if let Some(ents) = self
+            .bcast_entries
+            .iter()
+            .find(|e| e[0].index == pr.next_idx)
+        {
+            return Ok(ents.to_vec());
+        }
+        let ents = self.raft_log.shared_entries(
+            pr.next_idx,
+            self.max_msg_size,
+            GetEntriesContext(GetEntriesFor::SendAppend {
+                to,
+                term: self.term,
+                read_ahead: match pr.state {
+                    ProgressState::Replicate => Some(self.raft_log.last_index() + 1),
+                    _ => None,
+                },
+            }),
+        )?;
+        let res = ents.to_vec();
+        if self.broadcasting && !ents.is_empty() {
+            self.bcast_entries.push(ents);
+        }
+        Ok(res)
+    }
+
+    fn policy_prefers_snapshot(&mut self, to: u64, pr: &Progress) -> bool {
+        if pr.state != ProgressState::Probe {
+            return false;
+        }
+        let (first_index, last_index) = (self.raft_log.first_index(), self.raft_log.last_index());
+        if pr.next_idx < first_index || pr.next_idx > last_index {
+            // Either a snapshot is needed anyway or there is nothing to backfill.
+            return false;
+        }
+        match self.snapshot_policy.as_mut() {
+            Some(policy) => policy.should_send_snapshot(to, pr, first_index, last_index),
+            None => false,
+        }
+    }
+
+    // Records the time the leader heard from the peer to extend its lease.
+    fn record_active(&mut self, id: u64) {
+        if let Some(clock) = &self.clock {
+            self.active_at.insert(id, clock.now());
+        }
+    }
+
+    // Sends an empty MsgAppend carrying the commit index to a peer that is paused
+    // in replicate state. It follows the entries in flight, so the peer learns the
+    // commit index without waiting for the inflight window to drain or the next
+    // heartbeat. It's not tracked in inflights.
+    fn send_commit(&mut self, to: u64, pr: &Progress, msgs: &mut Vec<Message>) {
+        let index = pr.next_idx - 1;
+        let log_term = match self.raft_log.term(index) {
+            Ok(t) => t,
+            // The entries in flight will bring the commit index anyway.
+            Err(_) => return,
+        };
+        let mut m = Message::default();
+        m.to = to;
+        m.set_msg_type(MessageType::MsgAppend);
+        m.index = index;
+        m.log_term = log_term;
+        m.commit = self.raft_log.committed;
+        self.send(m, msgs);
+    }
+
     // send_heartbeat sends an empty MsgAppend
     fn send_heartbeat(
         &mut self,
@@ -821,6 +1151,7 @@ impl<T: Storage> RaftCore<T> {
         if let Some(context) = ctx {
             m.context = context;
         }
+        m.quiesce = self.quiesced;
         self.send(m, msgs);
     }
 }
@@ -833,16 +1164,64 @@ impl<T: Storage> Raft<T> {
         self.r.send_append(to, pr, &mut self.msgs)
     }
 
+    /// Retries sending the entries that the storage has finished fetching
+    /// asynchronously, see `GetEntriesContext`. Contexts raft never waits for are
+    /// ignored.
+    pub fn on_entries_fetched(&mut self, context: GetEntriesContext) {
+        match context.0 {
+            GetEntriesFor::SendAppend { to, term, .. } => {
+                if self.term != term || self.state != StateRole::Leader {
+                    // The entries are only needed by the leader of that term.
+                    return;
+                }
+                if self.prs.get(to).is_none() {
+                    return;
+                }
+                self.send_append(to);
+                let pr = self.prs.get_mut(to).unwrap();
+                while self.r.maybe_send_append(to, pr, false, &mut self.msgs) {}
+            }
+            GetEntriesFor::Empty(can_async) if can_async => {}
+            _ => warn!(
+                self.logger,
+                "ignored fetched entries of unexpected context";
+                "context" => ?context,
+            ),
+        }
+    }
+
     /// Sends RPC, with entries to all peers that are not up-to-date
     /// according to the progress recorded in r.prs().
     pub fn bcast_append(&mut self) {
         let self_id = self.id;
         let core = &mut self.r;
         let msgs = &mut self.msgs;
+        core.broadcasting = true;
         self.prs
             .iter_mut()
             .filter(|&(id, _)| *id != self_id)
             .for_each(|(id, pr)| core.send_append(*id, pr, msgs));
+        core.broadcasting = false;
+        core.bcast_entries.clear();
+    }
+
+    /// Broadcasts the commit index after it advances. Peers that are paused in
+    /// replicate state get an empty append carrying only the commit index instead
+    /// of nothing, so they don't need to wait for the next heartbeat.
+    fn bcast_commit(&mut self) {
+        let self_id = self.id;
+        let core = &mut self.r;
+        let msgs = &mut self.msgs;
+        self.prs
+            .iter_mut()
+            .filter(|&(id, _)| *id != self_id)
+            .for_each(|(id, pr)| {
+                if pr.state == ProgressState::Replicate && pr.is_paused() {
+                    core.send_commit(*id, pr, msgs)
+                } else {
+                    core.send_append(*id, pr, msgs)
+                }
+            });
     }
 
     /// Broadcasts heartbeats to all the followers if it's leader.
@@ -858,7 +1237,7 @@ impl<T: Storage> Raft<T> {
         self.bcast_heartbeat_with_ctx(ctx)
     }
 
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::needless_pass_by_value))]
+    #[allow(clippy::needless_pass_by_value)]
     fn bcast_heartbeat_with_ctx(&mut self, ctx: Option<Vec<u8>>) {
         let self_id = self.id;
         let core = &mut self.r;
@@ -871,9 +1250,31 @@ impl<T: Storage> Raft<T> {
 
     /// Attempts to advance the commit index. Returns true if the commit index
     /// changed (in which case the caller should call `r.bcast_append`).
+    ///
+    /// This is the only place a leader advances its commit index. An index
+    /// acknowledged by a quorum is only committed if its entry is from the
+    /// current term, see `commit_to_current_term`. When some voters are
+    /// witnesses, it's also only committed once a voter that is not a witness,
+    /// possibly the leader itself, holds the data of the entry. This only holds back
+    /// a commit acknowledged by a quorum of witnesses, see `max_non_witness_matched`.
     pub fn maybe_commit(&mut self) -> bool {
-        let mci = self.mut_prs().maximal_committed_index().0;
+        let mut mci = self.mut_prs().maximal_committed_index().0;
+        if let Some(matched) = self.prs.max_non_witness_matched() {
+            if matched < mci {
+                debug!(
+                    self.logger,
+                    "only witnesses acknowledged up to {index}, committing up to {matched}",
+                    index = mci,
+                    matched = matched;
+                );
+                mci = matched;
+            }
+        }
         if self.r.raft_log.maybe_commit(mci, self.r.term) {
+            debug_assert!(
+                self.commit_to_current_term(),
+                "leader committed an entry of a prior term"
+            );
             let (self_id, committed) = (self.id, self.raft_log.committed);
             self.mut_prs()
                 .get_mut(self_id)
@@ -891,12 +1292,19 @@ impl<T: Storage> Raft<T> {
     /// # Hooks
     ///
     /// * Post: Checks to see if it's time to finalize a Joint Consensus state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the applied index is not new or known.
     pub fn commit_apply(&mut self, applied: u64) {
         let old_applied = self.raft_log.applied;
         #[allow(deprecated)]
-        self.raft_log.applied_to(applied);
+        if let Err(e) = self.raft_log.applied_to(applied) {
+            fatal!(self.logger, "{}", e);
+        }
 
-        // TODO: it may never auto_leave if leader steps down before enter joint is applied.
+        // A leader elected while the joint configuration is pending sets
+        // `pending_conf_index` to its last index, so it will also get here.
         if self.prs.conf().auto_leave
             && old_applied <= self.pending_conf_index
             && applied >= self.pending_conf_index
@@ -923,12 +1331,15 @@ impl<T: Storage> Raft<T> {
     pub fn reset(&mut self, term: u64) {
         if self.term != term {
             self.term = term;
+            self.logged_term.store(term, Ordering::Relaxed);
             self.vote = INVALID_ID;
         }
         self.leader_id = INVALID_ID;
         self.reset_randomized_election_timeout();
         self.election_elapsed = 0;
         self.heartbeat_elapsed = 0;
+        self.quiesced = false;
+        self.lease_acks = None;
 
         self.abort_leader_transfer();
 
@@ -936,19 +1347,24 @@ impl<T: Storage> Raft<T> {
 
         self.pending_conf_index = 0;
         self.read_only = ReadOnly::new(self.read_only.option);
+        // Responses can only come from the leader the requests were forwarded
+        // to, so they will never be answered once the leader changes.
+        self.forwarded_reads.clear();
         self.pending_request_snapshot = INVALID_INDEX;
 
         let last_index = self.raft_log.last_index();
         let committed = self.raft_log.committed;
         let persisted = self.raft_log.persisted;
         let self_id = self.id;
-        for (&id, mut pr) in self.mut_prs().iter_mut() {
+        for (&id, pr) in self.mut_prs().iter_mut() {
             pr.reset(last_index + 1);
             if id == self_id {
                 pr.matched = persisted;
                 pr.committed_index = committed;
             }
         }
+        // All the progresses are probing now.
+        self.r.inflight_snapshots = 0;
     }
 
     /// Appends a slice of entries to the log.
@@ -991,13 +1407,14 @@ impl<T: Storage> Raft<T> {
             let self_id = self.id;
             let pr = self.mut_prs().get_mut(self_id).unwrap();
             if pr.maybe_update(index) && self.maybe_commit() && self.should_bcast_commit() {
-                self.bcast_append();
+                self.bcast_commit();
             }
         }
     }
 
     /// Returns true to indicate that there will probably be some readiness need to be handled.
     pub fn tick(&mut self) -> bool {
+        self.ticks += 1;
         match self.state {
             StateRole::Follower | StateRole::PreCandidate | StateRole::Candidate => {
                 self.tick_election()
@@ -1006,29 +1423,83 @@ impl<T: Storage> Raft<T> {
         }
     }
 
+    // Gives up the forwarded reads not responded within an election timeout, as the
+    // leader may never respond, e.g. if it dropped them. The application is expected
+    // to retry the reads it doesn't get a read state for.
+    fn expire_forwarded_reads(&mut self) {
+        if self.forwarded_reads.is_empty() {
+            return;
+        }
+        let ticks = self.ticks;
+        let before = self.forwarded_reads.len();
+        self.r
+            .forwarded_reads
+            .retain(|_, deadline| *deadline >= ticks);
+        let expired = before - self.forwarded_reads.len();
+        if expired > 0 {
+            debug!(
+                self.logger,
+                "gave up {expired} forwarded reads not responded in time",
+                expired = expired;
+            );
+        }
+    }
+
     // TODO: revoke pub when there is a better way to test.
     /// Run by followers and candidates after self.election_timeout.
     ///
     /// Returns true to indicate that there will probably be some readiness need to be handled.
     pub fn tick_election(&mut self) -> bool {
         self.election_elapsed += 1;
+        self.expire_forwarded_reads();
+        if self.quiesced {
+            // The leader sends nothing while the group is quiesced, so only give up
+            // on it after a much longer timeout.
+            let timeout = self.quiesced_election_timeout + self.randomized_election_timeout;
+            if self.election_elapsed < timeout {
+                return false;
+            }
+            info!(
+                self.logger,
+                "woke up after not hearing from the leader for {elapsed} ticks",
+                elapsed = self.election_elapsed;
+            );
+            self.quiesced = false;
+        }
         if !self.pass_election_timeout() || !self.promotable {
             return false;
         }
 
-        self.election_elapsed = 0;
+        // Resets the elapsed ticks after stepping, so the leader stickiness check
+        // sees the election timeout has passed.
         let m = new_message(INVALID_ID, MessageType::MsgHup, Some(self.id));
         let _ = self.step(m);
+        self.election_elapsed = 0;
         true
     }
 
     // tick_heartbeat is run by leaders to send a MsgBeat after self.heartbeat_timeout.
     // Returns true to indicate that there will probably be some readiness need to be handled.
     fn tick_heartbeat(&mut self) -> bool {
+        if self.quiesced {
+            return false;
+        }
+        if self.quiesce && self.is_idle() {
+            info!(
+                self.logger,
+                "quiesced at term {term}, index {index}",
+                term = self.term,
+                index = self.raft_log.last_index();
+            );
+            self.quiesced = true;
+            self.bcast_heartbeat_with_ctx(None);
+            return true;
+        }
+
         self.heartbeat_elapsed += 1;
         self.election_elapsed += 1;
 
-        let mut has_ready = false;
+        let mut has_ready = self.expire_inactive_peers();
         if self.election_elapsed >= self.election_timeout {
             self.election_elapsed = 0;
             if self.check_quorum {
@@ -1045,6 +1516,16 @@ impl<T: Storage> Raft<T> {
             return has_ready;
         }
 
+        if self.lease_step_down && self.check_quorum && self.lease_expired() {
+            warn!(
+                self.logger,
+                "stepped down to follower since lease expired";
+            );
+            let term = self.term;
+            self.become_follower(term, INVALID_ID);
+            return true;
+        }
+
         if self.heartbeat_elapsed >= self.heartbeat_timeout {
             self.heartbeat_elapsed = 0;
             has_ready = true;
@@ -1054,6 +1535,71 @@ impl<T: Storage> Raft<T> {
         has_ready
     }
 
+    // Whether the leader has nothing left to replicate, so it can quiesce.
+    fn is_idle(&self) -> bool {
+        let last_index = self.raft_log.last_index();
+        self.raft_log.committed == last_index
+            && self.lead_transferee.is_none()
+            && self.read_only.pending_read_count() == 0
+            && self
+                .prs
+                .iter()
+                .all(|(_, pr)| pr.matched == last_index && pr.state == ProgressState::Replicate)
+    }
+
+    /// Whether this node is quiesced, see `Config::quiesce`.
+    pub fn quiesced(&self) -> bool {
+        self.quiesced
+    }
+
+    /// Wakes up a quiesced node, it ticks as usual afterwards. A follower gives the
+    /// leader a full election timeout to show up before campaigning.
+    pub fn unquiesce(&mut self) {
+        if !self.quiesced {
+            return;
+        }
+        self.quiesced = false;
+        self.election_elapsed = 0;
+        if self.state == StateRole::Leader {
+            self.heartbeat_elapsed = 0;
+            let mut acks = HashSet::default();
+            acks.insert(self.id);
+            self.lease_acks = Some(acks);
+            self.reset_inactive_deadlines();
+        }
+    }
+
+    // Gives all the peers a fresh inactivity deadline, as the leader may not have
+    // heard from them for a while for a good reason.
+    fn reset_inactive_deadlines(&mut self) {
+        if self.track_inactive_peers {
+            let (ticks, id) = (self.ticks, self.id);
+            self.prs.reset_deadlines(ticks, id);
+        }
+    }
+
+    // Pushes back the inactivity deadline of a peer the leader heard from.
+    fn mark_active(&mut self, id: u64) {
+        if self.track_inactive_peers {
+            self.prs.mark_active(id);
+        }
+    }
+
+    // Queues the peers whose inactivity deadline passed at this tick. Returns true
+    // if there are any.
+    fn expire_inactive_peers(&mut self) -> bool {
+        if !self.track_inactive_peers {
+            return false;
+        }
+        let expired = self.prs.expire(self.r.ticks);
+        if expired.is_empty() {
+            return false;
+        }
+        warn!(self.logger, "peers became inactive"; "peers" => ?expired);
+        self.r.inactive_peers.extend(expired);
+        true
+    }
+
     /// Converts this node to a follower.
     pub fn become_follower(&mut self, term: u64, leader_id: u64) {
         let pending_request_snapshot = self.pending_request_snapshot;
@@ -1135,6 +1681,9 @@ impl<T: Storage> Raft<T> {
         self.reset(term);
         self.leader_id = self.id;
         self.state = StateRole::Leader;
+        self.quorum_active_at = self.clock.as_ref().map(|c| c.now());
+        self.active_at.clear();
+        self.reset_inactive_deadlines();
 
         let last_index = self.raft_log.last_index();
         // If there is only one peer, it becomes leader after campaigning
@@ -1188,9 +1737,15 @@ impl<T: Storage> Raft<T> {
 
     /// Campaign to attempt to become a leader.
     ///
-    /// If prevote is enabled, this is handled as well.
-    pub fn campaign(&mut self, campaign_type: &[u8]) {
-        let (vote_msg, term) = if campaign_type == CAMPAIGN_PRE_ELECTION {
+    /// Unlike stepping `MsgHup`, it doesn't check whether the node is promotable or
+    /// has pending conf changes to apply, so embedders can force a specific kind of
+    /// election deterministically.
+    pub fn campaign(&mut self, campaign_type: CampaignType) {
+        if self.is_witness() {
+            warn!(self.logger, "refused to campaign since it's a witness");
+            return;
+        }
+        let (vote_msg, term) = if campaign_type == CampaignType::PreElection {
             self.become_pre_candidate();
             // Pre-vote RPCs are sent for next term before we've incremented self.term.
             (MessageType::MsgRequestPreVote, self.term + 1)
@@ -1227,8 +1782,8 @@ impl<T: Storage> Raft<T> {
             m.log_term = self.raft_log.last_term();
             m.commit = commit;
             m.commit_term = commit_term;
-            if campaign_type == CAMPAIGN_TRANSFER {
-                m.context = campaign_type.to_vec();
+            if campaign_type == CampaignType::Transfer {
+                m.context = CAMPAIGN_TRANSFER.to_vec();
             }
             self.r.send(m, &mut self.msgs);
         }
@@ -1252,7 +1807,24 @@ impl<T: Storage> Raft<T> {
 
     /// Steps the raft along via a message. This should be called everytime your raft receives a
     /// message from a peer.
-    pub fn step(&mut self, m: Message) -> Result<()> {
+    pub fn step(&mut self, mut m: Message) -> Result<()> {
+        for interceptor in &mut self.r.interceptors {
+            m = match interceptor.on_step(m) {
+                Some(m) => m,
+                None => return Ok(()),
+            };
+        }
+
+        // A leader ignores the heartbeat responses to its last heartbeats before
+        // quiescing, anything else wakes it up.
+        if self.quiesced
+            && !m.quiesce
+            && !(self.state == StateRole::Leader
+                && m.get_msg_type() == MessageType::MsgHeartbeatResponse)
+        {
+            self.unquiesce();
+        }
+
         // Handle the message term, which may result in our stepping down to a follower.
         if m.term == 0 {
             // local message
@@ -1452,6 +2024,27 @@ impl<T: Storage> Raft<T> {
             );
             return;
         }
+        if self.is_witness() {
+            debug!(
+                self.logger,
+                "ignoring MsgHup because it's a witness";
+            );
+            return;
+        }
+        if !transfer_leader
+            && self.leader_stickiness
+            && self.state == StateRole::Follower
+            && self.leader_id != INVALID_ID
+            && self.election_elapsed < self.min_election_timeout
+        {
+            info!(
+                self.logger,
+                "ignoring MsgHup because leader {leader_id} was heard from {elapsed} ticks ago",
+                leader_id = self.leader_id,
+                elapsed = self.election_elapsed;
+            );
+            return;
+        }
 
         // If there is a pending snapshot, its index will be returned by
         // `maybe_first_index`. Note that snapshot updates configuration
@@ -1464,7 +2057,12 @@ impl<T: Storage> Raft<T> {
 
         let ents = self
             .raft_log
-            .slice(first_index, self.raft_log.committed + 1, None)
+            .slice(
+                first_index,
+                self.raft_log.committed + 1,
+                None,
+                GetEntriesContext(GetEntriesFor::Campaign),
+            )
             .unwrap_or_else(|e| {
                 fatal!(
                     self.logger,
@@ -1491,11 +2089,11 @@ impl<T: Storage> Raft<T> {
             "term" => self.term,
         );
         if transfer_leader {
-            self.campaign(CAMPAIGN_TRANSFER);
+            self.campaign(CampaignType::Transfer);
         } else if self.pre_vote {
-            self.campaign(CAMPAIGN_PRE_ELECTION);
+            self.campaign(CampaignType::PreElection);
         } else {
-            self.campaign(CAMPAIGN_ELECTION);
+            self.campaign(CampaignType::Election);
         }
     }
 
@@ -1532,6 +2130,7 @@ impl<T: Storage> Raft<T> {
     }
 
     fn handle_append_response(&mut self, m: &Message) {
+        self.mark_active(m.from);
         let pr = match self.prs.get_mut(m.from) {
             Some(pr) => pr,
             None => {
@@ -1544,6 +2143,7 @@ impl<T: Storage> Raft<T> {
             }
         };
         pr.recent_active = true;
+        self.r.record_active(m.from);
 
         // update followers committed index via append response
         pr.update_committed(m.commit);
@@ -1558,15 +2158,21 @@ impl<T: Storage> Raft<T> {
                 "last term" => m.log_term,
             );
 
-            if pr.maybe_decr_to(m.index, m.reject_hint, m.request_snapshot) {
-                if m.log_term > 0 {
-                    let conflict_index =
-                        self.raft_log.find_conflict_by_term(pr.next_idx, m.log_term);
-                    if conflict_index > 0 {
-                        pr.next_idx = conflict_index + 1
-                    }
-                }
+            // The follower's log doesn't match at m.index. It hints the largest
+            // index in its log with a term not greater than the one the leader has
+            // at m.index. Skip over the entries the leader has after that index
+            // with a greater term, they can't match either, so the next probe
+            // is likely to succeed.
+            let mut next_probe_index = m.reject_hint;
+            if m.log_term > 0 {
+                next_probe_index = self
+                    .r
+                    .raft_log
+                    .find_conflict_by_term(m.reject_hint, m.log_term)
+                    .0;
+            }
 
+            if pr.maybe_decr_to(m.index, next_probe_index, m.request_snapshot) {
                 debug!(
                     self.r.logger,
                     "decreased progress of {}",
@@ -1597,6 +2203,7 @@ impl<T: Storage> Raft<T> {
                         "progress" => ?pr,
                     );
                     pr.become_probe();
+                    self.r.inflight_snapshots = self.r.inflight_snapshots.saturating_sub(1);
                 }
             }
             ProgressState::Replicate => pr.ins.free_to(m.get_index()),
@@ -1604,7 +2211,7 @@ impl<T: Storage> Raft<T> {
 
         if self.maybe_commit() {
             if self.should_bcast_commit() {
-                self.bcast_append()
+                self.bcast_commit()
             }
         } else if old_paused {
             self.send_append(m.from)
@@ -1635,6 +2242,7 @@ impl<T: Storage> Raft<T> {
     }
 
     fn handle_heartbeat_response(&mut self, m: &Message) {
+        self.mark_active(m.from);
         // Update the node. Drop the value explicitly since we'll check the qourum after.
         let pr = match self.prs.get_mut(m.from) {
             Some(pr) => pr,
@@ -1650,6 +2258,7 @@ impl<T: Storage> Raft<T> {
         // update followers committed index via heartbeat response
         pr.update_committed(m.commit);
         pr.recent_active = true;
+        self.r.record_active(m.from);
         pr.resume();
 
         // free one slot for the full inflights window to allow progress.
@@ -1662,7 +2271,17 @@ impl<T: Storage> Raft<T> {
             self.r.send_append(m.from, pr, &mut self.msgs);
         }
 
-        if self.read_only.option != ReadOnlyOption::Safe || m.context.is_empty() {
+        // Answers to the heartbeats sent before quiescing don't renew the lease.
+        if !m.quiesce {
+            if let Some(acks) = &mut self.r.lease_acks {
+                acks.insert(m.from);
+                if self.prs.has_quorum(acks) {
+                    self.r.lease_acks = None;
+                }
+            }
+        }
+
+        if m.context.is_empty() {
             return;
         }
 
@@ -1689,7 +2308,7 @@ impl<T: Storage> Raft<T> {
         }
 
         let from = m.from;
-        if self.prs.conf().learners.contains(&from) {
+        if self.prs.conf().learners.contains(&from) || self.prs.conf().witnesses.contains(&from) {
             debug!(
                 self.logger,
                 "ignored transferring leadership";
@@ -1763,6 +2382,7 @@ impl<T: Storage> Raft<T> {
         if pr.state != ProgressState::Snapshot {
             return;
         }
+        self.r.inflight_snapshots = self.r.inflight_snapshots.saturating_sub(1);
         if m.reject {
             pr.snapshot_failure();
             pr.become_probe();
@@ -1839,6 +2459,7 @@ impl<T: Storage> Raft<T> {
                     // If we are not currently a member of the range (i.e. this node
                     // was removed from the configuration while serving as leader),
                     // drop any new proposals.
+                    self.drop_proposal(&m, ProposalDropReason::NotMember);
                     return Err(Error::ProposalDropped);
                 }
                 if self.lead_transferee.is_some() {
@@ -1849,7 +2470,8 @@ impl<T: Storage> Raft<T> {
                         term = self.term,
                         lead_transferee = self.lead_transferee.unwrap();
                     );
-                    return Err(Error::ProposalDropped);
+                    self.drop_proposal(&m, ProposalDropReason::LeaderTransferring);
+                    return Err(Error::LeaderTransferring);
                 }
 
                 for (i, e) in m.mut_entries().iter_mut().enumerate() {
@@ -1858,6 +2480,8 @@ impl<T: Storage> Raft<T> {
                         let mut cc_v1 = ConfChange::default();
                         if let Err(e) = cc_v1.merge_from_bytes(e.get_data()) {
                             error!(self.logger, "invalid confchange"; "error" => ?e);
+                            self.r
+                                .drop_proposal(&m, ProposalDropReason::InvalidConfChange);
                             return Err(Error::ProposalDropped);
                         }
                         cc = cc_v1.into_v2();
@@ -1865,6 +2489,8 @@ impl<T: Storage> Raft<T> {
                         cc = ConfChangeV2::default();
                         if let Err(e) = cc.merge_from_bytes(e.get_data()) {
                             error!(self.logger, "invalid confchangev2"; "error" => ?e);
+                            self.r
+                                .drop_proposal(&m, ProposalDropReason::InvalidConfChange);
                             return Err(Error::ProposalDropped);
                         }
                     } else {
@@ -1897,18 +2523,22 @@ impl<T: Storage> Raft<T> {
                             "index" => self.pending_conf_index,
                             "applied" => self.raft_log.applied,
                         );
+                        let context = e.get_context().to_vec();
+                        self.r
+                            .record_dropped(context, ProposalDropReason::ConfChangeRefused);
                         *e = Entry::default();
                         e.set_entry_type(EntryType::EntryNormal);
                     }
                 }
-                if !self.append_entry(&mut m.mut_entries()) {
-                    // return ProposalDropped when uncommitted size limit is reached
+                if !self.append_entry(m.mut_entries()) {
+                    // return QuotaExceeded when uncommitted size limit is reached
                     debug!(
                         self.logger,
                         "entries are dropped due to overlimit of max uncommitted size, uncommitted_size: {}",
                         self.uncommitted_size()
                     );
-                    return Err(Error::ProposalDropped);
+                    self.drop_proposal(&m, ProposalDropReason::QuotaExceeded);
+                    return Err(Error::QuotaExceeded);
                 }
                 self.bcast_append();
                 return Ok(());
@@ -1933,19 +2563,22 @@ impl<T: Storage> Raft<T> {
                 // a user-supplied value.
                 // This would allow multiple reads to piggyback on the same message.
                 match self.read_only.option {
-                    ReadOnlyOption::Safe => {
+                    ReadOnlyOption::LeaseBased if self.in_lease() => {
+                        let read_index = self.raft_log.committed;
+                        if let Some(m) = self.handle_ready_read_index(m, read_index) {
+                            self.r.send(m, &mut self.msgs);
+                        }
+                    }
+                    // Without check quorum, or once the lease has expired by the clock,
+                    // the leader holds no lease, so a lease based read degrades to a
+                    // safe one.
+                    _ => {
                         let ctx = m.entries[0].data.to_vec();
                         self.r
                             .read_only
                             .add_request(self.r.raft_log.committed, m, self.r.id);
                         self.bcast_heartbeat_with_ctx(Some(ctx));
                     }
-                    ReadOnlyOption::LeaseBased => {
-                        let read_index = self.raft_log.committed;
-                        if let Some(m) = self.handle_ready_read_index(m, read_index) {
-                            self.r.send(m, &mut self.msgs);
-                        }
-                    }
                 }
                 return Ok(());
             }
@@ -2005,7 +2638,12 @@ impl<T: Storage> Raft<T> {
 
         let ents = self
             .raft_log
-            .slice(last_commit + 1, self.raft_log.committed + 1, None)
+            .slice(
+                last_commit + 1,
+                self.raft_log.committed + 1,
+                None,
+                GetEntriesContext(GetEntriesFor::CommitByVote),
+            )
             .unwrap_or_else(|e| {
                 fatal!(
                     self.logger,
@@ -2043,7 +2681,7 @@ impl<T: Storage> Raft<T> {
         match res {
             VoteResult::Won => {
                 if self.state == StateRole::PreCandidate {
-                    self.campaign(CAMPAIGN_ELECTION);
+                    self.campaign(CampaignType::Election);
                 } else {
                     self.become_leader();
                     self.bcast_append();
@@ -2070,17 +2708,26 @@ impl<T: Storage> Raft<T> {
                     "no leader at term {term}; dropping proposal",
                     term = self.term;
                 );
-                return Err(Error::ProposalDropped);
+                self.drop_proposal(&m, ProposalDropReason::NoLeader);
+                return Err(Error::NoLeader);
+            }
+            MessageType::MsgReadIndex => {
+                info!(
+                    self.logger,
+                    "no leader at term {term}; dropping index reading msg",
+                    term = self.term;
+                );
+                return Err(Error::NoLeader);
             }
             MessageType::MsgAppend => {
                 debug_assert_eq!(self.term, m.term);
                 self.become_follower(m.term, m.from);
-                self.handle_append_entries(&m);
+                self.handle_append_entries(&m)?;
             }
             MessageType::MsgHeartbeat => {
                 debug_assert_eq!(self.term, m.term);
                 self.become_follower(m.term, m.from);
-                self.handle_heartbeat(m);
+                self.handle_heartbeat(m)?;
             }
             MessageType::MsgSnapshot => {
                 debug_assert_eq!(self.term, m.term);
@@ -2123,6 +2770,17 @@ impl<T: Storage> Raft<T> {
                         "no leader at term {term}; dropping proposal",
                         term = self.term;
                     );
+                    self.drop_proposal(&m, ProposalDropReason::NoLeader);
+                    return Err(Error::NoLeader);
+                }
+                if self.disable_proposal_forwarding {
+                    info!(
+                        self.logger,
+                        "not forwarding to leader {leader_id} at term {term}; dropping proposal",
+                        leader_id = self.leader_id,
+                        term = self.term;
+                    );
+                    self.drop_proposal(&m, ProposalDropReason::ForwardingDisabled);
                     return Err(Error::ProposalDropped);
                 }
                 m.to = self.leader_id;
@@ -2131,12 +2789,16 @@ impl<T: Storage> Raft<T> {
             MessageType::MsgAppend => {
                 self.election_elapsed = 0;
                 self.leader_id = m.from;
-                self.handle_append_entries(&m);
+                self.handle_append_entries(&m)?;
             }
             MessageType::MsgHeartbeat => {
                 self.election_elapsed = 0;
                 self.leader_id = m.from;
-                self.handle_heartbeat(m);
+                let quiesce = self.quiesce && m.quiesce;
+                self.handle_heartbeat(m)?;
+                if quiesce && self.raft_log.committed == self.raft_log.last_index() {
+                    self.quiesced = true;
+                }
             }
             MessageType::MsgSnapshot => {
                 self.election_elapsed = 0;
@@ -2183,7 +2845,16 @@ impl<T: Storage> Raft<T> {
                         "no leader at term {term}; dropping index reading msg",
                         term = self.term;
                     );
-                    return Ok(());
+                    return Err(Error::NoLeader);
+                }
+                // Like the leader, a context that is already pending is only
+                // responded once.
+                if let Some(e) = m.entries.first() {
+                    let deadline = self.ticks + self.election_timeout as u64;
+                    self.r
+                        .forwarded_reads
+                        .entry(e.get_data().to_vec())
+                        .or_insert(deadline);
                 }
                 m.to = self.leader_id;
                 self.r.send(m, &mut self.msgs);
@@ -2198,9 +2869,20 @@ impl<T: Storage> Raft<T> {
                     );
                     return Ok(());
                 }
+                let ctx: Vec<u8> = m.take_entries()[0].take_data().into();
+                if self.forwarded_reads.remove(&ctx).is_none() {
+                    info!(
+                        self.logger,
+                        "ignored MsgReadIndexResp from {from} at term {term}: no pending read \
+                         forwarded with the context",
+                        from = m.from,
+                        term = self.term;
+                    );
+                    return Ok(());
+                }
                 let rs = ReadState {
                     index: m.index,
-                    request_ctx: m.take_entries()[0].take_data(),
+                    request_ctx: ctx,
                 };
                 self.read_states.push(rs);
                 // `index` and `term` in MsgReadIndexResp is the leader's commit index and its current term,
@@ -2213,43 +2895,66 @@ impl<T: Storage> Raft<T> {
         Ok(())
     }
 
+    /// Reports that sending messages to the given peer failed. If the peer is being
+    /// replicated optimistically, it's moved back to probe state since it's very
+    /// likely that a MsgAppend was lost. A quiesced node wakes up, so a follower
+    /// that can't reach the leader campaigns after an election timeout.
+    pub fn report_unreachable(&mut self, id: u64) {
+        let mut m = Message::default();
+        m.set_msg_type(MessageType::MsgUnreachable);
+        m.from = id;
+        // we don't care if it is ok actually
+        let _ = self.step(m);
+    }
+
+    /// Reports the status of a snapshot sent to the given peer out of band. Either
+    /// way the peer leaves snapshot state and is probed again: after a success it
+    /// waits for the response of the next append, and after a failure it waits for
+    /// a heartbeat interval before retrying.
+    pub fn report_snapshot(&mut self, id: u64, status: SnapshotStatus) {
+        let mut m = Message::default();
+        m.set_msg_type(MessageType::MsgSnapStatus);
+        m.from = id;
+        m.reject = status == SnapshotStatus::Failure;
+        // we don't care if it is ok actually
+        let _ = self.step(m);
+    }
+
     /// Request a snapshot from a leader.
     pub fn request_snapshot(&mut self, request_index: u64) -> Result<()> {
-        if self.state == StateRole::Leader {
-            info!(
-                self.logger,
-                "can not request snapshot on leader; dropping request snapshot";
-            );
+        let reason = if self.state == StateRole::Leader {
+            "peer is leader"
         } else if self.leader_id == INVALID_ID {
-            info!(
-                self.logger,
-                "drop request snapshot because of no leader";
-                "term" => self.term,
-            );
+            "no leader"
         } else if self.snap().is_some() {
-            info!(
-                self.logger,
-                "there is a pending snapshot; dropping request snapshot";
-            );
+            "a snapshot is pending"
         } else if self.pending_request_snapshot != INVALID_INDEX {
-            info!(
-                self.logger,
-                "there is a pending snapshot; dropping request snapshot";
-            );
+            "a snapshot is already requested"
         } else {
             self.pending_request_snapshot = request_index;
             self.send_request_snapshot();
             return Ok(());
-        }
-        Err(Error::RequestSnapshotDropped)
+        };
+        info!(
+            self.logger,
+            "dropping request snapshot";
+            "reason" => reason,
+            "term" => self.term,
+            "request_index" => request_index,
+            "pending_request_snapshot" => self.pending_request_snapshot,
+        );
+        Err(Error::RequestSnapshotDropped(reason))
     }
 
     // TODO: revoke pub when there is a better way to test.
     /// For a given message, append the entries to the log.
-    pub fn handle_append_entries(&mut self, m: &Message) {
+    ///
+    /// Returns `Error::CommittedConflict` without responding if the entries conflict
+    /// with a committed entry.
+    pub fn handle_append_entries(&mut self, m: &Message) -> Result<()> {
         if self.pending_request_snapshot != INVALID_INDEX {
             self.send_request_snapshot();
-            return;
+            return Ok(());
         }
         if m.index < self.raft_log.committed {
             debug!(
@@ -2262,7 +2967,7 @@ impl<T: Storage> Raft<T> {
             to_send.index = self.raft_log.committed;
             to_send.commit = self.raft_log.committed;
             self.r.send(to_send, &mut self.msgs);
-            return;
+            return Ok(());
         }
 
         let mut to_send = Message::default();
@@ -2271,7 +2976,7 @@ impl<T: Storage> Raft<T> {
 
         if let Some((_, last_idx)) = self
             .raft_log
-            .maybe_append(m.index, m.log_term, m.commit, &m.entries)
+            .maybe_append(m.index, m.log_term, m.commit, &m.entries)?
         {
             to_send.set_index(last_idx);
         } else {
@@ -2285,25 +2990,45 @@ impl<T: Storage> Raft<T> {
                 "index" => m.index,
                 "logterm" => ?self.raft_log.term(m.index),
             );
+            // Hint the leader where to probe next: the largest index not beyond
+            // m.index whose term isn't greater than the leader's term at m.index,
+            // entries after it can't match. This lets the leader skip a whole
+            // divergent term per round trip instead of a single entry.
+            let hint_index = cmp::min(m.index, self.raft_log.last_index());
+            let (hint_index, hint_term) =
+                self.raft_log.find_conflict_by_term(hint_index, m.log_term);
             to_send.index = m.index;
             to_send.reject = true;
-            to_send.reject_hint = self.raft_log.last_index();
-            // to_send.reject_hint = cmp::min(self.raft_log.last_index(), m.index);
-            // to_send.log_term = self.raft_log.term(to_send.reject_hint).unwrap();
-            // self.send(to_send);
+            to_send.reject_hint = hint_index;
+            to_send.log_term = hint_term.unwrap_or(0);
         }
 
         to_send.set_commit(self.raft_log.committed);
         self.r.send(to_send, &mut self.msgs);
+        Ok(())
+    }
+
+    /// Reports where and why the log of this node diverges from the given
+    /// `MsgAppend`, before stepping it truncates anything. Returns `None` if it
+    /// doesn't diverge or isn't an append this node would handle.
+    pub fn find_divergence(&self, m: &Message) -> Option<LogDivergence> {
+        if m.get_msg_type() != MessageType::MsgAppend || m.index < self.raft_log.committed {
+            return None;
+        }
+        self.raft_log
+            .find_divergence(m.index, m.log_term, m.get_entries())
     }
 
     // TODO: revoke pub when there is a better way to test.
     /// For a message, commit and send out heartbeat.
-    pub fn handle_heartbeat(&mut self, mut m: Message) {
-        self.raft_log.commit_to(m.commit);
+    ///
+    /// Returns `Error::IndexOutOfRange` without responding if the commit index is
+    /// beyond the last index.
+    pub fn handle_heartbeat(&mut self, mut m: Message) -> Result<()> {
+        self.raft_log.commit_to(m.commit)?;
         if self.pending_request_snapshot != INVALID_INDEX {
             self.send_request_snapshot();
-            return;
+            return Ok(());
         }
         let mut to_send = Message::default();
         to_send.set_msg_type(MessageType::MsgHeartbeatResponse);
@@ -2311,6 +3036,7 @@ impl<T: Storage> Raft<T> {
         to_send.context = m.take_context();
         to_send.commit = self.raft_log.committed;
         self.r.send(to_send, &mut self.msgs);
+        Ok(())
     }
 
     fn handle_snapshot(&mut self, mut m: Message) {
@@ -2398,7 +3124,8 @@ impl<T: Storage> Raft<T> {
                 "snapshot_index" => snap_index,
                 "snapshot_term" => snap_term
             );
-            self.raft_log.commit_to(meta.index);
+            // The index matches the term, so it's in the log.
+            self.raft_log.commit_to(meta.index).unwrap();
             return false;
         }
 
@@ -2412,6 +3139,7 @@ impl<T: Storage> Raft<T> {
             .get_conf_state();
 
         self.prs.clear();
+        self.r.inflight_snapshots = 0;
         let last_index = self.raft_log.last_index();
         if let Err(e) = confchange::restore(&mut self.prs, last_index, cs) {
             // This should never happen. Either there's a bug in our config change
@@ -2457,17 +3185,33 @@ impl<T: Storage> Raft<T> {
         // progress tracker is better.
         let cs = self.prs.conf().to_conf_state();
         let is_voter = self.prs.conf().voters.contains(self.id);
-        self.promotable = is_voter;
+        self.promotable = is_voter && !self.is_witness();
         if !is_voter && self.state == StateRole::Leader {
-            // This node is leader and was removed or demoted. We prevent demotions
-            // at the time writing but hypothetically we handle them the same way as
-            // removing the leader: stepping down into the next Term.
-            //
-            // TODO(tbg): step down (for sanity) and ask follower with largest Match
-            // to TimeoutNow (to avoid interruption). This might still drop some
-            // proposals but it's better than nothing.
-            //
-            // TODO(tbg): test this branch. It is untested at the time of writing.
+            // This node is leader and was removed or demoted, e.g. by leaving a
+            // joint configuration. The change is applied, so it's committed, but
+            // the remaining peers may not know yet. Let them know before stepping
+            // down, and ask a voter that has the whole log to campaign right away
+            // to avoid waiting for an election timeout. It keeps the entries
+            // proposed after the change too. Pending read requests are dropped.
+            self.bcast_append();
+            let last_index = self.raft_log.last_index();
+            let voters = &self.prs.conf().voters;
+            let transferee = self
+                .prs
+                .iter()
+                .filter(|&(id, pr)| voters.contains(*id) && pr.matched == last_index)
+                .map(|(id, _)| *id)
+                .min();
+            if let Some(to) = transferee {
+                info!(
+                    self.logger,
+                    "removed from the configuration, transferring leadership to {to}",
+                    to = to;
+                );
+                self.send_timeout_now(to);
+            }
+            let term = self.term;
+            self.become_follower(term, INVALID_ID);
             return cs;
         }
 
@@ -2503,7 +3247,7 @@ impl<T: Storage> Raft<T> {
                 .r
                 .read_only
                 .recv_ack(self.id, &ctx)
-                .map_or(false, |acks| prs.has_quorum(acks))
+                .is_some_and(|acks| prs.has_quorum(acks))
             {
                 for rs in self.r.read_only.advance(&ctx, &self.r.logger) {
                     if let Some(m) = self.handle_ready_read_index(rs.req, rs.index) {
@@ -2515,7 +3259,7 @@ impl<T: Storage> Raft<T> {
 
         if self
             .lead_transferee
-            .map_or(false, |e| !self.prs.conf().voters.contains(e))
+            .is_some_and(|e| !self.prs.conf().voters.contains(e))
         {
             self.abort_leader_transfer();
         }
@@ -2543,6 +3287,16 @@ impl<T: Storage> Raft<T> {
 
     #[doc(hidden)]
     pub fn apply_conf_change(&mut self, cc: &ConfChangeV2) -> Result<ConfState> {
+        if cc.leave_joint() && !confchange::joint(self.prs.conf()) {
+            // More than one leader may propose the transition out of a joint
+            // configuration, applying the duplicated ones is a no-op.
+            info!(
+                self.logger,
+                "ignored leaving joint configuration since it's not joint";
+                "config" => ?self.prs.conf(),
+            );
+            return Ok(self.prs.conf().to_conf_state());
+        }
         let mut changer = Changer::new(&self.prs);
         let (cfg, changes) = if cc.leave_joint() {
             changer.leave_joint()?
@@ -2551,8 +3305,19 @@ impl<T: Storage> Raft<T> {
         } else {
             changer.simple(&cc.changes)?
         };
+        let removed_snapshots = changes
+            .iter()
+            .filter(|(id, change)| {
+                matches!(change, MapChangeType::Remove)
+                    && self
+                        .prs
+                        .get(*id)
+                        .is_some_and(|pr| pr.state == ProgressState::Snapshot)
+            })
+            .count();
         self.prs
             .apply_conf(cfg, changes, self.raft_log.last_index());
+        self.r.inflight_snapshots = self.r.inflight_snapshots.saturating_sub(removed_snapshots);
         Ok(self.post_conf_change())
     }
 
@@ -2566,6 +3331,72 @@ impl<T: Storage> Raft<T> {
         &mut self.prs
     }
 
+    /// Sets the clock used to expire the leader lease, together with the lease
+    /// duration. It should not exceed the time of `election_tick` ticks minus the
+    /// max message delay, or followers may elect a new leader while the old one
+    /// still serves lease reads.
+    ///
+    /// With a clock set, the lease is only extended by responses from a quorum,
+    /// from the time the oldest of them was received. A leader that can't hear
+    /// from a quorum stops serving lease reads once the lease expires.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>, lease_duration: Duration) {
+        self.quorum_active_at = if self.state == StateRole::Leader {
+            Some(clock.now())
+        } else {
+            None
+        };
+        self.clock = Some(clock);
+        self.lease_duration = lease_duration;
+    }
+
+    /// Sets whether the leader steps down on tick once its lease expires, instead
+    /// of waiting for the next quorum check. It only takes effect with a clock set
+    /// and `check_quorum` enabled.
+    pub fn set_lease_step_down(&mut self, step_down: bool) {
+        self.lease_step_down = step_down;
+    }
+
+    /// Sets the policy deciding whether a probing follower is caught up with a
+    /// snapshot instead of the entries in the log. See `SnapshotPolicy`.
+    pub fn set_snapshot_policy(&mut self, policy: Box<dyn SnapshotPolicy>) {
+        self.snapshot_policy = Some(policy);
+    }
+
+    /// Registers an interceptor for the messages stepped into and sent out of this
+    /// raft. See `MessageInterceptor` for details.
+    pub fn add_interceptor(&mut self, interceptor: Box<dyn MessageInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Marks whether the given peer is a witness.
+    ///
+    /// A witness still votes and acknowledges appends, but the leader only sends it
+    /// log metadata: normal entries are sent without payload and snapshots without
+    /// data. As its log can't serve the data, a witness never campaigns, and
+    /// leadership is never transferred to it.
+    ///
+    /// The role is part of the `ConfState`, so it should be set on every node and
+    /// persisted with the conf state, otherwise it's lost on restart and a new leader
+    /// doesn't know about it. A leader can't be made a witness.
+    pub fn set_witness(&mut self, id: u64, is_witness: bool) -> Result<()> {
+        if is_witness && id == self.id && self.state == StateRole::Leader {
+            return Err(Error::ConfChangeError(
+                "leader can't be a witness".to_owned(),
+            ));
+        }
+        self.prs.set_witness(id, is_witness)?;
+        if id == self.id {
+            self.promotable = !is_witness && self.prs.conf().voters.contains(id);
+        }
+        Ok(())
+    }
+
+    /// Whether this node is a witness.
+    #[inline]
+    pub fn is_witness(&self) -> bool {
+        self.prs.conf().witnesses.contains(&self.id)
+    }
+
     // TODO: revoke pub when there is a better way to test.
     /// For a given hardstate, load the state into self.
     pub fn load_state(&mut self, hs: &HardState) {
@@ -2580,6 +3411,7 @@ impl<T: Storage> Raft<T> {
         }
         self.raft_log.committed = hs.commit;
         self.term = hs.term;
+        self.logged_term.store(hs.term, Ordering::Relaxed);
         self.vote = hs.vote;
     }
 
@@ -2593,8 +3425,8 @@ impl<T: Storage> Raft<T> {
     /// Regenerates and stores the election timeout.
     pub fn reset_randomized_election_timeout(&mut self) {
         let prev_timeout = self.randomized_election_timeout;
-        let timeout =
-            rand::thread_rng().gen_range(self.min_election_timeout, self.max_election_timeout);
+        let (min, max) = (self.min_election_timeout, self.max_election_timeout);
+        let timeout = self.r.rng.gen_range(min, max);
         debug!(
             self.logger,
             "reset election timeout {prev_timeout} -> {timeout} at {election_elapsed}",
@@ -2641,7 +3473,7 @@ impl<T: Storage> Raft<T> {
         if req.from == INVALID_ID || req.from == self.id {
             let rs = ReadState {
                 index,
-                request_ctx: req.take_entries()[0].take_data(),
+                request_ctx: req.take_entries()[0].take_data().into(),
             };
             self.read_states.push(rs);
             return None;
@@ -0,0 +1,102 @@
+// Copyright 2026 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::eraftpb::{Entry, HardState};
+use crate::storage::MemStorage;
+use crate::{default_logger, Config, Raft};
+use datadriven::{run_test, walk, TestData};
+use std::fmt::Write;
+
+fn parse_u64s(data: &TestData, key: &str) -> Vec<u64> {
+    data.cmd_args
+        .iter()
+        .filter(|arg| arg.key == key)
+        .flat_map(|arg| arg.vals.iter())
+        .map(|val| {
+            val.parse()
+                .unwrap_or_else(|_| panic!("type of {} should be u64, check {}", key, data.pos))
+        })
+        .collect()
+}
+
+// Builds a leader with id 1 on top of a log with the given terms. It campaigns
+// at the given term, so the log ends with its own empty entry.
+fn new_leader(data: &TestData) -> Raft<MemStorage> {
+    let term = parse_u64s(data, "term")[0];
+    let voters = parse_u64s(data, "voters");
+    let ents: Vec<Entry> = parse_u64s(data, "log")
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let mut e = Entry::default();
+            e.index = i as u64 + 1;
+            e.term = t;
+            e
+        })
+        .collect();
+
+    let store = MemStorage::new_with_conf_state((voters, vec![]));
+    store.wl().append(&ents).unwrap();
+    let mut hs = HardState::default();
+    hs.term = term - 1;
+    store.wl().set_hardstate(hs);
+    let cfg = Config {
+        id: 1,
+        rng_seed: Some(1),
+        ..Default::default()
+    };
+    let mut r = Raft::new(&cfg, store, &default_logger()).unwrap();
+    r.become_candidate();
+    r.become_leader();
+    assert_eq!(r.term, term);
+    r
+}
+
+fn describe(r: &Raft<MemStorage>) -> String {
+    let mut buf = (r.raft_log.first_index()..=r.raft_log.last_index())
+        .map(|i| format!("{}/{}", i, r.raft_log.term(i).unwrap()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    writeln!(
+        buf,
+        "\ncommitted={} commit_to_current_term={}",
+        r.raft_log.committed,
+        r.commit_to_current_term()
+    )
+    .unwrap();
+    buf
+}
+
+#[test]
+fn test_raft_data_driven() -> anyhow::Result<()> {
+    walk("src/raft/testdata", |path| -> anyhow::Result<()> {
+        let logger = default_logger();
+        let mut r: Option<Raft<MemStorage>> = None;
+
+        run_test(
+            path.to_str().unwrap(),
+            |data| -> String {
+                match data.cmd.as_str() {
+                    "leader" => {
+                        let leader = new_leader(data);
+                        let res = describe(&leader);
+                        r = Some(leader);
+                        res
+                    }
+                    "ack" => {
+                        let r = r.as_mut().expect("leader must be created first");
+                        let (ids, idxs) = (parse_u64s(data, "id"), parse_u64s(data, "index"));
+                        assert_eq!(ids.len(), idxs.len(), "check {}", data.pos);
+                        for (id, idx) in ids.into_iter().zip(idxs) {
+                            r.mut_prs().get_mut(id).unwrap().maybe_update(idx);
+                        }
+                        r.maybe_commit();
+                        describe(r)
+                    }
+                    _ => panic!("unknown command: {}", data.cmd),
+                }
+            },
+            false,
+            &logger,
+        )
+    })
+}
@@ -14,18 +14,59 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cmp;
+use std::ops::Range;
+use std::sync::Arc;
+use std::{cmp, fmt};
 
+use crate::entry_cache::EntryCache;
 use crate::eraftpb::{Entry, Snapshot};
 use crate::errors::{Error, Result, StorageError};
 use crate::log_unstable::Unstable;
-use crate::storage::Storage;
+use crate::storage::{GetEntriesContext, GetEntriesFor, Storage};
+use crate::term_cache::TermCache;
 use crate::util;
 
 use slog::Logger;
 
 pub use crate::util::NO_LIMIT;
 
+// The number of terms fetched at once when the log is searched backwards.
+const TERMS_BATCH: u64 = 64;
+
+/// Describes where and why a log diverges from an append sent by the leader,
+/// see `RaftLog::find_divergence`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogDivergence {
+    /// The log doesn't have the entry at `index` the append follows, so the
+    /// append is rejected.
+    Missing {
+        /// The index of the entry the append follows.
+        index: u64,
+        /// The last index of the log.
+        last_index: u64,
+    },
+    /// The entry at `index` the append follows has another term in the log, so
+    /// the append is rejected.
+    Mismatch {
+        /// The index of the entry the append follows.
+        index: u64,
+        /// The term of the entry in the log.
+        local_term: u64,
+        /// The term of the entry on the leader.
+        remote_term: u64,
+    },
+    /// An appended entry has another term in the log. The log is truncated
+    /// from `index` once the append is accepted.
+    Conflict {
+        /// The index of the first conflicting entry.
+        index: u64,
+        /// The term of the entry in the log.
+        local_term: u64,
+        /// The term of the appended entry.
+        remote_term: u64,
+    },
+}
+
 /// Raft log implementation
 pub struct RaftLog<T: Storage> {
     /// Contains all stable entries since the last snapshot.
@@ -51,16 +92,30 @@ pub struct RaftLog<T: Storage> {
     /// The highest log position that the application has been instructed
     /// to apply to its state machine.
     ///
-    /// Invariant: applied <= min(committed, persisted)
+    /// Invariant: applied <= min(committed, persisted + max_apply_unpersisted_log_limit)
     pub applied: u64,
+
+    /// The max number of committed entries that can be applied before they are
+    /// persisted locally. It's 0 by default, i.e. only persisted entries are
+    /// applied.
+    pub max_apply_unpersisted_log_limit: u64,
+
+    /// Caches the latest appended entries to save reads from the storage.
+    /// It's disabled by default.
+    pub entry_cache: EntryCache,
+
+    /// Caches the terms of the latest appended entries to save looking them up in
+    /// the storage. It's disabled by default.
+    pub term_cache: TermCache,
 }
 
-impl<T> ToString for RaftLog<T>
+impl<T> fmt::Display for RaftLog<T>
 where
     T: Storage,
 {
-    fn to_string(&self) -> String {
-        format!(
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "committed={}, persisted={}, applied={}, unstable.offset={}, unstable.entries.len()={}",
             self.committed,
             self.persisted,
@@ -83,6 +138,9 @@ impl<T: Storage> RaftLog<T> {
             committed: first_index - 1,
             persisted: last_index,
             applied: first_index - 1,
+            max_apply_unpersisted_log_limit: 0,
+            entry_cache: EntryCache::new(0),
+            term_cache: TermCache::new(0),
             unstable: Unstable::new(last_index + 1, logger),
         }
     }
@@ -123,17 +181,54 @@ impl<T: Storage> RaftLog<T> {
             return Ok(0u64);
         }
 
-        match self.unstable.maybe_term(idx) {
-            Some(term) => Ok(term),
-            _ => self.store.term(idx).map_err(|e| {
-                match e {
-                    Error::Store(StorageError::Compacted)
-                    | Error::Store(StorageError::Unavailable) => {}
-                    _ => fatal!(self.unstable.logger, "unexpected error: {:?}", e),
-                }
-                e
-            }),
+        if let Some(term) = self.unstable.maybe_term(idx) {
+            return Ok(term);
         }
+        if let Some(term) = self.term_cache.get(idx) {
+            return Ok(term);
+        }
+        self.store.term(idx).map_err(|e| self.check_term_error(e))
+    }
+
+    /// Finds the terms of the indexes in `range` with at most one call to
+    /// `Storage::terms`. Like `term`, the term of an index out of the valid
+    /// range is 0.
+    pub fn terms(&self, range: Range<u64>) -> Result<Vec<u64>> {
+        let len = range.end.saturating_sub(range.start) as usize;
+        let low = cmp::max(range.start, self.first_index() - 1);
+        let high = cmp::min(range.end, self.last_index() + 1);
+        let mut terms = Vec::with_capacity(len);
+        if low < high {
+            terms.resize((low - range.start) as usize, 0);
+            // A pending snapshot is not in the storage yet.
+            let stable_high = match self.unstable.snapshot {
+                Some(_) => low,
+                None => cmp::min(high, cmp::max(low, self.unstable.offset)),
+            };
+            if low < stable_high {
+                let stable = match self.term_cache.get_range(low, stable_high) {
+                    Some(stable) => stable,
+                    None => self
+                        .store
+                        .terms(low..stable_high)
+                        .map_err(|e| self.check_term_error(e))?,
+                };
+                terms.extend(stable);
+            }
+            for idx in stable_high..high {
+                terms.push(self.unstable.maybe_term(idx).unwrap_or(0));
+            }
+        }
+        terms.resize(len, 0);
+        Ok(terms)
+    }
+
+    fn check_term_error(&self, e: Error) -> Error {
+        match e {
+            Error::Store(StorageError::Compacted) | Error::Store(StorageError::Unavailable) => {}
+            _ => fatal!(self.unstable.logger, "unexpected error: {:?}", e),
+        }
+        e
     }
 
     /// Returns th first index in the store that is available via entries
@@ -177,14 +272,20 @@ impl<T: Storage> RaftLog<T> {
     /// The first entry MUST have an index equal to the argument 'from'.
     /// The index of the given entries MUST be continuously increasing.
     pub fn find_conflict(&self, ents: &[Entry]) -> u64 {
-        for e in ents {
-            if !self.match_term(e.index, e.term) {
+        let terms = match (ents.first(), ents.last()) {
+            (Some(first), Some(last)) => self.terms(first.index..last.index + 1),
+            _ => return 0,
+        };
+        let terms = terms.unwrap_or_default();
+        for (i, e) in ents.iter().enumerate() {
+            let local_term = terms.get(i).copied();
+            if local_term != Some(e.term) {
                 if e.index <= self.last_index() {
                     info!(
                         self.unstable.logger,
                         "found conflict at index {index}",
                         index = e.index;
-                        "existing term" => self.term(e.index).unwrap_or(0),
+                        "existing term" => local_term.unwrap_or(0),
                         "conflicting term" => e.term,
                     );
                 }
@@ -194,17 +295,89 @@ impl<T: Storage> RaftLog<T> {
         0
     }
 
-    /// Finds the index of the conflict with the given term
+    /// Reports where and why the log diverges from entries appended after the
+    /// entry at `idx` with the given term, without changing the log. Returns
+    /// `None` if they can be appended without truncating anything.
     ///
-    /// It returns the first index that the term is not
-    /// greater than given term
-    pub fn find_conflict_by_term(&self, mut index: u64, term: u64) -> u64 {
-        let mut conflict_index = 0;
-        while self.term(index).ok().map_or(false, |t| t > term) {
-            index -= 1;
-            conflict_index = index;
+    /// It's useful to audit divergent logs before `maybe_append` truncates them.
+    pub fn find_divergence(&self, idx: u64, term: u64, ents: &[Entry]) -> Option<LogDivergence> {
+        let last_index = self.last_index();
+        if idx > last_index {
+            return Some(LogDivergence::Missing {
+                index: idx,
+                last_index,
+            });
+        }
+        let local_term = self.term(idx).unwrap_or(0);
+        if local_term != term {
+            return Some(LogDivergence::Mismatch {
+                index: idx,
+                local_term,
+                remote_term: term,
+            });
+        }
+        let ents: Vec<_> = ents.iter().take_while(|e| e.index <= last_index).collect();
+        let terms = match (ents.first(), ents.last()) {
+            (Some(first), Some(last)) => self.terms(first.index..last.index + 1),
+            _ => return None,
+        };
+        let terms = terms.unwrap_or_default();
+        for (i, e) in ents.into_iter().enumerate() {
+            let local_term = terms.get(i).copied().unwrap_or(0);
+            if local_term != e.term {
+                return Some(LogDivergence::Conflict {
+                    index: e.index,
+                    local_term,
+                    remote_term: e.term,
+                });
+            }
+        }
+        None
+    }
+
+    /// Finds the largest index, starting from the given one and going backwards,
+    /// whose entry has a term not greater than the given term, and returns it
+    /// along with its term.
+    ///
+    /// The term is `None` if no such index is known, e.g. it's compacted or the
+    /// given index is beyond the last index. The search stops at the index of the
+    /// snapshot, whose term is known, and returns the index before it otherwise.
+    pub fn find_conflict_by_term(&self, index: u64, term: u64) -> (u64, Option<u64>) {
+        let mut conflict_index = index;
+
+        let last_index = self.last_index();
+        if index > last_index {
+            warn!(
+                self.unstable.logger,
+                "index({}) is out of range [0, last_index({})] in find_conflict_by_term",
+                index,
+                last_index,
+            );
+            return (index, None);
+        }
+
+        // The term of the snapshot index is still known, not the ones before.
+        let snapshot_index = self.first_index() - 1;
+        // Fetch the terms backwards in batches, most conflicts are resolved in the first.
+        loop {
+            if conflict_index < snapshot_index {
+                return (conflict_index, None);
+            }
+            let low = cmp::max(
+                conflict_index.saturating_sub(TERMS_BATCH - 1),
+                snapshot_index,
+            );
+            let terms = match self.terms(low..conflict_index + 1) {
+                Ok(terms) => terms,
+                Err(_) => return (conflict_index, None),
+            };
+            for t in terms.into_iter().rev() {
+                if t <= term {
+                    return (conflict_index, Some(t));
+                }
+                conflict_index -= 1;
+            }
         }
-        conflict_index
     }
 
     /// Answers the question: Does this index belong to this term?
@@ -216,26 +389,25 @@ impl<T: Storage> RaftLog<T> {
     /// Returns None if the entries cannot be appended. Otherwise,
     /// it returns Some((conflict_index, last_index)).
     ///
-    /// # Panics
-    ///
-    /// Panics if it finds a conflicting index less than committed index.
+    /// Returns `Error::CommittedConflict` and leaves the log untouched if an entry
+    /// conflicts with a committed one, which means the log is corrupted or the
+    /// entries come from a faulty leader.
     pub fn maybe_append(
         &mut self,
         idx: u64,
         term: u64,
         committed: u64,
         ents: &[Entry],
-    ) -> Option<(u64, u64)> {
+    ) -> Result<Option<(u64, u64)>> {
         if self.match_term(idx, term) {
             let conflict_idx = self.find_conflict(ents);
             if conflict_idx == 0 {
             } else if conflict_idx <= self.committed {
-                fatal!(
+                error!(
                     self.unstable.logger,
-                    "entry {} conflict with committed entry {}",
-                    conflict_idx,
-                    self.committed
-                )
+                    "entry {} conflict with committed entry {}", conflict_idx, self.committed
+                );
+                return Err(Error::CommittedConflict(conflict_idx, self.committed));
             } else {
                 let start = (conflict_idx - (idx + 1)) as usize;
                 self.append(&ents[start..]);
@@ -245,55 +417,54 @@ impl<T: Storage> RaftLog<T> {
                 }
             }
             let last_new_index = idx + ents.len() as u64;
-            self.commit_to(cmp::min(committed, last_new_index));
-            return Some((conflict_idx, last_new_index));
+            self.commit_to(cmp::min(committed, last_new_index))?;
+            return Ok(Some((conflict_idx, last_new_index)));
         }
-        None
+        Ok(None)
     }
 
     /// Sets the last committed value to the passed in value.
     ///
-    /// # Panics
-    ///
-    /// Panics if the index goes past the last index.
-    pub fn commit_to(&mut self, to_commit: u64) {
+    /// Returns `Error::IndexOutOfRange` if the index goes past the last index.
+    pub fn commit_to(&mut self, to_commit: u64) -> Result<()> {
         // never decrease commit
         if self.committed >= to_commit {
-            return;
+            return Ok(());
         }
-        if self.last_index() < to_commit {
-            fatal!(
-                self.unstable.logger,
-                "to_commit {} is out of range [last_index {}]",
+        let last_index = self.last_index();
+        if last_index < to_commit {
+            return Err(Error::IndexOutOfRange(
+                "commit",
                 to_commit,
-                self.last_index()
-            )
+                self.committed,
+                last_index,
+            ));
         }
         self.committed = to_commit;
+        Ok(())
     }
 
     /// Advance the applied index to the passed in value.
     ///
-    /// # Panics
-    ///
-    /// Panics if the value passed in is not new or known.
+    /// Returns `Error::IndexOutOfRange` if the value passed in is not new or known,
+    /// see `applied_index_upper_bound`.
     #[deprecated = "Call raft::commit_apply(idx) instead. Joint Consensus requires an on-apply hook to
     finalize a configuration change. This will become internal API in future versions."]
-    pub fn applied_to(&mut self, idx: u64) {
+    pub fn applied_to(&mut self, idx: u64) -> Result<()> {
         if idx == 0 {
-            return;
+            return Ok(());
         }
-        if idx > cmp::min(self.committed, self.persisted) || idx < self.applied {
-            fatal!(
-                self.unstable.logger,
-                "applied({}) is out of range [prev_applied({}), min(committed({}), persisted({}))]",
+        let upper_bound = self.applied_index_upper_bound();
+        if idx > upper_bound || idx < self.applied {
+            return Err(Error::IndexOutOfRange(
+                "applied",
                 idx,
                 self.applied,
-                self.committed,
-                self.persisted,
-            )
+                upper_bound,
+            ));
         }
         self.applied = idx;
+        Ok(())
     }
 
     /// Returns the last applied index.
@@ -301,6 +472,16 @@ impl<T: Storage> RaftLog<T> {
         self.applied
     }
 
+    /// Returns the max index that can be applied, which may be beyond the
+    /// persisted index by at most `max_apply_unpersisted_log_limit`.
+    pub fn applied_index_upper_bound(&self) -> u64 {
+        cmp::min(
+            self.committed,
+            self.persisted
+                .saturating_add(self.max_apply_unpersisted_log_limit),
+        )
+    }
+
     /// Clears the unstable entries and moves the stable offset up to the
     /// last index, if there is any.
     pub fn stable_entries(&mut self) {
@@ -348,23 +529,30 @@ impl<T: Storage> RaftLog<T> {
             )
         }
         self.unstable.truncate_and_append(ents);
+        self.entry_cache.append(ents);
+        self.term_cache.append(ents);
         self.last_index()
     }
 
     /// Returns entries starting from a particular index and not exceeding a bytesize.
-    pub fn entries(&self, idx: u64, max_size: impl Into<Option<u64>>) -> Result<Vec<Entry>> {
+    pub fn entries(
+        &self,
+        idx: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
         let max_size = max_size.into();
         let last = self.last_index();
         if idx > last {
             return Ok(Vec::new());
         }
-        self.slice(idx, last + 1, max_size)
+        self.slice(idx, last + 1, max_size, context)
     }
 
     /// Returns all the entries.
     pub fn all_entries(&self) -> Vec<Entry> {
         let first_index = self.first_index();
-        match self.entries(first_index, None) {
+        match self.entries(first_index, None, GetEntriesContext::empty(false)) {
             Err(e) => {
                 // try again if there was a racing compaction
                 if e == Error::Store(StorageError::Compacted) {
@@ -387,11 +575,23 @@ impl<T: Storage> RaftLog<T> {
     }
 
     /// Returns committed and persisted entries since max(`since_idx` + 1, first_index).
-    pub fn next_entries_since(&self, since_idx: u64) -> Option<Vec<Entry>> {
+    /// Committed entries not persisted yet are also returned if they are within
+    /// `max_apply_unpersisted_log_limit`, see `applied_index_upper_bound`.
+    /// The result is truncated to `max_size` in bytes, but contains at least one entry.
+    pub fn next_entries_since(
+        &self,
+        since_idx: u64,
+        max_size: impl Into<Option<u64>>,
+    ) -> Option<Vec<Entry>> {
         let offset = cmp::max(since_idx + 1, self.first_index());
-        let high = cmp::min(self.committed, self.persisted) + 1;
+        let high = self.applied_index_upper_bound() + 1;
         if high > offset {
-            match self.slice(offset, high, None) {
+            match self.slice(
+                offset,
+                high,
+                max_size,
+                GetEntriesContext(GetEntriesFor::GenReady),
+            ) {
                 Ok(vec) => return Some(vec),
                 Err(e) => fatal!(self.unstable.logger, "{}", e),
             }
@@ -402,15 +602,16 @@ impl<T: Storage> RaftLog<T> {
     /// Returns all the available entries for execution.
     /// If applied is smaller than the index of snapshot, it returns all committed
     /// entries after the index of snapshot.
-    pub fn next_entries(&self) -> Option<Vec<Entry>> {
-        self.next_entries_since(self.applied)
+    pub fn next_entries(&self, max_size: impl Into<Option<u64>>) -> Option<Vec<Entry>> {
+        self.next_entries_since(self.applied, max_size)
     }
 
-    /// Returns whether there are committed and persisted entries since
+    /// Returns whether there are committed and persisted entries, or unpersisted
+    /// ones within `max_apply_unpersisted_log_limit`, since
     /// max(`since_idx` + 1, first_index).
     pub fn has_next_entries_since(&self, since_idx: u64) -> bool {
         let offset = cmp::max(since_idx + 1, self.first_index());
-        let high = cmp::min(self.committed, self.persisted) + 1;
+        let high = self.applied_index_upper_bound() + 1;
         high > offset
     }
 
@@ -435,36 +636,42 @@ impl<T: Storage> RaftLog<T> {
 
     fn must_check_outofbounds(&self, low: u64, high: u64) -> Option<Error> {
         if low > high {
-            fatal!(self.unstable.logger, "invalid slice {} > {}", low, high)
+            return Some(Error::IndexOutOfRange("slice low", low, 0, high));
         }
         let first_index = self.first_index();
         if low < first_index {
             return Some(Error::Store(StorageError::Compacted));
         }
-
-        let length = self.last_index() + 1 - first_index;
-        if low < first_index || high > first_index + length {
-            fatal!(
-                self.unstable.logger,
-                "slice[{},{}] out of bound[{},{}]",
-                low,
-                high,
-                first_index,
-                self.last_index()
-            )
+        // The entries may have been truncated, e.g. by a conflicting append.
+        if high > self.last_index() + 1 {
+            return Some(Error::Store(StorageError::Unavailable));
         }
         None
     }
 
     /// Attempts to commit the index and term and returns whether it did.
+    ///
+    /// Only an index whose entry has the given term is committed. Raft relies
+    /// on this to never commit entries of previous terms by counting replicas,
+    /// they are only committed along with an entry of the leader's term.
     pub fn maybe_commit(&mut self, max_index: u64, term: u64) -> bool {
-        if max_index > self.committed && self.term(max_index).map_or(false, |t| t == term) {
+        if max_index > self.committed && (self.term(max_index) == Ok(term)) {
             debug!(
                 self.unstable.logger,
                 "committing index {index}",
                 index = max_index
             );
-            self.commit_to(max_index);
+            // The term matches, so the index is in the log.
+            self.commit_to(max_index).unwrap();
+            // Entries of prior terms are only committed along with one of the given
+            // term, never by counting the replicas of their own.
+            debug_assert_eq!(
+                self.term(self.committed),
+                Ok(term),
+                "committed index {} isn't of term {}",
+                self.committed,
+                term
+            );
             true
         } else {
             false
@@ -478,9 +685,7 @@ impl<T: Storage> RaftLog<T> {
         // We handle these issues by not forwarding the persisted index. It's
         // pretty intuitive because the offset means there are some entries whose
         // index is greater than or equal to the offset has not been persisted yet.
-        if index > self.persisted
-            && index < self.unstable.offset
-            && self.term(index).map_or(false, |t| t == term)
+        if index > self.persisted && index < self.unstable.offset && (self.term(index) == Ok(term))
         {
             debug!(self.unstable.logger, "persisted index {}", index);
             self.persisted = index;
@@ -491,12 +696,15 @@ impl<T: Storage> RaftLog<T> {
     }
 
     /// Grabs a slice of entries from the raft. Unlike a rust slice pointer, these are
-    /// returned by value. The result is truncated to the max_size in bytes.
+    /// returned by value. The result is truncated to the max_size in bytes, counting the
+    /// encoded size of the entries, but contains at least one entry if the range is not
+    /// empty, see `util::limit_size`.
     pub fn slice(
         &self,
         low: u64,
         high: u64,
         max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
     ) -> Result<Vec<Entry>> {
         let max_size = max_size.into();
         if let Some(err) = self.must_check_outofbounds(low, high) {
@@ -510,21 +718,22 @@ impl<T: Storage> RaftLog<T> {
 
         if low < self.unstable.offset {
             let unstable_high = cmp::min(high, self.unstable.offset);
-            match self.store.entries(low, unstable_high, max_size) {
-                Err(e) => match e {
-                    Error::Store(StorageError::Compacted) => return Err(e),
-                    Error::Store(StorageError::Unavailable) => fatal!(
-                        self.unstable.logger,
-                        "entries[{}:{}] is unavailable from storage",
-                        low,
-                        unstable_high,
-                    ),
-                    _ => fatal!(self.unstable.logger, "unexpected error: {:?}", e),
-                },
-                Ok(entries) => {
-                    ents = entries;
-                    if (ents.len() as u64) < unstable_high - low {
-                        return Ok(ents);
+            if let Some(entries) = self.entry_cache.get(low, unstable_high, max_size) {
+                ents = entries;
+                if (ents.len() as u64) < unstable_high - low {
+                    return Ok(ents);
+                }
+            } else {
+                let can_async = context.can_async();
+                match self.store.entries(low, unstable_high, max_size, context) {
+                    Err(e) => {
+                        return Err(self.check_entries_error(e, low, unstable_high, can_async))
+                    }
+                    Ok(entries) => {
+                        ents = entries;
+                        if (ents.len() as u64) < unstable_high - low {
+                            return Ok(ents);
+                        }
                     }
                 }
             }
@@ -539,6 +748,50 @@ impl<T: Storage> RaftLog<T> {
         Ok(ents)
     }
 
+    /// Same as `entries`, but returns the entries as a shared slice. When they're all in
+    /// the storage, they're read with `Storage::shared_entries`.
+    pub fn shared_entries(
+        &self,
+        idx: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> Result<Arc<[Entry]>> {
+        let max_size = max_size.into();
+        let last = self.last_index();
+        if idx > last {
+            return Ok(Arc::from(Vec::new()));
+        }
+        let high = last + 1;
+        if high > self.unstable.offset {
+            return self.slice(idx, high, max_size, context).map(Arc::from);
+        }
+        if let Some(err) = self.must_check_outofbounds(idx, high) {
+            return Err(err);
+        }
+        if let Some(ents) = self.entry_cache.get(idx, high, max_size) {
+            return Ok(Arc::from(ents));
+        }
+        let can_async = context.can_async();
+        self.store
+            .shared_entries(idx, high, max_size, context)
+            .map_err(|e| self.check_entries_error(e, idx, high, can_async))
+    }
+
+    // Returns the errors of fetching entries raft can handle, and panics on the others.
+    fn check_entries_error(&self, e: Error, low: u64, high: u64, can_async: bool) -> Error {
+        match e {
+            Error::Store(StorageError::Compacted) => e,
+            Error::Store(StorageError::LogTemporarilyUnavailable) if can_async => e,
+            Error::Store(StorageError::Unavailable) => fatal!(
+                self.unstable.logger,
+                "entries[{}:{}] is unavailable from storage",
+                low,
+                high,
+            ),
+            _ => fatal!(self.unstable.logger, "unexpected error: {:?}", e),
+        }
+    }
+
     /// Restores the current log from a snapshot.
     pub fn restore(&mut self, snapshot: Snapshot) {
         info!(
@@ -561,6 +814,8 @@ impl<T: Storage> RaftLog<T> {
         }
         self.committed = index;
         self.unstable.restore(snapshot);
+        self.entry_cache.clear();
+        self.term_cache.clear();
     }
 
     /// Returns the committed index and its term.
@@ -587,8 +842,8 @@ mod test {
     use crate::default_logger;
     use crate::eraftpb;
     use crate::errors::{Error, StorageError};
-    use crate::raft_log::{self, RaftLog};
-    use crate::storage::MemStorage;
+    use crate::raft_log::{self, LogDivergence, RaftLog};
+    use crate::storage::{GetEntriesContext, MemStorage};
     use protobuf::Message as PbMessage;
 
     fn new_entry(index: u64, term: u64) -> eraftpb::Entry {
@@ -598,6 +853,52 @@ mod test {
         e
     }
 
+    #[test]
+    fn test_find_divergence() {
+        let previous_ents = vec![new_entry(1, 1), new_entry(2, 2), new_entry(3, 3)];
+        let store = MemStorage::new();
+        store.wl().append(&previous_ents).unwrap();
+        let raft_log = RaftLog::new(store, default_logger());
+        let tests = vec![
+            (0, 0, vec![], None),
+            (1, 1, vec![new_entry(2, 2), new_entry(3, 3)], None),
+            (3, 3, vec![new_entry(4, 4)], None),
+            (
+                4,
+                4,
+                vec![],
+                Some(LogDivergence::Missing {
+                    index: 4,
+                    last_index: 3,
+                }),
+            ),
+            (
+                2,
+                3,
+                vec![new_entry(3, 3)],
+                Some(LogDivergence::Mismatch {
+                    index: 2,
+                    local_term: 2,
+                    remote_term: 3,
+                }),
+            ),
+            (
+                1,
+                1,
+                vec![new_entry(2, 2), new_entry(3, 4), new_entry(4, 4)],
+                Some(LogDivergence::Conflict {
+                    index: 3,
+                    local_term: 3,
+                    remote_term: 4,
+                }),
+            ),
+        ];
+        for (i, (idx, term, ents, w)) in tests.into_iter().enumerate() {
+            assert_eq!(raft_log.find_divergence(idx, term, &ents), w, "#{}", i);
+        }
+        assert_eq!(raft_log.last_index(), 3);
+    }
+
     fn new_snapshot(meta_index: u64, meta_term: u64) -> eraftpb::Snapshot {
         let mut meta = eraftpb::SnapshotMetadata::default();
         meta.index = meta_index;
@@ -697,7 +998,7 @@ mod test {
     fn test_append() {
         let l = default_logger();
         let previous_ents = vec![new_entry(1, 1), new_entry(2, 2)];
-        let tests = vec![
+        let tests = [
             (vec![], 2, vec![new_entry(1, 1), new_entry(2, 2)], 3),
             (
                 vec![new_entry(3, 2)],
@@ -723,7 +1024,7 @@ mod test {
             if index != windex {
                 panic!("#{}: last_index = {}, want {}", i, index, windex);
             }
-            match raft_log.entries(1, None) {
+            match raft_log.entries(1, None, GetEntriesContext::empty(false)) {
                 Err(e) => panic!("#{}: unexpected error {}", i, e),
                 Ok(ref g) if g != wents => panic!("#{}: logEnts = {:?}, want {:?}", i, &g, &wents),
                 _ => {
@@ -745,12 +1046,12 @@ mod test {
         for i in 1..=unstable_index {
             storage
                 .wl()
-                .append(&[new_entry(i as u64, i as u64)])
+                .append(&[new_entry(i, i)])
                 .expect("append failed");
         }
         let mut raft_log = RaftLog::new(storage, default_logger());
         for i in unstable_index..last_index {
-            raft_log.append(&[new_entry(i as u64 + 1, i as u64 + 1)]);
+            raft_log.append(&[new_entry(i + 1, i + 1)]);
         }
         assert!(
             raft_log.maybe_commit(last_index, last_term),
@@ -780,7 +1081,9 @@ mod test {
         assert_eq!(prev + 1, raft_log.last_index());
 
         prev = raft_log.last_index();
-        let ents = raft_log.entries(prev, None).expect("unexpected error");
+        let ents = raft_log
+            .entries(prev, None, GetEntriesContext::empty(false))
+            .expect("unexpected error");
         assert_eq!(1, ents.len());
     }
 
@@ -798,7 +1101,7 @@ mod test {
         assert_eq!(raft_log.committed, unstablesnapi);
         assert_eq!(raft_log.persisted, storagesnapi);
 
-        let tests = vec![
+        let tests = [
             // cannot get term from storage
             (storagesnapi, 0),
             // cannot get term from the gap between storage ents and unstable snapshot
@@ -816,6 +1119,58 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_find_conflict_by_term() {
+        let l = default_logger();
+        let store = MemStorage::new();
+        store
+            .wl()
+            .apply_snapshot(new_snapshot(10, 3))
+            .expect("apply failed.");
+        let mut raft_log = RaftLog::new(store, l.clone());
+        let terms = [3, 4, 4, 5, 6];
+        let ents: Vec<_> = (11..).zip(terms).map(|(i, t)| new_entry(i, t)).collect();
+        raft_log.append(&ents[..3]);
+        raft_log.store.wl().append(&ents[..3]).unwrap();
+        raft_log.stable_entries();
+        // Both stable and unstable entries are searched.
+        raft_log.append(&ents[3..]);
+
+        let tests = vec![
+            // Beyond the last index.
+            (16, 6, (16, None)),
+            (15, 6, (15, Some(6))),
+            (15, 7, (15, Some(6))),
+            (15, 5, (14, Some(5))),
+            (15, 4, (13, Some(4))),
+            (15, 3, (11, Some(3))),
+            (12, 3, (11, Some(3))),
+            // The snapshot boundary.
+            (10, 3, (10, Some(3))),
+            (15, 2, (9, None)),
+            (10, 2, (9, None)),
+            // Compacted.
+            (5, 9, (5, None)),
+        ];
+        for (i, (index, term, w)) in tests.into_iter().enumerate() {
+            assert_eq!(raft_log.find_conflict_by_term(index, term), w, "#{}", i);
+        }
+
+        // A pending snapshot is the boundary as well.
+        raft_log.restore(new_snapshot(20, 7));
+        assert_eq!(raft_log.find_conflict_by_term(20, 8), (20, Some(7)));
+        assert_eq!(raft_log.find_conflict_by_term(20, 6), (19, None));
+        assert_eq!(raft_log.find_conflict_by_term(15, 6), (15, None));
+
+        // The search goes across the batches of terms.
+        let mut raft_log = RaftLog::new(MemStorage::new(), l);
+        assert_eq!(raft_log.find_conflict_by_term(0, 0), (0, Some(0)));
+        let ents: Vec<_> = (1..=200).map(|i| new_entry(i, 1 + i / 101)).collect();
+        raft_log.append(&ents);
+        assert_eq!(raft_log.find_conflict_by_term(200, 1), (100, Some(1)));
+        assert_eq!(raft_log.find_conflict_by_term(200, 0), (0, Some(0)));
+    }
+
     #[test]
     fn test_term() {
         let offset = 100u64;
@@ -831,7 +1186,7 @@ mod test {
             raft_log.append(&[new_entry(offset + i, i)]);
         }
 
-        let tests = vec![
+        let tests = [
             (offset - 1, 0),
             (offset, 1),
             (offset + num / 2, num / 2),
@@ -845,6 +1200,20 @@ mod test {
                 panic!("#{}: at = {}, want {}", i, term, w);
             }
         }
+
+        // Half of the entries are stable.
+        let store = MemStorage::new();
+        store.wl().apply_snapshot(new_snapshot(offset, 1)).unwrap();
+        let stable: Vec<_> = (1..num / 2).map(|i| new_entry(offset + i, i)).collect();
+        store.wl().append(&stable).unwrap();
+        let mut raft_log = RaftLog::new(store, default_logger());
+        for i in num / 2..num {
+            raft_log.append(&[new_entry(offset + i, i)]);
+        }
+        let range = offset - 1..offset + num + 1;
+        let want: Vec<_> = range.clone().map(|i| raft_log.term(i).unwrap()).collect();
+        assert_eq!(raft_log.terms(range), Ok(want));
+        assert_eq!(raft_log.terms(offset + 10..offset + 10), Ok(vec![]));
     }
 
     #[test]
@@ -955,13 +1324,123 @@ mod test {
         assert!(!raft_log.maybe_persist(102, 1));
     }
 
+    #[test]
+    fn test_slice_entry_cache() {
+        let l = default_logger();
+        let store = MemStorage::new();
+        let mut raft_log = RaftLog::new(store.clone(), l);
+        raft_log.entry_cache.set_capacity(1024);
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, 1)).collect();
+        raft_log.append(&ents);
+        store.wl().append(&ents).unwrap();
+        raft_log.stable_entries();
+        raft_log.append(&[new_entry(6, 1)]);
+
+        // The storage is not read, otherwise the fetch would be pending.
+        store.wl().trigger_log_unavailable(true);
+        let mut wents = ents.clone();
+        wents.push(new_entry(6, 1));
+        assert_eq!(
+            raft_log.slice(2, 7, None, GetEntriesContext::empty(true)),
+            Ok(wents[1..].to_vec())
+        );
+        assert_eq!(raft_log.entry_cache.hits(), 1);
+
+        // Entries that are not cached are read from the storage.
+        raft_log
+            .entry_cache
+            .set_capacity(u64::from(ents[0].compute_size()) * 3);
+        assert_eq!(
+            raft_log.slice(2, 5, None, GetEntriesContext::empty(true)),
+            Err(Error::Store(StorageError::LogTemporarilyUnavailable))
+        );
+        assert_eq!(raft_log.entry_cache.misses(), 1);
+        store.wl().trigger_log_unavailable(false);
+        assert_eq!(
+            raft_log.slice(2, 5, None, GetEntriesContext::empty(true)),
+            Ok(ents[1..4].to_vec())
+        );
+    }
+
+    #[test]
+    fn test_term_cache() {
+        let l = default_logger();
+        let store = MemStorage::new();
+        let mut raft_log = RaftLog::new(store.clone(), l);
+        raft_log.term_cache.set_capacity(3);
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, i)).collect();
+        raft_log.append(&ents);
+        store.wl().append(&ents).unwrap();
+        raft_log.stable_entries();
+
+        // The latest terms are not looked up in the storage.
+        assert_eq!(raft_log.term(5), Ok(5));
+        assert_eq!(raft_log.terms(3..6), Ok(vec![3, 4, 5]));
+        assert_eq!(raft_log.term_cache.hits(), 2);
+        assert_eq!(raft_log.term(2), Ok(2));
+        assert_eq!(raft_log.term_cache.misses(), 1);
+
+        // A conflicting append replaces the cached terms.
+        raft_log.append(&[new_entry(4, 6)]);
+        store.wl().append(&[new_entry(4, 6)]).unwrap();
+        raft_log.stable_entries();
+        assert_eq!(raft_log.terms(3..6), Ok(vec![3, 6, 0]));
+        assert_eq!(raft_log.last_index(), 4);
+
+        // A snapshot drops them.
+        raft_log.restore(new_snapshot(10, 7));
+        assert!(raft_log.term_cache.is_empty());
+        assert_eq!(raft_log.term(10), Ok(7));
+        assert_eq!(raft_log.term(4), Ok(0));
+    }
+
+    #[test]
+    fn test_shared_entries() {
+        let l = default_logger();
+        let store = MemStorage::new();
+        let mut raft_log = RaftLog::new(store.clone(), l);
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, 1)).collect();
+        raft_log.append(&ents);
+        store.wl().append(&ents).unwrap();
+        raft_log.stable_entries();
+
+        // All the entries are in the storage.
+        let size = u64::from(ents[0].compute_size());
+        let shared = raft_log.shared_entries(2, size * 2, GetEntriesContext::empty(false));
+        assert_eq!(shared.unwrap()[..], ents[1..3]);
+        store.wl().trigger_log_unavailable(true);
+        assert_eq!(
+            raft_log.shared_entries(2, None, GetEntriesContext::empty(true)),
+            Err(Error::Store(StorageError::LogTemporarilyUnavailable))
+        );
+        store.wl().trigger_log_unavailable(false);
+
+        // Some are still unstable.
+        raft_log.append(&[new_entry(6, 1)]);
+        let shared = raft_log.shared_entries(4, None, GetEntriesContext::empty(false));
+        assert_eq!(
+            shared.unwrap()[..],
+            [ents[3].clone(), ents[4].clone(), new_entry(6, 1)]
+        );
+        assert!(raft_log
+            .shared_entries(7, None, GetEntriesContext::empty(false))
+            .unwrap()
+            .is_empty());
+
+        store.wl().compact(3).unwrap();
+        assert_eq!(
+            raft_log.shared_entries(2, None, GetEntriesContext::empty(false)),
+            Err(Error::Store(StorageError::Compacted))
+        );
+    }
+
     // TestUnstableEnts ensures unstableEntries returns the unstable part of the
     // entries correctly.
     #[test]
     fn test_unstable_ents() {
         let l = default_logger();
         let previous_ents = vec![new_entry(1, 1), new_entry(2, 2)];
-        let tests = vec![(3, vec![]), (1, previous_ents.clone())];
+        let tests = [(3, vec![]), (1, previous_ents.clone())];
 
         for (i, &(unstable, ref wents)) in tests.iter().enumerate() {
             // append stable entries to storage
@@ -1039,7 +1518,7 @@ mod test {
                 i, raft_log.committed, committed
             );
             #[allow(deprecated)]
-            raft_log.applied_to(applied);
+            raft_log.applied_to(applied).unwrap();
 
             let expect_has_next = expect_entries.is_some();
             let actual_has_next = raft_log.has_next_entries();
@@ -1050,7 +1529,7 @@ mod test {
                 );
             }
 
-            let next_entries = raft_log.next_entries();
+            let next_entries = raft_log.next_entries(None);
             if next_entries != expect_entries.map(|n| n.to_vec()) {
                 panic!(
                     "#{}: next_entries = {:?}, want {:?}",
@@ -1060,6 +1539,48 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_next_ents_with_apply_unpersisted_log_limit() {
+        let l = default_logger();
+        let ents = [
+            new_entry(4, 1),
+            new_entry(5, 1),
+            new_entry(6, 1),
+            new_entry(7, 1),
+        ];
+        // limit, expect_entries
+        let tests = vec![
+            (0, None),
+            (1, Some(&ents[..1])),
+            (2, Some(&ents[..2])),
+            (5, Some(&ents[..3])),
+            (u64::MAX, Some(&ents[..3])),
+        ];
+        for (i, (limit, expect_entries)) in tests.into_iter().enumerate() {
+            let store = MemStorage::new();
+            store.wl().apply_snapshot(new_snapshot(3, 1)).expect("");
+            let mut raft_log = RaftLog::new(store, l.clone());
+            raft_log.max_apply_unpersisted_log_limit = limit;
+            // None of the entries is persisted.
+            raft_log.append(&ents);
+            raft_log.maybe_commit(6, 1);
+            assert_eq!(raft_log.persisted, 3, "#{}", i);
+            assert_eq!(
+                raft_log.has_next_entries(),
+                expect_entries.is_some(),
+                "#{}",
+                i
+            );
+            let next_entries = raft_log.next_entries(None);
+            assert_eq!(next_entries, expect_entries.map(|n| n.to_vec()), "#{}", i);
+            if let Some(ents) = next_entries {
+                #[allow(deprecated)]
+                raft_log.applied_to(ents.last().unwrap().index).unwrap();
+                assert!(!raft_log.has_next_entries(), "#{}", i);
+            }
+        }
+    }
+
     #[test]
     fn test_slice() {
         let (offset, num) = (100u64, 100u64);
@@ -1110,6 +1631,7 @@ mod test {
                 false,
             ),
             (last, last + 1, raft_log::NO_LIMIT, vec![], true),
+            (half + 1, half, raft_log::NO_LIMIT, vec![], true),
             // test limit
             (
                 half - 1,
@@ -1164,18 +1686,34 @@ mod test {
                 vec![new_entry(half, half), new_entry(half + 1, half + 1)],
                 false,
             ),
+            // at least one entry is returned from the storage or the unstable entries
+            (
+                half - 3,
+                half - 1,
+                0,
+                vec![new_entry(half - 3, half - 3)],
+                false,
+            ),
+            (
+                half + 1,
+                half + 3,
+                halfe_size - 1,
+                vec![new_entry(half + 1, half + 1)],
+                false,
+            ),
         ];
 
-        for (i, &(from, to, limit, ref w, wpanic)) in tests.iter().enumerate() {
-            let res =
-                panic::catch_unwind(AssertUnwindSafe(|| raft_log.slice(from, to, Some(limit))));
-            if res.is_err() ^ wpanic {
-                panic!("#{}: panic = {}, want {}: {:?}", i, true, false, res);
-            }
-            if res.is_err() {
+        for (i, &(from, to, limit, ref w, werr)) in tests.iter().enumerate() {
+            let slice_res = raft_log.slice(from, to, Some(limit), GetEntriesContext::empty(false));
+            if werr {
+                let want = if from > to {
+                    Error::IndexOutOfRange("slice low", from, 0, to)
+                } else {
+                    Error::Store(StorageError::Unavailable)
+                };
+                assert_eq!(slice_res, Err(want), "#{}", i);
                 continue;
             }
-            let slice_res = res.unwrap();
             if from <= offset && slice_res != Err(Error::Store(StorageError::Compacted)) {
                 let err = slice_res.err();
                 panic!("#{}: err = {:?}, want {}", i, err, StorageError::Compacted);
@@ -1373,7 +1911,7 @@ mod test {
             ),
         ];
 
-        for (i, &(log_term, index, committed, ref ents, wlasti, wcommit, wpersist, wpanic)) in
+        for (i, &(log_term, index, committed, ref ents, wlasti, wcommit, wpersist, werr)) in
             tests.iter().enumerate()
         {
             let store = MemStorage::new();
@@ -1381,15 +1919,18 @@ mod test {
             raft_log.append(&previous_ents);
             raft_log.committed = commit;
             raft_log.persisted = persist;
-            let res = panic::catch_unwind(AssertUnwindSafe(|| {
-                raft_log
-                    .maybe_append(index, log_term, committed, ents)
-                    .map(|(_, last_idx)| last_idx)
-            }));
-            if res.is_err() ^ wpanic {
-                panic!("#{}: panic = {}, want {}", i, res.is_err(), wpanic);
+            let res = raft_log
+                .maybe_append(index, log_term, committed, ents)
+                .map(|r| r.map(|(_, last_idx)| last_idx));
+            if res.is_err() ^ werr {
+                panic!("#{}: err = {:?}, want {}", i, res, werr);
             }
-            if res.is_err() {
+            if let Err(e) = res {
+                assert_eq!(e, Error::CommittedConflict(ents[0].index, commit), "#{}", i);
+                // The log is left untouched.
+                assert_eq!(raft_log.last_index(), last_index, "#{}", i);
+                assert_eq!(raft_log.committed, commit, "#{}", i);
+                assert_eq!(raft_log.persisted, persist, "#{}", i);
                 continue;
             }
             let glasti = res.unwrap();
@@ -1410,7 +1951,9 @@ mod test {
                     raft_log.last_index() - ents_len + 1,
                     raft_log.last_index() + 1,
                 );
-                let gents = raft_log.slice(from, to, None).expect("");
+                let gents = raft_log
+                    .slice(from, to, None, GetEntriesContext::empty(false))
+                    .expect("");
                 if &gents != ents {
                     panic!("#{}: appended entries = {:?}, want {:?}", i, gents, ents);
                 }
@@ -1423,22 +1966,28 @@ mod test {
         let l = default_logger();
         let previous_ents = vec![new_entry(1, 1), new_entry(2, 2), new_entry(3, 3)];
         let previous_commit = 2u64;
-        let tests = vec![
+        let tests = [
             (3, 3, false),
             (1, 2, false), // never decrease
-            (4, 0, true),  // commit out of range -> panic
+            (4, 2, true),  // commit out of range -> error
         ];
-        for (i, &(commit, wcommit, wpanic)) in tests.iter().enumerate() {
+        for (i, &(commit, wcommit, werr)) in tests.iter().enumerate() {
             let store = MemStorage::new();
             let mut raft_log = RaftLog::new(store, l.clone());
             raft_log.append(&previous_ents);
             raft_log.committed = previous_commit;
-            let has_panic =
-                panic::catch_unwind(AssertUnwindSafe(|| raft_log.commit_to(commit))).is_err();
-            if has_panic ^ wpanic {
-                panic!("#{}: panic = {}, want {}", i, has_panic, wpanic)
+            let res = raft_log.commit_to(commit);
+            if werr {
+                assert_eq!(
+                    res,
+                    Err(Error::IndexOutOfRange("commit", commit, previous_commit, 3)),
+                    "#{}",
+                    i
+                );
+            } else {
+                assert_eq!(res, Ok(()), "#{}", i);
             }
-            if !has_panic && raft_log.committed != wcommit {
+            if raft_log.committed != wcommit {
                 let actual_committed = raft_log.committed;
                 panic!("#{}: committed = {}, want {}", i, actual_committed, wcommit);
             }
@@ -1449,7 +1998,7 @@ mod test {
     #[test]
     fn test_compaction() {
         let l = default_logger();
-        let tests = vec![
+        let tests = [
             // out of upper bound
             (1000, vec![1001u64], vec![0usize], true),
             (
@@ -1471,7 +2020,7 @@ mod test {
             raft_log.maybe_commit(index - 1, 0);
             let committed = raft_log.committed;
             #[allow(deprecated)]
-            raft_log.applied_to(committed);
+            raft_log.applied_to(committed).unwrap();
 
             for (j, idx) in compact.iter().enumerate() {
                 let res =
@@ -1502,44 +2051,26 @@ mod test {
             raft_log.append(&[new_entry(i + offset, 0)]);
         }
         let first = offset + 1;
-        let tests = vec![
-            (first - 2, first + 1, false, true),
-            (first - 1, first + 1, false, true),
-            (first, first, false, false),
-            (first + num / 2, first + num / 2, false, false),
-            (first + num - 1, first + num - 1, false, false),
-            (first + num, first + num, false, false),
-            (first + num, first + num + 1, true, false),
-            (first + num + 1, first + num + 1, true, false),
+        let compacted = || Some(Error::Store(StorageError::Compacted));
+        let unavailable = || Some(Error::Store(StorageError::Unavailable));
+        let tests = [
+            (first - 2, first + 1, compacted()),
+            (first - 1, first + 1, compacted()),
+            (first, first, None),
+            (first + num / 2, first + num / 2, None),
+            (first + num - 1, first + num - 1, None),
+            (first + num, first + num, None),
+            (first + num, first + num + 1, unavailable()),
+            (first + num + 1, first + num + 1, unavailable()),
+            (
+                first + 1,
+                first,
+                Some(Error::IndexOutOfRange("slice low", first + 1, 0, first)),
+            ),
         ];
 
-        for (i, &(lo, hi, wpanic, w_err_compacted)) in tests.iter().enumerate() {
-            let res =
-                panic::catch_unwind(AssertUnwindSafe(|| raft_log.must_check_outofbounds(lo, hi)));
-            if res.is_err() ^ wpanic {
-                panic!(
-                    "#{}: panic = {}, want {}: {:?}",
-                    i,
-                    res.is_err(),
-                    wpanic,
-                    res
-                );
-            }
-            if res.is_err() {
-                continue;
-            }
-            let check_res = res.unwrap();
-            if w_err_compacted && check_res != Some(Error::Store(StorageError::Compacted)) {
-                panic!(
-                    "#{}: err = {:?}, want {}",
-                    i,
-                    check_res,
-                    StorageError::Compacted
-                );
-            }
-            if !w_err_compacted && check_res.is_some() {
-                panic!("#{}: unexpected err {:?}", i, check_res)
-            }
+        for (i, (lo, hi, werr)) in tests.iter().enumerate() {
+            assert_eq!(&raft_log.must_check_outofbounds(*lo, *hi), werr, "#{}", i);
         }
     }
 
@@ -20,18 +20,26 @@
 //! nodes but not the raft consensus itself. Generally, you'll interact with the
 //! RawNode first and use it to access the inner workings of the consensus protocol.
 
-use std::{collections::VecDeque, mem};
+use std::collections::{HashSet, VecDeque};
+use std::mem;
 
 use protobuf::Message as PbMessage;
 use raft_proto::ConfChangeI;
 
+use crate::apply_cursor::ApplyCursor;
 use crate::eraftpb::{ConfState, Entry, EntryType, HardState, Message, MessageType, Snapshot};
 use crate::errors::{Error, Result};
 use crate::read_only::ReadState;
-use crate::{config::Config, StateRole};
-use crate::{Raft, SoftState, Status, Storage};
+use crate::storage::GetEntriesContext;
+use crate::{config::Config, StateRole, INVALID_ID};
+use crate::{
+    DroppedProposal, ProposalContext, ProposalDropReason, Raft, SoftState, Status, Storage,
+};
 use slog::Logger;
 
+#[cfg(feature = "async")]
+use crate::proposal_future::{PendingProposals, ProposalFuture};
+
 /// Represents a Peer node in the cluster.
 #[derive(Debug, Default)]
 pub struct Peer {
@@ -74,6 +82,20 @@ fn is_response_msg(t: MessageType) -> bool {
     )
 }
 
+// Drops the heartbeats that are followed by another heartbeat to the same peer with the
+// same context, which carries a commit index at least as new and gets the same ack.
+fn dedup_heartbeats(msgs: &mut Vec<Message>) {
+    let mut later = HashSet::new();
+    let mut keep = vec![true; msgs.len()];
+    for (i, m) in msgs.iter().enumerate().rev() {
+        if m.get_msg_type() == MessageType::MsgHeartbeat && !later.insert((m.to, &m.context)) {
+            keep[i] = false;
+        }
+    }
+    let mut keep = keep.into_iter();
+    msgs.retain(|_| keep.next().unwrap());
+}
+
 /// For a given snapshot, determine if it's empty or not.
 #[deprecated(since = "0.6.0", note = "Please use `Snapshot::is_empty` instead")]
 pub fn is_empty_snap(s: &Snapshot) -> bool {
@@ -119,12 +141,20 @@ impl Ready {
 
     /// The current state of a Node to be saved to stable storage.
     /// HardState will be None state if there is no update.
+    ///
+    /// It *MUST* be persisted and visible through `Storage::initial_state` before the
+    /// Ready is passed back via `advance` or its families. Debug builds panic if the
+    /// term or vote of storage doesn't match it at that point.
     #[inline]
     pub fn hs(&self) -> Option<&HardState> {
         self.hs.as_ref()
     }
 
     /// ReadStates specifies the state for read only query.
+    ///
+    /// They are in the order the read requests were handled, and their indexes never
+    /// decrease. A read can be served once entries up to its `index` have been applied,
+    /// committed entries in the same ready may be needed to reach it.
     #[inline]
     pub fn read_states(&self) -> &Vec<ReadState> {
         &self.read_states
@@ -184,6 +214,9 @@ impl Ready {
 
     /// MustSync indicates whether the HardState and Entries must be synchronously
     /// written to disk or if an asynchronous write is permissible.
+    ///
+    /// It's true if the term or vote changed, or there are entries or a snapshot to
+    /// persist. A change of the commit index alone doesn't require a sync.
     #[inline]
     pub fn must_sync(&self) -> bool {
         self.must_sync
@@ -208,6 +241,7 @@ pub struct LightReady {
     commit_index: Option<u64>,
     committed_entries: Vec<Entry>,
     messages: Vec<Vec<Message>>,
+    read_states: Vec<ReadState>,
 }
 
 impl LightReady {
@@ -246,8 +280,46 @@ impl LightReady {
     pub fn take_messages(&mut self) -> Vec<Vec<Message>> {
         mem::take(&mut self.messages)
     }
+
+    /// ReadStates produced by read only requests stepped after the last `Ready` was
+    /// generated. They follow the ones in that `Ready`, see `Ready::read_states`.
+    ///
+    /// Only set if `Config::light_ready_read_states` is enabled, otherwise they are
+    /// returned by the next `Ready`.
+    #[inline]
+    pub fn read_states(&self) -> &Vec<ReadState> {
+        &self.read_states
+    }
+
+    /// Take the ReadStates.
+    #[inline]
+    pub fn take_read_states(&mut self) -> Vec<ReadState> {
+        mem::take(&mut self.read_states)
+    }
+}
+
+/// An iterator over batches of committed entries, created by
+/// [`RawNode::committed_entries_pager`].
+pub struct CommittedEntriesPager<'a, T: Storage> {
+    node: &'a mut RawNode<T>,
+}
+
+impl<T: Storage> Iterator for CommittedEntriesPager<'_, T> {
+    type Item = Vec<Entry>;
+
+    fn next(&mut self) -> Option<Vec<Entry>> {
+        let ents = self.node.next_committed_entries();
+        if ents.is_empty() {
+            None
+        } else {
+            Some(ents)
+        }
+    }
 }
 
+// The max number of buffers kept by each pool of a RawNode.
+const MAX_POOLED_BUFFERS: usize = 16;
+
 /// RawNode is a thread-unsafe Node.
 /// The methods of this struct correspond to the methods of Node and are described
 /// more fully there.
@@ -263,6 +335,16 @@ pub struct RawNode<T: Storage> {
     commit_since_index: u64,
     // Messages that need to be sent to other peers.
     messages: Vec<Vec<Message>>,
+    reject_unknown_senders: bool,
+    max_untrusted_entries: usize,
+    max_untrusted_entry_size: u64,
+    max_untrusted_msg_size: u64,
+    light_ready_read_states: bool,
+    // Empty buffers returned by the application, reused to avoid allocations.
+    msg_buffers: Vec<Vec<Message>>,
+    entry_buffers: Vec<Vec<Entry>>,
+    #[cfg(feature = "async")]
+    pending_proposals: PendingProposals,
 }
 
 impl<T: Storage> RawNode<T> {
@@ -279,6 +361,15 @@ impl<T: Storage> RawNode<T> {
             records: VecDeque::new(),
             commit_since_index: config.applied,
             messages: Vec::new(),
+            reject_unknown_senders: config.reject_unknown_senders,
+            max_untrusted_entries: config.max_untrusted_entries,
+            max_untrusted_entry_size: config.max_untrusted_entry_size,
+            max_untrusted_msg_size: config.max_untrusted_msg_size,
+            light_ready_read_states: config.light_ready_read_states,
+            msg_buffers: Vec::new(),
+            entry_buffers: Vec::new(),
+            #[cfg(feature = "async")]
+            pending_proposals: PendingProposals::new(config.id),
         };
         rn.prev_hs = rn.raft.hard_state();
         rn.prev_ss = rn.raft.soft_state();
@@ -313,6 +404,71 @@ impl<T: Storage> RawNode<T> {
         self.raft.tick()
     }
 
+    /// Advances the internal logical clock by the given number of ticks at once, for
+    /// applications driving raft with a coarse timer.
+    ///
+    /// Heartbeats superseded by a later heartbeat to the same peer with the same read
+    /// index context are dropped, so the next `Ready` carries at most one heartbeat per
+    /// peer and context.
+    ///
+    /// Returns true to indicate that there will probably be some readiness which
+    /// needs to be handled.
+    pub fn tick_batch(&mut self, ticks: usize) -> bool {
+        let mut ready = false;
+        for _ in 0..ticks {
+            ready |= self.raft.tick();
+        }
+        dedup_heartbeats(&mut self.raft.msgs);
+        ready
+    }
+
+    /// Runs the given number of ticks, steps the given messages in order and returns
+    /// a single `Ready` for all of them, for applications that collect the work of a
+    /// coarse timer period and handle it at once.
+    ///
+    /// Heartbeats are deduplicated as in `tick_batch`. A message that fails to be
+    /// stepped doesn't stop the batch, its error is returned along with the `Ready`,
+    /// in the order of the messages.
+    pub fn step_batch(
+        &mut self,
+        ticks: usize,
+        msgs: impl IntoIterator<Item = Message>,
+    ) -> (Ready, Vec<Error>) {
+        for _ in 0..ticks {
+            self.raft.tick();
+        }
+        let mut errors = vec![];
+        for m in msgs {
+            if let Err(e) = self.step(m) {
+                errors.push(e);
+            }
+        }
+        dedup_heartbeats(&mut self.raft.msgs);
+        (self.ready(), errors)
+    }
+
+    /// Like `tick`, but takes the messages that can be sent right away without
+    /// generating a `Ready`, e.g. heartbeats of an established leader. It helps
+    /// when ticking a lot of groups most of which only produce such messages.
+    ///
+    /// Returns true to indicate that there is still some readiness which needs to
+    /// be handled by `ready`, along with the messages taken. Messages that must not
+    /// be sent before persisting, like votes, are always left for `ready`.
+    pub fn tick_and_take_messages(&mut self) -> (bool, Vec<Message>) {
+        self.raft.tick();
+        let mut msgs = vec![];
+        // Messages of a leader can be sent immediately, see `gen_light_ready`. The
+        // leader must be the one seen by the last `Ready`, otherwise messages
+        // generated in the former role may still be waiting in `records`.
+        if self.raft.state == StateRole::Leader && self.prev_ss.raft_state == StateRole::Leader {
+            for m in self.messages.drain(..) {
+                msgs.extend(m);
+            }
+            msgs.append(&mut self.raft.msgs);
+        }
+        (self.has_ready(), msgs)
+    }
+
     /// Campaign causes this RawNode to transition to candidate state.
     pub fn campaign(&mut self) -> Result<()> {
         let mut m = Message::default();
@@ -326,12 +482,52 @@ impl<T: Storage> RawNode<T> {
         m.set_msg_type(MessageType::MsgPropose);
         m.from = self.raft.id;
         let mut e = Entry::default();
-        e.data = data;
-        e.context = context;
+        e.data = data.into();
+        e.context = context.into();
         m.set_entries(vec![e].into());
         self.raft.step(m)
     }
 
+    /// Proposes data with a context carrying the given token, so the committed
+    /// entry or the dropped proposal can be correlated back with
+    /// `ProposalContext::token_of_entry` and `ProposalContext::token_of_dropped`.
+    pub fn propose_with_token(&mut self, token: u64, data: Vec<u8>) -> Result<()> {
+        self.propose(ProposalContext::new(token).encode(), data)
+    }
+
+    /// Proposes data and returns a future resolved with the index and term of the entry
+    /// once it's handed out as committed, or with an error if the proposal is dropped.
+    ///
+    /// The context of the proposal carries a token allocated by the node together with
+    /// the node id and a random nonce, so only entries proposed by this very instance
+    /// resolve it. Proposals forwarded to the leader are only known to be dropped if the
+    /// leader is this node, otherwise they may never resolve and the caller should apply
+    /// a timeout. Only the latest 4096 forwarded proposals are waited on, older ones are
+    /// resolved as dropped.
+    #[cfg(feature = "async")]
+    pub fn propose_awaitable(&mut self, data: Vec<u8>) -> ProposalFuture {
+        let (token, ctx, fut) = self.pending_proposals.register();
+        match self.propose(ctx, data) {
+            Ok(()) => {
+                if self.raft.state == StateRole::Leader {
+                    let index = self.raft.raft_log.last_index();
+                    self.pending_proposals.appended(token, index);
+                } else {
+                    self.pending_proposals.forwarded(token);
+                }
+            }
+            Err(e) => self.pending_proposals.resolve(token, Err(e)),
+        }
+        fut
+    }
+
+    /// Notifies that the entries the storage returned `LogTemporarilyUnavailable` for
+    /// have been fetched, so they can be sent now. `context` is the one passed to
+    /// `Storage::entries`.
+    pub fn on_entries_fetched(&mut self, context: GetEntriesContext) {
+        self.raft.on_entries_fetched(context)
+    }
+
     /// Broadcast heartbeats to all the followers.
     ///
     /// If it's not leader, nothing will happen.
@@ -344,8 +540,24 @@ impl<T: Storage> RawNode<T> {
     /// If the node enters joint state with `auto_leave` set to true, it's
     /// caller's responsibility to propose an empty conf change again to force
     /// leaving joint state.
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::needless_pass_by_value))]
+    ///
+    /// Only one conf change can be pending at a time. If the node is leader and the
+    /// previous conf change hasn't been applied yet, the proposal is dropped and
+    /// `Error::ConfChangePending` is returned.
+    #[allow(clippy::needless_pass_by_value)]
     pub fn propose_conf_change(&mut self, context: Vec<u8>, cc: impl ConfChangeI) -> Result<()> {
+        if self.raft.state == StateRole::Leader && self.raft.has_pending_conf() {
+            let index = self.raft.pending_conf_index;
+            info!(
+                self.raft.logger,
+                "dropping conf change proposal since a conf change is pending";
+                "index" => index,
+                "applied" => self.raft.raft_log.applied,
+            );
+            self.raft
+                .record_dropped(context, ProposalDropReason::ConfChangeRefused);
+            return Err(Error::ConfChangePending(index));
+        }
         let (data, ty) = if let Some(cc) = cc.as_v1() {
             (cc.write_to_bytes()?, EntryType::EntryConfChange)
         } else {
@@ -355,8 +567,8 @@ impl<T: Storage> RawNode<T> {
         m.set_msg_type(MessageType::MsgPropose);
         let mut e = Entry::default();
         e.set_entry_type(ty);
-        e.data = data;
-        e.context = context;
+        e.data = data.into();
+        e.context = context.into();
         m.set_entries(vec![e].into());
         self.raft.step(m)
     }
@@ -368,41 +580,175 @@ impl<T: Storage> RawNode<T> {
         self.raft.apply_conf_change(&cc.as_v2())
     }
 
+    /// Same as `step` but also sanity checks the fields of the message, like entry indexes
+    /// and terms, so a message from an untrusted source is rejected with
+    /// `Error::InvalidMessage` instead of tripping assertions or corrupting the log.
+    /// Messages carrying more entries than allowed by `Config::max_untrusted_entries`,
+    /// `max_untrusted_entry_size` and `max_untrusted_msg_size` are rejected too.
+    pub fn step_untrusted(&mut self, m: Message) -> Result<()> {
+        if is_local_msg(m.get_msg_type()) {
+            return Err(Error::StepLocalMsg);
+        }
+        self.validate_message(&m)?;
+        self.validate_untrusted_message(&m)?;
+        self.step(m)
+    }
+
     /// Step advances the state machine using the given message.
     pub fn step(&mut self, m: Message) -> Result<()> {
         // Ignore unexpected local messages receiving over network
         if is_local_msg(m.get_msg_type()) {
             return Err(Error::StepLocalMsg);
         }
+        self.validate_message(&m)?;
         if self.raft.prs().get(m.from).is_some() || !is_response_msg(m.get_msg_type()) {
             return self.raft.step(m);
         }
         Err(Error::StepPeerNotFound)
     }
 
-    /// Generates a LightReady that has the committed entries and messages but no commit index.
-    fn gen_light_ready(&mut self) -> LightReady {
-        let mut rd = LightReady::default();
+    // Rejects messages that can't be meant for this peer, so a transport that
+    // misroutes messages, e.g. across raft groups, is caught instead of silently
+    // corrupting the state.
+    fn validate_message(&self, m: &Message) -> Result<()> {
+        let reason = if m.from == INVALID_ID {
+            "sender is not set"
+        } else if m.to != self.raft.id {
+            "receiver is not this peer"
+        } else if m.term == u64::MAX {
+            "term would overflow"
+        } else if self.reject_unknown_senders && self.raft.prs().get(m.from).is_none() {
+            "sender is not in the configuration"
+        } else {
+            return Ok(());
+        };
+        Err(self.reject_message(m, reason))
+    }
+
+    // Checks the fields that a buggy or malicious peer could set to values which
+    // would otherwise trip assertions or corrupt the log.
+    fn validate_untrusted_message(&self, m: &Message) -> Result<()> {
+        let ents = m.get_entries();
+        if ents.len() > self.max_untrusted_entries {
+            return Err(self.reject_message(m, "too many entries"));
+        }
+        let mut size = 0u64;
+        for e in ents {
+            let entry_size = u64::from(e.compute_size());
+            if entry_size > self.max_untrusted_entry_size {
+                return Err(self.reject_message(m, "entry is too large"));
+            }
+            size += entry_size;
+        }
+        if size > self.max_untrusted_msg_size {
+            return Err(self.reject_message(m, "entries are too large"));
+        }
+        let reason = match m.get_msg_type() {
+            MessageType::MsgAppend => {
+                if m.log_term > m.term {
+                    "log term is larger than term"
+                } else if m.term == self.raft.term && self.raft.state == StateRole::Leader {
+                    "append from another leader of the same term"
+                } else if ents
+                    .iter()
+                    .enumerate()
+                    .any(|(i, e)| Some(e.index) != m.index.checked_add(i as u64 + 1))
+                {
+                    "entries are not contiguous"
+                } else if ents.windows(2).any(|w| w[0].term > w[1].term)
+                    || ents.first().is_some_and(|e| e.term < m.log_term)
+                {
+                    "entry terms decrease"
+                } else if ents.last().is_some_and(|e| e.term > m.term) {
+                    "entry term is larger than term"
+                } else {
+                    return Ok(());
+                }
+            }
+            MessageType::MsgSnapshot => {
+                let meta = m.get_snapshot().get_metadata();
+                if m.get_snapshot().is_empty() {
+                    "snapshot is empty"
+                } else if meta.term > m.term {
+                    "snapshot term is larger than term"
+                } else {
+                    return Ok(());
+                }
+            }
+            MessageType::MsgPropose => return Ok(()),
+            _ if !ents.is_empty() => "unexpected entries",
+            _ => return Ok(()),
+        };
+        Err(self.reject_message(m, reason))
+    }
+
+    fn reject_message(&self, m: &Message, reason: &'static str) -> Error {
+        warn!(
+            self.raft.logger,
+            "rejected message: {reason}",
+            reason = reason;
+            "from" => m.from,
+            "to" => m.to,
+            "term" => m.term,
+            "msg type" => ?m.get_msg_type(),
+        );
+        Error::InvalidMessage(m.from, m.to, reason)
+    }
+
+    // Takes the next batch of committed entries that haven't been handed out yet,
+    // limited by `max_committed_size_per_ready`.
+    fn next_committed_entries(&mut self) -> Vec<Entry> {
+        let max_size = self.raft.max_committed_size_per_ready;
+        self.take_committed_entries(max_size, |ents| ents.len())
+    }
+
+    // Takes the next committed entries that haven't been handed out yet, of at most
+    // `max_size` bytes, keeping only the first `len` of them. The entries left out are
+    // handed out later.
+    pub(crate) fn take_committed_entries<F: FnOnce(&[Entry]) -> usize>(
+        &mut self,
+        max_size: impl Into<Option<u64>>,
+        len: F,
+    ) -> Vec<Entry> {
         let raft = &mut self.raft;
-        rd.committed_entries = raft
+        let mut ents = raft
             .raft_log
-            .next_entries_since(self.commit_since_index)
+            .next_entries_since(self.commit_since_index, max_size)
             .unwrap_or_default();
+        let len = len(&ents);
+        ents.truncate(len);
         // Update raft uncommitted entries size
-        raft.reduce_uncommitted_size(&rd.committed_entries);
-        if let Some(e) = rd.committed_entries.last() {
+        raft.reduce_uncommitted_size(&ents);
+        if let Some(e) = ents.last() {
             assert!(self.commit_since_index < e.get_index());
             self.commit_since_index = e.get_index();
         }
+        #[cfg(feature = "async")]
+        self.pending_proposals.on_committed(&ents);
+        ents
+    }
+
+    /// Generates a LightReady that has the committed entries and messages but no commit index.
+    fn gen_light_ready(&mut self) -> LightReady {
+        let mut rd = LightReady {
+            committed_entries: self.next_committed_entries(),
+            ..Default::default()
+        };
+        let raft = &mut self.raft;
 
         if !self.messages.is_empty() {
             mem::swap(&mut rd.messages, &mut self.messages);
         }
 
+        if self.light_ready_read_states && !raft.read_states.is_empty() {
+            mem::swap(&mut rd.read_states, &mut raft.read_states);
+        }
+
         if raft.state == StateRole::Leader && !raft.msgs.is_empty() {
             // Leader can send messages immediately to make replication concurrently.
             // For more details, check raft thesis 10.2.1.
-            rd.messages.push(mem::take(&mut raft.msgs));
+            let buf = self.msg_buffers.pop().unwrap_or_default();
+            rd.messages.push(mem::replace(&mut raft.msgs, buf));
         }
 
         rd
@@ -480,7 +826,9 @@ impl<T: Storage> RawNode<T> {
             rd.must_sync = true;
         }
 
-        rd.entries = raft.raft_log.unstable_entries().to_vec();
+        rd.entries = self.entry_buffers.pop().unwrap_or_default();
+        rd.entries
+            .extend_from_slice(raft.raft_log.unstable_entries());
         if let Some(e) = rd.entries.last() {
             // If the last entry exists, the entries must not empty, vice versa.
             rd.must_sync = true;
@@ -488,7 +836,8 @@ impl<T: Storage> RawNode<T> {
         }
 
         if !raft.msgs.is_empty() && raft.state != StateRole::Leader {
-            mem::swap(&mut rd_record.messages, &mut raft.msgs);
+            let buf = self.msg_buffers.pop().unwrap_or_default();
+            rd_record.messages = mem::replace(&mut raft.msgs, buf);
         }
 
         rd.light = self.gen_light_ready();
@@ -496,7 +845,44 @@ impl<T: Storage> RawNode<T> {
         rd
     }
 
+    /// Checks if there are committed entries that haven't been handed out by a `Ready`,
+    /// `LightReady` or `committed_entries_pager` yet.
+    #[inline]
+    pub fn has_committed_entries(&self) -> bool {
+        self.raft
+            .raft_log
+            .has_next_entries_since(self.commit_since_index)
+    }
+
+    /// Returns an iterator that pulls committed entries in batches no larger than
+    /// `max_committed_size_per_ready`, so they can be applied incrementally instead of
+    /// being collected into one `Ready`, e.g. after a restart with a long unapplied log.
+    ///
+    /// Entries yielded are considered handed out, they won't appear in later readies.
+    /// `advance_apply_to` should still be called once they are applied.
+    #[inline]
+    pub fn committed_entries_pager(&mut self) -> CommittedEntriesPager<'_, T> {
+        CommittedEntriesPager { node: self }
+    }
+
+    /// Returns a cursor that hands out committed entries in batches sized by the
+    /// caller, see `ApplyCursor`. Like the pager, the entries it hands out won't appear
+    /// in later readies.
+    #[inline]
+    pub fn apply_cursor(&self) -> ApplyCursor {
+        ApplyCursor::new(self.commit_since_index)
+    }
+
     /// HasReady called when RawNode user need to check if any Ready pending.
+    ///
+    /// It's cheap compared to `ready`, and returns true if and only if any of the following
+    /// holds:
+    /// - there are messages to send, including those held back until a previous ready is
+    ///   persisted;
+    /// - the soft state or hard state differs from the ones handed out last time;
+    /// - there are read states produced by read only requests;
+    /// - there are unstable entries or an unstable snapshot to persist;
+    /// - there are committed entries that haven't been handed out yet.
     pub fn has_ready(&self) -> bool {
         let raft = &self.raft;
         if !raft.msgs.is_empty() || !self.messages.is_empty() {
@@ -518,7 +904,7 @@ impl<T: Storage> RawNode<T> {
             return true;
         }
 
-        if self.snap().map_or(false, |s| !s.is_empty()) {
+        if self.snap().is_some_and(|s| !s.is_empty()) {
             return true;
         }
 
@@ -537,6 +923,8 @@ impl<T: Storage> RawNode<T> {
             self.prev_ss = ss;
         }
         if let Some(hs) = rd.hs {
+            #[cfg(debug_assertions)]
+            self.check_hard_state_persisted(&hs);
             self.prev_hs = hs;
         }
         let rd_record = self.records.back().unwrap();
@@ -550,6 +938,47 @@ impl<T: Storage> RawNode<T> {
         }
     }
 
+    // Term and vote must never be lost once a ready carrying them is advanced, otherwise
+    // the peer may vote twice in the same term after restart. Commit is allowed to lag
+    // behind since it can always be recovered from the leader.
+    #[cfg(debug_assertions)]
+    fn check_hard_state_persisted(&self, hs: &HardState) {
+        let stored = match self.raft.store().initial_state() {
+            Ok(state) => state.hard_state,
+            // Let the storage report the error when it's actually used.
+            Err(_) => return,
+        };
+        debug_assert!(
+            stored.term == hs.term && stored.vote == hs.vote,
+            "hard state {:?} is advanced before being persisted, storage has {:?}",
+            hs,
+            stored
+        );
+    }
+
+    /// Returns message buffers taken from `Ready` or `LightReady` after the messages
+    /// are sent, so their memory can be reused by later readies.
+    pub fn recycle_message_buffers(&mut self, bufs: Vec<Vec<Message>>) {
+        for mut buf in bufs {
+            if self.msg_buffers.len() >= MAX_POOLED_BUFFERS {
+                break;
+            }
+            if buf.capacity() > 0 {
+                buf.clear();
+                self.msg_buffers.push(buf);
+            }
+        }
+    }
+
+    /// Returns an entry buffer taken from `Ready::take_entries` after the entries are
+    /// persisted, so its memory can be reused by later readies.
+    pub fn recycle_entry_buffer(&mut self, mut buf: Vec<Entry>) {
+        if self.entry_buffers.len() < MAX_POOLED_BUFFERS && buf.capacity() > 0 {
+            buf.clear();
+            self.entry_buffers.push(buf);
+        }
+    }
+
     fn commit_apply(&mut self, applied: u64) {
         self.raft.commit_apply(applied);
     }
@@ -593,6 +1022,10 @@ impl<T: Storage> RawNode<T> {
     /// Fully processing a ready requires to persist snapshot, entries and hard states, apply all
     /// committed entries, send all messages.
     ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the hard state of the ready has not been persisted.
+    ///
     /// Returns the LightReady that contains commit index, committed entries and messages. `LightReady`
     /// contains updates that only valid after persisting last ready. It should also be fully processed.
     /// Then `advance_apply` or `advance_apply_to` should be used later to update applying progress.
@@ -660,32 +1093,26 @@ impl<T: Storage> RawNode<T> {
 
     /// Status returns the current status of the given group.
     #[inline]
-    pub fn status(&self) -> Status {
+    pub fn status(&self) -> Status<'_> {
         Status::new(&self.raft)
     }
 
     /// ReportUnreachable reports the given node is not reachable for the last send.
     pub fn report_unreachable(&mut self, id: u64) {
-        let mut m = Message::default();
-        m.set_msg_type(MessageType::MsgUnreachable);
-        m.from = id;
-        // we don't care if it is ok actually
-        let _ = self.raft.step(m);
+        self.raft.report_unreachable(id)
     }
 
     /// ReportSnapshot reports the status of the sent snapshot.
     pub fn report_snapshot(&mut self, id: u64, status: SnapshotStatus) {
-        let rej = status == SnapshotStatus::Failure;
-        let mut m = Message::default();
-        m.set_msg_type(MessageType::MsgSnapStatus);
-        m.from = id;
-        m.reject = rej;
-        // we don't care if it is ok actually
-        let _ = self.raft.step(m);
+        self.raft.report_snapshot(id, status)
     }
 
     /// Request a snapshot from a leader.
     /// The snapshot's index must be greater or equal to the request_index.
+    ///
+    /// Returns `Error::RequestSnapshotDropped` with the reason if the request can't be
+    /// sent, e.g. the peer is leader, there is no leader, or a snapshot is already
+    /// pending or requested.
     pub fn request_snapshot(&mut self, request_index: u64) -> Result<()> {
         self.raft.request_snapshot(request_index)
     }
@@ -702,13 +1129,15 @@ impl<T: Storage> RawNode<T> {
     /// Read State has a read index. Once the application advances further than the read
     /// index, any linearizable read requests issued before the read request can be
     /// processed safely. The read state will have the same rctx attached.
-    pub fn read_index(&mut self, rctx: Vec<u8>) {
+    ///
+    /// Returns `Error::NoLeader` if there is no known leader to serve the request.
+    pub fn read_index(&mut self, rctx: Vec<u8>) -> Result<()> {
         let mut m = Message::default();
         m.set_msg_type(MessageType::MsgReadIndex);
         let mut e = Entry::default();
-        e.data = rctx;
+        e.data = rctx.into();
         m.set_entries(vec![e].into());
-        let _ = self.raft.step(m);
+        self.raft.step(m)
     }
 
     /// Returns the store as an immutable reference.
@@ -734,6 +1163,27 @@ impl<T: Storage> RawNode<T> {
     pub fn set_batch_append(&mut self, batch_append: bool) {
         self.raft.set_batch_append(batch_append)
     }
+
+    /// Sets the max number of committed entries that can be applied before they
+    /// are persisted locally at runtime.
+    #[inline]
+    pub fn set_max_apply_unpersisted_log_limit(&mut self, limit: u64) {
+        self.raft.set_max_apply_unpersisted_log_limit(limit)
+    }
+
+    /// Takes the proposals dropped since the last call, so the application can
+    /// notify the proposers instead of waiting for them to time out. They're only
+    /// queued if `Config::track_dropped_proposals` is enabled.
+    #[inline]
+    pub fn take_dropped_proposals(&mut self) -> Vec<DroppedProposal> {
+        mem::take(&mut self.raft.dropped_proposals)
+    }
+
+    /// Takes the peers the leader found inactive, see `Config::inactive_peer_tick`.
+    #[inline]
+    pub fn take_inactive_peers(&mut self) -> Vec<u64> {
+        mem::take(&mut self.raft.inactive_peers)
+    }
 }
 
 #[cfg(test)]
@@ -22,10 +22,11 @@ use crate::eraftpb::Message;
 use crate::{HashMap, HashSet};
 
 /// Determines the relative safety of and consistency of read only requests.
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum ReadOnlyOption {
     /// Safe guarantees the linearizability of the read only request by
     /// communicating with the quorum. It is the default and suggested option.
+    #[default]
     Safe,
     /// LeaseBased ensures linearizability of the read only request by
     /// relying on the leader lease. It can be affected by clock drift.
@@ -35,12 +36,6 @@ pub enum ReadOnlyOption {
     LeaseBased,
 }
 
-impl Default for ReadOnlyOption {
-    fn default() -> ReadOnlyOption {
-        ReadOnlyOption::Safe
-    }
-}
-
 /// ReadState provides state for read only query.
 /// It's caller's responsibility to send MsgReadIndex first before getting
 /// this state from ready. It's also caller's duty to differentiate if this
@@ -85,7 +80,7 @@ impl ReadOnly {
     /// `m` is the original read only request message from the local or remote node.
     pub fn add_request(&mut self, index: u64, req: Message, self_id: u64) {
         let ctx = {
-            let key = &req.entries[0].data;
+            let key = req.entries[0].get_data();
             if self.pending_read_index.contains_key(key) {
                 return;
             }
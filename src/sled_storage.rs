@@ -0,0 +1,646 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! An implementation of `Storage` over the [sled](https://docs.rs/sled) embedded database.
+//!
+//! The raft log is kept in three trees: `entries` maps the big-endian index of every
+//! entry to the entry, `state` keeps the HardState, the conf state and the applied index,
+//! and `snapshot` keeps the metadata of the snapshot or compaction the log starts after.
+//! Every change is made in one transaction, so the trees are always consistent with each
+//! other when the database is opened again.
+//!
+//! Every entry is stored with its CRC32. The entries are verified when the database is
+//! opened, and the log is truncated at the first corrupted one.
+
+use std::cmp;
+use std::convert::TryInto;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use protobuf::Message as PbMessage;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, IVec, Transactional, Tree};
+
+use crate::eraftpb::{ConfState, Entry, HardState, Snapshot, SnapshotMetadata};
+use crate::errors::{Error, Result, StorageError};
+use crate::purge::PurgeStorage;
+use crate::storage::{GetEntriesContext, RaftState, Storage, SyncPolicy};
+use crate::util::crc32;
+
+const HARD_STATE_KEY: &[u8] = b"hard_state";
+const CONF_STATE_KEY: &[u8] = b"conf_state";
+const APPLIED_KEY: &[u8] = b"applied";
+const METADATA_KEY: &[u8] = b"metadata";
+
+type TxResult = std::result::Result<(), ConflictableTransactionError<()>>;
+
+fn db_error(e: sled::Error) -> Error {
+    Error::Store(StorageError::Other(Box::new(e)))
+}
+
+fn tx_error(e: TransactionError<()>) -> Error {
+    match e {
+        TransactionError::Storage(e) => db_error(e),
+        TransactionError::Abort(()) => unreachable!("transactions are never aborted"),
+    }
+}
+
+fn entry_key(index: u64) -> [u8; 8] {
+    index.to_be_bytes()
+}
+
+fn key_index(key: &[u8]) -> Result<u64> {
+    match key.try_into() {
+        Ok(index) => Ok(u64::from_be_bytes(index)),
+        Err(_) => Err(Error::Store(StorageError::Other(
+            format!("entry key of {} bytes", key.len()).into(),
+        ))),
+    }
+}
+
+// An entry is stored after the CRC32 of it.
+fn encode_entry(e: &Entry) -> Result<Vec<u8>> {
+    let data = e.write_to_bytes()?;
+    let mut value = Vec::with_capacity(4 + data.len());
+    value.extend_from_slice(&crc32(&[&data]).to_le_bytes());
+    value.extend_from_slice(&data);
+    Ok(value)
+}
+
+fn decode_entry(index: u64, value: &[u8]) -> Result<Entry> {
+    if value.len() < 4 || value[..4] != crc32(&[&value[4..]]).to_le_bytes() {
+        return Err(Error::Store(StorageError::Corruption {
+            index,
+            details: "entry checksum mismatch".to_owned(),
+        }));
+    }
+    let mut e = Entry::default();
+    e.merge_from_bytes(&value[4..])?;
+    Ok(e)
+}
+
+fn decode<M: PbMessage + Default>(value: Option<IVec>) -> Result<M> {
+    let mut m = M::default();
+    if let Some(v) = value {
+        m.merge_from_bytes(&v)?;
+    }
+    Ok(m)
+}
+
+/// `SledStorage` is a persistent implementation of `Storage` backed by sled.
+///
+/// Writes are durable once sled flushes them, which it does in background periodically,
+/// or when `sync` returns. Like `MemStorageCore`, it panics on appending entries that are
+/// compacted or not contiguous with the log.
+#[derive(Clone)]
+pub struct SledStorage {
+    db: Db,
+    entries: Tree,
+    state: Tree,
+    snapshot: Tree,
+    // Serializes the writes, which check the log before changing it.
+    write_lock: Arc<Mutex<()>>,
+    // The index and details of the corrupted entry the log is truncated at.
+    truncated: Option<(u64, String)>,
+}
+
+impl SledStorage {
+    /// Opens the database in `path`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledStorage> {
+        SledStorage::from_db(sled::open(path).map_err(db_error)?)
+    }
+
+    /// Keeps the raft log in an opened database, which may be shared with the
+    /// application for its own data.
+    pub fn from_db(db: Db) -> Result<SledStorage> {
+        let mut s = SledStorage {
+            entries: db.open_tree("entries").map_err(db_error)?,
+            state: db.open_tree("state").map_err(db_error)?,
+            snapshot: db.open_tree("snapshot").map_err(db_error)?,
+            db,
+            write_lock: Arc::default(),
+            truncated: None,
+        };
+        s.recover()?;
+        Ok(s)
+    }
+
+    // Verifies the entries and truncates the log at the first corrupted one.
+    fn recover(&mut self) -> Result<()> {
+        let mut expected = self.first_index()?;
+        for kv in self.entries.iter() {
+            let (key, value) = kv.map_err(db_error)?;
+            let index = key_index(&key)?;
+            let details = if index != expected {
+                format!("entry {} is missing", expected)
+            } else {
+                match decode_entry(index, &value) {
+                    Ok(_) => {
+                        expected += 1;
+                        continue;
+                    }
+                    Err(Error::Store(StorageError::Corruption { details, .. })) => details,
+                    Err(e) => format!("{}", e),
+                }
+            };
+            let last = self.last_index()?;
+            let mut batch = sled::Batch::default();
+            for i in expected..=last {
+                batch.remove(&entry_key(i)[..]);
+            }
+            self.entries.apply_batch(batch).map_err(db_error)?;
+            self.db.flush().map_err(db_error)?;
+            let discarded = last + 1 - expected;
+            let details = format!("{}, {} entries discarded", details, discarded);
+            self.truncated = Some((expected, details));
+            break;
+        }
+        Ok(())
+    }
+
+    /// Returns the corrupted entries discarded when the database is opened, as a
+    /// `StorageError::Corruption` telling the first entry lost.
+    pub fn truncated_tail(&self) -> Option<StorageError> {
+        let (index, details) = self.truncated.clone()?;
+        Some(StorageError::Corruption { index, details })
+    }
+
+    /// Returns the saved HardState.
+    pub fn hard_state(&self) -> Result<HardState> {
+        decode(self.state.get(HARD_STATE_KEY).map_err(db_error)?)
+    }
+
+    /// Saves the HardState of a `Ready`, which must be done before its messages are
+    /// sent.
+    pub fn set_hard_state(&self, hs: HardState) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.state
+            .insert(HARD_STATE_KEY, hs.write_to_bytes()?)
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Saves the current conf state.
+    pub fn set_conf_state(&self, cs: ConfState) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.state
+            .insert(CONF_STATE_KEY, cs.write_to_bytes()?)
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Returns the saved applied index.
+    pub fn applied(&self) -> Result<u64> {
+        match self.state.get(APPLIED_KEY).map_err(db_error)? {
+            Some(v) => match v.as_ref().try_into() {
+                Ok(applied) => Ok(u64::from_be_bytes(applied)),
+                Err(_) => Err(Error::Store(StorageError::Other(
+                    format!("applied index of {} bytes", v.len()).into(),
+                ))),
+            },
+            None => Ok(0),
+        }
+    }
+
+    /// Saves the index of the last applied entry, see `MemStorage::set_applied`.
+    pub fn set_applied(&self, applied: u64) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.state
+            .insert(APPLIED_KEY, &applied.to_be_bytes())
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Appends the new entries to the log.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `MemStorageCore::append`.
+    pub fn append(&self, ents: &[Entry]) -> Result<()> {
+        self.append_batch(ents, None, SyncPolicy::Defer)
+    }
+
+    /// Appends the new entries and saves the HardState, if any, in one transaction,
+    /// which is how a `Ready` is usually persisted. The database is flushed if `policy`
+    /// is `SyncPolicy::Sync`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `MemStorageCore::append`.
+    pub fn append_batch(
+        &self,
+        ents: &[Entry],
+        hs: Option<HardState>,
+        policy: SyncPolicy,
+    ) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let (first, last) = (self.first_index()?, self.last_index()?);
+        if let Some(e) = ents.first() {
+            if first > e.index {
+                panic!(
+                    "overwrite compacted raft logs, compacted: {}, append: {}",
+                    first - 1,
+                    e.index,
+                );
+            }
+            if last + 1 < e.index {
+                panic!(
+                    "raft logs should be continuous, last index: {}, new appended: {}",
+                    last, e.index,
+                );
+            }
+        }
+
+        let mut values = Vec::with_capacity(ents.len());
+        for e in ents {
+            values.push((entry_key(e.index), encode_entry(e)?));
+        }
+        // The entries after the appended ones conflict with them.
+        let conflicts = ents.last().map_or(0..0, |e| e.index + 1..last + 1);
+        let hs = match hs {
+            Some(hs) => Some(hs.write_to_bytes()?),
+            None => None,
+        };
+        (&self.entries, &self.state)
+            .transaction(|(entries, state)| -> TxResult {
+                for (key, value) in &values {
+                    entries.insert(&key[..], value.as_slice())?;
+                }
+                for index in conflicts.clone() {
+                    entries.remove(&entry_key(index)[..])?;
+                }
+                if let Some(hs) = &hs {
+                    state.insert(HARD_STATE_KEY, hs.as_slice())?;
+                }
+                Ok(())
+            })
+            .map_err(tx_error)?;
+        if policy == SyncPolicy::Sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Overwrites the state with the metadata of the given snapshot. The snapshot data is
+    /// not stored, it's up to the application.
+    pub fn apply_snapshot(&self, mut snapshot: Snapshot) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let meta = snapshot.take_metadata();
+        let (first, last) = (self.first_index()?, self.last_index()?);
+        if first > meta.index {
+            return Err(Error::Store(StorageError::SnapshotOutOfDate));
+        }
+
+        let mut hs = self.hard_state()?;
+        hs.term = cmp::max(hs.term, meta.term);
+        hs.commit = meta.index;
+        let (hs, cs) = (
+            hs.write_to_bytes()?,
+            meta.get_conf_state().write_to_bytes()?,
+        );
+        let meta = meta.write_to_bytes()?;
+        (&self.entries, &self.state, &self.snapshot)
+            .transaction(|(entries, state, snapshot)| -> TxResult {
+                for index in first..=last {
+                    entries.remove(&entry_key(index)[..])?;
+                }
+                state.insert(HARD_STATE_KEY, hs.as_slice())?;
+                state.insert(CONF_STATE_KEY, cs.as_slice())?;
+                snapshot.insert(METADATA_KEY, meta.as_slice())?;
+                Ok(())
+            })
+            .map_err(tx_error)
+    }
+
+    /// Discards all log entries prior to compact_index.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `MemStorageCore::compact`.
+    pub fn compact(&self, compact_index: u64) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let (first, last) = (self.first_index()?, self.last_index()?);
+        if compact_index <= first {
+            return Ok(());
+        }
+        if compact_index > last + 1 {
+            panic!(
+                "compact not received raft logs: {}, last index: {}",
+                compact_index, last
+            );
+        }
+
+        // The log starts after the last compacted entry from now on.
+        let mut meta = self.metadata()?;
+        meta.index = compact_index - 1;
+        meta.term = self.term(meta.index)?;
+        let meta = meta.write_to_bytes()?;
+        (&self.entries, &self.snapshot)
+            .transaction(|(entries, snapshot)| -> TxResult {
+                for index in first..compact_index {
+                    entries.remove(&entry_key(index)[..])?;
+                }
+                snapshot.insert(METADATA_KEY, meta.as_slice())?;
+                Ok(())
+            })
+            .map_err(tx_error)
+    }
+
+    /// Flushes all the writes so far to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.db.flush().map_err(db_error)?;
+        Ok(())
+    }
+
+    // The metadata of the snapshot or compaction the log starts after.
+    fn metadata(&self) -> Result<SnapshotMetadata> {
+        decode(self.snapshot.get(METADATA_KEY).map_err(db_error)?)
+    }
+
+    fn read_entry(&self, index: u64) -> Result<Option<Entry>> {
+        match self.entries.get(entry_key(index)).map_err(db_error)? {
+            Some(v) => decode_entry(index, &v).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Storage for SledStorage {
+    /// Implements the Storage trait.
+    fn initial_state(&self) -> Result<RaftState> {
+        Ok(RaftState::new(
+            self.hard_state()?,
+            decode(self.state.get(CONF_STATE_KEY).map_err(db_error)?)?,
+        ))
+    }
+
+    /// Implements the Storage trait.
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        _context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
+        let max_size = max_size.into();
+        if low < self.first_index()? {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        let last = self.last_index()?;
+        if high > last + 1 {
+            panic!("index out of bound (last: {}, high: {})", last + 1, high);
+        }
+
+        // Stop reading once the size limit is exceeded, but return at least one entry.
+        let mut ents = Vec::with_capacity((high - low) as usize);
+        let mut size = 0;
+        for kv in self.entries.range(entry_key(low)..entry_key(high)) {
+            let (key, value) = kv.map_err(db_error)?;
+            let e = decode_entry(key_index(&key)?, &value)?;
+            size += u64::from(e.compute_size());
+            if !ents.is_empty() && matches!(max_size, Some(max) if size > max) {
+                break;
+            }
+            ents.push(e);
+        }
+        Ok(ents)
+    }
+
+    /// Implements the Storage trait.
+    fn term(&self, idx: u64) -> Result<u64> {
+        let meta = self.metadata()?;
+        if idx == meta.index {
+            return Ok(meta.term);
+        }
+        if idx < meta.index {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        match self.read_entry(idx)? {
+            Some(e) => Ok(e.term),
+            None => Err(Error::Store(StorageError::Unavailable)),
+        }
+    }
+
+    /// Implements the Storage trait.
+    fn terms(&self, range: Range<u64>) -> Result<Vec<u64>> {
+        let meta = self.metadata()?;
+        if range.start < meta.index {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        let len = range.end.saturating_sub(range.start) as usize;
+        let mut terms = Vec::with_capacity(len);
+        if range.start == meta.index && range.start < range.end {
+            terms.push(meta.term);
+        }
+        let first = cmp::max(range.start, meta.index + 1);
+        if first < range.end {
+            for kv in self.entries.range(entry_key(first)..entry_key(range.end)) {
+                let (key, value) = kv.map_err(db_error)?;
+                terms.push(decode_entry(key_index(&key)?, &value)?.term);
+            }
+        }
+        if terms.len() < len {
+            return Err(Error::Store(StorageError::Unavailable));
+        }
+        Ok(terms)
+    }
+
+    /// Implements the Storage trait.
+    fn first_index(&self) -> Result<u64> {
+        Ok(self.metadata()?.index + 1)
+    }
+
+    /// Implements the Storage trait.
+    fn last_index(&self) -> Result<u64> {
+        match self.entries.last().map_err(db_error)? {
+            Some((key, _)) => key_index(&key),
+            None => Ok(self.metadata()?.index),
+        }
+    }
+
+    /// Implements the Storage trait.
+    fn snapshot(&self, request_index: u64) -> Result<Snapshot> {
+        let hs = self.hard_state()?;
+        let first = self.first_index()?;
+        // All the committed entries are assumed to be applied, like `MemStorage` does.
+        if hs.commit + 1 < first {
+            panic!("commit {} < compacted index {}", hs.commit, first - 1);
+        }
+        let mut snapshot = Snapshot::default();
+        let meta = snapshot.mut_metadata();
+        meta.index = hs.commit;
+        meta.term = self.term(hs.commit)?;
+        meta.set_conf_state(self.initial_state()?.conf_state);
+        if meta.index < request_index {
+            meta.index = request_index;
+        }
+        Ok(snapshot)
+    }
+}
+
+impl PurgeStorage for SledStorage {
+    fn purge(&self, compact_index: u64) -> Result<()> {
+        self.compact(compact_index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir =
+                std::env::temp_dir().join(format!("raft-sled-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.term = term;
+        e.index = index;
+        e.data = vec![index as u8; 16].into();
+        e
+    }
+
+    fn all_entries(s: &SledStorage) -> Vec<Entry> {
+        let (low, high) = (s.first_index().unwrap(), s.last_index().unwrap() + 1);
+        s.entries(low, high, None, GetEntriesContext::empty(false))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sled_storage_recover() {
+        let dir = TempDir::new("recover");
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, 1)).collect();
+        let mut hs = HardState::default();
+        hs.term = 2;
+        hs.vote = 1;
+        hs.commit = 3;
+        // The storage is recovered from the same database handle, reopening the
+        // files in the same process races with sled releasing its file lock.
+        let db = sled::open(&dir.0).unwrap();
+        {
+            let s = SledStorage::from_db(db.clone()).unwrap();
+            s.set_conf_state(ConfState::from((vec![1, 2, 3], vec![])))
+                .unwrap();
+            s.append(&ents).unwrap();
+            // Overwrites the conflicting tail.
+            s.append_batch(&[new_entry(4, 2)], Some(hs.clone()), SyncPolicy::Defer)
+                .unwrap();
+            s.set_applied(3).unwrap();
+            s.compact(2).unwrap();
+            s.sync().unwrap();
+        }
+
+        let s = SledStorage::from_db(db).unwrap();
+        let state = s.initial_state().unwrap();
+        assert_eq!(state.hard_state, hs);
+        assert_eq!(state.conf_state.voters, vec![1, 2, 3]);
+        assert_eq!(s.applied(), Ok(3));
+        assert_eq!(s.first_index(), Ok(2));
+        assert_eq!(s.last_index(), Ok(4));
+        assert_eq!(s.term(1), Ok(1));
+        assert_eq!(s.term(0), Err(Error::Store(StorageError::Compacted)));
+        assert_eq!(s.term(5), Err(Error::Store(StorageError::Unavailable)));
+        assert_eq!(s.terms(1..5), Ok(vec![1, 1, 1, 2]));
+        assert_eq!(s.terms(1..6), Err(Error::Store(StorageError::Unavailable)));
+        assert_eq!(
+            all_entries(&s),
+            vec![ents[1].clone(), ents[2].clone(), new_entry(4, 2)]
+        );
+        let size = u64::from(ents[1].compute_size());
+        assert_eq!(
+            s.entries(2, 5, size * 2, GetEntriesContext::empty(false)),
+            Ok(vec![ents[1].clone(), ents[2].clone()])
+        );
+        assert_eq!(
+            s.entries(2, 5, 0, GetEntriesContext::empty(false)),
+            Ok(vec![ents[1].clone()])
+        );
+        assert_eq!(s.snapshot(0).unwrap().get_metadata().index, 3);
+        assert_eq!(s.snapshot(0).unwrap().get_metadata().term, 1);
+    }
+
+    #[test]
+    fn test_sled_storage_corruption() {
+        let dir = TempDir::new("corruption");
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, 1)).collect();
+        let db = sled::open(&dir.0).unwrap();
+        {
+            let s = SledStorage::from_db(db.clone()).unwrap();
+            s.append(&ents).unwrap();
+            assert!(s.truncated_tail().is_none());
+            let mut value = s.entries.get(entry_key(3)).unwrap().unwrap().to_vec();
+            *value.last_mut().unwrap() ^= 1;
+            s.entries.insert(entry_key(3), value).unwrap();
+            assert_eq!(
+                s.term(3),
+                Err(Error::Store(StorageError::Corruption {
+                    index: 3,
+                    details: "entry checksum mismatch".to_owned(),
+                }))
+            );
+            s.sync().unwrap();
+        }
+
+        // The log is truncated at the corrupted entry.
+        let s = SledStorage::from_db(db).unwrap();
+        assert_eq!(all_entries(&s), &ents[..2]);
+        assert_eq!(
+            s.truncated_tail(),
+            Some(StorageError::Corruption {
+                index: 3,
+                details: "entry checksum mismatch, 3 entries discarded".to_owned(),
+            })
+        );
+        s.append(&[new_entry(3, 2)]).unwrap();
+        assert_eq!(s.term(3), Ok(2));
+    }
+
+    #[test]
+    fn test_sled_storage_apply_snapshot() {
+        let dir = TempDir::new("snapshot");
+        let s = SledStorage::open(&dir.0).unwrap();
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, 1)).collect();
+        s.append(&ents).unwrap();
+
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 10;
+        snapshot.mut_metadata().term = 3;
+        snapshot
+            .mut_metadata()
+            .set_conf_state(ConfState::from((vec![1, 2], vec![])));
+        s.apply_snapshot(snapshot.clone()).unwrap();
+        assert_eq!(s.first_index(), Ok(11));
+        assert_eq!(s.last_index(), Ok(10));
+        assert_eq!(s.term(10), Ok(3));
+        assert_eq!(s.hard_state().unwrap().commit, 10);
+        assert_eq!(s.initial_state().unwrap().conf_state.voters, vec![1, 2]);
+
+        snapshot.mut_metadata().index = 5;
+        assert_eq!(
+            s.apply_snapshot(snapshot),
+            Err(Error::Store(StorageError::SnapshotOutOfDate))
+        );
+
+        s.append(&[new_entry(11, 3)]).unwrap();
+        s.compact(12).unwrap();
+        assert_eq!(s.first_index(), Ok(12));
+        assert_eq!(s.term(11), Ok(3));
+        assert!(all_entries(&s).is_empty());
+    }
+}
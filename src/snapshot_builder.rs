@@ -0,0 +1,138 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::io::Read;
+
+use crate::eraftpb::{ConfState, Snapshot, SnapshotMetadata};
+use crate::errors::{Error, Result, StorageError};
+use crate::raw_node::RawNode;
+use crate::storage::Storage;
+
+/// Builds a `Snapshot` of the state machine at the applied index of a node.
+///
+/// The applied index and its term are taken from raft, so the application only
+/// attaches the configuration and the data of its state machine, which must both be
+/// captured at the same applied index.
+#[derive(Clone, Debug)]
+pub struct SnapshotBuilder {
+    metadata: SnapshotMetadata,
+    data: Vec<u8>,
+}
+
+impl SnapshotBuilder {
+    /// Captures the metadata of a snapshot at the applied index of `node`, with
+    /// `conf_state` as the configuration at that index. It's the one returned by the
+    /// last `RawNode::apply_conf_change` for an entry at or before the applied index, or
+    /// the initial one if there is none. The configuration of raft can't be used, as
+    /// conf changes after the applied index may already be applied to it.
+    ///
+    /// Returns `StorageError::SnapshotTemporarilyUnavailable` if nothing is applied yet,
+    /// so it can be returned by `Storage::snapshot` as is.
+    pub fn new<T: Storage>(node: &RawNode<T>, conf_state: ConfState) -> Result<SnapshotBuilder> {
+        let applied = node.raft.raft_log.applied;
+        let term = node.raft.raft_log.term(applied)?;
+        if applied == 0 || term == 0 {
+            return Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable));
+        }
+        let mut metadata = SnapshotMetadata::default();
+        metadata.index = applied;
+        metadata.term = term;
+        metadata.set_conf_state(conf_state);
+        Ok(SnapshotBuilder {
+            metadata,
+            data: vec![],
+        })
+    }
+
+    /// The index of the snapshot.
+    #[inline]
+    pub fn index(&self) -> u64 {
+        self.metadata.index
+    }
+
+    /// The term of the snapshot.
+    #[inline]
+    pub fn term(&self) -> u64 {
+        self.metadata.term
+    }
+
+    /// The configuration at the index of the snapshot.
+    #[inline]
+    pub fn conf_state(&self) -> &ConfState {
+        self.metadata.get_conf_state()
+    }
+
+    /// Sets the data of the snapshot.
+    pub fn data(mut self, data: Vec<u8>) -> SnapshotBuilder {
+        self.data = data;
+        self
+    }
+
+    /// Reads the data of the snapshot from `reader` until its end.
+    pub fn read_data<R: Read>(mut self, mut reader: R) -> Result<SnapshotBuilder> {
+        self.data.clear();
+        reader.read_to_end(&mut self.data)?;
+        Ok(self)
+    }
+
+    /// Builds the snapshot.
+    pub fn build(self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_metadata(self.metadata);
+        snapshot.set_data(self.data.into());
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::default_logger;
+    use crate::eraftpb::{ConfChange, ConfChangeType, Entry, EntryType};
+    use crate::storage::MemStorage;
+    use crate::Config;
+
+    use protobuf::Message as PbMessage;
+
+    #[test]
+    fn test_snapshot_builder() {
+        let cfg = Config {
+            id: 1,
+            ..Default::default()
+        };
+        let store = MemStorage::new_with_conf_state((vec![1], vec![]));
+        let mut node = RawNode::new(&cfg, store, &default_logger()).unwrap();
+        assert_eq!(
+            SnapshotBuilder::new(&node, ConfState::default()).unwrap_err(),
+            Error::Store(StorageError::SnapshotTemporarilyUnavailable)
+        );
+
+        // Apply a conf change at index 1 and term 2.
+        let mut cc = ConfChange::default();
+        cc.set_change_type(ConfChangeType::AddLearnerNode);
+        cc.node_id = 2;
+        let mut e = Entry::default();
+        e.set_entry_type(EntryType::EntryConfChange);
+        e.index = 1;
+        e.term = 2;
+        e.data = cc.write_to_bytes().unwrap().into();
+        node.raft.raft_log.append(&[e]);
+        node.raft.raft_log.commit_to(1).unwrap();
+        node.raft.raft_log.persisted = 1;
+        let cs = node.apply_conf_change(&cc).unwrap();
+        node.advance_apply_to(1);
+
+        // A conf change after the applied index doesn't show up.
+        let mut cc = ConfChange::default();
+        cc.set_change_type(ConfChangeType::AddLearnerNode);
+        cc.node_id = 3;
+        node.apply_conf_change(&cc).unwrap();
+
+        let builder = SnapshotBuilder::new(&node, cs).unwrap();
+        assert_eq!((builder.index(), builder.term()), (1, 2));
+        assert_eq!(builder.conf_state().learners, vec![2]);
+        let snapshot = builder.read_data(&b"state"[..]).unwrap().build();
+        assert_eq!(snapshot.get_metadata().index, 1);
+        assert_eq!(snapshot.get_metadata().get_conf_state().voters, vec![1]);
+        assert_eq!(snapshot.get_data(), b"state");
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cmp;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::eraftpb::{Snapshot, SnapshotMetadata};
+use crate::errors::{Error, Result};
+use crate::raw_node::{RawNode, SnapshotStatus};
+use crate::storage::Storage;
+
+/// A piece of the data of a snapshot in transfer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SnapshotChunk {
+    /// The metadata of the snapshot, which identifies the transfer.
+    pub metadata: SnapshotMetadata,
+    /// The position of `data` in the snapshot data.
+    pub offset: u64,
+    /// The size of the whole snapshot data.
+    pub total: u64,
+    /// The data of the chunk.
+    pub data: Vec<u8>,
+}
+
+impl SnapshotChunk {
+    /// Whether it's the last chunk of the snapshot.
+    #[inline]
+    pub fn is_last(&self) -> bool {
+        self.offset + self.data.len() as u64 == self.total
+    }
+}
+
+/// Splits the data of a snapshot sent by raft into chunks of bounded size.
+///
+/// The data is read from `source` one chunk at a time, so it never has to be held in
+/// memory as a whole. The application takes the metadata out of a `MsgSnapshot`, opens
+/// the data it stands for, sends the chunks returned by `next_chunk` and feeds back the
+/// offsets acknowledged by the receiver. If the transfer is interrupted, it can be
+/// resumed from the offset the receiver reports. Once the transfer is done or given up,
+/// `report` tells raft the result, which is the same as calling
+/// `RawNode::report_snapshot`.
+pub struct SnapshotSender<R> {
+    to: u64,
+    metadata: SnapshotMetadata,
+    source: R,
+    total: u64,
+    chunk_size: usize,
+    // The offset of the next chunk to send.
+    next: u64,
+    // Everything before this offset is received by the peer.
+    acked: u64,
+    status: Option<SnapshotStatus>,
+}
+
+impl<R: Read + Seek> SnapshotSender<R> {
+    /// Creates a sender to peer `to` of the snapshot described by `metadata`, whose data
+    /// is read from `source` and split into chunks of at most `chunk_size` bytes. The
+    /// data is everything from the start of `source` to its end.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn new(
+        to: u64,
+        metadata: SnapshotMetadata,
+        mut source: R,
+        chunk_size: usize,
+    ) -> Result<SnapshotSender<R>> {
+        let total = source.seek(SeekFrom::End(0))?;
+        Ok(SnapshotSender::with_total(
+            to, metadata, source, total, chunk_size,
+        ))
+    }
+
+    fn with_total(
+        to: u64,
+        metadata: SnapshotMetadata,
+        source: R,
+        total: u64,
+        chunk_size: usize,
+    ) -> SnapshotSender<R> {
+        assert!(chunk_size > 0, "chunk size must be greater than 0");
+        SnapshotSender {
+            to,
+            metadata,
+            source,
+            total,
+            chunk_size,
+            next: 0,
+            acked: 0,
+            status: None,
+        }
+    }
+
+    /// The peer the snapshot is sent to.
+    #[inline]
+    pub fn to(&self) -> u64 {
+        self.to
+    }
+
+    /// The bytes received by the peer so far.
+    #[inline]
+    pub fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    /// The result of the transfer, `None` if it's still in progress.
+    #[inline]
+    pub fn status(&self) -> Option<SnapshotStatus> {
+        self.status
+    }
+
+    /// Returns the next chunk to send, or `None` if all the chunks are sent or the
+    /// transfer is over. If reading the source fails, the same chunk is read again by
+    /// the next call.
+    ///
+    /// Even a snapshot without any data is sent in one empty chunk.
+    pub fn next_chunk(&mut self) -> Result<Option<SnapshotChunk>> {
+        let total = self.total;
+        if self.status.is_some() || self.next > total || (self.next == total && total > 0) {
+            return Ok(None);
+        }
+        let end = cmp::min(self.next + self.chunk_size as u64, total);
+        let mut data = vec![0; (end - self.next) as usize];
+        self.source.seek(SeekFrom::Start(self.next))?;
+        self.source.read_exact(&mut data)?;
+        let chunk = SnapshotChunk {
+            metadata: self.metadata.clone(),
+            offset: self.next,
+            total,
+            data,
+        };
+        // Mark an empty snapshot as sent.
+        self.next = cmp::max(end, self.next + 1);
+        Ok(Some(chunk))
+    }
+
+    /// Records that the peer has received everything before `offset`. The transfer
+    /// is finished once the whole data is acknowledged.
+    pub fn on_ack(&mut self, offset: u64) {
+        if self.status.is_some() {
+            return;
+        }
+        self.acked = cmp::min(cmp::max(self.acked, offset), self.total);
+        if offset >= self.total {
+            self.status = Some(SnapshotStatus::Finish);
+        }
+    }
+
+    /// Resumes an interrupted transfer from `offset`, usually the one reported by
+    /// `SnapshotReceiver::offset` of the peer. The chunks after it are sent again.
+    pub fn resume(&mut self, offset: u64) {
+        self.on_ack(offset);
+        self.next = self.acked;
+    }
+
+    /// Gives up the transfer.
+    pub fn fail(&mut self) {
+        if self.status.is_none() {
+            self.status = Some(SnapshotStatus::Failure);
+        }
+    }
+
+    /// Reports the result of the transfer to raft. Returns false if the transfer is
+    /// still in progress, in which case nothing is reported.
+    pub fn report<T: Storage>(&self, node: &mut RawNode<T>) -> bool {
+        match self.status {
+            Some(status) => {
+                node.report_snapshot(self.to, status);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl SnapshotSender<Cursor<Vec<u8>>> {
+    /// Creates a sender of a snapshot whose data is already in memory, e.g. the one in
+    /// a `MsgSnapshot`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn from_snapshot(
+        to: u64,
+        mut snapshot: Snapshot,
+        chunk_size: usize,
+    ) -> SnapshotSender<Cursor<Vec<u8>>> {
+        let data: Vec<u8> = snapshot.take_data().into();
+        let total = data.len() as u64;
+        SnapshotSender::with_total(
+            to,
+            snapshot.take_metadata(),
+            Cursor::new(data),
+            total,
+            chunk_size,
+        )
+    }
+}
+
+/// Where a `SnapshotReceiver` writes the data of the snapshot it receives, e.g. a file
+/// the application restores its state machine from.
+pub trait SnapshotSink: Write {
+    /// Discards the data written so far, as the transfer restarts from offset 0 with
+    /// the snapshot described by `metadata`.
+    fn reset(&mut self, metadata: &SnapshotMetadata) -> io::Result<()>;
+}
+
+impl SnapshotSink for Vec<u8> {
+    fn reset(&mut self, _: &SnapshotMetadata) -> io::Result<()> {
+        self.clear();
+        Ok(())
+    }
+}
+
+/// Writes the chunks sent by a `SnapshotSender` to a sink, and assembles the metadata
+/// back into a snapshot once all of them are received.
+#[derive(Default)]
+pub struct SnapshotReceiver<W> {
+    sink: W,
+    metadata: SnapshotMetadata,
+    offset: u64,
+    // Whether the snapshot has been returned by `receive`.
+    finished: bool,
+}
+
+impl<W: SnapshotSink> SnapshotReceiver<W> {
+    /// Creates a receiver writing the data to `sink`.
+    pub fn new(sink: W) -> SnapshotReceiver<W> {
+        SnapshotReceiver {
+            sink,
+            metadata: SnapshotMetadata::default(),
+            offset: 0,
+            finished: false,
+        }
+    }
+
+    /// The offset to resume the transfer from, which is also the one to acknowledge.
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The sink the data is written to.
+    #[inline]
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    /// Consumes the receiver and returns its sink.
+    #[inline]
+    pub fn into_sink(self) -> W {
+        self.sink
+    }
+
+    /// Receives a chunk and writes its data to the sink. Returns the snapshot to step
+    /// into raft with a `MsgSnapshot` once its last chunk is received. The snapshot
+    /// only carries the metadata, its data is in the sink.
+    ///
+    /// A chunk at offset 0 of another snapshot starts a new transfer, resetting the
+    /// sink. Chunks that are received already are ignored, so they can be sent again
+    /// safely. If the sink fails, the transfer has to restart from offset 0.
+    pub fn receive(&mut self, chunk: SnapshotChunk) -> Result<Option<Snapshot>> {
+        if chunk.metadata != self.metadata {
+            if chunk.offset != 0 {
+                return Err(Error::InvalidSnapshotChunk("unknown snapshot"));
+            }
+            self.metadata = chunk.metadata;
+            self.offset = 0;
+            self.finished = false;
+            self.write(|sink, metadata| sink.reset(metadata))?;
+        }
+        if chunk.offset > self.offset {
+            return Err(Error::InvalidSnapshotChunk("chunks are not contiguous"));
+        }
+        let end = chunk.offset + chunk.data.len() as u64;
+        if end > chunk.total {
+            return Err(Error::InvalidSnapshotChunk("chunk exceeds the snapshot"));
+        }
+        if self.finished {
+            return Ok(None);
+        }
+        if end > self.offset {
+            let data = &chunk.data[(self.offset - chunk.offset) as usize..];
+            self.write(|sink, _| sink.write_all(data))?;
+            self.offset = end;
+        }
+        if self.offset < chunk.total {
+            return Ok(None);
+        }
+
+        self.write(|sink, _| sink.flush())?;
+        self.finished = true;
+        let mut snapshot = Snapshot::default();
+        snapshot.set_metadata(self.metadata.clone());
+        Ok(Some(snapshot))
+    }
+
+    // Runs `f` on the sink. On failure, forgets the snapshot so that the transfer
+    // restarts from offset 0, as the sink may be left with partial data.
+    fn write<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut W, &SnapshotMetadata) -> io::Result<()>,
+    {
+        if let Err(e) = f(&mut self.sink, &self.metadata) {
+            self.metadata = SnapshotMetadata::default();
+            self.offset = 0;
+            self.finished = false;
+            return Err(Error::Io(e));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_snapshot(index: u64, term: u64, data: &[u8]) -> Snapshot {
+        let mut s = Snapshot::default();
+        s.mut_metadata().index = index;
+        s.mut_metadata().term = term;
+        s.set_data(data.to_vec());
+        s
+    }
+
+    fn metadata_of(snap: &Snapshot) -> Snapshot {
+        let mut s = Snapshot::default();
+        s.set_metadata(snap.get_metadata().clone());
+        s
+    }
+
+    #[test]
+    fn test_snapshot_chunks() {
+        let data: Vec<u8> = (0..10).collect();
+        let snap = new_snapshot(5, 2, &data);
+        let mut sender = SnapshotSender::from_snapshot(2, snap.clone(), 4);
+        let mut receiver = SnapshotReceiver::new(vec![]);
+
+        let mut chunks = vec![];
+        while let Some(c) = sender.next_chunk().unwrap() {
+            chunks.push(c);
+        }
+        let sizes: Vec<_> = chunks.iter().map(|c| c.data.len()).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert!(chunks[2].is_last());
+
+        assert_eq!(receiver.receive(chunks[0].clone()), Ok(None));
+        // A gap is rejected, and the transfer can be resumed from the receiver's offset.
+        assert!(receiver.receive(chunks[2].clone()).is_err());
+        sender.resume(receiver.offset());
+        assert_eq!(sender.acked(), 4);
+        assert_eq!(sender.next_chunk(), Ok(Some(chunks[1].clone())));
+        // Duplicated chunks are fine.
+        assert_eq!(receiver.receive(chunks[0].clone()), Ok(None));
+        assert_eq!(receiver.receive(chunks[1].clone()), Ok(None));
+        assert_eq!(
+            receiver.receive(chunks[2].clone()),
+            Ok(Some(metadata_of(&snap)))
+        );
+        assert_eq!(receiver.offset(), 10);
+        assert_eq!(receiver.sink(), &data);
+        assert_eq!(receiver.receive(chunks[2].clone()), Ok(None));
+
+        assert_eq!(sender.status(), None);
+        sender.on_ack(10);
+        assert_eq!(sender.status(), Some(SnapshotStatus::Finish));
+        assert_eq!(sender.next_chunk(), Ok(None));
+    }
+
+    #[test]
+    fn test_snapshot_chunks_empty() {
+        let snap = new_snapshot(5, 2, &[]);
+        let mut sender = SnapshotSender::from_snapshot(2, snap.clone(), 4);
+        let chunk = sender.next_chunk().unwrap().unwrap();
+        assert_eq!(sender.next_chunk(), Ok(None));
+        assert!(chunk.is_last());
+        let mut receiver = SnapshotReceiver::new(vec![]);
+        assert_eq!(receiver.receive(chunk), Ok(Some(snap)));
+    }
+
+    #[test]
+    fn test_snapshot_chunks_restart() {
+        let mut receiver = SnapshotReceiver::new(vec![]);
+        let snap = new_snapshot(5, 2, b"abcdef");
+        let mut sender = SnapshotSender::from_snapshot(2, snap, 4);
+        let chunk = sender.next_chunk().unwrap().unwrap();
+        assert_eq!(receiver.receive(chunk), Ok(None));
+        sender.fail();
+        assert_eq!(sender.status(), Some(SnapshotStatus::Failure));
+        assert_eq!(sender.next_chunk(), Ok(None));
+
+        // A newer snapshot replaces the unfinished one.
+        let snap = new_snapshot(8, 3, b"xyz");
+        let mut sender = SnapshotSender::from_snapshot(2, snap.clone(), 4);
+        let chunk = sender.next_chunk().unwrap().unwrap();
+        let mut stale = chunk.clone();
+        stale.metadata.index = 5;
+        stale.offset = 4;
+        assert_eq!(
+            receiver.receive(stale),
+            Err(Error::InvalidSnapshotChunk("unknown snapshot"))
+        );
+        assert_eq!(receiver.receive(chunk), Ok(Some(metadata_of(&snap))));
+        assert_eq!(receiver.sink(), b"xyz");
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::Progress;
+
+/// Decides whether the leader catches a lagging follower up with a snapshot
+/// or by backfilling entries from its log.
+///
+/// Raft always sends a snapshot when the entries a follower needs have been
+/// compacted. Registered with `Raft::set_snapshot_policy`, a policy can make
+/// the leader send a snapshot earlier, for example when the gap is so large
+/// that replaying the log costs more than transferring the state.
+pub trait SnapshotPolicy: Send {
+    /// Called before the leader sends entries to a follower that is probing,
+    /// i.e. newly added or which just rejected an append.
+    ///
+    /// `first_index` and `last_index` are the bounds of the leader's log, so
+    /// the follower is missing the entries from `pr.next_idx` to `last_index`.
+    /// Returns true to send a snapshot instead.
+    fn should_send_snapshot(
+        &mut self,
+        to: u64,
+        pr: &Progress,
+        first_index: u64,
+        last_index: u64,
+    ) -> bool;
+}
@@ -21,12 +21,18 @@
 // limitations under the License.
 
 use std::cmp;
+use std::ops::Range;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
 
+use protobuf::Message as PbMessage;
+
+use crate::compaction_policy::CompactionPolicy;
 use crate::eraftpb::*;
 
 use crate::errors::{Error, Result, StorageError};
-use crate::util::limit_size;
+use crate::instrumentation::{StorageInstrumentation, StorageOp};
+use crate::util::limit_len;
 
 /// Holds both the hard state (commit index, vote leader, term) and the configuration state
 /// (Current node IDs)
@@ -54,6 +60,59 @@ impl RaftState {
     }
 }
 
+/// Records why `Storage::entries` is called, so a storage fetching entries asynchronously
+/// can hand it back to `RawNode::on_entries_fetched` once the entries are ready.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetEntriesContext(pub(crate) GetEntriesFor);
+
+impl GetEntriesContext {
+    /// Used by callers out of raft, `can_async` tells whether they can handle
+    /// `StorageError::LogTemporarilyUnavailable`.
+    pub fn empty(can_async: bool) -> Self {
+        GetEntriesContext(GetEntriesFor::Empty(can_async))
+    }
+
+    /// Checks if the caller can wait for the entries to be fetched asynchronously.
+    pub fn can_async(&self) -> bool {
+        match self.0 {
+            GetEntriesFor::SendAppend { .. } => true,
+            GetEntriesFor::Empty(can_async) => can_async,
+            _ => false,
+        }
+    }
+
+    /// Returns the index up to which the following entries are likely to be read in
+    /// order after this call, as when the leader streams a backlog to a follower. A
+    /// storage on disk can prefetch the entries after the returned ones up to it
+    /// (exclusive), since raft asks for them next.
+    pub fn read_ahead(&self) -> Option<u64> {
+        match self.0 {
+            GetEntriesFor::SendAppend { read_ahead, .. } => read_ahead,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum GetEntriesFor {
+    // The entries are sent to the given peer in the given term. `read_ahead` is
+    // set when the peer is replicating, so the entries are sent in order.
+    SendAppend {
+        to: u64,
+        term: u64,
+        read_ahead: Option<u64>,
+    },
+    // The entries are committed entries handed out by a ready.
+    GenReady,
+    // The entries are scanned for conf changes before campaigning.
+    Campaign,
+    // The entries are scanned for conf changes after the commit index is
+    // advanced by a vote request or response.
+    CommitByVote,
+    // It's not called by raft itself.
+    Empty(bool),
+}
+
 /// Storage saves all the information about the current Raft implementation, including Raft Log,
 /// commit index, the leader to vote for, etc.
 ///
@@ -73,10 +132,37 @@ pub trait Storage {
     /// the slice of entries returned will always have length at least 1 if entries are
     /// found in the range.
     ///
+    /// If `context.can_async()` is true, the storage may start fetching the entries in the
+    /// background and return `StorageError::LogTemporarilyUnavailable`. The application
+    /// must then call `RawNode::on_entries_fetched` with the context once they are fetched,
+    /// so raft can retry. Returning that error for other contexts is fatal.
+    ///
     /// # Panics
     ///
     /// Panics if `high` is higher than `Storage::last_index(&self) + 1`.
-    fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>) -> Result<Vec<Entry>>;
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> Result<Vec<Entry>>;
+
+    /// Same as `entries`, but returns the entries as a shared slice, which raft hands to
+    /// all the followers replicating the same range during a broadcast.
+    ///
+    /// The default implementation wraps the result of `entries`, so existing storages
+    /// work as is. A storage that already keeps its entries in shared buffers can
+    /// override it to return them without copying.
+    fn shared_entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> Result<Arc<[Entry]>> {
+        self.entries(low, high, max_size, context).map(Arc::from)
+    }
 
     /// Returns the term of entry idx, which must be in the range
     /// [first_index()-1, last_index()]. The term of the entry before
@@ -84,6 +170,13 @@ pub trait Storage {
     /// rest of that entry may not be available.
     fn term(&self, idx: u64) -> Result<u64>;
 
+    /// Returns the terms of the entries in `range`, whose indexes must be in the range
+    /// [first_index()-1, last_index()]. Raft calls it instead of `term` to look up a
+    /// span of entries, so a storage on disk should override it to read them at once.
+    fn terms(&self, range: Range<u64>) -> Result<Vec<u64>> {
+        range.map(|idx| self.term(idx)).collect()
+    }
+
     /// Returns the index of the first log entry that is possible available via entries, which will
     /// always equal to `truncated index` plus 1.
     ///
@@ -103,30 +196,41 @@ pub trait Storage {
     fn snapshot(&self, request_index: u64) -> Result<Snapshot>;
 }
 
+/// Whether a write to the storage is made durable right away, see
+/// `MemStorageCore::append_batch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// The write is durable when it returns.
+    Sync,
+    /// The write is made durable by a later sync, so that the writes of several
+    /// `Ready`s can share one sync. It's fine when `Ready::must_sync` is false.
+    Defer,
+}
+
 /// The Memory Storage Core instance holds the actual state of the storage struct. To access this
 /// value, use the `rl` and `wl` functions on the main MemStorage implementation.
+#[derive(Default)]
 pub struct MemStorageCore {
     raft_state: RaftState,
     // entries[i] has raft log position i+snapshot.get_metadata().index
     entries: Vec<Entry>,
     // Metadata of the last snapshot received.
     snapshot_metadata: SnapshotMetadata,
-    // If it is true, the next snapshot will return a
+    // The number of the next snapshot requests that will return a
     // SnapshotTemporarilyUnavailable error.
-    trigger_snap_unavailable: bool,
-}
-
-impl Default for MemStorageCore {
-    fn default() -> MemStorageCore {
-        MemStorageCore {
-            raft_state: Default::default(),
-            entries: vec![],
-            // Every time a snapshot is applied to the storage, the metadata will be stored here.
-            snapshot_metadata: Default::default(),
-            // When starting from scratch populate the list with a dummy entry at term zero.
-            trigger_snap_unavailable: false,
-        }
-    }
+    snap_unavailable: usize,
+    // If it is true, fetching entries with an async context will return a
+    // LogTemporarilyUnavailable error.
+    trigger_log_unavailable: bool,
+    // The context of the last entries fetch that returned LogTemporarilyUnavailable.
+    get_entries_context: Option<GetEntriesContext>,
+    // The index and term of the last entry discarded by compaction.
+    truncated_index: u64,
+    truncated_term: u64,
+    // The log is never compacted beyond it automatically.
+    applied: u64,
+    compaction_policy: Option<Box<dyn CompactionPolicy>>,
+    instrumentation: Option<Arc<dyn StorageInstrumentation>>,
 }
 
 impl MemStorageCore {
@@ -176,14 +280,65 @@ impl MemStorageCore {
     fn first_index(&self) -> u64 {
         match self.entries.first() {
             Some(e) => e.index,
-            None => self.snapshot_metadata.index + 1,
+            None => self.base().0 + 1,
         }
     }
 
-    fn last_index(&self) -> u64 {
+    pub(crate) fn last_index(&self) -> u64 {
         match self.entries.last() {
             Some(e) => e.index,
-            None => self.snapshot_metadata.index,
+            None => self.base().0,
+        }
+    }
+
+    /// The index and term right before the first entry, either from the last
+    /// snapshot or the last compaction.
+    fn base(&self) -> (u64, u64) {
+        if self.truncated_index > self.snapshot_metadata.index {
+            (self.truncated_index, self.truncated_term)
+        } else {
+            (self.snapshot_metadata.index, self.snapshot_metadata.term)
+        }
+    }
+
+    /// The index of the last entry discarded by compaction, 0 if there is none.
+    pub fn truncated_index(&self) -> u64 {
+        self.truncated_index
+    }
+
+    /// The term of the last entry discarded by compaction, 0 if there is none.
+    pub fn truncated_term(&self) -> u64 {
+        self.truncated_term
+    }
+
+    /// Sets the policy to compact the log automatically, which is consulted after
+    /// entries are appended or the applied index advances. See `CompactionPolicy`.
+    pub fn set_compaction_policy(&mut self, policy: Box<dyn CompactionPolicy>) {
+        self.compaction_policy = Some(policy);
+    }
+
+    /// Records the applied index, which bounds automatic compaction.
+    pub fn set_applied(&mut self, applied: u64) {
+        self.applied = applied;
+        self.maybe_compact();
+    }
+
+    /// Get the applied index.
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    fn maybe_compact(&mut self) {
+        let policy = match self.compaction_policy.as_mut() {
+            Some(policy) => policy,
+            None => return,
+        };
+        let index = match policy.compact_index(&self.entries) {
+            Some(index) => cmp::min(index, self.applied + 1),
+            None => return,
+        };
+        if index > self.first_index() && index <= self.last_index() + 1 {
+            self.compact(index).unwrap();
         }
     }
 
@@ -218,18 +373,16 @@ impl MemStorageCore {
         // have been applied, so use the latest commit index to construct the snapshot.
         // TODO: This is not true for async ready.
         let meta = snapshot.mut_metadata();
+        let (base_index, base_term) = self.base();
         meta.index = self.raft_state.hard_state.commit;
-        meta.term = match meta.index.cmp(&self.snapshot_metadata.index) {
-            cmp::Ordering::Equal => self.snapshot_metadata.term,
+        meta.term = match meta.index.cmp(&base_index) {
+            cmp::Ordering::Equal => base_term,
             cmp::Ordering::Greater => {
                 let offset = self.entries[0].index;
                 self.entries[(meta.index - offset) as usize].term
             }
             cmp::Ordering::Less => {
-                panic!(
-                    "commit {} < snapshot_metadata.index {}",
-                    meta.index, self.snapshot_metadata.index
-                );
+                panic!("commit {} < compacted index {}", meta.index, base_index);
             }
         };
 
@@ -260,7 +413,9 @@ impl MemStorageCore {
 
         if let Some(entry) = self.entries.first() {
             let offset = compact_index - entry.index;
-            self.entries.drain(..offset as usize);
+            let last = self.entries.drain(..offset as usize).last().unwrap();
+            self.truncated_index = last.index;
+            self.truncated_term = last.term;
         }
         Ok(())
     }
@@ -272,6 +427,16 @@ impl MemStorageCore {
     /// Panics if `ents` contains compacted entries, or there's a gap between `ents` and the last
     /// received entry in the storage.
     pub fn append(&mut self, ents: &[Entry]) -> Result<()> {
+        let start = Instant::now();
+        self.append_entries(ents)?;
+        if let Some(i) = &self.instrumentation {
+            i.on_append(start.elapsed(), ents.len(), entries_size(ents));
+        }
+        Ok(())
+    }
+
+    // Appends the entries without reporting to the instrumentation.
+    pub(crate) fn append_entries(&mut self, ents: &[Entry]) -> Result<()> {
         if ents.is_empty() {
             return Ok(());
         }
@@ -293,7 +458,31 @@ impl MemStorageCore {
         // Remove all entries overwritten by `ents`.
         let diff = ents[0].index - self.first_index();
         self.entries.drain(diff as usize..);
-        self.entries.extend_from_slice(&ents);
+        self.entries.extend_from_slice(ents);
+        if let Some(policy) = self.compaction_policy.as_mut() {
+            policy.on_append(ents);
+        }
+        self.maybe_compact();
+        Ok(())
+    }
+
+    /// Appends the new entries and saves the HardState, if any, in one write, which
+    /// is how a `Ready` is usually persisted. `MemStorage` is never durable, so the
+    /// sync policy is ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `append`.
+    pub fn append_batch(
+        &mut self,
+        ents: &[Entry],
+        hs: Option<HardState>,
+        _: SyncPolicy,
+    ) -> Result<()> {
+        self.append(ents)?;
+        if let Some(hs) = hs {
+            self.set_hardstate(hs);
+        }
         Ok(())
     }
 
@@ -306,12 +495,60 @@ impl MemStorageCore {
         Ok(())
     }
 
+    /// Sets the hooks to report the reads, appends and snapshot builds to.
+    pub fn set_instrumentation(&mut self, instrumentation: Arc<dyn StorageInstrumentation>) {
+        self.instrumentation = Some(instrumentation);
+    }
+
+    pub(crate) fn instrumentation(&self) -> Option<Arc<dyn StorageInstrumentation>> {
+        self.instrumentation.clone()
+    }
+
     /// Trigger a SnapshotTemporarilyUnavailable error.
     pub fn trigger_snap_unavailable(&mut self) {
-        self.trigger_snap_unavailable = true;
+        self.snap_unavailable = cmp::max(self.snap_unavailable, 1);
+    }
+
+    /// Set whether fetching entries with an async context returns a
+    /// LogTemporarilyUnavailable error, as if the entries were fetched in background.
+    pub fn trigger_log_unavailable(&mut self, v: bool) {
+        self.trigger_log_unavailable = v;
+    }
+
+    /// Takes the context of the last entries fetch that returned
+    /// LogTemporarilyUnavailable.
+    pub fn take_get_entries_context(&mut self) -> Option<GetEntriesContext> {
+        self.get_entries_context.take()
     }
 }
 
+pub(crate) fn entries_size(ents: &[Entry]) -> u64 {
+    ents.iter().map(|e| u64::from(e.compute_size())).sum()
+}
+
+/// Checks that `ents` follow the snapshot, that is their indexes are contiguous from the
+/// one right after the snapshot and their terms never decrease from the snapshot term.
+pub(crate) fn check_initial_log(snapshot: &SnapshotMetadata, ents: &[Entry]) -> Result<()> {
+    let (mut index, mut term) = (snapshot.index, snapshot.term);
+    for e in ents {
+        if e.index != index + 1 {
+            return Err(Error::InvalidInitialLog(format!(
+                "entry {} follows {}",
+                e.index, index
+            )));
+        }
+        if e.term < term {
+            return Err(Error::InvalidInitialLog(format!(
+                "term {} of entry {} is less than {}",
+                e.term, e.index, term
+            )));
+        }
+        index = e.index;
+        term = e.term;
+    }
+    Ok(())
+}
+
 /// `MemStorage` is a thread-safe but incomplete implementation of `Storage`, mainly for tests.
 ///
 /// A real `Storage` should save both raft logs and applied data. However `MemStorage` only
@@ -345,6 +582,21 @@ impl MemStorage {
         store
     }
 
+    /// Creates a `MemStorage` from an existing snapshot and the entries after it, e.g. to
+    /// rebuild a node. The HardState and the conf state are taken from the snapshot.
+    ///
+    /// Returns `Error::InvalidInitialLog` if the entries don't follow the snapshot.
+    pub fn new_with_snapshot(snapshot: Snapshot, ents: &[Entry]) -> Result<MemStorage> {
+        check_initial_log(snapshot.get_metadata(), ents)?;
+        let store = MemStorage::new();
+        {
+            let mut core = store.wl();
+            core.apply_snapshot(snapshot)?;
+            core.append(ents)?;
+        }
+        Ok(store)
+    }
+
     /// Initialize a `MemStorage` with a given `Config`.
     ///
     /// You should use the same input to initialize all nodes.
@@ -374,18 +626,51 @@ impl MemStorage {
     pub fn wl(&self) -> RwLockWriteGuard<'_, MemStorageCore> {
         self.core.write().unwrap()
     }
-}
 
-impl Storage for MemStorage {
-    /// Implements the Storage trait.
-    fn initial_state(&self) -> Result<RaftState> {
-        Ok(self.rl().raft_state.clone())
+    /// Saves the HardState of a `Ready`, which must be done before its messages are
+    /// sent.
+    pub fn set_hard_state(&self, hs: HardState) {
+        self.wl().set_hardstate(hs);
     }
 
-    /// Implements the Storage trait.
-    fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>) -> Result<Vec<Entry>> {
-        let max_size = max_size.into();
+    /// Returns the saved HardState.
+    pub fn hard_state(&self) -> HardState {
+        self.rl().hard_state().clone()
+    }
+
+    /// Saves the index of the last entry applied to the state machine. It must not be
+    /// greater than the committed index of the saved HardState.
+    ///
+    /// After a restart, it should be passed as `Config::applied` so that the committed
+    /// entries are not applied again.
+    pub fn set_applied(&self, applied: u64) {
+        self.wl().set_applied(applied);
+    }
+
+    /// Returns the saved applied index.
+    pub fn applied(&self) -> u64 {
+        self.rl().applied()
+    }
+
+    /// Makes the next `n` snapshot requests return a SnapshotTemporarilyUnavailable
+    /// error, so that the retries of the leader can be tested.
+    pub fn trigger_snap_unavailable(&self, n: usize) {
+        self.wl().snap_unavailable = n;
+    }
+
+    fn fetch_entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: Option<u64>,
+        context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
         let core = self.rl();
+        if core.trigger_log_unavailable && context.can_async() {
+            drop(core);
+            self.wl().get_entries_context = Some(context);
+            return Err(Error::Store(StorageError::LogTemporarilyUnavailable));
+        }
         if low < core.first_index() {
             return Err(Error::Store(StorageError::Compacted));
         }
@@ -401,16 +686,57 @@ impl Storage for MemStorage {
         let offset = core.entries[0].index;
         let lo = (low - offset) as usize;
         let hi = (high - offset) as usize;
-        let mut ents = core.entries[lo..hi].to_vec();
-        limit_size(&mut ents, max_size);
-        Ok(ents)
+        let ents = &core.entries[lo..hi];
+        Ok(ents[..limit_len(ents, max_size)].to_vec())
+    }
+
+    fn build_snapshot(&self, request_index: u64) -> Result<Snapshot> {
+        let mut core = self.wl();
+        if core.snap_unavailable > 0 {
+            core.snap_unavailable -= 1;
+            Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable))
+        } else {
+            let mut snap = core.snapshot();
+            if snap.get_metadata().index < request_index {
+                snap.mut_metadata().index = request_index;
+            }
+            Ok(snap)
+        }
+    }
+}
+
+impl Storage for MemStorage {
+    /// Implements the Storage trait.
+    fn initial_state(&self) -> Result<RaftState> {
+        Ok(self.rl().raft_state.clone())
+    }
+
+    /// Implements the Storage trait.
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
+        let instrumentation = self.rl().instrumentation();
+        let start = Instant::now();
+        let res = self.fetch_entries(low, high, max_size.into(), context);
+        if let Some(i) = instrumentation {
+            match &res {
+                Ok(ents) => i.on_entries(start.elapsed(), ents.len(), entries_size(ents)),
+                Err(e) => i.on_error(StorageOp::Entries, e),
+            }
+        }
+        res
     }
 
     /// Implements the Storage trait.
     fn term(&self, idx: u64) -> Result<u64> {
         let core = self.rl();
-        if idx == core.snapshot_metadata.index {
-            return Ok(core.snapshot_metadata.term);
+        let (base_index, base_term) = core.base();
+        if idx == base_index {
+            return Ok(base_term);
         }
 
         if idx < core.first_index() {
@@ -425,6 +751,29 @@ impl Storage for MemStorage {
         Ok(core.entries[(idx - offset) as usize].term)
     }
 
+    /// Implements the Storage trait.
+    fn terms(&self, range: Range<u64>) -> Result<Vec<u64>> {
+        let core = self.rl();
+        let (base_index, base_term) = core.base();
+        if range.start != base_index && range.start < core.first_index() {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        if range.end > core.last_index() + 1 {
+            return Err(Error::Store(StorageError::Unavailable));
+        }
+        let mut terms = Vec::with_capacity(range.end.saturating_sub(range.start) as usize);
+        if range.start == base_index && range.start < range.end {
+            terms.push(base_term);
+        }
+        let first = cmp::max(range.start, core.first_index());
+        if first < range.end {
+            let offset = core.entries[0].index;
+            let ents = &core.entries[(first - offset) as usize..(range.end - offset) as usize];
+            terms.extend(ents.iter().map(|e| e.term));
+        }
+        Ok(terms)
+    }
+
     /// Implements the Storage trait.
     fn first_index(&self) -> Result<u64> {
         Ok(self.rl().first_index())
@@ -437,30 +786,33 @@ impl Storage for MemStorage {
 
     /// Implements the Storage trait.
     fn snapshot(&self, request_index: u64) -> Result<Snapshot> {
-        let mut core = self.wl();
-        if core.trigger_snap_unavailable {
-            core.trigger_snap_unavailable = false;
-            Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable))
-        } else {
-            let mut snap = core.snapshot();
-            if snap.get_metadata().index < request_index {
-                snap.mut_metadata().index = request_index;
+        let instrumentation = self.rl().instrumentation();
+        let start = Instant::now();
+        let res = self.build_snapshot(request_index);
+        if let Some(i) = instrumentation {
+            match &res {
+                Ok(snap) => i.on_snapshot(start.elapsed(), u64::from(snap.compute_size())),
+                Err(e) => i.on_error(StorageOp::Snapshot, e),
             }
-            Ok(snap)
         }
+        res
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     use protobuf::Message as PbMessage;
 
     use crate::eraftpb::{ConfState, Entry, Snapshot};
     use crate::errors::{Error as RaftError, StorageError};
 
-    use super::{MemStorage, Storage};
+    use super::{GetEntriesContext, MemStorage, Storage};
+    use crate::compaction_policy::{CompactByCount, CompactBySize, CompactionPolicy};
+    use crate::instrumentation::{StorageInstrumentation, StorageOp};
 
     fn new_entry(index: u64, term: u64) -> Entry {
         let mut e = Entry::default();
@@ -470,7 +822,7 @@ mod test {
     }
 
     fn size_of<T: PbMessage>(m: &T) -> u32 {
-        m.compute_size() as u32
+        m.compute_size()
     }
 
     fn new_snapshot(index: u64, term: u64, voters: Vec<u64>) -> Snapshot {
@@ -500,7 +852,15 @@ mod test {
             if t != wterm {
                 panic!("#{}: expect res {:?}, got {:?}", i, wterm, t);
             }
+            assert_eq!(storage.terms(idx..idx + 1).map(|t| t[0]), wterm, "#{}", i);
         }
+
+        let storage = MemStorage::new();
+        storage.wl().entries = ents;
+        assert_eq!(storage.terms(3..6), Ok(vec![3, 4, 5]));
+        assert_eq!(storage.terms(4..4), Ok(vec![]));
+        storage.wl().compact(5).unwrap();
+        assert_eq!(storage.terms(4..6), Ok(vec![4, 5]));
     }
 
     #[test]
@@ -511,7 +871,7 @@ mod test {
             new_entry(5, 5),
             new_entry(6, 6),
         ];
-        let max_u64 = u64::max_value();
+        let max_u64 = u64::MAX;
         let mut tests = vec![
             (
                 2,
@@ -560,7 +920,7 @@ mod test {
         for (i, (lo, hi, maxsize, wentries)) in tests.drain(..).enumerate() {
             let storage = MemStorage::new();
             storage.wl().entries = ents.clone();
-            let e = storage.entries(lo, hi, maxsize);
+            let e = storage.entries(lo, hi, maxsize, GetEntriesContext::empty(false));
             if e != wentries {
                 panic!("#{}: expect entries {:?}, got {:?}", i, wentries, e);
             }
@@ -611,7 +971,9 @@ mod test {
             if index != windex {
                 panic!("#{}: want {}, index {}", i, windex, index);
             }
-            let term = if let Ok(v) = storage.entries(index, index + 1, 1) {
+            let term = if let Ok(v) =
+                storage.entries(index, index + 1, 1, GetEntriesContext::empty(false))
+            {
                 v.first().map_or(0, |e| e.term)
             } else {
                 0
@@ -620,7 +982,10 @@ mod test {
                 panic!("#{}: want {}, term {}", i, wterm, term);
             }
             let last = storage.last_index().unwrap();
-            let len = storage.entries(index, last + 1, 100).unwrap().len();
+            let len = storage
+                .entries(index, last + 1, 100, GetEntriesContext::empty(false))
+                .unwrap()
+                .len();
             if len != wlen {
                 panic!("#{}: want {}, term {}", i, wlen, len);
             }
@@ -661,6 +1026,106 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_storage_compaction_policy() {
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, i)).collect();
+        let size = u64::from(size_of(&ents[0]));
+        let policies: Vec<Box<dyn CompactionPolicy>> = vec![
+            Box::new(CompactByCount { max_entries: 2 }),
+            Box::new(CompactBySize {
+                max_bytes: 2 * size,
+            }),
+        ];
+        for (i, policy) in policies.into_iter().enumerate() {
+            let storage = MemStorage::new();
+            storage.wl().set_compaction_policy(policy);
+            storage.wl().append(&ents).unwrap();
+            // Nothing is applied yet.
+            assert_eq!(storage.first_index(), Ok(1), "#{}", i);
+
+            storage.wl().set_applied(2);
+            assert_eq!(storage.first_index(), Ok(3), "#{}", i);
+            storage.wl().set_applied(5);
+            assert_eq!(storage.first_index(), Ok(4), "#{}", i);
+            assert_eq!(storage.rl().truncated_index(), 3, "#{}", i);
+            assert_eq!(storage.rl().truncated_term(), 3, "#{}", i);
+            assert_eq!(storage.term(3), Ok(3), "#{}", i);
+            assert_eq!(
+                storage.term(2),
+                Err(RaftError::Store(StorageError::Compacted)),
+                "#{}",
+                i
+            );
+
+            storage
+                .wl()
+                .append(&[new_entry(6, 6), new_entry(7, 6)])
+                .unwrap();
+            assert_eq!(storage.first_index(), Ok(6), "#{}", i);
+            assert_eq!(storage.last_index(), Ok(7), "#{}", i);
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(StorageOp, usize, u64)>>);
+
+    impl StorageInstrumentation for Recorder {
+        fn on_entries(&self, _: Duration, count: usize, bytes: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((StorageOp::Entries, count, bytes));
+        }
+
+        fn on_append(&self, _: Duration, count: usize, bytes: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((StorageOp::Append, count, bytes));
+        }
+
+        fn on_snapshot(&self, _: Duration, bytes: u64) {
+            self.0.lock().unwrap().push((StorageOp::Snapshot, 0, bytes));
+        }
+
+        fn on_error(&self, op: StorageOp, _: &RaftError) {
+            self.0.lock().unwrap().push((op, 0, 0));
+        }
+    }
+
+    #[test]
+    fn test_storage_instrumentation() {
+        let ents: Vec<_> = (1..=3).map(|i| new_entry(i, 1)).collect();
+        let size = u64::from(size_of(&ents[0]));
+        let recorder = Arc::new(Recorder::default());
+        let storage = MemStorage::new();
+        storage.wl().set_instrumentation(recorder.clone());
+
+        storage.wl().append(&ents).unwrap();
+        storage
+            .entries(2, 4, None, GetEntriesContext::empty(false))
+            .unwrap();
+        storage.wl().trigger_log_unavailable(true);
+        storage
+            .entries(2, 4, None, GetEntriesContext::empty(true))
+            .unwrap_err();
+        storage.wl().commit_to(3).unwrap();
+        let snap = storage.snapshot(0).unwrap();
+        storage.wl().trigger_snap_unavailable();
+        storage.snapshot(0).unwrap_err();
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                (StorageOp::Append, 3, 3 * size),
+                (StorageOp::Entries, 2, 2 * size),
+                (StorageOp::Entries, 0, 0),
+                (StorageOp::Snapshot, 0, u64::from(size_of(&snap))),
+                (StorageOp::Snapshot, 0, 0),
+            ]
+        );
+    }
+
     #[test]
     fn test_storage_append() {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
@@ -724,6 +1189,47 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_storage_new_with_snapshot() {
+        let snap = new_snapshot(3, 2, vec![1, 2, 3]);
+        let tests = vec![
+            (vec![], true),
+            (vec![new_entry(4, 2), new_entry(5, 3)], true),
+            // Gap after the snapshot.
+            (vec![new_entry(5, 2)], false),
+            // Overlaps the snapshot.
+            (vec![new_entry(3, 2), new_entry(4, 2)], false),
+            // Gap between entries.
+            (vec![new_entry(4, 2), new_entry(6, 2)], false),
+            // Term less than the snapshot.
+            (vec![new_entry(4, 1)], false),
+            // Decreasing terms.
+            (vec![new_entry(4, 3), new_entry(5, 2)], false),
+        ];
+        for (i, (ents, ok)) in tests.into_iter().enumerate() {
+            match MemStorage::new_with_snapshot(snap.clone(), &ents) {
+                Ok(storage) => {
+                    assert!(ok, "#{}: expect error", i);
+                    assert_eq!(storage.first_index(), Ok(4), "#{}", i);
+                    assert_eq!(storage.last_index(), Ok(3 + ents.len() as u64), "#{}", i);
+                    assert_eq!(storage.term(3), Ok(2), "#{}", i);
+                    let state = storage.initial_state().unwrap();
+                    assert_eq!(state.hard_state.commit, 3, "#{}", i);
+                    assert_eq!(state.conf_state.voters, vec![1, 2, 3], "#{}", i);
+                }
+                Err(e) => {
+                    assert!(!ok, "#{}: unexpected error {:?}", i, e);
+                    assert!(
+                        matches!(e, RaftError::InvalidInitialLog(_)),
+                        "#{}: {:?}",
+                        i,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_storage_apply_snapshot() {
         let nodes = vec![1, 2, 3];
@@ -0,0 +1,181 @@
+// Copyright 2026 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::eraftpb::Entry;
+
+/// Keeps the terms of the latest appended entries, so that looking them up, e.g. to
+/// check votes, conflicts and commits, doesn't go to `Storage` once the entries are
+/// persisted.
+///
+/// The cached terms are of contiguous indexes, at most `capacity` of them, older ones
+/// are evicted first. A capacity of 0 disables the cache.
+#[derive(Debug, Default)]
+pub struct TermCache {
+    // The index of the first cached term.
+    offset: u64,
+    terms: VecDeque<u64>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TermCache {
+    /// Creates a cache holding the terms of at most `capacity` indexes.
+    pub fn new(capacity: usize) -> TermCache {
+        TermCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// The number of indexes the cache can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of cached terms.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    /// Whether no term is cached.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// The number of lookups served by the cache.
+    #[inline]
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of lookups that had to go to `Storage`.
+    #[inline]
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Changes the number of indexes to hold, evicting the oldest if it's exceeded.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Caches the terms of the entries appended to the log, replacing the conflicting
+    /// ones.
+    pub fn append(&mut self, ents: &[Entry]) {
+        if self.capacity == 0 || ents.is_empty() {
+            return;
+        }
+        let first = ents[0].index;
+        if first > self.offset && first <= self.offset + self.terms.len() as u64 {
+            self.terms.truncate((first - self.offset) as usize);
+        } else {
+            self.terms.clear();
+            self.offset = first;
+        }
+        self.terms.extend(ents.iter().map(|e| e.term));
+        self.evict();
+    }
+
+    /// Returns the term of `idx` if it's cached.
+    pub fn get(&self, idx: u64) -> Option<u64> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let term = idx
+            .checked_sub(self.offset)
+            .and_then(|i| self.terms.get(i as usize).copied());
+        let counter = if term.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        term
+    }
+
+    /// Returns the terms of `[low, high)` if all of them are cached.
+    pub fn get_range(&self, low: u64, high: u64) -> Option<Vec<u64>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        if low < self.offset || high > self.offset + self.terms.len() as u64 {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let range = (low - self.offset) as usize..(high - self.offset) as usize;
+        Some(self.terms.range(range).copied().collect())
+    }
+
+    /// Drops all the cached terms.
+    pub fn clear(&mut self) {
+        self.terms.clear();
+    }
+
+    fn evict(&mut self) {
+        while self.terms.len() > self.capacity {
+            self.terms.pop_front();
+            self.offset += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_entries(low: u64, high: u64, term: u64) -> Vec<Entry> {
+        (low..high)
+            .map(|i| {
+                let mut e = Entry::default();
+                e.index = i;
+                e.term = term;
+                e
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_term_cache() {
+        let mut cache = TermCache::new(3);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.misses(), 1);
+
+        cache.append(&new_entries(1, 3, 1));
+        assert_eq!(cache.get(1), Some(1));
+        assert_eq!(cache.get_range(1, 3), Some(vec![1, 1]));
+        assert_eq!(cache.hits(), 2);
+
+        // The oldest terms are evicted.
+        cache.append(&new_entries(3, 5, 2));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get_range(2, 5), Some(vec![1, 2, 2]));
+        assert_eq!(cache.get_range(1, 3), None);
+
+        // Conflicting terms are replaced.
+        cache.append(&new_entries(4, 5, 3));
+        assert_eq!(cache.get_range(2, 5), Some(vec![1, 2, 3]));
+        assert_eq!(cache.get(5), None);
+
+        // Terms that are not contiguous replace everything.
+        cache.append(&new_entries(7, 8, 3));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(4), None);
+        assert_eq!(cache.get(7), Some(3));
+        assert_eq!((cache.hits(), cache.misses()), (5, 5));
+
+        cache.set_capacity(0);
+        assert!(cache.is_empty());
+        cache.append(&new_entries(8, 9, 3));
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(8), None);
+    }
+}
@@ -24,10 +24,12 @@ pub use self::state::ProgressState;
 
 use slog::Logger;
 
-use crate::confchange::{MapChange, MapChangeType};
-use crate::eraftpb::ConfState;
+use crate::confchange::{self, MapChange, MapChangeType};
+pub use crate::eraftpb::ProgressTrackerState;
+use crate::eraftpb::{ConfState, PeerProgress, PeerProgressState};
 use crate::quorum::{AckedIndexer, Index, VoteResult};
-use crate::{DefaultHashBuilder, HashMap, HashSet, JointConfig};
+use crate::{DefaultHashBuilder, Error, HashMap, HashSet, JointConfig, Result};
+use std::cmp;
 use std::fmt::Debug;
 
 /// Config reflects the configuration tracked in a ProgressTracker.
@@ -87,6 +89,10 @@ pub struct Configuration {
     /// initiates the transition manually.
     #[get = "pub"]
     pub(crate) auto_leave: bool,
+    /// The peers that are witnesses. A witness only keeps log metadata, so it must
+    /// never become leader, and the leader strips payloads before sending to it.
+    #[get = "pub"]
+    pub(crate) witnesses: HashSet<u64>,
 }
 
 // Display and crate::itertools used only for test
@@ -144,6 +150,7 @@ impl Configuration {
             auto_leave: false,
             learners: learners.into_iter().collect(),
             learners_next: HashSet::default(),
+            witnesses: HashSet::default(),
         }
     }
 
@@ -153,6 +160,7 @@ impl Configuration {
             learners: HashSet::with_capacity_and_hasher(learners, DefaultHashBuilder::default()),
             learners_next: HashSet::default(),
             auto_leave: false,
+            witnesses: HashSet::default(),
         }
     }
 
@@ -165,6 +173,7 @@ impl Configuration {
         state.set_learners(self.learners.iter().cloned().collect());
         state.set_learners_next(self.learners_next.iter().cloned().collect());
         state.auto_leave = self.auto_leave;
+        state.set_witnesses(self.witnesses.iter().cloned().collect());
         state
     }
 
@@ -173,9 +182,30 @@ impl Configuration {
         self.learners.clear();
         self.learners_next.clear();
         self.auto_leave = false;
+        self.witnesses.clear();
     }
 }
 
+// The plain data of a progress kept by `ProgressTrackerState`.
+fn peer_progress(id: u64, pr: &Progress) -> PeerProgress {
+    let mut p = PeerProgress::default();
+    p.id = id;
+    p.matched = pr.matched;
+    p.next_idx = pr.next_idx;
+    p.set_state(match pr.state {
+        ProgressState::Probe => PeerProgressState::ProgressProbe,
+        ProgressState::Replicate => PeerProgressState::ProgressReplicate,
+        ProgressState::Snapshot => PeerProgressState::ProgressSnapshot,
+    });
+    p.paused = pr.paused;
+    p.pending_snapshot = pr.pending_snapshot;
+    p.pending_request_snapshot = pr.pending_request_snapshot;
+    p.recent_active = pr.recent_active;
+    p.commit_group_id = pr.commit_group_id;
+    p.committed_index = pr.committed_index;
+    p
+}
+
 pub type ProgressMap = HashMap<u64, Progress>;
 
 impl AckedIndexer for ProgressMap {
@@ -203,6 +233,18 @@ pub struct ProgressTracker {
     max_inflight: usize,
 
     group_commit: bool,
+    /// Scratch buffer of the voters sorted by their acked index, reused by
+    /// `voters_sorted_by_matched` and `maximal_committed_index` to avoid allocating
+    /// on every call.
+    sorted_buf: Vec<(Index, u64)>,
+
+    /// The tick at which each peer is considered inactive unless it's marked
+    /// active again. Peers are removed once they expire.
+    deadlines: HashMap<u64, u64>,
+    /// How many ticks a peer stays active after `mark_active`.
+    inactive_timeout: u64,
+    /// The latest tick passed to `expire`.
+    now: u64,
     pub(crate) logger: Logger,
 }
 
@@ -228,10 +270,103 @@ impl ProgressTracker {
             votes: HashMap::with_capacity_and_hasher(voters, DefaultHashBuilder::default()),
             max_inflight,
             group_commit: false,
+            sorted_buf: Vec::with_capacity(voters),
+            deadlines: HashMap::default(),
+            inactive_timeout: 0,
+            now: 0,
             logger,
         }
     }
 
+    /// Captures the configuration, all progresses and votes, so the tracker can be
+    /// persisted and restored by `from_state`. The progresses and votes are sorted by
+    /// peer id, so two captures of the same view compare equal.
+    ///
+    /// The messages in flight and the inactivity deadlines are not captured.
+    pub fn to_state(&self) -> ProgressTrackerState {
+        let mut state = ProgressTrackerState::default();
+        let mut conf = self.conf.to_conf_state();
+        conf.mut_voters().sort_unstable();
+        conf.mut_voters_outgoing().sort_unstable();
+        conf.mut_learners().sort_unstable();
+        conf.mut_learners_next().sort_unstable();
+        conf.mut_witnesses().sort_unstable();
+        state.set_conf_state(conf);
+        let mut ids: Vec<_> = self.progress.keys().cloned().collect();
+        ids.sort_unstable();
+        for id in ids {
+            state
+                .mut_progress()
+                .push(peer_progress(id, &self.progress[&id]));
+        }
+        for (id, granted) in &self.votes {
+            if *granted {
+                state.mut_votes_granted().push(*id);
+            } else {
+                state.mut_votes_rejected().push(*id);
+            }
+        }
+        state.mut_votes_granted().sort_unstable();
+        state.mut_votes_rejected().sort_unstable();
+        state.max_inflight = self.max_inflight as u64;
+        state.group_commit = self.group_commit;
+        state
+    }
+
+    /// Restores a tracker from a state captured by `to_state`. The progresses
+    /// have no messages in flight.
+    pub fn from_state(state: ProgressTrackerState, logger: Logger) -> Self {
+        let conf = state.get_conf_state();
+        let max_inflight = state.max_inflight as usize;
+        let mut tracker =
+            Self::with_capacity(conf.voters.len(), conf.learners.len(), max_inflight, logger);
+        tracker
+            .conf
+            .voters
+            .incoming
+            .extend(conf.voters.iter().cloned());
+        tracker
+            .conf
+            .voters
+            .outgoing
+            .extend(conf.voters_outgoing.iter().cloned());
+        tracker.conf.learners.extend(conf.learners.iter().cloned());
+        tracker
+            .conf
+            .learners_next
+            .extend(conf.learners_next.iter().cloned());
+        tracker.conf.auto_leave = conf.auto_leave;
+        tracker
+            .conf
+            .witnesses
+            .extend(conf.witnesses.iter().cloned());
+        for p in state.get_progress() {
+            let mut pr = Progress::new(p.next_idx, max_inflight);
+            pr.matched = p.matched;
+            pr.state = match p.get_state() {
+                PeerProgressState::ProgressProbe => ProgressState::Probe,
+                PeerProgressState::ProgressReplicate => ProgressState::Replicate,
+                PeerProgressState::ProgressSnapshot => ProgressState::Snapshot,
+            };
+            pr.paused = p.paused;
+            pr.pending_snapshot = p.pending_snapshot;
+            pr.pending_request_snapshot = p.pending_request_snapshot;
+            pr.recent_active = p.recent_active;
+            pr.commit_group_id = p.commit_group_id;
+            pr.committed_index = p.committed_index;
+            pr.is_witness = tracker.conf.witnesses.contains(&p.id);
+            tracker.progress.insert(p.id, pr);
+        }
+        for id in state.get_votes_granted() {
+            tracker.votes.insert(*id, true);
+        }
+        for id in state.get_votes_rejected() {
+            tracker.votes.insert(*id, false);
+        }
+        tracker.group_commit = state.group_commit;
+        tracker
+    }
+
     /// Configures group commit.
     pub fn enable_group_commit(&mut self, enable: bool) {
         self.group_commit = enable;
@@ -246,6 +381,7 @@ impl ProgressTracker {
         self.progress.clear();
         self.conf.clear();
         self.votes.clear();
+        self.deadlines.clear();
     }
 
     /// Returns true if (and only if) there is only one voting member
@@ -284,15 +420,67 @@ impl ProgressTracker {
         self.progress.iter_mut()
     }
 
+    /// Returns an iterator over the voters (of both halves of a joint config) and
+    /// their progress, sorted by `matched` in descending order. Ties are ordered
+    /// by id.
+    ///
+    /// The ordering is computed in an internal buffer that is reused between
+    /// calls and by `maximal_committed_index`, so this is cheap enough to call on
+    /// every tick even for wide configurations.
+    pub fn voters_sorted_by_matched(&mut self) -> impl Iterator<Item = (u64, &Progress)> {
+        self.sort_voters_by_matched();
+        let progress = &self.progress;
+        self.sorted_buf
+            .iter()
+            .filter_map(move |(_, id)| progress.get(id).map(|pr| (*id, pr)))
+    }
+
+    fn sort_voters_by_matched(&mut self) {
+        self.sorted_buf.clear();
+        for id in self.conf.voters.ids().iter() {
+            let index = self.progress.acked_index(id).unwrap_or_default();
+            self.sorted_buf.push((index, id));
+        }
+        self.sorted_buf
+            .sort_unstable_by(|a, b| b.0.index.cmp(&a.0.index).then(a.1.cmp(&b.1)));
+    }
+
     /// Returns the maximal committed index for the cluster. The bool flag indicates whether
     /// the index is computed by group commit algorithm successfully.
     ///
     /// Eg. If the matched indexes are [2,2,2,4,5], it will return 2.
     /// If the matched indexes and groups are `[(1, 1), (2, 2), (3, 2)]`, it will return 1.
     pub fn maximal_committed_index(&mut self) -> (u64, bool) {
+        self.sort_voters_by_matched();
+        let sorted = self.sorted_buf.iter().map(|(index, id)| (*id, *index));
         self.conf
             .voters
-            .committed_index(self.group_commit, &self.progress)
+            .committed_index_sorted(self.group_commit, sorted)
+    }
+
+    /// Returns the highest index matched by a voter that is not a witness, the leader
+    /// included. It's `None` if no voter is a witness, in which case any quorum holds
+    /// the data of the entries it matched.
+    ///
+    /// Capping the commit index with it only matters when the witnesses alone make
+    /// up a quorum: any other quorum includes a voter holding the data. The leader
+    /// counts once it has persisted the entries, so an entry may be committed while
+    /// only the leader holds its data, e.g. when the other data replicas are down.
+    pub fn max_non_witness_matched(&self) -> Option<u64> {
+        let mut has_witness = false;
+        let mut matched = 0;
+        for id in self.conf.voters.ids().iter() {
+            match self.progress.get(&id) {
+                Some(pr) if pr.is_witness => has_witness = true,
+                Some(pr) => matched = cmp::max(matched, pr.matched),
+                None => {}
+            }
+        }
+        if has_witness {
+            Some(matched)
+        } else {
+            None
+        }
     }
 
     /// Prepares for a new round of vote counting via recordVote.
@@ -358,6 +546,52 @@ impl ProgressTracker {
         self.has_quorum(&active)
     }
 
+    /// Sets how many ticks a peer stays active after it's marked by `mark_active`.
+    pub fn set_inactive_timeout(&mut self, ticks: u64) {
+        self.inactive_timeout = ticks;
+    }
+
+    /// Marks the peer as active, pushing its deadline to `inactive_timeout` ticks
+    /// after the latest tick passed to `expire`.
+    ///
+    /// Peers that are not tracked are ignored.
+    pub fn mark_active(&mut self, id: u64) {
+        if self.progress.contains_key(&id) {
+            self.deadlines.insert(id, self.now + self.inactive_timeout);
+        }
+    }
+
+    /// Advances the clock to `now_ticks` and gives all the peers but `self_id` a new
+    /// deadline, e.g. when a node becomes leader.
+    pub(crate) fn reset_deadlines(&mut self, now_ticks: u64, self_id: u64) {
+        self.now = cmp::max(self.now, now_ticks);
+        self.deadlines.clear();
+        let deadline = self.now + self.inactive_timeout;
+        for id in self.progress.keys() {
+            if *id != self_id {
+                self.deadlines.insert(*id, deadline);
+            }
+        }
+    }
+
+    /// Advances the clock to `now_ticks` and returns the peers whose deadline has
+    /// passed since the last call. Each peer is reported only once; it has to be
+    /// marked active again before it can expire again.
+    pub fn expire(&mut self, now_ticks: u64) -> Vec<u64> {
+        self.now = cmp::max(self.now, now_ticks);
+        let now = self.now;
+        let mut expired = vec![];
+        self.deadlines.retain(|id, deadline| {
+            if *deadline < now {
+                expired.push(*id);
+                return false;
+            }
+            true
+        });
+        expired.sort_unstable();
+        expired
+    }
+
     /// Determine if a quorum is formed from the given set of nodes.
     ///
     /// This is the only correct way to verify you have reached a quorum for the whole group.
@@ -374,6 +608,22 @@ impl ProgressTracker {
         &self.progress
     }
 
+    /// Marks whether the given peer is a witness, in both the configuration and its
+    /// progress.
+    pub fn set_witness(&mut self, id: u64, is_witness: bool) -> Result<()> {
+        let pr = match self.progress.get_mut(&id) {
+            Some(pr) => pr,
+            None => return Err(Error::NotExists(id, "progress")),
+        };
+        pr.is_witness = is_witness;
+        if is_witness {
+            self.conf.witnesses.insert(id);
+        } else {
+            self.conf.witnesses.remove(&id);
+        }
+        Ok(())
+    }
+
     /// Applies configuration and updates progress map to match the configuration.
     pub fn apply_conf(&mut self, conf: Configuration, changes: MapChange, next_idx: u64) {
         self.conf = conf;
@@ -381,16 +631,266 @@ impl ProgressTracker {
             match change_type {
                 MapChangeType::Add => {
                     let mut pr = Progress::new(next_idx, self.max_inflight);
+                    pr.is_witness = self.conf.witnesses.contains(&id);
                     // When a node is first added, we should mark it as recently active.
                     // Otherwise, CheckQuorum may cause us to step down if it is invoked
                     // before the added node has had a chance to communicate with us.
                     pr.recent_active = true;
                     self.progress.insert(id, pr);
+                    // Likewise, it's given a full inactivity timeout.
+                    if self.inactive_timeout > 0 {
+                        self.deadlines.insert(id, self.now + self.inactive_timeout);
+                    }
                 }
                 MapChangeType::Remove => {
                     self.progress.remove(&id);
+                    self.deadlines.remove(&id);
+                    self.conf.witnesses.remove(&id);
                 }
             }
         }
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.check_invariants() {
+                fatal!(self.logger, "invalid progress tracker state: {}", e);
+            }
+        }
+    }
+
+    /// Cross-validates the configuration against the progress map.
+    ///
+    /// It's the runtime counterpart of the checks done by `Changer`: every member
+    /// must have a progress and every progress must belong to a member, learners
+    /// and voters must not intersect, and `learners_next` is only allowed when the
+    /// configuration is joint. It's invoked automatically after applying a
+    /// configuration when debug assertions are enabled.
+    pub fn check_invariants(&self) -> Result<()> {
+        let conf = &self.conf;
+        for id in conf.voters.ids().iter() {
+            if !self.progress.contains_key(&id) {
+                return Err(Error::ConfChangeError(format!(
+                    "no progress for voter {}",
+                    id
+                )));
+            }
+        }
+        for id in &conf.learners {
+            if !self.progress.contains_key(id) {
+                return Err(Error::ConfChangeError(format!(
+                    "no progress for learner {}",
+                    id
+                )));
+            }
+            if conf.voters.contains(*id) {
+                return Err(Error::ConfChangeError(format!(
+                    "{} is in both learners and voters",
+                    id
+                )));
+            }
+        }
+        for id in &conf.learners_next {
+            if !self.progress.contains_key(id) {
+                return Err(Error::ConfChangeError(format!(
+                    "no progress for learner(next) {}",
+                    id
+                )));
+            }
+            if !conf.voters.outgoing.contains(id) {
+                return Err(Error::ConfChangeError(format!(
+                    "{} is in learners_next but not in outgoing voters",
+                    id
+                )));
+            }
+        }
+        for id in &conf.witnesses {
+            if !self.progress.get(id).is_some_and(|pr| pr.is_witness) {
+                return Err(Error::ConfChangeError(format!(
+                    "witness {} has no progress marked as witness",
+                    id
+                )));
+            }
+        }
+        for (id, pr) in &self.progress {
+            if pr.is_witness && !conf.witnesses.contains(id) {
+                return Err(Error::ConfChangeError(format!(
+                    "progress {} is marked as witness but not in the configuration",
+                    id
+                )));
+            }
+        }
+        for id in self.progress.keys() {
+            if !conf.voters.contains(*id)
+                && !conf.learners.contains(id)
+                && !conf.learners_next.contains(id)
+            {
+                return Err(Error::ConfChangeError(format!(
+                    "progress {} is not in the configuration",
+                    id
+                )));
+            }
+        }
+        if !confchange::joint(conf) {
+            if !conf.learners_next.is_empty() {
+                return Err(Error::ConfChangeError(
+                    "learners_next must be empty when not joint".to_owned(),
+                ));
+            }
+            if conf.auto_leave {
+                return Err(Error::ConfChangeError(
+                    "auto_leave must be false when not joint".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_logger;
+    use protobuf::Message as PbMessage;
+
+    #[test]
+    fn test_check_invariants() {
+        let mut tr = ProgressTracker::new(10, default_logger());
+        assert_eq!(tr.check_invariants(), Ok(()));
+
+        tr.conf.voters.incoming.insert(1);
+        tr.progress.insert(1, Progress::new(1, 10));
+        assert_eq!(tr.check_invariants(), Ok(()));
+
+        // Voter without progress.
+        tr.conf.voters.incoming.insert(2);
+        assert!(tr.check_invariants().is_err());
+        tr.progress.insert(2, Progress::new(1, 10));
+        assert_eq!(tr.check_invariants(), Ok(()));
+
+        // Progress without membership.
+        tr.progress.insert(3, Progress::new(1, 10));
+        assert!(tr.check_invariants().is_err());
+        tr.conf.learners.insert(3);
+        assert_eq!(tr.check_invariants(), Ok(()));
+
+        // Learner in voters.
+        tr.conf.learners.insert(2);
+        assert!(tr.check_invariants().is_err());
+        tr.conf.learners.remove(&2);
+
+        // learners_next is only allowed when joint.
+        tr.conf.learners_next.insert(2);
+        assert!(tr.check_invariants().is_err());
+        tr.conf.voters.outgoing.insert(1);
+        tr.conf.voters.outgoing.insert(2);
+        assert_eq!(tr.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_voters_sorted_by_matched() {
+        let mut tr = ProgressTracker::new(10, default_logger());
+        for (id, matched) in [(1, 5), (2, 7), (3, 5), (4, 1)] {
+            tr.conf.voters.incoming.insert(id);
+            let mut pr = Progress::new(matched + 1, 10);
+            pr.matched = matched;
+            tr.progress.insert(id, pr);
+        }
+        // Learners are not included.
+        tr.conf.learners.insert(5);
+        tr.progress.insert(5, Progress::new(100, 10));
+        // Outgoing voters are.
+        tr.conf.voters.outgoing.insert(6);
+        let mut pr = Progress::new(4, 10);
+        pr.matched = 3;
+        tr.progress.insert(6, pr);
+
+        let sorted: Vec<_> = tr
+            .voters_sorted_by_matched()
+            .map(|(id, pr)| (id, pr.matched))
+            .collect();
+        assert_eq!(sorted, vec![(2, 7), (1, 5), (3, 5), (6, 3), (4, 1)]);
+
+        // The buffer is reused and reflects the latest progress.
+        tr.get_mut(4).unwrap().matched = 9;
+        let ids: Vec<_> = tr.voters_sorted_by_matched().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![4, 2, 1, 3, 6]);
+
+        // The commit index is computed from the same view.
+        for group_commit in [false, true] {
+            tr.enable_group_commit(group_commit);
+            for (id, group) in [(1, 1), (2, 2), (3, 1), (4, 2), (6, 1)] {
+                tr.get_mut(id).unwrap().commit_group_id = group;
+            }
+            let expected = tr.conf.voters.committed_index(group_commit, &tr.progress);
+            assert_eq!(tr.maximal_committed_index(), expected);
+        }
+        assert_eq!(tr.maximal_committed_index(), (3, false));
+    }
+
+    #[test]
+    fn test_inactivity_deadlines() {
+        let mut tr = ProgressTracker::new(10, default_logger());
+        for id in 1..=3 {
+            tr.conf.voters.incoming.insert(id);
+            tr.progress.insert(id, Progress::new(1, 10));
+        }
+        tr.set_inactive_timeout(3);
+        tr.mark_active(1);
+        tr.mark_active(2);
+        // Unknown peers are ignored.
+        tr.mark_active(4);
+
+        assert!(tr.expire(2).is_empty());
+        tr.mark_active(2);
+        assert!(tr.expire(3).is_empty());
+        assert_eq!(tr.expire(4), vec![1]);
+        // Already reported.
+        assert!(tr.expire(4).is_empty());
+        assert_eq!(tr.expire(6), vec![2]);
+
+        // Peers become eligible again once marked active.
+        tr.mark_active(1);
+        tr.mark_active(3);
+        assert!(tr.expire(9).is_empty());
+        assert_eq!(tr.expire(10), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_to_state_from_state() {
+        let mut tr = ProgressTracker::new(10, default_logger());
+        tr.enable_group_commit(true);
+        for id in [3, 1, 2] {
+            tr.conf.voters.incoming.insert(id);
+            tr.progress.insert(id, Progress::new(5, 10));
+        }
+        tr.conf.voters.outgoing.insert(1);
+        tr.conf.voters.outgoing.insert(4);
+        tr.conf.learners_next.insert(4);
+        tr.progress.insert(4, Progress::new(5, 10));
+        tr.conf.auto_leave = true;
+        tr.get_mut(2).unwrap().become_replicate();
+        tr.get_mut(2).unwrap().maybe_update(7);
+        tr.record_vote(2, true);
+        tr.record_vote(1, false);
+
+        let state = tr.to_state();
+        assert_eq!(state.get_conf_state().voters, vec![1, 2, 3]);
+        assert_eq!(state.get_votes_granted(), &[2]);
+        assert_eq!(state.get_votes_rejected(), &[1]);
+        let ids: Vec<_> = state.get_progress().iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        // The state survives an encoding round trip.
+        let mut decoded = ProgressTrackerState::default();
+        decoded
+            .merge_from_bytes(&state.write_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(decoded, state);
+
+        let restored = ProgressTracker::from_state(decoded.clone(), default_logger());
+        assert_eq!(restored.conf(), tr.conf());
+        assert_eq!(restored.progress(), tr.progress());
+        assert_eq!(restored.votes(), tr.votes());
+        assert!(restored.group_commit());
+        assert_eq!(restored.to_state(), decoded);
+        assert_eq!(restored.check_invariants(), Ok(()));
     }
 }
@@ -53,6 +53,10 @@ pub struct Progress {
 
     /// Committed index in raft_log
     pub committed_index: u64,
+
+    /// Whether the peer is a witness. A witness only keeps log metadata, so the
+    /// leader strips entry payloads and snapshot data before sending to it.
+    pub is_witness: bool,
 }
 
 impl Progress {
@@ -69,6 +73,7 @@ impl Progress {
             ins: Inflights::new(ins_size),
             commit_group_id: 0,
             committed_index: 0,
+            is_witness: false,
         }
     }
 
@@ -263,7 +268,7 @@ mod tests {
 
     #[test]
     fn test_progress_is_paused() {
-        let tests = vec![
+        let tests = [
             (ProgressState::Probe, false, false),
             (ProgressState::Probe, true, true),
             (ProgressState::Replicate, false, false),
@@ -296,7 +301,7 @@ mod tests {
     #[test]
     fn test_progress_become_probe() {
         let matched = 1u64;
-        let mut tests = vec![
+        let mut tests = [
             (
                 new_progress(ProgressState::Replicate, matched, 5, 0, 256),
                 2,
@@ -350,7 +355,7 @@ mod tests {
     #[test]
     fn test_progress_update() {
         let (prev_m, prev_n) = (3u64, 5u64);
-        let tests = vec![
+        let tests = [
             (prev_m - 1, prev_m, prev_n, false),
             (prev_m, prev_m, prev_n, false),
             (prev_m + 1, prev_m + 1, prev_n, true),
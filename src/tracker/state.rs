@@ -18,9 +18,10 @@ use std::fmt;
 use std::fmt::{Display, Formatter};
 
 /// The state of the progress.
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum ProgressState {
     /// Whether it's probing.
+    #[default]
     Probe,
     /// Whether it's replicating.
     Replicate,
@@ -28,12 +29,6 @@ pub enum ProgressState {
     Snapshot,
 }
 
-impl Default for ProgressState {
-    fn default() -> ProgressState {
-        ProgressState::Probe
-    }
-}
-
 impl Display for ProgressState {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
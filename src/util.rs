@@ -6,7 +6,6 @@
 use slog::{OwnedKVList, Record, KV};
 use std::fmt;
 use std::fmt::Write;
-use std::u64;
 
 use crate::eraftpb::{Entry, Message};
 use crate::HashSet;
@@ -25,7 +24,7 @@ pub const NO_LIMIT: u64 = u64::MAX;
 ///
 /// let template = {
 ///     let mut entry = Entry::default();
-///     entry.data = "*".repeat(100).into_bytes();
+///     entry.data = "*".repeat(100).into_bytes().into();
 ///     entry
 /// };
 ///
@@ -47,29 +46,40 @@ pub const NO_LIMIT: u64 = u64::MAX;
 /// assert_eq!(entries.len(), 1);
 /// ```
 pub fn limit_size<T: PbMessage + Clone>(entries: &mut Vec<T>, max: Option<u64>) {
-    if entries.len() <= 1 {
-        return;
-    }
+    let len = limit_len(entries, max);
+    entries.truncate(len);
+}
+
+/// Returns the number of leading entries `limit_size` keeps, so a storage can stop
+/// copying entries once the limit is reached. The size of an entry is its encoded size,
+/// and the first entry is always kept even if it's larger than `max`.
+///
+/// # Examples
+///
+/// ```
+/// use raft::{util::limit_len, prelude::*};
+///
+/// let mut entry = Entry::default();
+/// entry.data = "*".repeat(100).into_bytes().into();
+/// let entries = vec![entry; 5];
+///
+/// assert_eq!(limit_len(&entries, Some(220)), 2);
+/// assert_eq!(limit_len(&entries, Some(0)), 1);
+/// assert_eq!(limit_len(&entries, None), 5);
+/// ```
+pub fn limit_len<T: PbMessage>(entries: &[T], max: Option<u64>) -> usize {
     let max = match max {
-        None | Some(NO_LIMIT) => return,
+        None | Some(NO_LIMIT) => return entries.len(),
         Some(max) => max,
     };
-
     let mut size = 0;
-    let limit = entries
-        .iter()
-        .take_while(|&e| {
-            if size == 0 {
-                size += u64::from(e.compute_size());
-                true
-            } else {
-                size += u64::from(e.compute_size());
-                size <= max
-            }
-        })
-        .count();
-
-    entries.truncate(limit);
+    for (i, e) in entries.iter().enumerate() {
+        size += u64::from(e.compute_size());
+        if i > 0 && size > max {
+            return i;
+        }
+    }
+    entries.len()
 }
 
 /// Check whether the entry is continuous to the message.
@@ -150,6 +160,42 @@ impl<'a> Union<'a> {
     /// The time complexity is O(n).
     pub fn len(&self) -> usize {
         // Usually, second is empty.
-        self.first.len() + self.second.len() - self.second.intersection(&self.first).count()
+        self.first.len() + self.second.len() - self.second.intersection(self.first).count()
+    }
+}
+
+#[cfg(any(feature = "wal-storage", feature = "sled-storage"))]
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+#[cfg(any(feature = "wal-storage", feature = "sled-storage"))]
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Computes the IEEE CRC32 of the concatenation of `parts`.
+#[cfg(any(feature = "wal-storage", feature = "sled-storage"))]
+pub(crate) fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for b in *part {
+            crc = CRC32_TABLE[((crc ^ u32::from(*b)) & 0xff) as usize] ^ (crc >> 8);
+        }
     }
+    !crc
 }
@@ -0,0 +1,1285 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A file-backed, append-only write ahead log implementation of `Storage`.
+//!
+//! `WalStorage` keeps the raft log in memory like `MemStorage`, and writes every change
+//! to a series of segment files in a directory. Every record is checksummed, so a torn
+//! write at the tail of the log is detected and discarded when the log is opened again.
+//!
+//! Every segment but the first one starts with a checkpoint of the state when it's
+//! created, so the segments before it can be deleted by `WalStorage::purge_to` once
+//! their entries are compacted.
+
+use std::cmp;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLockReadGuard};
+use std::time::Instant;
+
+use protobuf::Message as PbMessage;
+
+use crate::eraftpb::{ConfState, Entry, HardState, Snapshot, SnapshotMetadata};
+use crate::errors::{Error, Result, StorageError};
+use crate::instrumentation::{StorageInstrumentation, StorageOp};
+use crate::purge::PurgeStorage;
+use crate::storage::{
+    check_initial_log, entries_size, GetEntriesContext, MemStorage, MemStorageCore, RaftState,
+    Storage, SyncPolicy,
+};
+use crate::util::crc32;
+
+const SEGMENT_EXT: &str = "wal";
+// A segment is written under this extension until its checkpoint is durable.
+const TEMP_EXT: &str = "tmp";
+
+// Every record starts with the payload length, the checksum of kind and payload, and
+// the kind of the record.
+const RECORD_HEADER_SIZE: usize = 9;
+
+const RECORD_ENTRY: u8 = 1;
+const RECORD_HARD_STATE: u8 = 2;
+const RECORD_CONF_STATE: u8 = 3;
+const RECORD_SNAPSHOT: u8 = 4;
+const RECORD_COMPACT: u8 = 5;
+const RECORD_CHECKPOINT: u8 = 6;
+const RECORD_APPLIED: u8 = 7;
+
+/// The options of a `WalStorage`.
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    /// A new segment file is started once the active one grows beyond this size. Writes
+    /// are never split, so a segment can be larger by the size of one write.
+    pub segment_size: u64,
+    /// Whether every write is fsynced before it returns. Otherwise writes are only made
+    /// durable by `WalStorage::sync`, so that several writes can share one fsync.
+    pub sync_on_write: bool,
+}
+
+impl Default for WalConfig {
+    fn default() -> WalConfig {
+        WalConfig {
+            segment_size: 64 * 1024 * 1024,
+            sync_on_write: false,
+        }
+    }
+}
+
+/// The statistics of the segment files of a `WalStorage`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WalMetrics {
+    /// The number of segment files.
+    pub segments: usize,
+    /// The total size of the segment files.
+    pub bytes: u64,
+    /// The number of segment files deleted by `purge_to` since the log is opened.
+    pub purged_segments: u64,
+    /// The bytes reclaimed by `purge_to` since the log is opened.
+    pub reclaimed_bytes: u64,
+    /// The number of purged segment files moved to the archive since the log is opened.
+    pub archived_segments: u64,
+}
+
+/// Retains the segments purged from a `WalStorage`, e.g. on cheaper storage, when the
+/// log must be kept longer than the WAL directory can hold it.
+///
+/// Registered with `WalStorage::set_archive`, a segment is archived before it's deleted.
+/// The archived entries are not served to raft anymore, but they can be read back with
+/// `WalStorage::restore_archived`.
+pub trait WalArchive: Send + Sync {
+    /// Stores the segment file `seq`, whose largest entry index is `last_index`. If it
+    /// fails, the segment is not deleted and the purge returns the error.
+    fn archive(&self, seq: u64, last_index: u64, data: &[u8]) -> Result<()>;
+
+    /// Returns the sequence and the largest entry index of all the archived segments.
+    fn segments(&self) -> Result<Vec<(u64, u64)>>;
+
+    /// Returns the content of the archived segment `seq`.
+    fn read(&self, seq: u64) -> Result<Vec<u8>>;
+}
+
+/// A `WalArchive` keeping the segments as files in a directory.
+#[derive(Clone, Debug)]
+pub struct WalDirArchive {
+    dir: PathBuf,
+}
+
+impl WalDirArchive {
+    /// Creates an archive in `dir`, creating it if it doesn't exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<WalDirArchive> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(WalDirArchive {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, seq: u64, last_index: u64) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}-{:016x}.{}", seq, last_index, SEGMENT_EXT))
+    }
+}
+
+impl WalArchive for WalDirArchive {
+    fn archive(&self, seq: u64, last_index: u64, data: &[u8]) -> Result<()> {
+        let mut f = File::create(self.path(seq, last_index))?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        sync_dir(&self.dir)
+    }
+
+    fn segments(&self) -> Result<Vec<(u64, u64)>> {
+        let mut segments = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension() != Some(SEGMENT_EXT.as_ref()) {
+                continue;
+            }
+            let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            let mut parts = name.split('-').map(|p| u64::from_str_radix(p, 16).ok());
+            if let (Some(Some(seq)), Some(Some(last_index)), None) =
+                (parts.next(), parts.next(), parts.next())
+            {
+                segments.push((seq, last_index));
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    fn read(&self, seq: u64) -> Result<Vec<u8>> {
+        match self.segments()?.into_iter().find(|s| s.0 == seq) {
+            Some((seq, last_index)) => Ok(fs::read(self.path(seq, last_index))?),
+            None => Err(Error::Store(StorageError::Unavailable)),
+        }
+    }
+}
+
+struct Segment {
+    seq: u64,
+    size: u64,
+    // The largest index of the entries in the segment, 0 if there is none.
+    last_index: u64,
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:016x}.{}", seq, SEGMENT_EXT))
+}
+
+/// Appends a record of `kind` carrying `payload` to `buf`.
+fn encode_record(buf: &mut Vec<u8>, kind: u8, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(&[&[kind], payload]).to_le_bytes());
+    buf.push(kind);
+    buf.extend_from_slice(payload);
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn corruption(index: u64, details: String) -> Error {
+    Error::Store(StorageError::Corruption { index, details })
+}
+
+/// Decodes the record at the beginning of `buf`, returns `None` if it's incomplete or
+/// doesn't match its checksum.
+fn decode_record(buf: &[u8]) -> Option<(u8, &[u8])> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    let kind = buf[8];
+    let payload = buf.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+    if crc32(&[&[kind], payload]) != checksum {
+        return None;
+    }
+    Some((kind, payload))
+}
+
+fn decode_index(record: &str, payload: &[u8], core: &MemStorageCore) -> Result<u64> {
+    match payload.try_into() {
+        Ok(index) => Ok(u64::from_le_bytes(index)),
+        Err(_) => Err(corruption(
+            core.last_index() + 1,
+            format!("{} record of {} bytes", record, payload.len()),
+        )),
+    }
+}
+
+fn apply_record(kind: u8, payload: &[u8], core: &mut MemStorageCore) -> Result<()> {
+    match kind {
+        RECORD_HARD_STATE => {
+            let mut hs = HardState::default();
+            hs.merge_from_bytes(payload)?;
+            core.set_hardstate(hs);
+            Ok(())
+        }
+        RECORD_CONF_STATE => {
+            let mut cs = ConfState::default();
+            cs.merge_from_bytes(payload)?;
+            core.set_conf_state(cs);
+            Ok(())
+        }
+        RECORD_SNAPSHOT => {
+            let mut snapshot = Snapshot::default();
+            snapshot.mut_metadata().merge_from_bytes(payload)?;
+            core.apply_snapshot(snapshot)
+        }
+        RECORD_COMPACT => core.compact(decode_index("compact", payload, core)?),
+        RECORD_APPLIED => {
+            core.set_applied(decode_index("applied", payload, core)?);
+            Ok(())
+        }
+        RECORD_CHECKPOINT => {
+            let mut meta = SnapshotMetadata::default();
+            meta.merge_from_bytes(payload)?;
+            // The log may start from the very beginning, which is not a snapshot.
+            if meta.index == 0 {
+                core.set_conf_state(meta.take_conf_state());
+                return Ok(());
+            }
+            let mut snapshot = Snapshot::default();
+            snapshot.set_metadata(meta);
+            core.apply_snapshot(snapshot)
+        }
+        _ => Err(corruption(
+            core.last_index() + 1,
+            format!("unknown record kind {}", kind),
+        )),
+    }
+}
+
+/// Replays the records in `buf` into `core`, returns the size of the valid prefix and
+/// the largest index of the entries.
+///
+/// The checkpoint is only applied for the first segment, all the other segments have
+/// already been replayed into the state it records.
+fn replay(buf: &[u8], core: &mut MemStorageCore, first: bool) -> Result<(usize, u64)> {
+    let (mut offset, mut last_index) = (0, 0);
+    while let Some((kind, payload)) = decode_record(&buf[offset..]) {
+        match kind {
+            RECORD_ENTRY => {
+                let mut e = Entry::default();
+                e.merge_from_bytes(payload)?;
+                last_index = cmp::max(last_index, e.index);
+                core.append(&[e])?;
+            }
+            RECORD_CHECKPOINT if !first || offset > 0 => {}
+            _ => apply_record(kind, payload, core)?,
+        }
+        offset += RECORD_HEADER_SIZE + payload.len();
+    }
+    Ok((offset, last_index))
+}
+
+struct Wal {
+    dir: PathBuf,
+    cfg: WalConfig,
+    // Sorted by sequence, the last one is being appended to.
+    segments: Vec<Segment>,
+    writer: BufWriter<File>,
+    // Whether there are writes not fsynced yet.
+    dirty: bool,
+    purged_segments: u64,
+    reclaimed_bytes: u64,
+    archive: Option<Arc<dyn WalArchive>>,
+    archived_segments: u64,
+    // The index and details of the torn tail discarded by the recovery.
+    truncated: Option<(u64, String)>,
+    // The error of a failed write or fsync. The log may end with a partial record and
+    // be behind the in-memory state then, so nothing is written after it.
+    failed: Option<String>,
+}
+
+impl Wal {
+    fn open(dir: &Path, cfg: WalConfig, core: &mut MemStorageCore) -> Result<Wal> {
+        fs::create_dir_all(dir)?;
+        let mut seqs = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() == Some(TEMP_EXT.as_ref()) {
+                // A segment whose checkpoint was not durable yet.
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if path.extension() != Some(SEGMENT_EXT.as_ref()) {
+                continue;
+            }
+            let seq = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| u64::from_str_radix(s, 16).ok());
+            if let Some(seq) = seq {
+                seqs.push(seq);
+            }
+        }
+        seqs.sort_unstable();
+
+        let mut segments = Vec::with_capacity(seqs.len());
+        let mut truncated = None;
+        for (i, seq) in seqs.iter().enumerate() {
+            let path = segment_path(dir, *seq);
+            let buf = fs::read(&path)?;
+            if i > 0 && buf.is_empty() && i + 1 == seqs.len() {
+                // Created by a rotation that crashed before writing the checkpoint.
+                fs::remove_file(&path)?;
+                sync_dir(dir)?;
+                break;
+            }
+            if i > 0 && decode_record(&buf).map(|(kind, _)| kind) != Some(RECORD_CHECKPOINT) {
+                return Err(corruption(
+                    core.last_index() + 1,
+                    format!("{} doesn't start with a checkpoint", path.display()),
+                ));
+            }
+            let (valid, last_index) = replay(&buf, core, i == 0)?;
+            if valid < buf.len() {
+                // Only the last segment can be partially written by a crash.
+                let details = format!("invalid record at offset {} of {}", valid, path.display());
+                if i + 1 != seqs.len() {
+                    return Err(corruption(core.last_index() + 1, details));
+                }
+                let f = OpenOptions::new().write(true).open(&path)?;
+                f.set_len(valid as u64)?;
+                f.sync_all()?;
+                let details = format!("{}, {} bytes discarded", details, buf.len() - valid);
+                truncated = Some((core.last_index() + 1, details));
+            }
+            segments.push(Segment {
+                seq: *seq,
+                size: valid as u64,
+                last_index,
+            });
+        }
+        if segments.is_empty() {
+            File::create(segment_path(dir, 0))?;
+            sync_dir(dir)?;
+            segments.push(Segment {
+                seq: 0,
+                size: 0,
+                last_index: 0,
+            });
+        }
+
+        let active = segments.last().unwrap().seq;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir, active))?;
+        Ok(Wal {
+            dir: dir.to_path_buf(),
+            cfg,
+            segments,
+            writer: BufWriter::new(file),
+            dirty: false,
+            purged_segments: 0,
+            reclaimed_bytes: 0,
+            archive: None,
+            archived_segments: 0,
+            truncated,
+            failed: None,
+        })
+    }
+
+    fn check(&self) -> Result<()> {
+        match &self.failed {
+            Some(details) => Err(Error::Store(StorageError::Failed(details.clone()))),
+            None => Ok(()),
+        }
+    }
+
+    // Marks the log as failed if `res` is an error.
+    fn fail_on<T>(&mut self, res: Result<T>) -> Result<T> {
+        if let Err(e) = &res {
+            self.failed = Some(e.to_string());
+        }
+        res
+    }
+
+    /// Writes the records encoded in `buf` and finishes the write, which `mem` has
+    /// applied already. Starts a new segment if the active one is full and fsyncs if
+    /// `policy` tells, or the config without it. Any failure fails the log.
+    fn commit(
+        &mut self,
+        mem: &MemStorage,
+        buf: &[u8],
+        last_index: u64,
+        policy: Option<SyncPolicy>,
+    ) -> Result<()> {
+        let res = self.write_and_sync(mem, buf, last_index, policy);
+        self.fail_on(res)
+    }
+
+    fn write_and_sync(
+        &mut self,
+        mem: &MemStorage,
+        buf: &[u8],
+        last_index: u64,
+        policy: Option<SyncPolicy>,
+    ) -> Result<()> {
+        self.dirty = true;
+        self.writer.write_all(buf)?;
+        let active = self.segments.last_mut().unwrap();
+        active.size += buf.len() as u64;
+        active.last_index = cmp::max(active.last_index, last_index);
+        if active.size >= self.cfg.segment_size {
+            self.rotate(mem)?;
+        }
+        let sync = match policy {
+            Some(policy) => policy == SyncPolicy::Sync,
+            None => self.cfg.sync_on_write,
+        };
+        if sync {
+            self.flush_and_sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.check()?;
+        let res = self.flush_and_sync();
+        self.fail_on(res)
+    }
+
+    fn flush_and_sync(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // The new segment is written and fsynced under a temporary name and then renamed,
+    // so a segment found by `open` always starts with a durable checkpoint.
+    fn rotate(&mut self, mem: &MemStorage) -> Result<()> {
+        self.flush_and_sync()?;
+        let seq = self.segments.last().unwrap().seq + 1;
+
+        let state = mem.initial_state()?;
+        let mut meta = SnapshotMetadata::default();
+        meta.index = mem.last_index()?;
+        meta.term = mem.term(meta.index)?;
+        meta.set_conf_state(state.conf_state);
+        let mut buf = vec![];
+        encode_record(&mut buf, RECORD_CHECKPOINT, &meta.write_to_bytes()?);
+        encode_record(
+            &mut buf,
+            RECORD_HARD_STATE,
+            &state.hard_state.write_to_bytes()?,
+        );
+        encode_record(&mut buf, RECORD_APPLIED, &mem.rl().applied().to_le_bytes());
+
+        let path = segment_path(&self.dir, seq);
+        let temp = path.with_extension(TEMP_EXT);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&temp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, &path)?;
+        sync_dir(&self.dir)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        self.writer = BufWriter::new(file);
+        self.segments.push(Segment {
+            seq,
+            size: buf.len() as u64,
+            last_index: 0,
+        });
+        Ok(())
+    }
+
+    fn purge_to(&mut self, index: u64) -> Result<u64> {
+        // The checkpoint of the first segment left must be durable.
+        self.sync()?;
+        let mut reclaimed = 0;
+        while self.segments.len() > 1 && self.segments[0].last_index < index {
+            let path = segment_path(&self.dir, self.segments[0].seq);
+            if let Some(archive) = &self.archive {
+                let segment = &self.segments[0];
+                archive.archive(segment.seq, segment.last_index, &fs::read(&path)?)?;
+                self.archived_segments += 1;
+            }
+            let segment = self.segments.remove(0);
+            fs::remove_file(path)?;
+            self.purged_segments += 1;
+            reclaimed += segment.size;
+        }
+        if reclaimed > 0 {
+            sync_dir(&self.dir)?;
+        }
+        self.reclaimed_bytes += reclaimed;
+        Ok(reclaimed)
+    }
+
+    fn metrics(&self) -> WalMetrics {
+        WalMetrics {
+            segments: self.segments.len(),
+            bytes: self.segments.iter().map(|s| s.size).sum(),
+            purged_segments: self.purged_segments,
+            reclaimed_bytes: self.reclaimed_bytes,
+            archived_segments: self.archived_segments,
+        }
+    }
+}
+
+/// `WalStorage` is a persistent implementation of `Storage` backed by a write ahead log.
+///
+/// Changes are applied to the in-memory state first, which panics on invalid input just
+/// like `MemStorageCore`, and are then appended to the log. Unless
+/// `WalConfig::sync_on_write` is set, they're not durable until `sync` returns, so the
+/// application should call it before sending the messages of a `Ready` and advancing.
+///
+/// Once a write or fsync fails, the log may end with a partial record and be behind the
+/// in-memory state, so every later write and sync returns `StorageError::Failed`. The
+/// application should reopen the log to recover.
+#[derive(Clone)]
+pub struct WalStorage {
+    mem: MemStorage,
+    wal: Arc<Mutex<Wal>>,
+}
+
+impl WalStorage {
+    /// Opens the log in `dir`, creating it if it doesn't exist, and recovers the raft
+    /// state from it. A torn record at the tail of the log is discarded.
+    pub fn open<P: AsRef<Path>>(dir: P, cfg: WalConfig) -> Result<WalStorage> {
+        let mem = MemStorage::new();
+        let wal = Wal::open(dir.as_ref(), cfg, &mut mem.wl())?;
+        Ok(WalStorage {
+            mem,
+            wal: Arc::new(Mutex::new(wal)),
+        })
+    }
+
+    /// Creates the log in `dir` from an existing snapshot and the entries after it, e.g. to
+    /// rebuild a node, and syncs it. The snapshot data is not stored.
+    ///
+    /// Returns `Error::InvalidInitialLog` if the entries don't follow the snapshot or if
+    /// the log in `dir` already has entries or a snapshot.
+    pub fn open_with_snapshot<P: AsRef<Path>>(
+        dir: P,
+        cfg: WalConfig,
+        snapshot: Snapshot,
+        ents: &[Entry],
+    ) -> Result<WalStorage> {
+        check_initial_log(snapshot.get_metadata(), ents)?;
+        let storage = WalStorage::open(dir.as_ref(), cfg)?;
+        if storage.last_index()? != 0 {
+            return Err(Error::InvalidInitialLog(format!(
+                "log in {} is not empty",
+                dir.as_ref().display()
+            )));
+        }
+        storage.apply_snapshot(snapshot)?;
+        storage.append(ents)?;
+        storage.sync()?;
+        Ok(storage)
+    }
+
+    /// Opens up a read lock on the in-memory state and returns a guard handle.
+    pub fn rl(&self) -> RwLockReadGuard<'_, MemStorageCore> {
+        self.mem.rl()
+    }
+
+    /// Appends the new entries to the log.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `MemStorageCore::append`.
+    pub fn append(&self, ents: &[Entry]) -> Result<()> {
+        self.append_with(ents, None, None)
+    }
+
+    /// Appends the new entries and saves the HardState, if any, in one write, which
+    /// is how a `Ready` is usually persisted. The write is synced as `policy` tells
+    /// regardless of `WalConfig::sync_on_write`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `MemStorageCore::append`.
+    pub fn append_batch(
+        &self,
+        ents: &[Entry],
+        hs: Option<HardState>,
+        policy: SyncPolicy,
+    ) -> Result<()> {
+        self.append_with(ents, hs, Some(policy))
+    }
+
+    // Appends and reports to the instrumentation. Without a sync policy, the one of
+    // the config is used.
+    fn append_with(
+        &self,
+        ents: &[Entry],
+        hs: Option<HardState>,
+        policy: Option<SyncPolicy>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let res = self.write_batch(ents, hs, policy);
+        if let Some(i) = self.mem.rl().instrumentation() {
+            match &res {
+                Ok(()) => i.on_append(start.elapsed(), ents.len(), entries_size(ents)),
+                Err(e) => i.on_error(StorageOp::Append, e),
+            }
+        }
+        res
+    }
+
+    fn write_batch(
+        &self,
+        ents: &[Entry],
+        hs: Option<HardState>,
+        policy: Option<SyncPolicy>,
+    ) -> Result<()> {
+        let mut buf = vec![];
+        for e in ents {
+            encode_record(&mut buf, RECORD_ENTRY, &e.write_to_bytes()?);
+        }
+        if let Some(hs) = &hs {
+            encode_record(&mut buf, RECORD_HARD_STATE, &hs.write_to_bytes()?);
+        }
+        let last_index = ents.last().map_or(0, |e| e.index);
+        self.write(&buf, last_index, policy, |mem| {
+            let mut core = mem.wl();
+            core.append_entries(ents)?;
+            if let Some(hs) = hs {
+                core.set_hardstate(hs);
+            }
+            Ok(())
+        })
+    }
+
+    // Writes the records encoded in `buf` once `update` has applied them to the
+    // in-memory state. If the write fails, the log refuses any further write.
+    fn write<F>(
+        &self,
+        buf: &[u8],
+        last_index: u64,
+        policy: Option<SyncPolicy>,
+        update: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&MemStorage) -> Result<()>,
+    {
+        let mut wal = self.wal.lock().unwrap();
+        wal.check()?;
+        update(&self.mem)?;
+        wal.commit(&self.mem, buf, last_index, policy)
+    }
+
+    fn write_record<F>(&self, kind: u8, payload: &[u8], update: F) -> Result<()>
+    where
+        F: FnOnce(&MemStorage) -> Result<()>,
+    {
+        let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        encode_record(&mut buf, kind, payload);
+        self.write(&buf, 0, None, update)
+    }
+
+    /// Saves the current HardState.
+    pub fn set_hard_state(&self, hs: HardState) -> Result<()> {
+        self.write_record(RECORD_HARD_STATE, &hs.write_to_bytes()?, |mem| {
+            mem.wl().set_hardstate(hs);
+            Ok(())
+        })
+    }
+
+    /// Returns the saved HardState.
+    pub fn hard_state(&self) -> HardState {
+        self.mem.hard_state()
+    }
+
+    /// Saves the index of the last applied entry, see `MemStorage::set_applied`.
+    pub fn set_applied(&self, applied: u64) -> Result<()> {
+        self.write_record(RECORD_APPLIED, &applied.to_le_bytes(), |mem| {
+            mem.set_applied(applied);
+            Ok(())
+        })
+    }
+
+    /// Returns the saved applied index.
+    pub fn applied(&self) -> u64 {
+        self.mem.applied()
+    }
+
+    /// Saves the current conf state.
+    pub fn set_conf_state(&self, cs: ConfState) -> Result<()> {
+        self.write_record(RECORD_CONF_STATE, &cs.write_to_bytes()?, |mem| {
+            mem.wl().set_conf_state(cs);
+            Ok(())
+        })
+    }
+
+    /// Overwrites the state with the metadata of the given snapshot. The snapshot data is
+    /// not stored, it's up to the application.
+    pub fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+        let meta = snapshot.get_metadata().write_to_bytes()?;
+        self.write_record(RECORD_SNAPSHOT, &meta, |mem| {
+            mem.wl().apply_snapshot(snapshot)
+        })
+    }
+
+    /// Discards all log entries prior to compact_index.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `MemStorageCore::compact`.
+    pub fn compact(&self, compact_index: u64) -> Result<()> {
+        self.write_record(RECORD_COMPACT, &compact_index.to_le_bytes(), |mem| {
+            mem.wl().compact(compact_index)
+        })
+    }
+
+    /// Compacts the log to `index` and deletes the segments that only contain entries
+    /// before it. Returns the number of bytes reclaimed.
+    ///
+    /// `index` must not be greater than the applied index, and entries are still kept
+    /// in the active segment and the segments that contain any entry at or after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `MemStorageCore::compact`.
+    pub fn purge_to(&self, index: u64) -> Result<u64> {
+        self.compact(index)?;
+        self.wal.lock().unwrap().purge_to(index)
+    }
+
+    /// Sets the hooks to report the reads, appends and snapshot builds to. The reported
+    /// append latency includes writing the log.
+    pub fn set_instrumentation(&self, instrumentation: Arc<dyn StorageInstrumentation>) {
+        self.mem.wl().set_instrumentation(instrumentation);
+    }
+
+    /// Sets the archive the segments are moved to by `purge_to`, instead of being only
+    /// deleted.
+    pub fn set_archive(&self, archive: Arc<dyn WalArchive>) {
+        self.wal.lock().unwrap().archive = Some(archive);
+    }
+
+    /// Reads the entries in `[low, high)` back from the archive, e.g. for an audit. The
+    /// entries are not restored into the log.
+    ///
+    /// Returns `StorageError::Unavailable` if there is no archive or it doesn't hold all
+    /// the entries.
+    pub fn restore_archived(&self, low: u64, high: u64) -> Result<Vec<Entry>> {
+        let archive = self.wal.lock().unwrap().archive.clone();
+        let archive = archive.ok_or(Error::Store(StorageError::Unavailable))?;
+        let mut ents: Vec<Entry> = vec![];
+        for (seq, last_index) in archive.segments()? {
+            if last_index < low {
+                continue;
+            }
+            let buf = archive.read(seq)?;
+            let mut offset = 0;
+            while let Some((kind, payload)) = decode_record(&buf[offset..]) {
+                offset += RECORD_HEADER_SIZE + payload.len();
+                if kind != RECORD_ENTRY {
+                    continue;
+                }
+                let mut e = Entry::default();
+                e.merge_from_bytes(payload)?;
+                // A later entry overwrites the conflicting tail, like in the log.
+                while ents.last().is_some_and(|last| last.index >= e.index) {
+                    ents.pop();
+                }
+                if e.index >= low && e.index < high {
+                    ents.push(e);
+                }
+            }
+            if last_index + 1 >= high {
+                break;
+            }
+        }
+        // The indexes are increasing, so they're all there if the count matches.
+        if ents.len() as u64 != high.saturating_sub(low) {
+            return Err(Error::Store(StorageError::Unavailable));
+        }
+        Ok(ents)
+    }
+
+    /// Returns the torn tail discarded when the log is opened, as a
+    /// `StorageError::Corruption` telling the first entry lost and where the log is
+    /// truncated. Entries after the last sync may be lost in a crash, but the
+    /// application should report it if they're expected to be durable.
+    pub fn truncated_tail(&self) -> Option<StorageError> {
+        let wal = self.wal.lock().unwrap();
+        let (index, details) = wal.truncated.clone()?;
+        Some(StorageError::Corruption { index, details })
+    }
+
+    /// Returns the statistics of the segment files.
+    pub fn metrics(&self) -> WalMetrics {
+        self.wal.lock().unwrap().metrics()
+    }
+
+    /// Flushes and fsyncs all the writes so far.
+    pub fn sync(&self) -> Result<()> {
+        self.wal.lock().unwrap().sync()
+    }
+}
+
+impl Storage for WalStorage {
+    /// Implements the Storage trait.
+    fn initial_state(&self) -> Result<RaftState> {
+        self.mem.initial_state()
+    }
+
+    /// Implements the Storage trait.
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
+        self.mem.entries(low, high, max_size, context)
+    }
+
+    /// Implements the Storage trait.
+    fn term(&self, idx: u64) -> Result<u64> {
+        self.mem.term(idx)
+    }
+
+    /// Implements the Storage trait.
+    fn terms(&self, range: Range<u64>) -> Result<Vec<u64>> {
+        self.mem.terms(range)
+    }
+
+    /// Implements the Storage trait.
+    fn first_index(&self) -> Result<u64> {
+        self.mem.first_index()
+    }
+
+    /// Implements the Storage trait.
+    fn last_index(&self) -> Result<u64> {
+        self.mem.last_index()
+    }
+
+    /// Implements the Storage trait.
+    fn snapshot(&self, request_index: u64) -> Result<Snapshot> {
+        self.mem.snapshot(request_index)
+    }
+}
+
+impl PurgeStorage for WalStorage {
+    fn purge(&self, compact_index: u64) -> Result<()> {
+        self.purge_to(compact_index).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir =
+                std::env::temp_dir().join(format!("raft-wal-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.term = term;
+        e.index = index;
+        e.data = vec![index as u8; 16].into();
+        e
+    }
+
+    fn segment_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn all_entries(s: &WalStorage) -> Vec<Entry> {
+        let (low, high) = (s.first_index().unwrap(), s.last_index().unwrap() + 1);
+        s.entries(low, high, None, GetEntriesContext::empty(false))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_wal_storage_recover() {
+        let dir = TempDir::new("recover");
+        let ents: Vec<_> = (1..=5).map(|i| new_entry(i, 1)).collect();
+        let mut hs = HardState::default();
+        hs.term = 2;
+        hs.vote = 1;
+        hs.commit = 3;
+        {
+            let s = WalStorage::open(&dir.0, WalConfig::default()).unwrap();
+            s.set_conf_state(ConfState::from((vec![1, 2, 3], vec![])))
+                .unwrap();
+            s.append(&ents).unwrap();
+            // Overwrites the conflicting tail.
+            s.append(&[new_entry(4, 2)]).unwrap();
+            s.set_hard_state(hs.clone()).unwrap();
+            s.set_applied(3).unwrap();
+            s.compact(2).unwrap();
+            s.sync().unwrap();
+        }
+
+        let s = WalStorage::open(&dir.0, WalConfig::default()).unwrap();
+        let state = s.initial_state().unwrap();
+        assert_eq!(state.hard_state, hs);
+        assert_eq!(s.hard_state(), hs);
+        assert_eq!(s.applied(), 3);
+        assert_eq!(state.conf_state.voters, vec![1, 2, 3]);
+        assert_eq!(
+            all_entries(&s),
+            vec![ents[1].clone(), ents[2].clone(), new_entry(4, 2)]
+        );
+
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 10;
+        snapshot.mut_metadata().term = 3;
+        s.apply_snapshot(snapshot).unwrap();
+        s.append(&[new_entry(11, 3)]).unwrap();
+        s.sync().unwrap();
+        drop(s);
+
+        let s = WalStorage::open(&dir.0, WalConfig::default()).unwrap();
+        assert_eq!(s.first_index(), Ok(11));
+        assert_eq!(s.term(10), Ok(3));
+        assert_eq!(all_entries(&s), vec![new_entry(11, 3)]);
+    }
+
+    #[test]
+    fn test_wal_storage_open_with_snapshot() {
+        let dir = TempDir::new("open-with-snapshot");
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 5;
+        snapshot.mut_metadata().term = 2;
+        snapshot.mut_metadata().mut_conf_state().voters = vec![1, 2];
+
+        let gap = [new_entry(7, 2)];
+        assert!(matches!(
+            WalStorage::open_with_snapshot(&dir.0, WalConfig::default(), snapshot.clone(), &gap),
+            Err(Error::InvalidInitialLog(_))
+        ));
+
+        let ents = vec![new_entry(6, 2), new_entry(7, 3)];
+        let s =
+            WalStorage::open_with_snapshot(&dir.0, WalConfig::default(), snapshot.clone(), &ents)
+                .unwrap();
+        drop(s);
+        assert!(matches!(
+            WalStorage::open_with_snapshot(&dir.0, WalConfig::default(), snapshot, &ents),
+            Err(Error::InvalidInitialLog(_))
+        ));
+
+        let s = WalStorage::open(&dir.0, WalConfig::default()).unwrap();
+        assert_eq!(s.first_index(), Ok(6));
+        assert_eq!(s.term(5), Ok(2));
+        assert_eq!(all_entries(&s), ents);
+        assert_eq!(s.initial_state().unwrap().conf_state.voters, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_wal_storage_torn_tail() {
+        let dir = TempDir::new("torn-tail");
+        let cfg = WalConfig {
+            sync_on_write: true,
+            ..Default::default()
+        };
+        {
+            let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+            s.append(&[new_entry(1, 1), new_entry(2, 1)]).unwrap();
+        }
+        let path = segment_files(&dir.0).pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        // Half of the last record is written.
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(len - 5).unwrap();
+
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        assert_eq!(all_entries(&s), vec![new_entry(1, 1)]);
+        assert!(matches!(
+            s.truncated_tail(),
+            Some(StorageError::Corruption { index: 2, .. })
+        ));
+        s.append(&[new_entry(2, 2)]).unwrap();
+        drop(s);
+
+        // A record with a mismatched checksum is discarded with everything after it.
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[3, 0, 0, 0, 1, 2, 3, 4, RECORD_COMPACT, 1, 2, 3])
+            .unwrap();
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        assert_eq!(all_entries(&s), vec![new_entry(1, 1), new_entry(2, 2)]);
+        match s.truncated_tail() {
+            Some(StorageError::Corruption { index, details }) => {
+                assert_eq!(index, 3);
+                assert!(details.ends_with("12 bytes discarded"), "{}", details);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+        drop(s);
+
+        // Nothing is discarded since the log is truncated already.
+        let s = WalStorage::open(&dir.0, cfg).unwrap();
+        assert!(s.truncated_tail().is_none());
+    }
+
+    #[test]
+    fn test_wal_storage_segments() {
+        let dir = TempDir::new("segments");
+        let cfg = WalConfig {
+            segment_size: 128,
+            ..Default::default()
+        };
+        let ents: Vec<_> = (1..=20).map(|i| new_entry(i, 1)).collect();
+        {
+            let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+            // A write is never split into two segments.
+            s.append(&ents[..10]).unwrap();
+            assert_eq!(s.metrics().segments, 2);
+            for e in ents[10..].chunks(1) {
+                s.append(e).unwrap();
+            }
+            s.sync().unwrap();
+        }
+        let files = segment_files(&dir.0);
+        assert!(files.len() > 3, "{:?}", files);
+
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        assert_eq!(all_entries(&s), ents);
+        drop(s);
+
+        // Only the last segment is allowed to have a torn tail.
+        let f = OpenOptions::new().write(true).open(&files[0]).unwrap();
+        f.set_len(fs::metadata(&files[0]).unwrap().len() - 1)
+            .unwrap();
+        // The first entry lost is reported.
+        assert!(matches!(
+            WalStorage::open(&dir.0, cfg).err(),
+            Some(Error::Store(StorageError::Corruption { index: 10, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_wal_storage_rotate_crash() {
+        let dir = TempDir::new("rotate-crash");
+        let cfg = WalConfig {
+            segment_size: 128,
+            sync_on_write: true,
+        };
+        let ents: Vec<_> = (1..=10).map(|i| new_entry(i, 1)).collect();
+        {
+            let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+            for e in ents.chunks(1) {
+                s.append(e).unwrap();
+            }
+        }
+        let files = segment_files(&dir.0);
+        assert!(files.len() > 2, "{:?}", files);
+        assert!(files.iter().all(|f| f.extension().unwrap() == SEGMENT_EXT));
+
+        // A crash left the next segment before its checkpoint was durable, with the old
+        // and the new way of rotating.
+        let last = files.last().unwrap();
+        let next = |ext| last.with_file_name(format!("{:016x}.{}", files.len(), ext));
+        fs::write(next(TEMP_EXT), b"partial checkpoint").unwrap();
+        File::create(next(SEGMENT_EXT)).unwrap();
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        assert_eq!(all_entries(&s), ents);
+        assert_eq!(segment_files(&dir.0), files);
+        s.append(&[new_entry(11, 1)]).unwrap();
+        drop(s);
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        assert_eq!(s.last_index(), Ok(11));
+        drop(s);
+
+        // Any other segment without a checkpoint is corrupted.
+        fs::write(&files[1], b"").unwrap();
+        assert!(matches!(
+            WalStorage::open(&dir.0, cfg).err(),
+            Some(Error::Store(StorageError::Corruption { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_wal_storage_purge() {
+        let dir = TempDir::new("purge");
+        let cfg = WalConfig {
+            segment_size: 128,
+            ..Default::default()
+        };
+        let ents: Vec<_> = (1..=20).map(|i| new_entry(i, 1)).collect();
+        let mut hs = HardState::default();
+        hs.term = 1;
+        hs.commit = 20;
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        s.set_conf_state(ConfState::from((vec![1, 2], vec![])))
+            .unwrap();
+        for e in ents.chunks(1) {
+            s.append(e).unwrap();
+            if e[0].index == 5 {
+                s.set_applied(5).unwrap();
+            }
+        }
+        s.set_hard_state(hs.clone()).unwrap();
+        let before = s.metrics();
+
+        assert_eq!(s.purge_to(1), Ok(0));
+        let reclaimed = s.purge_to(15).unwrap();
+        assert!(reclaimed > 0);
+        let after = s.metrics();
+        assert!(after.segments < before.segments);
+        assert_eq!(
+            after.purged_segments as usize,
+            before.segments - after.segments
+        );
+        assert_eq!(after.reclaimed_bytes, reclaimed);
+        assert_eq!(s.first_index(), Ok(15));
+        assert_eq!(segment_files(&dir.0).len(), after.segments);
+        drop(s);
+
+        // The state in the purged segments is recovered from the checkpoints.
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        let state = s.initial_state().unwrap();
+        assert_eq!(state.hard_state, hs);
+        assert_eq!(state.conf_state.voters, vec![1, 2]);
+        assert_eq!(s.applied(), 5);
+        assert_eq!(s.first_index(), Ok(15));
+        assert_eq!(all_entries(&s), &ents[14..]);
+
+        s.purge_to(20).unwrap();
+        s.append(&[new_entry(21, 2)]).unwrap();
+        s.sync().unwrap();
+        drop(s);
+        let s = WalStorage::open(&dir.0, cfg).unwrap();
+        assert_eq!(all_entries(&s), vec![ents[19].clone(), new_entry(21, 2)]);
+        assert_eq!(s.initial_state().unwrap().hard_state, hs);
+    }
+
+    #[test]
+    fn test_wal_storage_archive() {
+        let dir = TempDir::new("archive");
+        let archive_dir = TempDir::new("archive-segments");
+        let cfg = WalConfig {
+            segment_size: 128,
+            ..Default::default()
+        };
+        let ents: Vec<_> = (1..=20).map(|i| new_entry(i, 1)).collect();
+        let s = WalStorage::open(&dir.0, cfg).unwrap();
+        for e in ents.chunks(1) {
+            s.append(e).unwrap();
+        }
+        assert_eq!(
+            s.restore_archived(1, 5),
+            Err(Error::Store(StorageError::Unavailable))
+        );
+
+        let archive = WalDirArchive::new(&archive_dir.0).unwrap();
+        s.set_archive(Arc::new(archive.clone()));
+        let before = s.metrics();
+        s.purge_to(15).unwrap();
+        let after = s.metrics();
+        assert_eq!(
+            after.archived_segments as usize,
+            before.segments - after.segments
+        );
+        assert_eq!(
+            archive.segments().unwrap().len(),
+            after.archived_segments as usize
+        );
+        assert_eq!(s.first_index(), Ok(15));
+
+        assert_eq!(s.restore_archived(1, 5), Ok(ents[..4].to_vec()));
+        assert_eq!(s.restore_archived(3, 3), Ok(vec![]));
+        let last_archived = archive.segments().unwrap().last().unwrap().1;
+        assert_eq!(
+            s.restore_archived(2, last_archived + 1),
+            Ok(ents[1..last_archived as usize].to_vec())
+        );
+        // The entries still in the log are not archived.
+        assert_eq!(
+            s.restore_archived(1, 21),
+            Err(Error::Store(StorageError::Unavailable))
+        );
+    }
+
+    #[test]
+    fn test_wal_storage_write_failure() {
+        let dir = TempDir::new("write-failure");
+        let cfg = WalConfig {
+            sync_on_write: true,
+            ..Default::default()
+        };
+        let s = WalStorage::open(&dir.0, cfg.clone()).unwrap();
+        s.append(&[new_entry(1, 1)]).unwrap();
+
+        // Writes to a read only file fail once they're flushed.
+        let path = segment_files(&dir.0).pop().unwrap();
+        s.wal.lock().unwrap().writer = BufWriter::new(File::open(&path).unwrap());
+        assert!(s.append(&[new_entry(2, 1)]).is_err());
+        assert!(matches!(
+            s.append(&[new_entry(3, 1)]),
+            Err(Error::Store(StorageError::Failed(_)))
+        ));
+        assert!(matches!(
+            s.set_hard_state(HardState::default()),
+            Err(Error::Store(StorageError::Failed(_)))
+        ));
+        assert!(matches!(
+            s.sync(),
+            Err(Error::Store(StorageError::Failed(_)))
+        ));
+        assert_eq!(s.last_index(), Ok(2));
+        drop(s);
+
+        let s = WalStorage::open(&dir.0, cfg).unwrap();
+        assert_eq!(all_entries(&s), vec![new_entry(1, 1)]);
+    }
+
+    #[test]
+    fn test_wal_storage_append_batch() {
+        let dir = TempDir::new("append-batch");
+        let s = WalStorage::open(&dir.0, WalConfig::default()).unwrap();
+        let path = segment_files(&dir.0).pop().unwrap();
+        let mut hs = HardState::default();
+        hs.term = 1;
+        hs.commit = 1;
+        s.append_batch(&[new_entry(1, 1)], Some(hs.clone()), SyncPolicy::Defer)
+            .unwrap();
+        // Deferred writes are left in the buffer.
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        hs.commit = 2;
+        s.append_batch(&[new_entry(2, 1)], Some(hs.clone()), SyncPolicy::Sync)
+            .unwrap();
+        assert_eq!(s.metrics().bytes, fs::metadata(&path).unwrap().len());
+        s.append_batch(&[], None, SyncPolicy::Sync).unwrap();
+        drop(s);
+
+        let s = WalStorage::open(&dir.0, WalConfig::default()).unwrap();
+        assert_eq!(s.initial_state().unwrap().hard_state, hs);
+        assert_eq!(all_entries(&s), vec![new_entry(1, 1), new_entry(2, 1)]);
+    }
+}